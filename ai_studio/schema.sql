@@ -117,4 +117,104 @@ CREATE TABLE audio_batch_data (
     "created_at" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
     "updated_at" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
     PRIMARY KEY ("id")
-);
\ No newline at end of file
+);
+
+-- When the video was charged to the credits ledger. Videos that existed before charging was
+-- introduced are backfilled as charged.
+ALTER TABLE generated_videos ADD COLUMN IF NOT EXISTS charged_at TIMESTAMP(3) DEFAULT CURRENT_TIMESTAMP;
+ALTER TABLE generated_videos ALTER COLUMN charged_at DROP DEFAULT;
+
+-- Owner of each WsBoard document, the first user to open it. Only owners can share a board.
+CREATE TABLE IF NOT EXISTS ws_boards (
+    id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id)
+);
+
+-- Row-level security: rows are only visible to the tenant set by microservice_utils::server::tenant
+-- (app.current_user / app.current_workspace). Without a tenant in the transaction nothing is
+-- visible; internal paths that cross tenants use a service transaction (app.service_role).
+-- Superusers and BYPASSRLS roles skip policies entirely, so the service has to connect as a
+-- regular role for this to take effect.
+CREATE OR REPLACE FUNCTION app_current_user() RETURNS TEXT AS $$
+    SELECT NULLIF(current_setting('app.current_user', true), '')
+$$ LANGUAGE SQL STABLE;
+
+CREATE OR REPLACE FUNCTION app_current_workspace() RETURNS TEXT AS $$
+    SELECT NULLIF(current_setting('app.current_workspace', true), '')
+$$ LANGUAGE SQL STABLE;
+
+CREATE OR REPLACE FUNCTION app_service_role() RETURNS BOOLEAN AS $$
+    SELECT COALESCE(current_setting('app.service_role', true), '') <> ''
+$$ LANGUAGE SQL STABLE;
+
+ALTER TABLE folders ENABLE ROW LEVEL SECURITY;
+ALTER TABLE folders FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON folders;
+CREATE POLICY tenant_isolation ON folders
+    USING (app_service_role() OR (user_id = app_current_user() AND (app_current_workspace() IS NULL OR workspace_id::text = app_current_workspace())))
+    WITH CHECK (app_service_role() OR (user_id = app_current_user() AND (app_current_workspace() IS NULL OR workspace_id::text = app_current_workspace())));
+
+ALTER TABLE actors ENABLE ROW LEVEL SECURITY;
+ALTER TABLE actors FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON actors;
+CREATE POLICY tenant_isolation ON actors
+    USING (app_service_role() OR user_id = app_current_user())
+    WITH CHECK (app_service_role() OR user_id = app_current_user());
+
+ALTER TABLE video_instances ENABLE ROW LEVEL SECURITY;
+ALTER TABLE video_instances FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON video_instances;
+CREATE POLICY tenant_isolation ON video_instances
+    USING (app_service_role() OR user_id = app_current_user())
+    WITH CHECK (app_service_role() OR user_id = app_current_user());
+
+ALTER TABLE videos ENABLE ROW LEVEL SECURITY;
+ALTER TABLE videos FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON videos;
+CREATE POLICY tenant_isolation ON videos
+    USING (app_service_role() OR user_id = app_current_user())
+    WITH CHECK (app_service_role() OR user_id = app_current_user());
+
+ALTER TABLE audio_batch ENABLE ROW LEVEL SECURITY;
+ALTER TABLE audio_batch FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON audio_batch;
+CREATE POLICY tenant_isolation ON audio_batch
+    USING (app_service_role() OR user_id = app_current_user())
+    WITH CHECK (app_service_role() OR user_id = app_current_user());
+
+ALTER TABLE generated_videos ENABLE ROW LEVEL SECURITY;
+ALTER TABLE generated_videos FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON generated_videos;
+CREATE POLICY tenant_isolation ON generated_videos
+    USING (app_service_role() OR user_id = app_current_user())
+    WITH CHECK (app_service_role() OR user_id = app_current_user());
+
+ALTER TABLE segments ENABLE ROW LEVEL SECURITY;
+ALTER TABLE segments FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON segments;
+CREATE POLICY tenant_isolation ON segments
+    USING (app_service_role() OR user_id = app_current_user())
+    WITH CHECK (app_service_role() OR user_id = app_current_user());
+
+ALTER TABLE audios ENABLE ROW LEVEL SECURITY;
+ALTER TABLE audios FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON audios;
+CREATE POLICY tenant_isolation ON audios
+    USING (app_service_role() OR user_id = app_current_user())
+    WITH CHECK (app_service_role() OR user_id = app_current_user());
+
+ALTER TABLE audio_batch_data ENABLE ROW LEVEL SECURITY;
+ALTER TABLE audio_batch_data FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON audio_batch_data;
+CREATE POLICY tenant_isolation ON audio_batch_data
+    USING (app_service_role() OR user_id::text = app_current_user())
+    WITH CHECK (app_service_role() OR user_id::text = app_current_user());
+
+ALTER TABLE ws_boards ENABLE ROW LEVEL SECURITY;
+ALTER TABLE ws_boards FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON ws_boards;
+CREATE POLICY tenant_isolation ON ws_boards
+    USING (app_service_role() OR user_id = app_current_user())
+    WITH CHECK (app_service_role() OR user_id = app_current_user());
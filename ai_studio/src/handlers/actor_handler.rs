@@ -6,7 +6,6 @@ use axum_macros::debug_handler;
 use sqlx::PgPool;
 use std::sync::Arc;
 use uuid::Uuid;
-use std::fmt::Write;
 use chrono::Utc;
 use chrono::NaiveDateTime;
 
@@ -17,7 +16,7 @@ use openapi_rs::OpenApiFromData;
 
 use crate::models::actor::{Actor, CreateActor, UpdateActor};
 use crate::models::param::{OptionalId, RequiredId};
-use microservice_utils::{jwt::extractor::AuthToken, server::response::{into_reponse, AxumResult, AxumRes}};
+use microservice_utils::{jwt::extractor::AuthToken, server::{response::{into_reponse, AxumResult, AxumRes}, tenant::begin_tenant_tx}};
 
 // API
 #[debug_handler]
@@ -148,6 +147,7 @@ pub async fn db_create_actor(
     actor: &CreateActor,
     pool: &PgPool,
 ) -> Result<Actor, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     let out_actor = sqlx::query_as!(
         Actor,
         r#"INSERT INTO actors (
@@ -155,8 +155,9 @@ pub async fn db_create_actor(
         user_id,
         actor.name,
     )
-    .fetch_one(pool)
+    .fetch_one(&mut tx)
     .await?;
+    tx.commit().await?;
     Ok(out_actor)
 }
 
@@ -165,6 +166,7 @@ pub async fn db_update_actor(
     actor: &UpdateActor,
     pool: &PgPool,
 ) -> Result<Actor, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     let out_actor = sqlx::query_as!(Actor, 
         r#"UPDATE actors SET name = $1, updated_at = $2 WHERE id = $3 AND user_id = $4 RETURNING *"#,
         actor.name,
@@ -172,8 +174,9 @@ pub async fn db_update_actor(
         actor.id,
         user_id
     )
-    .fetch_one(pool)
+    .fetch_one(&mut tx)
     .await?;    
+    tx.commit().await?;
     Ok(out_actor)
 }
 
@@ -182,17 +185,15 @@ pub async fn db_get_actor(
     params: &OptionalId,
     pool: &PgPool,
 ) -> Result<Vec<Actor>, sqlx::Error> {
-    let mut query = "SELECT * FROM actors WHERE ".to_string();
-    write!(query, "user_id = '{}' ", user_id).unwrap();
-
-    if let Some(params_id) = params.id {
-        write!(query, "And id = '{}' ", params_id).unwrap();
-    }
-
-    let str_query: &str = &query[..];
-    let actors = sqlx::query_as::<_, Actor>(str_query)
-        .fetch_all(pool)
-        .await?;
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let actors = sqlx::query_as::<_, Actor>(
+        "SELECT * FROM actors WHERE user_id = $1 AND ($2::uuid IS NULL OR id = $2)",
+    )
+    .bind(user_id)
+    .bind(params.id)
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
     Ok(actors)
 }
 
@@ -201,6 +202,7 @@ pub async fn db_delete_actor(
     params: &RequiredId,
     pool: &PgPool,
 ) -> Result<(), sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     match db_check_audios(&user_id, &params.id, &pool).await {
         Ok(_) => Err(sqlx::Error::Protocol(
             "Can't delete, this actor have audio".to_string(),
@@ -211,8 +213,9 @@ pub async fn db_delete_actor(
                 params.id,
                 user_id
             )
-            .execute(pool)
+            .execute(&mut tx)
             .await?;
+            tx.commit().await?;
             Ok(())
         }
     }
@@ -223,12 +226,14 @@ pub async fn db_check_audios(
     actor_id: &Uuid,
     pool: &PgPool,
 ) -> Result<(), sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     let _ = sqlx::query!(
         "SELECT id FROM audios WHERE actor_id = $1 AND user_id = $2",
         actor_id,
         user_id
     )
-    .fetch_one(pool)
+    .fetch_one(&mut tx)
     .await?;
+    tx.commit().await?;
     Ok(())
 }
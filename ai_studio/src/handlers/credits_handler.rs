@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use microservice_utils::server::tenant::begin_service_tx;
+use microservice_utils::server::users::charge_credits;
+
+const MAX_CHARGES_PER_RUN: i64 = 100;
+
+lazy_static! {
+    static ref VIDEO_CREDITS: i64 = std::env::var("VIDEO_CREDIT_COST")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1);
+    static ref CHARGE_POLL_SECS: u64 = std::env::var("VIDEO_CHARGE_POLL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30);
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+struct UnchargedVideo {
+    id: Uuid,
+    user_id: String,
+    name: String,
+    workspace_id: Option<Uuid>,
+}
+
+// Charges the credits of generated videos to the user_microservice ledger. The charge is keyed
+// by the video, so a video marked as charged late is not charged twice.
+pub fn spawn_charge_job(pool: &PgPool) {
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(*CHARGE_POLL_SECS));
+        loop {
+            interval.tick().await;
+            let videos = match db_list_uncharged_videos(&pool).await {
+                Ok(videos) => videos,
+                Err(e) => {
+                    println!("{:?}", e.to_string());
+                    continue;
+                }
+            };
+            for video in videos {
+                let workspace_id = video.workspace_id.map(|id| id.to_string()).unwrap_or_default();
+                let charge = charge_credits(
+                    &video.user_id,
+                    &workspace_id,
+                    *VIDEO_CREDITS,
+                    &format!("video:{}", video.id),
+                    &format!("Generated video {}", video.name),
+                )
+                .await;
+                match charge {
+                    Ok(_) => {
+                        if let Err(e) = db_set_charged(&video.id, &pool).await {
+                            println!("{:?}", e.to_string());
+                        }
+                    }
+                    Err(e) => println!("Unable to charge video {}: {:?}", video.id, e),
+                }
+            }
+        }
+    });
+}
+
+// Videos are charged once they have been rendered, the workspace comes from their folder.
+async fn db_list_uncharged_videos(pool: &PgPool) -> Result<Vec<UnchargedVideo>, sqlx::Error> {
+    let mut tx = begin_service_tx(pool, "charge_videos").await?;
+    let videos: Vec<UnchargedVideo> = sqlx::query_as(
+        "SELECT g.id, g.user_id, g.name, f.workspace_id FROM generated_videos g
+        LEFT JOIN video_instances vi ON vi.id = g.video_instance_id
+        LEFT JOIN folders f ON f.id = vi.folder_id
+        WHERE g.charged_at IS NULL AND g.video_url IS NOT NULL
+        ORDER BY g.created_at LIMIT $1",
+    )
+    .bind(MAX_CHARGES_PER_RUN)
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(videos)
+}
+
+async fn db_set_charged(video_id: &Uuid, pool: &PgPool) -> Result<(), sqlx::Error> {
+    let mut tx = begin_service_tx(pool, "charge_videos").await?;
+    sqlx::query("UPDATE generated_videos SET charged_at = CURRENT_TIMESTAMP WHERE id = $1")
+        .bind(video_id)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
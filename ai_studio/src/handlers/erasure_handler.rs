@@ -0,0 +1,195 @@
+use rusoto_core::{Region, RusotoError};
+use rusoto_s3::{Delete, DeleteObjectsError, DeleteObjectsRequest, ObjectIdentifier, S3Client, S3};
+use sqlx::PgPool;
+
+use microservice_utils::server::erasure::{db_erase_user_rows, Erasure};
+use microservice_utils::server::tenant::begin_tenant_tx;
+
+pub(crate) fn bucket_name() -> String {
+    std::env::var("S3_BUCKET").unwrap_or("henry-bhuman-bucket".to_string())
+}
+
+// Key of a media url pointing into our bucket, None for anything hosted elsewhere (vimeo, thumbnails
+// of other services...).
+fn object_key(url: &str, bucket: &str) -> Option<String> {
+    let rest = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+        .or_else(|| url.strip_prefix("s3://"))?;
+    let (host, path) = rest.split_once('/')?;
+    let path = path.split(|c| c == '?' || c == '#').next().unwrap_or("");
+    let key = if host == bucket {
+        path // s3://bucket/key
+    } else if host.starts_with(&format!("{}.s3", bucket)) && host.ends_with(".amazonaws.com") {
+        path // virtual hosted style
+    } else if host.starts_with("s3") && host.ends_with(".amazonaws.com") {
+        path.strip_prefix(&format!("{}/", bucket))? // path style
+    } else {
+        return None;
+    };
+    if key.is_empty() {
+        None
+    } else {
+        Some(key.to_string())
+    }
+}
+
+// Keys of the uploaded videos and audios of a user and of the videos generated for them.
+pub async fn db_list_user_media_keys(user_id: &String, pool: &PgPool) -> Result<Vec<String>, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let urls: Vec<(String,)> = sqlx::query_as(
+        "SELECT url FROM videos WHERE user_id = $1
+        UNION SELECT url FROM audios WHERE user_id = $1
+        UNION SELECT video_url FROM generated_videos WHERE user_id = $1 AND video_url IS NOT NULL
+        UNION SELECT thumbnail FROM generated_videos WHERE user_id = $1 AND thumbnail IS NOT NULL")
+        .bind(user_id)
+        .fetch_all(&mut tx)
+        .await?;
+    tx.commit().await?;
+    let bucket = bucket_name();
+    Ok(urls.iter().filter_map(|(url,)| object_key(url, &bucket)).collect())
+}
+
+// Removes objects from the bucket, in batches of the most S3 accepts per request.
+pub async fn delete_media(keys: &[String]) -> Result<(), RusotoError<DeleteObjectsError>> {
+    let s3 = S3Client::new(Region::UsEast1);
+    for chunk in keys.chunks(1000) {
+        s3.delete_objects(DeleteObjectsRequest {
+            bucket: bucket_name(),
+            delete: Delete {
+                objects: chunk
+                    .iter()
+                    .map(|key| ObjectIdentifier {
+                        key: key.clone(),
+                        version_id: None,
+                    })
+                    .collect(),
+                quiet: Some(true),
+            },
+            ..Default::default()
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+// Ids of the WsBoard documents a user owns.
+pub async fn db_list_user_ws_boards(user_id: &String, pool: &PgPool) -> Result<Vec<String>, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let ids: Vec<(String,)> = sqlx::query_as("SELECT id FROM ws_boards WHERE user_id = $1")
+        .bind(user_id)
+        .fetch_all(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(ids.into_iter().map(|(id,)| id).collect())
+}
+
+// Removes the persisted documents of WsBoards.
+pub fn delete_ws_board_files(ids: &[String]) -> std::io::Result<()> {
+    for id in ids {
+        match std::fs::remove_file(format!("spreadsheet/{}.json", id)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Erases the studio data of a deleted account, children before the rows they reference.
+pub async fn db_erase_user_studio(user_id: &String, pool: &PgPool) -> Result<Erasure, sqlx::Error> {
+    db_erase_user_rows(
+        user_id,
+        &[
+            "DELETE FROM segments WHERE user_id = $1",
+            "DELETE FROM generated_videos WHERE user_id = $1",
+            "DELETE FROM video_instances WHERE user_id = $1",
+            "DELETE FROM audio_batch_data WHERE user_id::text = $1",
+            "DELETE FROM audio_batch WHERE user_id = $1",
+            "DELETE FROM audios WHERE user_id = $1",
+            "DELETE FROM actors WHERE user_id = $1",
+            "DELETE FROM videos WHERE user_id = $1",
+            "DELETE FROM folders WHERE user_id = $1",
+            "DELETE FROM ws_boards WHERE user_id = $1",
+        ],
+        &[
+            "SELECT count(*) FROM segments WHERE user_id = $1",
+            "SELECT count(*) FROM generated_videos WHERE user_id = $1",
+            "SELECT count(*) FROM video_instances WHERE user_id = $1",
+            "SELECT count(*) FROM audio_batch_data WHERE user_id::text = $1",
+            "SELECT count(*) FROM audio_batch WHERE user_id = $1",
+            "SELECT count(*) FROM audios WHERE user_id = $1",
+            "SELECT count(*) FROM actors WHERE user_id = $1",
+            "SELECT count(*) FROM videos WHERE user_id = $1",
+            "SELECT count(*) FROM folders WHERE user_id = $1",
+            "SELECT count(*) FROM ws_boards WHERE user_id = $1",
+        ],
+        pool,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::{test_pool, test_user_id};
+
+    #[test]
+    fn keys_of_bucket_urls() {
+        let bucket = "media-bucket";
+        assert_eq!(
+            object_key("https://media-bucket.s3.amazonaws.com/user/video.mp4", bucket),
+            Some("user/video.mp4".to_string())
+        );
+        assert_eq!(
+            object_key("https://media-bucket.s3.us-east-1.amazonaws.com/user/audio.mp3?X-Amz-Expires=60", bucket),
+            Some("user/audio.mp3".to_string())
+        );
+        assert_eq!(
+            object_key("https://s3.amazonaws.com/media-bucket/user/video.mp4", bucket),
+            Some("user/video.mp4".to_string())
+        );
+        assert_eq!(object_key("s3://media-bucket/user/video.mp4", bucket), Some("user/video.mp4".to_string()));
+    }
+
+    #[test]
+    fn other_urls_have_no_key() {
+        let bucket = "media-bucket";
+        assert_eq!(object_key("https://vimeo.com/12345", bucket), None);
+        assert_eq!(object_key("https://other-bucket.s3.amazonaws.com/user/video.mp4", bucket), None);
+        assert_eq!(object_key("https://s3.amazonaws.com/other-bucket/user/video.mp4", bucket), None);
+        assert_eq!(object_key("https://media-bucket.s3.amazonaws.com/", bucket), None);
+        assert_eq!(object_key("video.mp4", bucket), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn lists_the_media_of_the_user() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        let url = format!("https://{}.s3.amazonaws.com/{}/video.mp4", bucket_name(), user_id);
+        sqlx::query("INSERT INTO videos (user_id, name, url, length) VALUES ($1, 'video', $2, '10')")
+            .bind(&user_id)
+            .bind(&url)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            db_list_user_media_keys(&user_id, &pool).await.unwrap(),
+            vec![format!("{}/video.mp4", user_id)]
+        );
+        assert!(db_list_user_media_keys(&test_user_id(), &pool).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn lists_the_boards_of_the_user() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        let board = format!("board-{}", user_id);
+        crate::handlers::ws_handler::db_claim_ws_board(&user_id, &board, &pool).await.unwrap();
+
+        assert_eq!(db_list_user_ws_boards(&user_id, &pool).await.unwrap(), vec![board]);
+        assert!(db_list_user_ws_boards(&test_user_id(), &pool).await.unwrap().is_empty());
+    }
+}
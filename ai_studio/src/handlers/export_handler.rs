@@ -0,0 +1,133 @@
+use rusoto_core::{Region, RusotoError};
+use rusoto_s3::{GetObjectError, GetObjectRequest, S3Client, S3};
+use sqlx::PgPool;
+use tokio::io::AsyncReadExt;
+use tonic::async_trait;
+use tonic::Status;
+
+use microservice_utils::server::export::{csv_file, db_export_rows, export_response};
+use microservice_utils::server::grpc::data_export::data_export_server::DataExport;
+use microservice_utils::server::grpc::data_export::{ExportFile, ExportUserDataRequest, ExportUserDataResponse};
+use microservice_utils::server::service_auth::{authorize, USER_SERVICE};
+
+use crate::handlers::erasure_handler::{bucket_name, db_list_user_media_keys};
+
+// Exported files with the rows of the user they hold.
+const EXPORT_TABLES: &[(&str, &str)] = &[
+    (
+        "folders",
+        "SELECT id, workspace_id, name, parent_videos, generated_videos, created_at, updated_at FROM folders WHERE user_id = $1 ORDER BY created_at",
+    ),
+    (
+        "actors",
+        "SELECT id, name, created_at, updated_at FROM actors WHERE user_id = $1 ORDER BY created_at",
+    ),
+    (
+        "videos",
+        "SELECT id, name, url, length, created_at, updated_at FROM videos WHERE user_id = $1 ORDER BY created_at",
+    ),
+    (
+        "audios",
+        "SELECT id, actor_id, name, url, audio_length, created_at, updated_at FROM audios WHERE user_id = $1 ORDER BY created_at",
+    ),
+    (
+        "video_instances",
+        "SELECT id, name, folder_id, video_id, actor_id, audio_batch_id, image_column_id, created_at, updated_at
+        FROM video_instances WHERE user_id = $1 ORDER BY created_at",
+    ),
+    (
+        "segments",
+        "SELECT id, video_instance_id, audio_variable_name, audio_variable_column_id, prefix_time_marker_start,
+        prefix_time_marker_end, suffix_time_marker_start, suffix_time_marker_end, variable_time_marker_start,
+        variable_time_marker_end, created_at, updated_at FROM segments WHERE user_id = $1 ORDER BY created_at",
+    ),
+    (
+        "audio_batches",
+        "SELECT id, name, created_at, updated_at FROM audio_batch WHERE user_id = $1 ORDER BY created_at",
+    ),
+    (
+        "audio_batch_data",
+        "SELECT id, audio_batch_id, name, audio_id, image_id, row_id, column_id, created_at, updated_at
+        FROM audio_batch_data WHERE user_id::text = $1 ORDER BY audio_batch_id, row_id, column_id",
+    ),
+    (
+        "generated_videos",
+        "SELECT id, batch_id, video_instance_id, name, audio_lables, video_url, vimeo_url, thumbnail, status,
+        vimeo_status, created_at, updated_at FROM generated_videos WHERE user_id = $1 ORDER BY created_at",
+    ),
+    (
+        "ws_boards",
+        "SELECT id, created_at FROM ws_boards WHERE user_id = $1 ORDER BY created_at",
+    ),
+];
+
+// gRPC
+pub struct MyDataExport {
+    pool: PgPool
+}
+
+impl MyDataExport {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool
+        }
+    }
+}
+
+#[async_trait]
+impl DataExport for MyDataExport {
+    async fn export_user_data(
+        &self,
+        request: tonic::Request<ExportUserDataRequest>,
+    ) -> Result<tonic::Response<ExportUserDataResponse>, Status> {
+
+        authorize(&request, &[USER_SERVICE])?;
+
+        let req: ExportUserDataRequest = request.into_inner();
+        println!("Export User Data {}", req.user_id);
+
+        let files = export_files(&req.user_id, &self.pool)
+            .await
+            .map_err(|e| Status::internal(format!("{:?}", e)))?;
+        Ok(tonic::Response::new(export_response(files)))
+    }
+}
+
+async fn export_files(user_id: &String, pool: &PgPool) -> anyhow::Result<Vec<ExportFile>> {
+    let mut files = Vec::new();
+    for (name, query) in EXPORT_TABLES {
+        files.push(csv_file(name, &db_export_rows(query, user_id, pool).await?));
+    }
+    files.extend(media_files(user_id, pool).await?);
+    Ok(files)
+}
+
+// The uploaded videos and audios and the generated videos of the user, under media/ with
+// their key in the bucket. Objects already gone from the bucket are left out.
+async fn media_files(user_id: &String, pool: &PgPool) -> anyhow::Result<Vec<ExportFile>> {
+    let s3 = S3Client::new(Region::UsEast1);
+    let mut files = Vec::new();
+    for key in db_list_user_media_keys(user_id, pool).await? {
+        let object = match s3
+            .get_object(GetObjectRequest {
+                bucket: bucket_name(),
+                key: key.clone(),
+                ..Default::default()
+            })
+            .await
+        {
+            Ok(object) => object,
+            Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let mut content = Vec::new();
+        if let Some(body) = object.body {
+            body.into_async_read().read_to_end(&mut content).await?;
+        }
+        files.push(ExportFile {
+            name: format!("media/{}", key),
+            content,
+        });
+    }
+    Ok(files)
+}
@@ -6,7 +6,6 @@ use axum_macros::debug_handler;
 use sqlx::PgPool;
 use std::sync::Arc;
 use uuid::Uuid;
-use std::fmt::Write;
 use chrono::Utc;
 use chrono::NaiveDateTime;
 
@@ -17,7 +16,7 @@ use openapi_rs::OpenApiFromData;
 
 use crate::models::folder::{CreateFolder, Folder, FolderOptionalId, UpdateFolder};
 use crate::models::param::RequiredId;
-use microservice_utils::{jwt::extractor::AuthToken, server::{grpc::check_workspace,response::{into_reponse, AxumResult, AxumRes}}};
+use microservice_utils::{jwt::extractor::AuthToken, server::{grpc::check_workspace,response::{into_reponse, AxumResult, AxumRes},tenant::begin_tenant_tx}};
 
 // API
 #[debug_handler]
@@ -161,6 +160,7 @@ pub async fn db_create_folder(
     g_videos: i64,
     pool: &PgPool,
 ) -> Result<Folder, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, Some(&folder.workspace_id.to_string())).await?;
     let out_folder = sqlx::query_as!(Folder, 
         r#"INSERT INTO folders (
             user_id, workspace_id, name, parent_videos, generated_videos) VALUES ($1, $2, $3, $4, $5) RETURNING *"#,
@@ -169,7 +169,8 @@ pub async fn db_create_folder(
             folder.name,
             p_videos,
             g_videos,
-    ).fetch_one(pool).await?;    
+    ).fetch_one(&mut tx).await?;    
+    tx.commit().await?;
     Ok(out_folder)
 }
 
@@ -178,6 +179,7 @@ pub async fn db_update_folder(
     folder: &UpdateFolder,
     pool: &PgPool,
 ) -> Result<Folder, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     let out_folder = sqlx::query_as!(Folder, 
         r#"UPDATE folders SET name = $1, updated_at = $2 WHERE id = $3 AND user_id = $4 RETURNING *"#,
         folder.name,
@@ -185,8 +187,9 @@ pub async fn db_update_folder(
         folder.id,
         user_id
     )
-    .fetch_one(pool)
+    .fetch_one(&mut tx)
     .await?;    
+    tx.commit().await?;
     Ok(out_folder)
 }
 
@@ -195,20 +198,17 @@ pub async fn db_get_folder(
     params: &FolderOptionalId,
     pool: &PgPool,
 ) -> Result<Vec<Folder>, sqlx::Error> {
-    let mut query = "SELECT * FROM folders WHERE ".to_string();
-    write!(query, "user_id = '{}' ", user_id).unwrap();
-
-    if let Some(params_id) = params.id {
-        write!(query, "And id = '{}' ", params_id).unwrap();
-    }
-    if let Some(params_workspace_id) = params.workspace_id {
-        write!(query, "And workspace_id = '{}' ", params_workspace_id).unwrap();
-    }
-
-    let str_query: &str = &query[..];
-    let folders = sqlx::query_as::<_, Folder>(str_query)
-        .fetch_all(pool)
-        .await?;
+    let workspace_id = params.workspace_id.map(|id| id.to_string());
+    let mut tx = begin_tenant_tx(pool, user_id, workspace_id.as_deref()).await?;
+    let folders = sqlx::query_as::<_, Folder>(
+        "SELECT * FROM folders WHERE user_id = $1 AND ($2::uuid IS NULL OR id = $2) AND ($3::uuid IS NULL OR workspace_id = $3)",
+    )
+    .bind(user_id)
+    .bind(params.id)
+    .bind(params.workspace_id)
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
     Ok(folders)
 }
 
@@ -217,6 +217,7 @@ pub async fn db_delete_folder(
     params: &RequiredId,
     pool: &PgPool,
 ) -> Result<(), sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     match db_check_v_instance(&user_id, &params.id, &pool).await {
         Ok(_) => Err(sqlx::Error::Protocol(
             "Can't delete, this folder have video instances".to_string(),
@@ -227,8 +228,9 @@ pub async fn db_delete_folder(
                 params.id,
                 user_id
             )
-            .execute(pool)
+            .execute(&mut tx)
             .await?;
+            tx.commit().await?;
             Ok(())
         }
     }
@@ -239,12 +241,59 @@ pub async fn db_check_v_instance(
     folder_id: &Uuid,
     pool: &PgPool,
 ) -> Result<(), sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     let _ = sqlx::query!(
         "SELECT id FROM video_instances WHERE folder_id = $1 AND user_id = $2",
         folder_id,
         user_id
     )
-    .fetch_one(pool)
+    .fetch_one(&mut tx)
     .await?;
+    tx.commit().await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::{tenant_test_tx, test_pool, test_user_id};
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn folders_of_other_tenants_are_out_of_reach() {
+        let pool = test_pool().await;
+        let (owner, other) = (test_user_id(), test_user_id());
+        let workspace_id = Uuid::new_v4();
+        let folder = CreateFolder { workspace_id, name: "Folder".to_string() };
+        let folder = db_create_folder(&owner, &folder, 0, 0, &pool).await.unwrap();
+
+        // The queries without any user_id filter, from another user and from the owner in another workspace
+        for (user_id, workspace_id) in [(&other, workspace_id), (&owner, Uuid::new_v4())] {
+            let workspace_id = workspace_id.to_string();
+            let mut tx = tenant_test_tx(&pool, user_id, Some(&workspace_id)).await;
+            let rows: Vec<(Uuid,)> = sqlx::query_as("SELECT id FROM folders WHERE id = $1")
+                .bind(folder.id)
+                .fetch_all(&mut tx)
+                .await
+                .unwrap();
+            assert!(rows.is_empty());
+            let renamed = sqlx::query("UPDATE folders SET name = 'Taken' WHERE id = $1")
+                .bind(folder.id)
+                .execute(&mut tx)
+                .await
+                .unwrap();
+            assert_eq!(renamed.rows_affected(), 0);
+            let deleted = sqlx::query("DELETE FROM folders WHERE id = $1")
+                .bind(folder.id)
+                .execute(&mut tx)
+                .await
+                .unwrap();
+            assert_eq!(deleted.rows_affected(), 0);
+            tx.commit().await.unwrap();
+        }
+
+        let params = FolderOptionalId { id: Some(folder.id), workspace_id: Some(workspace_id) };
+        let folders = db_get_folder(&owner, &params, &pool).await.unwrap();
+        assert_eq!(folders.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(), vec!["Folder"]);
+    }
+}
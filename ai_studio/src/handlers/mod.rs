@@ -3,4 +3,8 @@ pub mod actor_handler;
 pub mod video_instance_handler;
 pub mod segment_handler;
 pub mod csv_handler;
-pub mod ws_handler;
\ No newline at end of file
+pub mod ws_handler;
+pub mod credits_handler;
+pub mod erasure_handler;
+pub mod export_handler;
+pub mod share_handler;
\ No newline at end of file
@@ -4,7 +4,6 @@ use axum::{
 };
 use axum_macros::debug_handler;
 use sqlx::PgPool;
-use std::fmt::Write;
 use std::sync::Arc;
 use chrono::Utc;
 use chrono::NaiveDateTime;
@@ -15,7 +14,7 @@ use openapi_rs::gen::OpenApiGenerator;
 use openapi_rs::OpenApiFromData;
 
 use crate::models::segment::{CreateSegment, Segment, SegmentOptionalId, UpdateSegment};
-use microservice_utils::{jwt::extractor::AuthToken, server::response::{into_reponse, AxumResult, AxumRes}};
+use microservice_utils::{jwt::extractor::AuthToken, server::{response::{into_reponse, AxumResult, AxumRes}, tenant::begin_tenant_tx}};
 
 // API
 #[debug_handler]
@@ -146,12 +145,13 @@ pub async fn db_create_segment(
     segment: &CreateSegment,
     pool: &PgPool,
 ) -> Result<Segment, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     let _ = sqlx::query!(
         "SELECT id FROM video_instances WHERE id=$1 AND user_id = $2",
         segment.video_instance_id,
         user_id
     )
-    .fetch_one(pool)
+    .fetch_one(&mut tx)
     .await?;
 
     let out_segment = sqlx::query_as!(Segment, 
@@ -170,7 +170,8 @@ pub async fn db_create_segment(
             segment.audio_variable_name.to_lowercase(),
             segment.variable_time_marker_start,
             segment.variable_time_marker_end,
-    ).fetch_one(pool).await?;   
+    ).fetch_one(&mut tx).await?;   
+    tx.commit().await?;
     Ok(out_segment)
 }
 
@@ -179,6 +180,7 @@ pub async fn db_update_segment(
     params: &UpdateSegment,
     pool: &PgPool,
 ) -> Result<Segment, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     let out_segment = sqlx::query_as!(Segment, 
             r#"UPDATE segments SET audio_variable_name = $1, updated_at = $2 WHERE id = $3 AND user_id = $4 RETURNING *"#,
             params.audio_variable_name.to_lowercase(),
@@ -186,8 +188,9 @@ pub async fn db_update_segment(
             params.id,
             user_id
         )
-        .fetch_one(pool)
+        .fetch_one(&mut tx)
         .await?;    
+    tx.commit().await?;
     Ok(out_segment)
 }
 
@@ -196,25 +199,16 @@ pub async fn db_get_segment(
     params: &SegmentOptionalId,
     pool: &PgPool,
 ) -> Result<Vec<Segment>, sqlx::Error> {
-    let mut query = "SELECT * FROM segments WHERE ".to_string();
-    write!(query, "user_id = '{}' ", user_id).unwrap();
-
-    if let Some(params_id) = params.id {
-        write!(query, "And id = '{}' ", params_id).unwrap();
-    }
-    if let Some(params_video_instance_id) = params.video_instance_id {
-        write!(
-            query,
-            "And video_instance_id = '{}' ",
-            params_video_instance_id
-        )
-        .unwrap();
-    }
-
-    let str_query: &str = &query[..];
-    let out_segments = sqlx::query_as::<_, Segment>(str_query)
-        .fetch_all(pool)
-        .await?;
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let out_segments = sqlx::query_as::<_, Segment>(
+        "SELECT * FROM segments WHERE user_id = $1 AND ($2::uuid IS NULL OR id = $2) AND ($3::uuid IS NULL OR video_instance_id = $3)",
+    )
+    .bind(user_id)
+    .bind(params.id)
+    .bind(params.video_instance_id)
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
     Ok(out_segments)
 }
 
@@ -223,17 +217,15 @@ pub async fn db_delete_segment(
     params: &SegmentOptionalId,
     pool: &PgPool,
 ) -> Result<(), sqlx::Error> {
-    let mut query = "DELETE FROM segments WHERE ".to_string();
-    write!(query, "user_id = '{}' ", user_id).unwrap();
-    
-    if let Some(params_id) = params.id {
-        write!(query, "And id = '{}' ", params_id).unwrap();
-    }
-    if let Some(params_video_instance_id) = params.video_instance_id{
-        write!(query, "And video_instance_id = '{}' ", params_video_instance_id).unwrap();
-    }
-
-    let str_query: &str = &query[..];
-    let _ = sqlx::query(str_query).execute(pool).await?;
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let _ = sqlx::query(
+        "DELETE FROM segments WHERE user_id = $1 AND ($2::uuid IS NULL OR id = $2) AND ($3::uuid IS NULL OR video_instance_id = $3)",
+    )
+    .bind(user_id)
+    .bind(params.id)
+    .bind(params.video_instance_id)
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
     Ok(())
 }
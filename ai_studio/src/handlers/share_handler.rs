@@ -0,0 +1,110 @@
+use sqlx::PgPool;
+use tonic::async_trait;
+use tonic::Status;
+use uuid::Uuid;
+
+use microservice_utils::jwt::share::{VIDEO_RESOURCE, WS_BOARD_RESOURCE};
+use microservice_utils::server::grpc::shared_resources::shared_resources_server::SharedResources;
+use microservice_utils::server::grpc::shared_resources::{CheckOwnerRequest, CheckOwnerResponse};
+use microservice_utils::server::service_auth::{authorize, AUTH_SERVICE};
+
+use crate::handlers::video_instance_handler::db_get_generated_video;
+use crate::handlers::ws_handler::db_owns_ws_board;
+
+// gRPC
+pub struct MySharedResources {
+    pool: PgPool
+}
+
+impl MySharedResources {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool
+        }
+    }
+}
+
+#[async_trait]
+impl SharedResources for MySharedResources {
+    async fn check_owner(
+        &self,
+        request: tonic::Request<CheckOwnerRequest>,
+    ) -> Result<tonic::Response<CheckOwnerResponse>, Status> {
+
+        authorize(&request, &[AUTH_SERVICE])?;
+
+        let req: CheckOwnerRequest = request.into_inner();
+        let owner = owns_resource(&req, &self.pool)
+            .await
+            .map_err(|e| Status::internal(format!("{:?}", e)))?;
+        Ok(tonic::Response::new(CheckOwnerResponse {
+            status: "success".to_string(),
+            owner,
+        }))
+    }
+}
+
+async fn owns_resource(req: &CheckOwnerRequest, pool: &PgPool) -> Result<bool, sqlx::Error> {
+    match req.resource_type.as_str() {
+        VIDEO_RESOURCE => {
+            let id = match Uuid::parse_str(&req.resource_id) {
+                Ok(id) => id,
+                Err(_) => return Ok(false),
+            };
+            match db_get_generated_video(&req.user_id, &id, pool).await {
+                Ok(_) => Ok(true),
+                Err(sqlx::Error::RowNotFound) => Ok(false),
+                Err(e) => Err(e),
+            }
+        }
+        WS_BOARD_RESOURCE => db_owns_ws_board(&req.user_id, &req.resource_id, pool).await,
+        _ => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::ws_handler::db_claim_ws_board;
+    use crate::test_db::{test_pool, test_user_id};
+
+    fn request(user_id: &String, resource_type: &str, resource_id: &String) -> CheckOwnerRequest {
+        CheckOwnerRequest {
+            user_id: user_id.clone(),
+            resource_type: resource_type.to_string(),
+            resource_id: resource_id.clone(),
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn only_owners_own_boards() {
+        let pool = test_pool().await;
+        let owner = test_user_id();
+        let board = format!("board-{}", owner);
+        db_claim_ws_board(&owner, &board, &pool).await.unwrap();
+
+        assert!(owns_resource(&request(&owner, WS_BOARD_RESOURCE, &board), &pool).await.unwrap());
+        assert!(!owns_resource(&request(&test_user_id(), WS_BOARD_RESOURCE, &board), &pool).await.unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn only_owners_own_videos() {
+        let pool = test_pool().await;
+        let owner = test_user_id();
+        let (id,): (Uuid,) = sqlx::query_as(
+            "INSERT INTO generated_videos (audio_lables, name, user_id, batch_id, video_instance_id, status, vimeo_status)
+            VALUES ('{}', 'video', $1, uuid_generate_v4(), uuid_generate_v4(), 'done', 'done') RETURNING id",
+        )
+        .bind(&owner)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let video = id.to_string();
+
+        assert!(owns_resource(&request(&owner, VIDEO_RESOURCE, &video), &pool).await.unwrap());
+        assert!(!owns_resource(&request(&test_user_id(), VIDEO_RESOURCE, &video), &pool).await.unwrap());
+        assert!(!owns_resource(&request(&owner, VIDEO_RESOURCE, &"not-a-video".to_string()), &pool).await.unwrap());
+    }
+}
@@ -6,7 +6,6 @@ use axum_macros::debug_handler;
 use sqlx::types::chrono::NaiveDateTime;
 use sqlx::types::chrono::Utc;
 use sqlx::PgPool;
-use std::fmt::Write;
 use std::sync::Arc;
 use uuid::Uuid;
 
@@ -18,12 +17,15 @@ use openapi_rs::OpenApiFromData;
 use crate::handlers::actor_handler::db_get_actor;
 use crate::models::audio::AudioBatch;
 use crate::models::param::{OptionalId, RequiredId};
+use crate::models::share::VideoRead;
 use crate::models::video::{
     CreateVideoInstance, GeneratedVideo, UpdateVideoinstance, Video, VideoInstance,
 };
 use microservice_utils::{
     jwt::extractor::AuthToken,
+    jwt::share::ShareToken,
     server::response::{into_reponse, AxumRes, AxumResult},
+    server::tenant::begin_tenant_tx,
 };
 
 // API
@@ -161,18 +163,46 @@ pub async fn delete_video_instance(
     }
 }
 
+/// A generated video opened through a share link, without signing in.
+#[debug_handler]
+#[handler(method = "GET",tag = "video_instance")]
+pub async fn get_shared_video(
+    params: Query<RequiredId>,
+    share: ShareToken<VideoRead>,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    match db_get_generated_video(&share.user_id, &params.id, &pool).await {
+        Ok(result) => Ok(axum::Json(AxumRes {
+            code: 200,
+            result: serde_json::json!(&result),
+        })),
+        Err(sqlx::Error::RowNotFound) => Err(into_reponse(
+            404,
+            serde_json::json!({ "error": "Video not found" }),
+        )),
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(500, ret))
+        }
+    }
+}
+
 // Database
 pub async fn db_create_v_instance(
     user_id: &String,
     v_inst: &CreateVideoInstance,
     pool: &PgPool,
 ) -> Result<VideoInstance, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     let _ = sqlx::query!(
         "SELECT id FROM folders WHERE id=$1 AND user_id = $2",
         v_inst.folder_id,
         user_id
     )
-    .fetch_one(pool)
+    .fetch_one(&mut tx)
     .await?;
 
     let out_inst = sqlx::query_as!(
@@ -183,8 +213,9 @@ pub async fn db_create_v_instance(
         user_id,
         v_inst.folder_id,
     )
-    .fetch_one(pool)
+    .fetch_one(&mut tx)
     .await?;
+    tx.commit().await?;
     Ok(out_inst)
 }
 
@@ -193,81 +224,53 @@ pub async fn db_update_v_instance(
     v_inst: &UpdateVideoinstance,
     pool: &PgPool,
 ) -> Result<VideoInstance, sqlx::Error> {
-    let mut video_id_validation: bool = true;
-    let mut actor_id_validation: bool = true;
-    let mut audio_batch_id_validation: bool = true;
-
-    let mut query = "UPDATE video_instances SET ".to_string();
-    write!(
-        query,
-        "updated_at = '{}' ,",
-        Utc::now().naive_utc() as NaiveDateTime
-    )
-    .unwrap();
-
-    if let Some(video_name) = &v_inst.name {
-        write!(query, "name = '{}' ,", video_name).unwrap();
-    }
-
     if let Some(video_id) = v_inst.video_id {
-        let res = db_get_video(&user_id, &video_id, &pool).await;
-        if res.is_ok() {
-            write!(query, "video_id = '{}' ,", video_id).unwrap();
-            video_id_validation = true;
-        } else {
-            video_id_validation = false;
+        if db_get_video(&user_id, &video_id, &pool).await.is_err() {
+            return Err(sqlx::Error::Protocol(
+                "Video_id not exists for this user".to_string(),
+            ));
         }
     }
 
-    if let Some(column_id) = v_inst.image_column_id {
-        write!(query, "image_column_id = '{}' ,", column_id).unwrap();
-    }
-
     if let Some(actor_id) = v_inst.actor_id {
         let actor = OptionalId { id: Some(actor_id) };
-        let res = db_get_actor(&user_id, &actor, &pool).await;
-        if res.is_ok() {
-            write!(query, "actor_id = '{}' ,", actor_id).unwrap();
-            actor_id_validation = true;
-        } else {
-            actor_id_validation = false;
+        if db_get_actor(&user_id, &actor, &pool).await.is_err() {
+            return Err(sqlx::Error::Protocol(
+                "Actor_id not exists for this user".to_string(),
+            ));
         }
     }
 
     if let Some(audio_batch_id) = v_inst.audio_batch_id {
-        let res = db_get_audio_batch(&user_id, &audio_batch_id, &pool).await;
-        if res.is_ok() {
-            write!(query, "audio_batch_id = '{}' ,", audio_batch_id).unwrap();
-            audio_batch_id_validation = true;
-        } else {
-            audio_batch_id_validation = false;
+        if db_get_audio_batch(&user_id, &audio_batch_id, &pool).await.is_err() {
+            return Err(sqlx::Error::Protocol(
+                "Audio_batch_id not exists for this user".to_string(),
+            ));
         }
     }
 
-    query.pop();
-
-    if !video_id_validation {
-        Err(sqlx::Error::Protocol(
-            "Video_id not exists for this user".to_string(),
-        ))
-    } else if !actor_id_validation {
-        Err(sqlx::Error::Protocol(
-            "Actor_id not exists for this user".to_string(),
-        ))
-    } else if !audio_batch_id_validation {
-        Err(sqlx::Error::Protocol(
-            "Audio_batch_id not exists for this user".to_string(),
-        ))
-    } else {
-        write!(query, "WHERE id = '{}' ", v_inst.id).unwrap();
-        write!(query, "And user_id = '{}' RETURNING *", user_id).unwrap();
-
-        let str_query: &str = &query[..];
-        let out_inst = sqlx::query_as::<_, VideoInstance>(str_query)
-            .fetch_one(pool)
-            .await?;
-        Ok(out_inst)
-    }
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let out_inst = sqlx::query_as::<_, VideoInstance>(
+        "UPDATE video_instances SET updated_at = $3,
+            name = COALESCE($4, name),
+            video_id = COALESCE($5, video_id),
+            image_column_id = COALESCE($6, image_column_id),
+            actor_id = COALESCE($7, actor_id),
+            audio_batch_id = COALESCE($8, audio_batch_id)
+        WHERE id = $1 AND user_id = $2 RETURNING *",
+    )
+    .bind(v_inst.id)
+    .bind(user_id)
+    .bind(Utc::now().naive_utc() as NaiveDateTime)
+    .bind(&v_inst.name)
+    .bind(v_inst.video_id)
+    .bind(v_inst.image_column_id)
+    .bind(v_inst.actor_id)
+    .bind(v_inst.audio_batch_id)
+    .fetch_one(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(out_inst)
 }
 
 pub async fn db_get_video(
@@ -275,30 +278,52 @@ pub async fn db_get_video(
     id: &Uuid,
     pool: &PgPool,
 ) -> Result<Video, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     let out_video = sqlx::query_as!(
         Video,
         r#"SELECT * FROM videos WHERE id = $1 AND user_id = $2"#,
         id,
         user_id
     )
-    .fetch_one(pool)
+    .fetch_one(&mut tx)
     .await?;
+    tx.commit().await?;
     Ok(out_video)
 }
 
+pub async fn db_get_generated_video(
+    user_id: &String,
+    id: &Uuid,
+    pool: &PgPool,
+) -> Result<GeneratedVideo, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let video = sqlx::query_as!(
+        GeneratedVideo,
+        r#"SELECT * FROM generated_videos WHERE id = $1 AND user_id = $2"#,
+        id,
+        user_id
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(video)
+}
+
 pub async fn db_get_audio_batch(
     user_id: &String,
     id: &Uuid,
     pool: &PgPool,
 ) -> Result<AudioBatch, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     let out_audio_batch = sqlx::query_as!(
         AudioBatch,
         r#"SELECT * FROM audio_batch WHERE id = $1 AND user_id = $2"#,
         id,
         user_id
     )
-    .fetch_one(pool)
+    .fetch_one(&mut tx)
     .await?;
+    tx.commit().await?;
     Ok(out_audio_batch)
 }
 
@@ -307,17 +332,15 @@ pub async fn db_get_v_instance(
     params: &OptionalId,
     pool: &PgPool,
 ) -> Result<Vec<VideoInstance>, sqlx::Error> {
-    let mut query = "SELECT * FROM video_instances WHERE ".to_string();
-    write!(query, "user_id = '{}' ", user_id).unwrap();
-
-    if let Some(params_id) = params.id {
-        write!(query, "And id = '{}' ", params_id).unwrap();
-    }
-
-    let str_query: &str = &query[..];
-    let instances = sqlx::query_as::<_, VideoInstance>(str_query)
-        .fetch_all(pool)
-        .await?;
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let instances = sqlx::query_as::<_, VideoInstance>(
+        "SELECT * FROM video_instances WHERE user_id = $1 AND ($2::uuid IS NULL OR id = $2)",
+    )
+    .bind(user_id)
+    .bind(params.id)
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
     Ok(instances)
 }
 
@@ -326,6 +349,7 @@ pub async fn db_delete_v_instance(
     params: &RequiredId,
     pool: &PgPool,
 ) -> Result<(), sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     match db_check_generated_videos(&user_id, &params, &pool).await {
         Ok(_) => Err(sqlx::Error::Protocol(
             "Can't delete, this Video Instance have generated videos".to_string(),
@@ -336,8 +360,9 @@ pub async fn db_delete_v_instance(
                 params.id,
                 user_id
             )
-            .execute(pool)
+            .execute(&mut tx)
             .await?;
+            tx.commit().await?;
             Ok(())
         }
     }
@@ -348,13 +373,15 @@ pub async fn db_check_generated_videos(
     params: &RequiredId,
     pool: &PgPool,
 ) -> Result<(), sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     let _ = sqlx::query!(
         "SELECT id FROM generated_videos WHERE video_instance_id = $1 AND user_id = $2",
         params.id,
         user_id
     )
-    .fetch_one(pool)
+    .fetch_one(&mut tx)
     .await?;
+    tx.commit().await?;
     Ok(())
 }
 
@@ -363,18 +390,15 @@ pub async fn db_get_generated_videos(
     params: &RequiredId,
     pool: &PgPool,
 ) -> Result<Vec<GeneratedVideo>, sqlx::Error> {
-    let mut sql = "SELECT * FROM generated_videos WHERE ".to_string();
-    write!(
-        sql,
-        "user_id = '{}' AND video_instance_id = '{}'",
-        user_id, params.id
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let res = sqlx::query_as::<_, GeneratedVideo>(
+        "SELECT * FROM generated_videos WHERE user_id = $1 AND video_instance_id = $2",
     )
-    .unwrap();
-
-    let str_query: &str = &sql[..];
-    let res = sqlx::query_as::<_, GeneratedVideo>(str_query)
-        .fetch_all(pool)
-        .await?;
+    .bind(user_id)
+    .bind(params.id)
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
     Ok(res)
 }
 
@@ -384,26 +408,29 @@ pub async fn create_batch_data(
     user_id: &String,
     pool: &PgPool,
 ) {
-    let mut query =
-        "INSERT INTO audio_batch_data (name, user_id, row_id, column_id, audio_batch_id) VALUES "
-            .to_string();
-    let mut row_counter: i64 = 0;
-    let mut column_counter: i64 = 0;
-    for first in payload.iter() {
-        for second in first.iter() {
-            write!(
-                query,
-                "('{}', '{}', '{}', '{}', '{}'),",
-                second, user_id, row_counter, column_counter, audio_batch_id
-            )
-            .unwrap();
-            column_counter += 1;
+    let mut names = Vec::new();
+    let mut row_ids: Vec<i64> = Vec::new();
+    let mut column_ids: Vec<i64> = Vec::new();
+    for (row_counter, first) in payload.iter().enumerate() {
+        for (column_counter, second) in first.iter().enumerate() {
+            names.push(second.clone());
+            row_ids.push(row_counter as i64);
+            column_ids.push(column_counter as i64);
         }
-        column_counter = 0;
-        row_counter += 1;
     }
 
-    query.pop();
-    let str_query: &str = &query[..];
-    sqlx::query(str_query).execute(pool).await.unwrap();
+    let mut tx = begin_tenant_tx(pool, user_id, None).await.unwrap();
+    sqlx::query(
+        "INSERT INTO audio_batch_data (name, user_id, row_id, column_id, audio_batch_id)
+        SELECT name, $4::uuid, row_id, column_id, $5 FROM UNNEST($1::text[], $2::bigint[], $3::bigint[]) AS t(name, row_id, column_id)",
+    )
+    .bind(&names)
+    .bind(&row_ids)
+    .bind(&column_ids)
+    .bind(user_id)
+    .bind(audio_batch_id)
+    .execute(&mut tx)
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
 }
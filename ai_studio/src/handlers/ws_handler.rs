@@ -5,23 +5,66 @@ use axum::{
 };
 use axum_macros::debug_handler;
 use dashmap::mapref::entry::Entry;
+use sqlx::PgPool;
 use std::time::Duration;
 use std::{path::Path as FilePath, sync::Arc};
 use tokio::time::{self, Instant};
 
+use microservice_utils::jwt::{extractor::AuthToken, share::ShareToken};
+use microservice_utils::server::response::{into_reponse, AxumResult, ResponseError};
+use microservice_utils::server::tenant::begin_tenant_tx;
+
+use crate::models::share::WsBoardRead;
 use crate::models::ws_board::WsBoard;
 use crate::models::ws_types::{Document, PersistedDocument, ServerState};
 
+/// Editing connection, for the owner of the document. The first user to open a document owns it.
 #[debug_handler]
 pub async fn socket_handler(
     Path(id): Path<String>,
     ws: WebSocketUpgrade,
     user_agent: Option<TypedHeader<headers::UserAgent>>,
+    AuthToken(user_id): AuthToken,
     Extension(state): Extension<ServerState>,
-) -> impl IntoResponse {
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<impl IntoResponse> {
+    if !db_claim_ws_board(&user_id, &id, &pool).await.map_err(internal_error)? {
+        return Err(board_not_found());
+    }
     if let Some(TypedHeader(user_agent)) = user_agent {
         println!("`{}` connected with {}", user_agent.as_str(), id);
     }
+    let wsboard = open_document(id, &state);
+    Ok(ws.on_upgrade(|socket| async move { wsboard.on_connection(socket, false).await }))
+}
+
+/// Read-only connection through a share link: viewers see edits and presence live but can't edit.
+#[debug_handler]
+pub async fn shared_socket_handler(
+    Path(id): Path<String>,
+    ws: WebSocketUpgrade,
+    share: ShareToken<WsBoardRead>,
+    Extension(state): Extension<ServerState>,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<impl IntoResponse> {
+    if !db_owns_ws_board(&share.user_id, &id, &pool).await.map_err(internal_error)? {
+        return Err(board_not_found());
+    }
+    println!("read-only connection with {}", id);
+    let wsboard = open_document(id, &state);
+    Ok(ws.on_upgrade(|socket| async move { wsboard.on_connection(socket, true).await }))
+}
+
+fn board_not_found() -> ResponseError {
+    into_reponse(404, serde_json::json!({ "error": "Board not found" }))
+}
+
+fn internal_error(e: sqlx::Error) -> ResponseError {
+    println!("{:?}", e);
+    into_reponse(500, serde_json::json!({ "error": format!("{:?}", e) }))
+}
+
+fn open_document(id: String, state: &ServerState) -> Arc<WsBoard> {
     let mut entry = match state.documents.entry(id.clone()) {
         Entry::Occupied(e) => e.into_ref(),
         Entry::Vacant(e) => {
@@ -35,8 +78,7 @@ pub async fn socket_handler(
 
     let value = entry.value_mut();
     value.last_accessed = Instant::now();
-    let wsboard = Arc::clone(&value.wsboard);
-    ws.on_upgrade(|socket| async move { wsboard.on_connection(socket).await })
+    Arc::clone(&value.wsboard)
 }
 
 const PERSIST_INTERVAL: Duration = Duration::from_secs(3);
@@ -63,3 +105,56 @@ fn load(id: &String, wsboard: &WsBoard) {
         wsboard.set_persist(&persist);
     }
 }
+
+// Database
+// Makes the user the owner of a document nobody owns yet, whether they own it now.
+pub async fn db_claim_ws_board(user_id: &String, id: &String, pool: &PgPool) -> Result<bool, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    sqlx::query("INSERT INTO ws_boards (id, user_id) VALUES ($1, $2) ON CONFLICT (id) DO NOTHING")
+        .bind(id)
+        .bind(user_id)
+        .execute(&mut tx)
+        .await?;
+    // Boards of other users aren't visible to the tenant
+    let owned: Option<(String,)> = sqlx::query_as("SELECT id FROM ws_boards WHERE id = $1 AND user_id = $2")
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(owned.is_some())
+}
+
+pub async fn db_owns_ws_board(user_id: &String, id: &String, pool: &PgPool) -> Result<bool, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let owned: Option<(String,)> = sqlx::query_as("SELECT id FROM ws_boards WHERE id = $1 AND user_id = $2")
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(owned.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::{test_pool, test_user_id};
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn first_user_to_open_a_board_owns_it() {
+        let pool = test_pool().await;
+        let owner = test_user_id();
+        let other = test_user_id();
+        let board = format!("board-{}", owner);
+
+        assert!(!db_owns_ws_board(&owner, &board, &pool).await.unwrap());
+        assert!(db_claim_ws_board(&owner, &board, &pool).await.unwrap());
+        assert!(db_claim_ws_board(&owner, &board, &pool).await.unwrap());
+        assert!(!db_claim_ws_board(&other, &board, &pool).await.unwrap());
+
+        assert!(db_owns_ws_board(&owner, &board, &pool).await.unwrap());
+        assert!(!db_owns_ws_board(&other, &board, &pool).await.unwrap());
+    }
+}
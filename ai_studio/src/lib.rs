@@ -16,6 +16,8 @@ use tower_http::{
 
 pub mod handlers;
 pub mod models;
+#[cfg(test)]
+mod test_db;
 
 use crate::handlers::actor_handler::{
     create_actor, create_actor_spec, delete_actor, delete_actor_spec, get_actor, get_actor_spec,
@@ -32,16 +34,30 @@ use crate::handlers::segment_handler::{
 };
 use crate::handlers::video_instance_handler::{
     create_video_instance, create_video_instance_spec, delete_video_instance,
-    delete_video_instance_spec, get_video_instance, get_video_instance_spec, update_video_instance,
-    update_video_instance_spec,
+    delete_video_instance_spec, get_shared_video, get_shared_video_spec, get_video_instance,
+    get_video_instance_spec, update_video_instance, update_video_instance_spec,
 };
-use crate::handlers::ws_handler::socket_handler;
+use crate::handlers::credits_handler::spawn_charge_job;
+use crate::handlers::erasure_handler::{
+    db_erase_user_studio, db_list_user_media_keys, db_list_user_ws_boards, delete_media, delete_ws_board_files,
+};
+use crate::handlers::export_handler::MyDataExport;
+use crate::handlers::share_handler::MySharedResources;
+use crate::handlers::ws_handler::{shared_socket_handler, socket_handler};
 use crate::models::ws_types::ServerState;
+use microservice_utils::server::audit::audit_impersonation;
 use microservice_utils::{open_api::gen::generate_openapi_spec, server::{consumer::get_consumer, spa::SpaRouter}};
 use microservice_utils::{
     open_api::gen::{GenSpec, Spec},
     server::error_404::error_404,
 };
+use microservice_utils::server::grpc::data_export::data_export_server::DataExportServer;
+use microservice_utils::server::grpc::shared_resources::shared_resources_server::SharedResourcesServer;
+use microservice_utils::server::grpc_support::{grpc_web, health_service};
+use microservice_utils::events::{event_producer, start_event_consumer, Event};
+use microservice_utils::server::erasure::report_erasure;
+use microservice_utils::server::hybrid::hybrid;
+use microservice_utils::server::service_auth::{check_service_config, service_interceptor, AI_STUDIO_SERVICE};
 
 #[macro_use]
 extern crate lazy_static;
@@ -57,6 +73,7 @@ lazy_static! {
 #[tokio::main]
 pub async fn main() {
     dotenv().expect("Failed to read .env file");
+    check_service_config().expect("Invalid service token config");
     lazy_static::initialize(&DATABASE_URL);
 
     let pool = PgPool::connect(&std::env::var("DATABASE_URL").unwrap())
@@ -66,12 +83,31 @@ pub async fn main() {
     let axum_make_service = create_app(&pool);
 
     start_consumer();
+    start_events(&pool);
+    spawn_charge_job(&pool);
+
+    let grpc_service = tonic::transport::Server::builder()
+        .add_service(grpc_web(DataExportServer::with_interceptor(
+            MyDataExport::new(pool.clone()),
+            service_interceptor(AI_STUDIO_SERVICE),
+        )))
+        .add_service(grpc_web(SharedResourcesServer::with_interceptor(
+            MySharedResources::new(pool),
+            service_interceptor(AI_STUDIO_SERVICE),
+        )))
+        .add_service(health_service::<DataExportServer<MyDataExport>>().await)
+        .into_service();
+
+    let hybrid_make_service = hybrid(
+        axum_make_service.into_make_service_with_connect_info::<SocketAddr>(),
+        grpc_service,
+    );
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 5000));
     println!("Listening on http://{}", addr);
 
     axum_server::bind(addr)
-        .serve(axum_make_service.into_make_service())
+        .serve(hybrid_make_service)
         .await
         .unwrap();
 }
@@ -158,6 +194,10 @@ fn create_app(pool: &PgPool) -> Router {
             route: "/api/ai_studio/csv".into(),
             gen: Box::new(import_from_csv_spec),
         },
+        Spec {
+            route: "/api/ai_studio/shared/video".into(),
+            gen: Box::new(get_shared_video_spec),
+        },
     ];
 
     generate_openapi_spec(specs).expect("failed to generate openapi spec");
@@ -178,6 +218,7 @@ fn create_app(pool: &PgPool) -> Router {
         )
         .layer(ConcurrencyLimitLayer::new(64))
         .layer(cors)
+        .layer(axum::middleware::from_fn(audit_impersonation))
         .into_inner();
 
     let app = Router::new()
@@ -214,7 +255,9 @@ fn create_app(pool: &PgPool) -> Router {
             "/api/ai_studio/csv",
             post(import_from_csv).get(export_to_csv),
         )
+        .route("/api/ai_studio/shared/video", get(get_shared_video))
         .route("/socket/:id", get(socket_handler))
+        .route("/socket/shared/:id", get(shared_socket_handler))
         .fallback(get(error_404))
         .layer(Extension(state))
         .layer(Extension(pool_arc))
@@ -223,6 +266,28 @@ fn create_app(pool: &PgPool) -> Router {
     return app;
 }
 
+fn start_events(pool: &PgPool) {
+    let pool = pool.clone();
+    let producer = event_producer();
+    start_event_consumer("ai_studio", move |event| {
+        let pool = pool.clone();
+        let producer = producer.clone();
+        async move {
+            match event {
+                Event::UserDeletionRequested { deletion_id, user_id, .. } => {
+                    // Objects go first, the rows are how they are found when this is retried.
+                    delete_media(&db_list_user_media_keys(&user_id, &pool).await?).await?;
+                    delete_ws_board_files(&db_list_user_ws_boards(&user_id, &pool).await?)?;
+                    let erasure = db_erase_user_studio(&user_id, &pool).await?;
+                    report_erasure(&deletion_id, &user_id, AI_STUDIO_SERVICE, erasure, &producer).await?;
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+    });
+}
+
 fn start_consumer() {
     tokio::spawn(async move {
         let consumer = Arc::new(get_consumer("127.0.0.1:9092", "1234", &["bhuman_channel"]));
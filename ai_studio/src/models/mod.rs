@@ -8,4 +8,5 @@ pub mod client;
 pub mod ws_board;
 pub mod ws_types;
 pub mod ws_message;
-pub mod csv;
\ No newline at end of file
+pub mod csv;
+pub mod share;
\ No newline at end of file
@@ -0,0 +1,19 @@
+use microservice_utils::jwt::share::{ShareScope, READ_ACTION, VIDEO_RESOURCE, WS_BOARD_RESOURCE};
+
+// Read access to one generated video, its id in the `id` query parameter.
+pub struct VideoRead;
+
+impl ShareScope for VideoRead {
+    const RESOURCE: &'static str = VIDEO_RESOURCE;
+    const ACTION: &'static str = READ_ACTION;
+    const PARAM: &'static str = "id";
+}
+
+// Read-only connection to one WsBoard document, its id in the `id` path segment.
+pub struct WsBoardRead;
+
+impl ShareScope for WsBoardRead {
+    const RESOURCE: &'static str = WS_BOARD_RESOURCE;
+    const ACTION: &'static str = READ_ACTION;
+    const PARAM: &'static str = "id";
+}
@@ -46,11 +46,11 @@ impl From<ServerMsg> for Message {
 }
 
 impl WsBoard {
-    /// Handle a connection from a WebSocket.
-    pub async fn on_connection(&self, socket: WebSocket) {
+    /// Handle a connection from a WebSocket. Read-only connections can only share their presence.
+    pub async fn on_connection(&self, socket: WebSocket, read_only: bool) {
         let id = self.count.fetch_add(1, Ordering::Relaxed);
         println!("connection! id = {}", id);
-        if let Err(e) = self.handle_connection(id, socket).await {
+        if let Err(e) = self.handle_connection(id, socket, read_only).await {
             println!("connection terminated early: {}", e);
         }
         println!("disconnection, id = {}", id);
@@ -117,7 +117,7 @@ impl WsBoard {
         self.killed.load(Ordering::Relaxed)
     }
 
-    async fn handle_connection(&self, id: u64, mut socket: WebSocket) -> Result<()> {
+    async fn handle_connection(&self, id: u64, mut socket: WebSocket, read_only: bool) -> Result<()> {
         let mut update_rx = self.update.subscribe();
 
         self.send_initial(id, &mut socket).await?;
@@ -140,7 +140,7 @@ impl WsBoard {
                     match result {
                         None => break,
                         Some(message) => {
-                            self.handle_message(id, message?).await?;
+                            self.handle_message(id, message?, read_only).await?;
                         }
                     }
                 }
@@ -191,11 +191,14 @@ impl WsBoard {
         Ok(())
     }
 
-    async fn handle_message(&self, id: u64, message: Message) -> Result<()> {
+    async fn handle_message(&self, id: u64, message: Message, read_only: bool) -> Result<()> {
         if let Message::Text(_message) = message {
             println!("====================={:?}", _message);
             let msg: ClientMsg =
                 serde_json::from_str(&_message).context("failed to deserialize message")?;
+            if read_only && !matches!(msg, ClientMsg::ClientInfo(_) | ClientMsg::CursorData(_)) {
+                return Ok(());
+            }
             match msg {
                 ClientMsg::ClientInfo(info) => {
                     self.state.write().users.insert(id, info.clone());
@@ -0,0 +1,14 @@
+use std::{env, path::PathBuf};
+
+fn main() {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let proto_file = "./proto/api_keygen_service.proto";
+
+    tonic_build::configure()
+        .build_server(true)
+        .file_descriptor_set_path(out_dir.join("api_keygen_microservice_descriptor.bin"))
+        .compile(&[proto_file], &["."])
+        .unwrap_or_else(|e| panic!("protobuf compile error: {}", e));
+
+    println!("cargo:rerun-if-changed={}", proto_file);
+}
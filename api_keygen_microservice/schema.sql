@@ -1,8 +1,11 @@
-DROP generated_keys;
-
 CREATE TABLE IF NOT EXISTS generated_keys (
     id SERIAL PRIMARY KEY, 
     user_id TEXT NOT NULL UNIQUE,
     client_id TEXT NOT NULL UNIQUE,
     client_secret TEXT NOT NULL UNIQUE 
-)
\ No newline at end of file
+);
+
+ALTER TABLE generated_keys ADD COLUMN IF NOT EXISTS scopes TEXT[] NOT NULL DEFAULT '{}';
+
+-- Keys generated before secrets were hashed store the plain secret, it is hashed on first use.
+ALTER TABLE generated_keys ADD COLUMN IF NOT EXISTS secret_hashed BOOLEAN NOT NULL DEFAULT false;
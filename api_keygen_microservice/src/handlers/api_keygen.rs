@@ -1,18 +1,24 @@
 use std::sync::Arc;
 
+use axum::extract::rejection::JsonRejection;
 use axum::Extension;
 use axum::Json;
 use axum_macros::debug_handler;
+use microservice_utils::server::service_auth::{authorize, ALL_SERVICES};
+use microservice_utils::jwt::authenticated::{is_api_key_scope, API_KEY_SCOPES};
 use microservice_utils::jwt::extractor::AuthToken;
 use openapi_rs::openapi_proc_macro::{handler};
 
 use okapi::openapi3::RefOr;
 use openapi_rs::gen::OpenApiGenerator;
+use openapi_rs::OpenApiFromData;
 
 use microservice_utils::server::response::{into_reponse, AxumRes, AxumResult};
+use microservice_utils::server::erasure::{db_erase_user_rows, Erasure};
 
 // use openssl::pkey::PKey;
 use openssl::hash::{hash, MessageDigest};
+use openssl::memcmp;
 use openssl::rand::rand_bytes;
 // use openssl::rsa::Rsa;
 use serde_json::json;
@@ -20,6 +26,14 @@ use serde_json::json;
 
 use nanoid::nanoid;
 use sqlx::PgPool;
+use tonic::async_trait;
+use tonic::{Code, Status};
+
+use crate::api_keygen_service::api_keygen_service_server::ApiKeygenService;
+use crate::api_keygen_service::{
+    CheckClientRequest, CheckClientResponse, VerifyApiKeyRequest, VerifyApiKeyResponse,
+};
+use crate::models::keygen::GenerateKeypair;
 
 const CHARS: &[char; 63] = &[
     'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's',
@@ -35,24 +49,102 @@ fn random(size: usize) -> Vec<u8> {
     buf.to_vec()
 }
 
+// Only a digest of the secret is stored, the plain secret is returned once on generation.
+fn hash_secret(client_secret: &str) -> Result<String, openssl::error::ErrorStack> {
+    let bytes = hash(MessageDigest::sha256(), client_secret.as_bytes())?;
+    Ok(hex::encode(bytes))
+}
+
+// Compares in constant time, so the stored value can't be guessed byte by byte.
+fn secrets_match(a: &str, b: &str) -> bool {
+    a.len() == b.len() && memcmp::eq(a.as_bytes(), b.as_bytes())
+}
+
+// gRPC
+#[derive(Debug)]
+pub struct MyApiKeygenService {
+    pool: PgPool,
+}
+
+impl MyApiKeygenService {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl ApiKeygenService for MyApiKeygenService {
+    async fn verify_api_key(
+        &self,
+        request: tonic::Request<VerifyApiKeyRequest>,
+    ) -> Result<tonic::Response<VerifyApiKeyResponse>, tonic::Status> {
+        authorize(&request, ALL_SERVICES)?;
+
+        let req: VerifyApiKeyRequest = request.into_inner();
+
+        let (user_id, scopes) = db_verify_api_key(&req.client_id, &req.client_secret, &self.pool)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => Status::new(Code::Unauthenticated, "Invalid api key"),
+                e => Status::new(Code::Internal, format!("{:?}", e)),
+            })?;
+
+        Ok(tonic::Response::new(VerifyApiKeyResponse {
+            status: "success".to_string(),
+            user_id,
+            scopes,
+        }))
+    }
+
+    async fn check_client(
+        &self,
+        request: tonic::Request<CheckClientRequest>,
+    ) -> Result<tonic::Response<CheckClientResponse>, tonic::Status> {
+        authorize(&request, ALL_SERVICES)?;
+
+        let req: CheckClientRequest = request.into_inner();
+        let active = db_client_active(&req.client_id, &self.pool)
+            .await
+            .map_err(|e| Status::new(Code::Internal, format!("{:?}", e)))?;
+
+        Ok(tonic::Response::new(CheckClientResponse {
+            status: "success".to_string(),
+            active,
+        }))
+    }
+}
+
+// API
 #[debug_handler]
 #[handler(method = "POST", tag = "keygen")]
 pub async fn generate_keypairs(
+    payload: Result<Json<GenerateKeypair>, JsonRejection>,
     AuthToken(user_id): AuthToken,
     Extension(pool): Extension<Arc<PgPool>>,
 ) -> AxumResult<Json<AxumRes>> {
-    let client_id = nanoid!(37, CHARS, random);
+    let scopes = match payload {
+        Ok(Json(req)) => req.scopes,
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            return Err(into_reponse(400, json!({"error": format!("{:?}", e)})));
+        }
+    };
+    if scopes.is_empty() || !scopes.iter().all(|s| is_api_key_scope(s)) {
+        return Err(into_reponse(400, json!({"error": format!("scopes must be among {:?}", API_KEY_SCOPES)})));
+    }
 
-    let bytes = hash(MessageDigest::sha256(), client_id.as_bytes())
-        .map_err(|e| into_reponse(500, json!(e.to_string())))?;
+    let client_id = nanoid!(37, CHARS, random);
+    let client_secret = hex::encode(random(32));
 
-    let client_secret = hex::encode(bytes);
+    let secret_hash =
+        hash_secret(&client_secret).map_err(|e| into_reponse(500, json!(e.to_string())))?;
 
     let _ = sqlx::query!(
-        "INSERT INTO generated_keys(user_id,client_id,client_secret) VALUES($1,$2,$3)",
+        "INSERT INTO generated_keys(user_id,client_id,client_secret,scopes,secret_hashed) VALUES($1,$2,$3,$4,true)",
         user_id,
         client_id,
-        client_secret
+        secret_hash,
+        &scopes
     )
     .execute(&*pool)
     .await
@@ -60,6 +152,169 @@ pub async fn generate_keypairs(
 
     Ok(Json(AxumRes {
         code: 200,
-        result: json!({ "client_id": client_id,"client_secret": client_secret }),
+        result: json!({ "client_id": client_id,"client_secret": client_secret, "scopes": scopes }),
     }))
 }
+
+// Database
+// RowNotFound when the client id is unknown or the secret doesn't match.
+pub async fn db_verify_api_key(
+    client_id: &String,
+    client_secret: &String,
+    pool: &PgPool,
+) -> Result<(String, Vec<String>), sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT id, user_id, scopes, client_secret, secret_hashed FROM generated_keys WHERE client_id = $1",
+        client_id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let secret_hash =
+        hash_secret(client_secret).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+    let presented = if row.secret_hashed { &secret_hash } else { client_secret };
+    if !secrets_match(presented, &row.client_secret) {
+        return Err(sqlx::Error::RowNotFound);
+    }
+
+    if !row.secret_hashed {
+        let _ = sqlx::query!(
+            "UPDATE generated_keys SET client_secret = $1, secret_hashed = true WHERE id = $2 AND secret_hashed = false",
+            secret_hash,
+            row.id
+        )
+        .execute(pool)
+        .await?;
+    }
+    Ok((row.user_id, row.scopes))
+}
+
+// Client ids are never reused, a missing key was deleted.
+pub async fn db_client_active(client_id: &String, pool: &PgPool) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!("SELECT id FROM generated_keys WHERE client_id = $1", client_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.is_some())
+}
+
+// Revokes the key pair of a deleted account, and with it the tokens issued to it.
+pub async fn db_erase_user_keys(user_id: &String, pool: &PgPool) -> Result<Erasure, sqlx::Error> {
+    db_erase_user_rows(
+        user_id,
+        &["DELETE FROM generated_keys WHERE user_id = $1"],
+        &["SELECT count(*) FROM generated_keys WHERE user_id = $1"],
+        pool,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::{test_pool, test_user_id};
+
+    async fn insert_key(user_id: &String, stored_secret: &str, hashed: bool, pool: &PgPool) -> String {
+        let client_id = nanoid!(37, CHARS, random);
+        sqlx::query(
+            "INSERT INTO generated_keys (user_id, client_id, client_secret, scopes, secret_hashed) VALUES ($1, $2, $3, '{contacts:read}', $4)",
+        )
+        .bind(user_id)
+        .bind(&client_id)
+        .bind(stored_secret)
+        .bind(hashed)
+        .execute(pool)
+        .await
+        .unwrap();
+        client_id
+    }
+
+    async fn stored_secret(client_id: &String, pool: &PgPool) -> (String, bool) {
+        sqlx::query_as("SELECT client_secret, secret_hashed FROM generated_keys WHERE client_id = $1")
+            .bind(client_id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn secrets_match_needs_equal_values() {
+        assert!(secrets_match("abc", "abc"));
+        assert!(!secrets_match("abc", "abd"));
+        assert!(!secrets_match("abc", "abcd"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn hashed_secret_verifies() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        let secret = hex::encode(random(32));
+        let client_id = insert_key(&user_id, &hash_secret(&secret).unwrap(), true, &pool).await;
+
+        let (owner, scopes) = db_verify_api_key(&client_id, &secret, &pool).await.unwrap();
+        assert_eq!(owner, user_id);
+        assert_eq!(scopes, vec!["contacts:read".to_string()]);
+        // the stored hash is not a valid secret
+        let stored = hash_secret(&secret).unwrap();
+        assert!(matches!(
+            db_verify_api_key(&client_id, &stored, &pool).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn legacy_secret_verifies_and_is_hashed_on_first_use() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        let secret = hex::encode(random(32));
+        let client_id = insert_key(&user_id, &secret, false, &pool).await;
+
+        assert_eq!(db_verify_api_key(&client_id, &secret, &pool).await.unwrap().0, user_id);
+        assert_eq!(stored_secret(&client_id, &pool).await, (hash_secret(&secret).unwrap(), true));
+        // still valid once upgraded
+        assert_eq!(db_verify_api_key(&client_id, &secret, &pool).await.unwrap().0, user_id);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn wrong_secret_is_rejected_without_upgrading() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        let secret = hex::encode(random(32));
+        let client_id = insert_key(&user_id, &secret, false, &pool).await;
+
+        assert!(matches!(
+            db_verify_api_key(&client_id, &hex::encode(random(32)), &pool).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        assert_eq!(stored_secret(&client_id, &pool).await, (secret, false));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn erased_key_is_no_longer_active() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        let client_id = insert_key(&user_id, &hash_secret("secret").unwrap(), true, &pool).await;
+
+        assert!(db_client_active(&client_id, &pool).await.unwrap());
+        db_erase_user_keys(&user_id, &pool).await.unwrap();
+        assert!(!db_client_active(&client_id, &pool).await.unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn keys_are_only_granted_known_scopes() {
+        let pool = Arc::new(test_pool().await);
+        let generate = |scopes: &[&str]| {
+            let payload = GenerateKeypair { scopes: scopes.iter().map(|s| s.to_string()).collect() };
+            generate_keypairs(Ok(Json(payload)), AuthToken(test_user_id()), Extension(pool.clone()))
+        };
+
+        assert!(generate(&[]).await.is_err());
+        assert!(generate(&["contacts:read", "admin"]).await.is_err());
+        let res = generate(&["contacts:read", "files:write"]).await.unwrap();
+        assert_eq!(res.0.result["scopes"], json!(["contacts:read", "files:write"]));
+    }
+}
@@ -4,7 +4,7 @@ use axum::{
     Router,
 };
 use dotenv::dotenv;
-use handlers::api_keygen::{generate_keypairs,generate_keypairs_spec};
+use handlers::api_keygen::{db_erase_user_keys,generate_keypairs,generate_keypairs_spec,MyApiKeygenService};
 use shuttle_service::error::CustomError;
 use sqlx::{Executor, PgPool};
 use std::{env, ffi::OsStr, net::SocketAddr, sync::Arc};
@@ -16,18 +16,28 @@ use tower_http::{
 };
 
 
+use microservice_utils::server::audit::audit_impersonation;
 use microservice_utils::{open_api::gen::{generate_openapi_spec, Spec, GenSpec}, server::{spa::SpaRouter}};
-// use microservice_utils::server::hybrid::hybrid;
+use microservice_utils::server::hybrid::hybrid;
+use microservice_utils::server::grpc_support::{grpc_web, health_service, reflection_service};
+use microservice_utils::server::service_auth::{check_service_config, service_interceptor, API_KEYGEN_SERVICE};
 use microservice_utils::server::error_404::error_404;
+use microservice_utils::server::erasure::report_erasure;
+use microservice_utils::events::{event_producer, start_event_consumer, Event};
 
 pub mod handlers;
 pub mod models;
+#[cfg(test)]
+mod test_db;
 
-// pub mod auth_service {
-//     tonic::include_proto!("auth_service");
-// }
+pub mod api_keygen_service {
+    tonic::include_proto!("api_keygen_service");
+}
+
+use api_keygen_service::api_keygen_service_server::ApiKeygenServiceServer;
 
-// use auth_service::auth_service_server::AuthServiceServer;
+pub(crate) const FILE_DESCRIPTOR_SET: &[u8] =
+    tonic::include_file_descriptor_set!("api_keygen_microservice_descriptor");
 
 #[macro_use]
 extern crate lazy_static;
@@ -43,36 +53,73 @@ lazy_static! {
 #[tokio::main]
 pub async fn main() {
     dotenv().expect("Failed to read .env file");
+    check_service_config().expect("Invalid service token config");
     lazy_static::initialize(&DATABASE_URL);
+    let reflection = reflection_service(&[FILE_DESCRIPTOR_SET]).expect("Invalid gRPC reflection descriptors");
 
     let pool = PgPool::connect(&std::env::var("DATABASE_URL").unwrap())
         .await
         .unwrap();
 
+    start_events(&pool);
     let axum_make_service = create_app(&pool);
 
-    // let hybrid_make_service = hybrid(, grpc_service);
+    let grpc_service = tonic::transport::Server::builder()
+        .add_service(grpc_web(ApiKeygenServiceServer::with_interceptor(
+            MyApiKeygenService::new(pool),
+            service_interceptor(API_KEYGEN_SERVICE),
+        )))
+        .add_service(health_service::<ApiKeygenServiceServer<MyApiKeygenService>>().await)
+        .add_service(reflection)
+        .into_service();
+
+    let hybrid_make_service = hybrid(
+        axum_make_service.into_make_service_with_connect_info::<SocketAddr>(),
+        grpc_service,
+    );
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 4005));
     println!("Listening on http://{}", addr);
 
     axum_server::bind(addr)
-        .serve(axum_make_service.into_make_service())
+        .serve(hybrid_make_service)
         .await
         .unwrap();
 }
 
 #[shuttle_service::main]
 async fn axum(pool: PgPool) -> shuttle_service::ShuttleAxum {
+    // refuse to start without a service signing key
+    check_service_config()?;
     pool.execute(include_str!("../schema.sql"))
         .await
         .map_err(CustomError::new)?;
 
+    start_events(&pool);
     let app = create_app(&pool);
     let sync_wrapper = SyncWrapper::new(app);
     Ok(sync_wrapper)
 }
 
+fn start_events(pool: &PgPool) {
+    let pool = pool.clone();
+    let producer = event_producer();
+    start_event_consumer("api_keygen_service", move |event| {
+        let pool = pool.clone();
+        let producer = producer.clone();
+        async move {
+            match event {
+                Event::UserDeletionRequested { deletion_id, user_id, .. } => {
+                    let erasure = db_erase_user_keys(&user_id, &pool).await?;
+                    report_erasure(&deletion_id, &user_id, API_KEYGEN_SERVICE, erasure, &producer).await?;
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+    });
+}
+
 fn create_app(pool: &PgPool) -> Router {
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var(
@@ -106,6 +153,7 @@ fn create_app(pool: &PgPool) -> Router {
         )
         .layer(ConcurrencyLimitLayer::new(64))
         .layer(cors)
+        .layer(axum::middleware::from_fn(audit_impersonation))
         .into_inner();
 
     let app = Router::new()
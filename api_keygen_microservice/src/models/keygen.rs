@@ -0,0 +1,9 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Default, Debug, Clone, JsonSchema, PartialEq, Serialize, Deserialize)]
+pub struct GenerateKeypair {
+    #[serde(default)]
+    pub scopes: Vec<String>, // scopes granted to the integration, e.g. "contacts:read"
+}
@@ -0,0 +1,22 @@
+// Database of the tests that need one: TEST_DATABASE_URL, with schema.sql applied once per run.
+// Tests use their own user ids, so they don't see each other's rows.
+use sqlx::{Executor, PgPool};
+use tokio::sync::OnceCell;
+use uuid::Uuid;
+
+static SCHEMA: OnceCell<()> = OnceCell::const_new();
+
+pub(crate) async fn test_pool() -> PgPool {
+    let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL");
+    let pool = PgPool::connect(&url).await.unwrap();
+    SCHEMA
+        .get_or_init(|| async {
+            pool.execute(include_str!("../schema.sql")).await.unwrap();
+        })
+        .await;
+    pool
+}
+
+pub(crate) fn test_user_id() -> String {
+    format!("test-{}", Uuid::new_v4())
+}
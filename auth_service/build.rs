@@ -1,8 +1,12 @@
+use std::{env, path::PathBuf};
+
 fn main() {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
     let proto_file = "./proto/auth_service.proto";
 
     tonic_build::configure()
         .build_server(true)
+        .file_descriptor_set_path(out_dir.join("auth_service_descriptor.bin"))
         .compile(&[proto_file], &["."])
         .unwrap_or_else(|e| panic!("protobuf compile error: {}", e));
 
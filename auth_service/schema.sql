@@ -1,16 +1,321 @@
-DROP TABLE auth;
-
-CREATE TABLE IF NOT EXISTS auth (
-    id SERIAL PRIMARY KEY, 
-    user_id TEXT NOT NULL UNIQUE, 
-    access_token TEXT NOT NULL, 
-    refresh_token TEXT NOT NULL, 
-    provider_type TEXT NOT NULL
-);
+DROP TABLE IF EXISTS auth;
 
 CREATE TABLE IF NOT EXISTS shopify_auth (
     id SERIAL PRIMARY KEY, 
     user_id TEXT NOT NULL UNIQUE, 
     token TEXT NOT NULL, 
     email TEXT NOT NULL
-);
\ No newline at end of file
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+    id uuid DEFAULT uuid_generate_v4(),
+    user_id TEXT NOT NULL,
+    access_token TEXT NOT NULL,
+    provider_type TEXT NOT NULL,
+    device TEXT,
+    user_agent TEXT,
+    ip TEXT,
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_seen_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    revoked_at TIMESTAMP(3),
+    PRIMARY KEY (id)
+);
+
+CREATE INDEX IF NOT EXISTS sessions_user_id_idx ON sessions (user_id);
+
+-- Every refresh token of a session belongs to the same family (session_id).
+-- A token can be used once; presenting a used one revokes the whole session.
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    id uuid DEFAULT uuid_generate_v4(),
+    session_id uuid NOT NULL REFERENCES sessions (id) ON DELETE CASCADE,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    used_at TIMESTAMP(3),
+    PRIMARY KEY (id)
+);
+
+CREATE INDEX IF NOT EXISTS refresh_tokens_session_id_idx ON refresh_tokens (session_id);
+
+-- Sessions from before rotation kept their refresh token in the clear, hashed like hash_token
+-- (microservice_utils/src/jwt/auth.rs) so they can still be refreshed.
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM information_schema.columns WHERE table_name = 'sessions' AND column_name = 'refresh_token') THEN
+        INSERT INTO refresh_tokens (session_id, token_hash)
+            SELECT id, encode(sha256(convert_to(refresh_token, 'UTF8')), 'hex') FROM sessions
+            WHERE refresh_token IS NOT NULL AND revoked_at IS NULL
+            ON CONFLICT (token_hash) DO NOTHING;
+        ALTER TABLE sessions DROP COLUMN refresh_token;
+    END IF;
+END $$;
+
+-- Used by the local identity provider (IDENTITY_PROVIDER=local)
+CREATE TABLE IF NOT EXISTS local_users (
+    user_id TEXT NOT NULL,
+    email TEXT UNIQUE,
+    phone_number TEXT UNIQUE,
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id)
+);
+
+CREATE TABLE IF NOT EXISTS local_codes (
+    id uuid DEFAULT uuid_generate_v4(),
+    user_id TEXT NOT NULL REFERENCES local_users (user_id) ON DELETE CASCADE,
+    kind TEXT NOT NULL,
+    code_hash TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    expires_at TIMESTAMP(3) NOT NULL,
+    used_at TIMESTAMP(3),
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id)
+);
+
+CREATE INDEX IF NOT EXISTS local_codes_code_hash_idx ON local_codes (code_hash);
+
+CREATE TABLE IF NOT EXISTS password_credentials (
+    user_id TEXT NOT NULL,
+    email TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id)
+);
+
+CREATE TABLE IF NOT EXISTS password_resets (
+    id uuid DEFAULT uuid_generate_v4(),
+    user_id TEXT NOT NULL REFERENCES password_credentials (user_id) ON DELETE CASCADE,
+    token_hash TEXT NOT NULL UNIQUE,
+    expires_at TIMESTAMP(3) NOT NULL,
+    used_at TIMESTAMP(3),
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id)
+);
+
+-- Passwords only log in once the email was verified, with a link sent on registration.
+-- Registrations from before keep logging in.
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM information_schema.columns WHERE table_name = 'password_credentials' AND column_name = 'verified_at') THEN
+        ALTER TABLE password_credentials ADD COLUMN verified_at TIMESTAMP(3);
+        UPDATE password_credentials SET verified_at = created_at;
+    END IF;
+END $$;
+-- 'reset' or 'verify'
+ALTER TABLE password_resets ADD COLUMN IF NOT EXISTS kind TEXT NOT NULL DEFAULT 'reset';
+
+-- Two-factor authentication. A row without confirmed_at is a pending enrolment.
+CREATE TABLE IF NOT EXISTS mfa_totp (
+    user_id TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    last_used_step BIGINT NOT NULL DEFAULT 0,
+    confirmed_at TIMESTAMP(3),
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id)
+);
+
+CREATE TABLE IF NOT EXISTS mfa_recovery_codes (
+    id uuid DEFAULT uuid_generate_v4(),
+    user_id TEXT NOT NULL,
+    code_hash TEXT NOT NULL,
+    used_at TIMESTAMP(3),
+    PRIMARY KEY (id)
+);
+
+CREATE INDEX IF NOT EXISTS mfa_recovery_codes_user_id_idx ON mfa_recovery_codes (user_id);
+
+-- Issued after the first factor, exchanged for tokens once the second factor is verified.
+CREATE TABLE IF NOT EXISTS mfa_challenges (
+    id uuid DEFAULT uuid_generate_v4(),
+    user_id TEXT NOT NULL,
+    provider_type TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    expires_at TIMESTAMP(3) NOT NULL,
+    used_at TIMESTAMP(3),
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id)
+);
+
+-- Failed verification counters, keyed by identifier (OTP method id) and by client ip.
+CREATE TABLE IF NOT EXISTS auth_attempts (
+    scope TEXT NOT NULL,
+    key TEXT NOT NULL,
+    failures INTEGER NOT NULL DEFAULT 0,
+    lockouts INTEGER NOT NULL DEFAULT 0,
+    locked_until TIMESTAMP(3),
+    last_failure_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (scope, key)
+);
+
+-- Codes sent per destination (email / phone number) and per client ip.
+CREATE TABLE IF NOT EXISTS send_throttle (
+    key TEXT NOT NULL,
+    window_start TIMESTAMP(3) NOT NULL,
+    sent_count INTEGER NOT NULL DEFAULT 0,
+    last_sent_at TIMESTAMP(3) NOT NULL,
+    PRIMARY KEY (key)
+);
+
+CREATE TABLE IF NOT EXISTS lockout_events (
+    id uuid DEFAULT uuid_generate_v4(),
+    scope TEXT NOT NULL,
+    key TEXT NOT NULL,
+    ip TEXT,
+    user_agent TEXT,
+    lockouts INTEGER NOT NULL,
+    locked_until TIMESTAMP(3) NOT NULL,
+    released_by TEXT,
+    released_at TIMESTAMP(3),
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id)
+);
+
+CREATE INDEX IF NOT EXISTS lockout_events_created_at_idx ON lockout_events (created_at);
+
+-- Internal accounts. Ids are the provider user id of the first login, so existing users keep theirs.
+CREATE TABLE IF NOT EXISTS accounts (
+    id TEXT NOT NULL,
+    merged_into TEXT,
+    merged_at TIMESTAMP(3),
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id)
+);
+
+-- Login methods (email, phone, Google, Microsoft, LinkedIn, Shopify, ...) of an account.
+CREATE TABLE IF NOT EXISTS identities (
+    id uuid DEFAULT uuid_generate_v4(),
+    account_id TEXT NOT NULL REFERENCES accounts (id),
+    provider_type TEXT NOT NULL,
+    provider_user_id TEXT NOT NULL,
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id),
+    UNIQUE (provider_type, provider_user_id)
+);
+
+CREATE INDEX IF NOT EXISTS identities_account_id_idx ON identities (account_id);
+
+-- Emails and phone numbers login codes were sent to, by blind index, with the provider user they
+-- belong to. A contact is proven once that provider user signed in, i.e. is one of the identities.
+CREATE TABLE IF NOT EXISTS provider_contacts (
+    provider_user_id TEXT NOT NULL,
+    contact_index TEXT NOT NULL,
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (provider_user_id, contact_index)
+);
+
+CREATE INDEX IF NOT EXISTS provider_contacts_contact_index_idx ON provider_contacts (contact_index);
+
+-- Shops that installed the Shopify app, with the offline access token from the OAuth callback.
+-- The token is cleared when the app is uninstalled.
+CREATE TABLE IF NOT EXISTS shopify_shops (
+    shop TEXT NOT NULL,
+    access_token TEXT,
+    scope TEXT NOT NULL,
+    installed_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    uninstalled_at TIMESTAMP(3),
+    PRIMARY KEY (shop)
+);
+
+-- Pending install flows, `state` is checked and consumed by the callback.
+CREATE TABLE IF NOT EXISTS shopify_oauth_states (
+    state TEXT NOT NULL,
+    shop TEXT NOT NULL,
+    expires_at TIMESTAMP(3) NOT NULL,
+    PRIMARY KEY (state)
+);
+
+-- Users are linked to a shop instead of storing a token they posted themselves. Tokens posted
+-- before the install flow are kept as `legacy_token` and served until the user is linked to a shop.
+ALTER TABLE shopify_auth ADD COLUMN IF NOT EXISTS shop TEXT;
+ALTER TABLE shopify_auth ALTER COLUMN email DROP NOT NULL;
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM information_schema.columns WHERE table_name = 'shopify_auth' AND column_name = 'token') THEN
+        ALTER TABLE shopify_auth RENAME COLUMN token TO legacy_token;
+    END IF;
+END $$;
+ALTER TABLE shopify_auth ADD COLUMN IF NOT EXISTS legacy_token TEXT;
+ALTER TABLE shopify_auth ALTER COLUMN legacy_token DROP NOT NULL;
+
+-- Admins acting as a user. Each one has its own session, revoked when it ends.
+CREATE TABLE IF NOT EXISTS impersonations (
+    id uuid DEFAULT uuid_generate_v4(),
+    admin_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    session_id uuid NOT NULL,
+    scopes TEXT[] NOT NULL,
+    reason TEXT NOT NULL,
+    expires_at TIMESTAMP(3) NOT NULL,
+    ended_at TIMESTAMP(3),
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id)
+);
+
+CREATE INDEX IF NOT EXISTS impersonations_user_id_idx ON impersonations (user_id);
+CREATE UNIQUE INDEX IF NOT EXISTS impersonations_session_id_idx ON impersonations (session_id);
+
+-- Every request made with an impersonation token, including the refused ones.
+CREATE TABLE IF NOT EXISTS impersonation_requests (
+    id uuid DEFAULT uuid_generate_v4(),
+    impersonation_id uuid NOT NULL REFERENCES impersonations (id),
+    method TEXT NOT NULL,
+    path TEXT NOT NULL,
+    allowed BOOLEAN NOT NULL,
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id)
+);
+
+CREATE INDEX IF NOT EXISTS impersonation_requests_impersonation_id_idx ON impersonation_requests (impersonation_id);
+
+-- Down-scoped tokens for one action on one resource, e.g. a public link to a video.
+CREATE TABLE IF NOT EXISTS share_tokens (
+    id uuid DEFAULT uuid_generate_v4(),
+    user_id TEXT NOT NULL,
+    resource_type TEXT NOT NULL,
+    resource_id TEXT NOT NULL,
+    action TEXT NOT NULL,
+    max_uses INTEGER,
+    uses INTEGER NOT NULL DEFAULT 0,
+    expires_at TIMESTAMP(3) NOT NULL,
+    revoked_at TIMESTAMP(3),
+    last_used_at TIMESTAMP(3),
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id)
+);
+
+CREATE INDEX IF NOT EXISTS share_tokens_user_id_idx ON share_tokens (user_id);
+
+-- Every completed login, with what was unusual about it (new_device, new_country, impossible_travel).
+CREATE TABLE IF NOT EXISTS login_events (
+    id uuid DEFAULT uuid_generate_v4(),
+    user_id TEXT NOT NULL,
+    session_id uuid REFERENCES sessions (id) ON DELETE SET NULL,
+    provider_type TEXT NOT NULL,
+    ip TEXT,
+    user_agent TEXT,
+    device TEXT,
+    country TEXT,
+    city TEXT,
+    latitude DOUBLE PRECISION,
+    longitude DOUBLE PRECISION,
+    flags TEXT[] NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id)
+);
+
+CREATE INDEX IF NOT EXISTS login_events_user_id_created_at_idx ON login_events (user_id, created_at);
+
+-- Events published after the change announcing them committed, see microservice_utils::events::enqueue.
+CREATE TABLE IF NOT EXISTS event_outbox (
+    id BIGSERIAL PRIMARY KEY,
+    payload TEXT NOT NULL,
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Accounts whose erasure started, by blind index of the user id: no session is issued for them anymore.
+CREATE TABLE IF NOT EXISTS closed_accounts (
+    subject_index TEXT NOT NULL,
+    closed_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (subject_index)
+);
@@ -0,0 +1,54 @@
+use std::net::IpAddr;
+
+use maxminddb::{geoip2, Reader};
+
+lazy_static! {
+    // A MaxMind or DB-IP city database (.mmdb). Without one logins are recorded without a location.
+    static ref GEOIP: Option<Reader<Vec<u8>>> = match std::env::var("GEOIP_DATABASE_PATH") {
+        Ok(path) if !path.is_empty() => match Reader::open_readfile(&path) {
+            Ok(reader) => Some(reader),
+            Err(e) => {
+                println!("Unable to open GeoIP database {}: {:?}", path, e);
+                None
+            }
+        },
+        _ => None,
+    };
+}
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Location {
+    pub country: Option<String>, // ISO 3166 code
+    pub city: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+pub fn lookup(ip: &str) -> Option<Location> {
+    let reader = GEOIP.as_ref()?;
+    let ip: IpAddr = ip.parse().ok()?;
+    let city: geoip2::City = reader.lookup(ip).ok()?;
+    let location = city.location.as_ref();
+    Some(Location {
+        country: city.country.as_ref().and_then(|c| c.iso_code).map(str::to_string),
+        city: city
+            .city
+            .as_ref()
+            .and_then(|c| c.names.as_ref())
+            .and_then(|names| names.get("en"))
+            .map(|name| name.to_string()),
+        latitude: location.and_then(|l| l.latitude),
+        longitude: location.and_then(|l| l.longitude),
+    })
+}
+
+/// Great-circle distance between two (latitude, longitude) points.
+pub fn distance_km(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (lat1, lon1) = (from.0.to_radians(), from.1.to_radians());
+    let (lat2, lon2) = (to.0.to_radians(), to.1.to_radians());
+    let a = ((lat2 - lat1) / 2.0).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
@@ -6,6 +6,8 @@ use axum::{
 use axum_macros::debug_handler;
 use openapi_rs::openapi_proc_macro::handler;
 use sqlx::PgPool;
+use sqlx::types::chrono::Utc;
+use uuid::Uuid;
 use std::sync::Arc;
 use tonic::async_trait;
 use tonic::{Code, Status};
@@ -14,21 +16,28 @@ use okapi::openapi3::RefOr;
 use openapi_rs::gen::OpenApiGenerator;
 use openapi_rs::OpenApiFromData;
 
+use microservice_utils::server::service_auth::{authorize, ALL_SERVICES, CONTACTS_SERVICE, INVITE_SERVICE};
+use microservice_utils::jwt::share::decode_share_token;
+use microservice_utils::server::client_info::ClientInfo;
 use microservice_utils::{
-    jwt::auth::{create_token, Token},
-    server::response::{into_reponse, AxumRes,AxumResult},
+    jwt::auth::{create_token, decode_token, Token},
+    server::response::{into_reponse, AxumRes, AxumResult, ResponseError},
 };
 
-use crate::models::auth::{Email, PhoneNumber, Shopify, StytchOTP, StytchToken, StytchAuth};
+use crate::handlers::erasure_handler::db_account_closed;
+use crate::handlers::identity_handler::{db_owns_contact, db_record_contact, resolve_account};
+use crate::handlers::impersonation_handler::authorize_impersonation;
+use crate::handlers::login_handler::record_login;
+use crate::handlers::mfa_handler::mfa_challenge;
+use crate::handlers::share_handler::db_use_share_token;
+use crate::handlers::shopify_handler::{claims_shop, db_get_shopify_token, db_link_shop, require_installed, verify_session_token};
+use crate::handlers::token_handler::{db_insert_refresh_token, rotate_refresh_token, RefreshError};
+use crate::models::auth::{Email, PhoneNumber, Shopify, ShopifyOTP, StytchOTP, StytchToken, StytchAuth};
+use crate::providers::IdentityProvider;
+use crate::throttle::{check_attempts, check_send, otp_keys, record_failure, record_success, release_attempts};
 
 use crate::auth_service::auth_service_server::AuthService;
-use crate::auth_service::{CheckTokenRequest, CheckTokenResponse, TokenRefreshRequest, TokenRefreshResponse, CheckShopifyToken, ShopifyTokenResponse};
-
-lazy_static! {
-    static ref BASE_URL: String = "https://api.stytch.com/v1".to_owned();
-    static ref PROJECT_ID: String = "project-live-e3b801f1-b65a-44b1-ac74-006cbe4cebe1".to_owned();
-    static ref SECRET_ID: String = "secret-live-Abpes6Vi7UPs3vpxH6vA3LZtivK_zzEXyAs=".to_owned();
-}
+use crate::auth_service::{CheckTokenRequest, CheckTokenResponse, TokenRefreshRequest, TokenRefreshResponse, CheckShopifyToken, ShopifyTokenResponse, CheckShareTokenRequest, CheckShareTokenResponse, OwnsContactRequest, OwnsContactResponse};
 
 // gRPC
 pub struct MyAuthService {
@@ -50,14 +59,23 @@ impl AuthService for MyAuthService {
         request: tonic::Request<CheckTokenRequest>,
     ) -> Result<tonic::Response<CheckTokenResponse>, tonic::Status> {
 
+        authorize(&request, ALL_SERVICES)?;
+
         let req: CheckTokenRequest = request.into_inner();
         println!("Check Token {:?}", req);
 
-        let _ = db_check_token(&req, &self.pool)
+        let session_id = db_check_token(&req, &self.pool)
             .await
             .with_context(|| anyhow::anyhow!("Access token does not exist"))
             .map_err(|e| Status::new(Code::Internal, format!("{:?}", e)))?;
 
+        let allowed = authorize_impersonation(&session_id, &req.method, &req.path, &self.pool)
+            .await
+            .map_err(|e| Status::new(Code::Internal, format!("{:?}", e)))?;
+        if !allowed {
+            return Err(Status::new(Code::PermissionDenied, "Not allowed while impersonating"));
+        }
+
         Ok(tonic::Response::new(CheckTokenResponse {
             status: "success".to_string(),
         }))
@@ -68,25 +86,31 @@ impl AuthService for MyAuthService {
         request: tonic::Request<TokenRefreshRequest>,
     ) -> Result<tonic::Response<TokenRefreshResponse>, tonic::Status> {
 
+        authorize(&request, ALL_SERVICES)?;
+
         let req: TokenRefreshRequest = request.into_inner();
         println!("Refresh Token {:?}", req);
 
-        let _ = db_check_refresh_token(&req, &self.pool)
-            .await
-            .with_context(|| anyhow::anyhow!("Refresh token does not exist"))
-            .map_err(|e| Status::new(Code::Internal, format!("{:?}", e)))?;
+        let claims = decode_token(&req.refresh_token)
+            .map_err(|e| Status::new(Code::Unauthenticated, e.to_string()))?;
+        if claims.sub != req.user_id {
+            return Err(Status::new(Code::Unauthenticated, "Refresh token does not belong to user"));
+        }
 
-        let token = create_token(&req.user_id);
-        match db_update_token(&req.user_id, &token.access_token, &self.pool).await {
-            Ok(_) => {
+        match rotate_refresh_token(&req.refresh_token, &self.pool).await {
+            Ok(token) => {
                 Ok(tonic::Response::new(TokenRefreshResponse {
                     status: "success".to_string(),
                     access_token: token.access_token,
+                    refresh_token: token.refresh_token,
                 }))
             }
-            Err(e) => {
+            Err(RefreshError::Database(e)) => {
                 Err(Status::new(Code::Internal, format!("{:?}", e)))
             }
+            Err(e) => {
+                Err(Status::new(Code::Unauthenticated, e.to_string()))
+            }
         }
     }
 
@@ -95,17 +119,69 @@ impl AuthService for MyAuthService {
         request: tonic::Request<CheckShopifyToken>,
     ) -> Result<tonic::Response<ShopifyTokenResponse>, tonic::Status> {
 
+        authorize(&request, &[CONTACTS_SERVICE])?;
+
         let req: CheckShopifyToken = request.into_inner();
         println!("Request Shopify Token {:?}", req);
 
-        let ret = db_get_shopify_token(&req, &self.pool)
+        let (shop, token) = db_get_shopify_token(&req.user_id, &self.pool)
             .await
             .with_context(|| anyhow::anyhow!("Shopify token does not exist"))
             .map_err(|e| Status::new(Code::Internal, format!("{:?}", e)))?;
 
         Ok(tonic::Response::new(ShopifyTokenResponse {
             status: "success".to_string(),
-            token: ret.to_string(),
+            token,
+            shop,
+        }))
+    }
+
+    async fn check_share_token(
+        &self,
+        request: tonic::Request<CheckShareTokenRequest>,
+    ) -> Result<tonic::Response<CheckShareTokenResponse>, tonic::Status> {
+
+        authorize(&request, ALL_SERVICES)?;
+
+        let req: CheckShareTokenRequest = request.into_inner();
+        println!("Check Share Token {} {} {}", req.resource_type, req.resource_id, req.action);
+
+        let claims = decode_share_token(&req.token)
+            .map_err(|e| Status::new(Code::Unauthenticated, e.to_string()))?;
+        if claims.resource_type != req.resource_type
+            || claims.resource_id != req.resource_id
+            || claims.action != req.action
+        {
+            return Err(Status::new(Code::PermissionDenied, "Share token is not valid for this resource"));
+        }
+
+        let user_id = db_use_share_token(&claims, &self.pool)
+            .await
+            .map_err(|e| Status::new(Code::Internal, format!("{:?}", e)))?
+            .ok_or_else(|| Status::new(Code::Unauthenticated, "Share token is revoked, expired or used up"))?;
+
+        Ok(tonic::Response::new(CheckShareTokenResponse {
+            status: "success".to_string(),
+            user_id,
+        }))
+    }
+
+    async fn owns_contact(
+        &self,
+        request: tonic::Request<OwnsContactRequest>,
+    ) -> Result<tonic::Response<OwnsContactResponse>, tonic::Status> {
+
+        authorize(&request, &[INVITE_SERVICE])?;
+
+        let req: OwnsContactRequest = request.into_inner();
+
+        let owner = db_owns_contact(&req.user_id, &req.contact, &self.pool)
+            .await
+            .map_err(|e| Status::new(Code::Internal, format!("{:?}", e)))?;
+
+        Ok(tonic::Response::new(OwnsContactResponse {
+            status: "success".to_string(),
+            owner,
         }))
     }
 }
@@ -114,42 +190,22 @@ impl AuthService for MyAuthService {
 #[debug_handler]
 #[handler(method = "POST",tag = "auth")]
 pub async fn email_auth_link(
-    payload: Result<Json<Email>, JsonRejection>
+    payload: Result<Json<Email>, JsonRejection>,
+    Extension(pool): Extension<Arc<PgPool>>,
+    Extension(provider): Extension<Arc<dyn IdentityProvider>>,
+    client: ClientInfo,
 ) -> AxumResult<Json<AxumRes>> {
     match payload {
         Ok(payload) => {
             let mut email = payload.0;
             email.expiration_minutes = Some(5);
-
-            let client = reqwest::Client::new();
-            let request = client
-                .post(format!(
-                    "{}/magic_links/email/login_or_create",
-                    BASE_URL.to_string()
-                ))
-                .header("Content-Type", "application/json")
-                .basic_auth(PROJECT_ID.to_string(), Some(SECRET_ID.to_string()))
-                .body(serde_json::to_string(&email).unwrap())
-                .send()
-                .await
-                .unwrap();
-
-            let response = request.text().await.unwrap();
-
-            let v: serde_json::Value = serde_json::from_str(&response).unwrap();
-            let code = v["status_code"].as_i64().unwrap();
-            if code == 200 {
-                let ret = serde_json::json!({
-                    "user_created": v["user_created"],
-                    "method_id": v["email_id"],
-                    "user_id": v["user_id"],
-                });
-                Ok(axum::Json(AxumRes{code: 200, result: ret}))
-            } else {
-                let ret = serde_json::json!({
-                    "error": v["error_message"],
-                });
-                Err(into_reponse(code, ret))
+            check_send(&email.email.to_lowercase(), &client, &pool).await?;
+            match provider.send_magic_link(&email).await {
+                Ok(sent) => {
+                    db_record_contact(&sent.user_id, &email.email, &pool).await.map_err(internal_error)?;
+                    Ok(axum::Json(AxumRes{code: 200, result: serde_json::json!(&sent)}))
+                }
+                Err(e) => Err(e.into()),
             }
         }
         Err(e) => {
@@ -165,39 +221,22 @@ pub async fn email_auth_link(
 #[debug_handler]
 #[handler(method = "POST",tag = "auth")]
 pub async fn email_auth_otp(
-    payload: Result<Json<Email>, JsonRejection>
+    payload: Result<Json<Email>, JsonRejection>,
+    Extension(pool): Extension<Arc<PgPool>>,
+    Extension(provider): Extension<Arc<dyn IdentityProvider>>,
+    client: ClientInfo,
 ) -> AxumResult<Json<AxumRes>> {
     match payload {
         Ok(payload) => {
             let mut email = payload.0;
             email.expiration_minutes = Some(5);
-
-            let client = reqwest::Client::new();
-            let request = client
-                .post(format!("{}/otps/email/login_or_create", BASE_URL.to_string()))
-                .header("Content-Type", "application/json")
-                .basic_auth(PROJECT_ID.to_string(), Some(SECRET_ID.to_string()))
-                .body(serde_json::to_string(&email).unwrap())
-                .send()
-                .await
-                .unwrap();
-
-            let response = request.text().await.unwrap();
-
-            let v: serde_json::Value = serde_json::from_str(&response).unwrap();
-            let code = v["status_code"].as_i64().unwrap();
-            if code == 200 {
-                let ret = serde_json::json!({
-                    "user_created": v["user_created"],
-                    "method_id": v["email_id"],
-                    "user_id": v["user_id"],
-                });
-                Ok(axum::Json(AxumRes{code: 200, result: ret}))
-            } else {
-                let ret = serde_json::json!({
-                    "error": v["error_message"],
-                });
-                Err(into_reponse(code, ret))
+            check_send(&email.email.to_lowercase(), &client, &pool).await?;
+            match provider.send_email_otp(&email).await {
+                Ok(sent) => {
+                    db_record_contact(&sent.user_id, &email.email, &pool).await.map_err(internal_error)?;
+                    Ok(axum::Json(AxumRes{code: 200, result: serde_json::json!(&sent)}))
+                }
+                Err(e) => Err(e.into()),
             }
         }
         Err(e) => {
@@ -213,40 +252,23 @@ pub async fn email_auth_otp(
 #[debug_handler]
 #[handler(method = "POST",tag = "auth")]
 pub async fn phone_auth_otp(
-    payload: Result<Json<PhoneNumber>, JsonRejection>
+    payload: Result<Json<PhoneNumber>, JsonRejection>,
+    Extension(pool): Extension<Arc<PgPool>>,
+    Extension(provider): Extension<Arc<dyn IdentityProvider>>,
+    client: ClientInfo,
 ) -> AxumResult<Json<AxumRes>> {
     match payload {
         Ok(payload) => {
             let mut phone = payload.0;
             phone.expiration_minutes = Some(5);
             phone.e164_format();
-
-            let client = reqwest::Client::new();
-            let request = client
-                .post(format!("{}/otps/sms/login_or_create", BASE_URL.to_string()))
-                .header("Content-Type", "application/json")
-                .basic_auth(PROJECT_ID.to_string(), Some(SECRET_ID.to_string()))
-                .body(serde_json::to_string(&phone).unwrap())
-                .send()
-                .await
-                .unwrap();
-
-            let response = request.text().await.unwrap();
-
-            let v: serde_json::Value = serde_json::from_str(&response).unwrap();
-            let code = v["status_code"].as_i64().unwrap();
-            if code == 200 {
-                let ret = serde_json::json!({
-                    "user_created": v["user_created"],
-                    "method_id": v["phone_id"],
-                    "user_id": v["user_id"],
-                });
-                Ok(axum::Json(AxumRes{code: 200, result: ret}))
-            } else {
-                let ret = serde_json::json!({
-                    "error": v["error_message"],
-                });
-                Err(into_reponse(code, ret))
+            check_send(&phone.phone_number, &client, &pool).await?;
+            match provider.send_sms_otp(&phone).await {
+                Ok(sent) => {
+                    db_record_contact(&sent.user_id, &phone.phone_number, &pool).await.map_err(internal_error)?;
+                    Ok(axum::Json(AxumRes{code: 200, result: serde_json::json!(&sent)}))
+                }
+                Err(e) => Err(e.into()),
             }
         }
         Err(e) => {
@@ -264,64 +286,66 @@ pub async fn phone_auth_otp(
 pub async fn shopify_auth_otp(
     payload: Result<Json<Shopify>, JsonRejection>,
     Extension(pool): Extension<Arc<PgPool>>,
+    Extension(provider): Extension<Arc<dyn IdentityProvider>>,
+    client: ClientInfo,
 ) -> AxumResult<Json<AxumRes>> {
-    match payload {
-        Ok(payload) => {
-            let shopify_info = payload.0;
-            
-            let email = Email {
-                email: shopify_info.email.clone(),
-                expiration_minutes: Some(5),
-            };
+    let shopify_info = payload.map_err(bad_request)?.0;
+    // The shop comes from the verified session token, never from the client.
+    let claims = verify_session_token(&shopify_info.session_token)?;
+    let shop = claims_shop(&claims);
+    require_installed(&shop, &pool).await?;
+
+    let email = Email {
+        email: shopify_info.email.clone(),
+        expiration_minutes: Some(5),
+        captcha_token: None,
+    };
+
+    check_send(&email.email.to_lowercase(), &client, &pool).await?;
+    let sent = provider.send_email_otp(&email).await?;
+    db_record_contact(&sent.user_id, &email.email, &pool).await.map_err(internal_error)?;
+    Ok(axum::Json(AxumRes{code: 200, result: serde_json::json!(&sent)}))
+}
 
-            let client = reqwest::Client::new();
-            let request = client
-                .post(format!("{}/otps/email/login_or_create", BASE_URL.to_string()))
-                .header("Content-Type", "application/json")
-                .basic_auth(PROJECT_ID.to_string(), Some(SECRET_ID.to_string()))
-                .body(serde_json::to_string(&email).unwrap())
-                .send()
-                .await
-                .unwrap();
-
-            let response = request.text().await.unwrap();
-
-            let v: serde_json::Value = serde_json::from_str(&response).unwrap();
-            let code = v["status_code"].as_i64().unwrap();
-            if code == 200 {
-
-                match db_create_shopify_auth(&v["user_id"].as_str().unwrap().to_string(), &shopify_info, &pool).await {
-                    Ok(_) => {
-                        let ret = serde_json::json!({
-                            "user_created": v["user_created"],
-                            "method_id": v["email_id"],
-                            "user_id": v["user_id"],
-                        });
-                        Ok(axum::Json(AxumRes{code: 200, result: ret}))
-                    }
-                    Err(e) => {
-                        println!("{:?}", e.to_string());
-                        let ret = serde_json::json!({
-                            "error": format!("{:?}", e),
-                        });
-                        Err(into_reponse(500, ret))
-                    }
-                }
+// The account is linked to the shop only once the code proves the email belongs to the caller.
+#[debug_handler]
+#[handler(method = "POST",tag = "auth_verify")]
+pub async fn shopify_verify_otp(
+    payload: Result<Json<ShopifyOTP>, JsonRejection>,
+    Extension(pool): Extension<Arc<PgPool>>,
+    Extension(provider): Extension<Arc<dyn IdentityProvider>>,
+    client: ClientInfo,
+) -> AxumResult<Json<AxumRes>> {
+    let otp = payload.map_err(bad_request)?.0;
+    let claims = verify_session_token(&otp.session_token)?;
+    let shop = claims_shop(&claims);
+    require_installed(&shop, &pool).await?;
+
+    let token = StytchOTP {
+        code: otp.code,
+        method_id: otp.method_id,
+        captcha_token: otp.captcha_token,
+    };
+    let keys = otp_keys(&token.method_id, &client);
+    check_attempts(&keys, token.captcha_token.as_ref(), &client, &pool).await?;
+    let verified = match provider.verify_otp(&token).await {
+        Ok(verified) => verified,
+        Err(e) => {
+            // only wrong or expired codes count, not provider outages
+            if e.code < 500 {
+                record_failure(&keys, &client, &pool).await?;
             } else {
-                let ret = serde_json::json!({
-                    "error": v["error_message"],
-                });
-                Err(into_reponse(code, ret))
+                release_attempts(&keys, &pool).await?;
             }
+            return Err(e.into());
         }
-        Err(e) => {
-            println!("{:?}", e.to_string());
-            let ret = serde_json::json!({
-                "error": format!("{:?}", e),
-            });
-            Err(into_reponse(400, ret))
-        }
-    }
+    };
+    record_success(&keys, &pool).await?;
+
+    db_link_shop(&verified.user_id, &shop, otp.email.as_ref(), &pool)
+        .await
+        .map_err(internal_error)?;
+    login(&verified.user_id, &"Email".to_string(), &client, &pool).await
 }
 
 #[debug_handler]
@@ -329,55 +353,15 @@ pub async fn shopify_auth_otp(
 pub async fn email_verify_link(
     payload: Result<Json<StytchToken>, JsonRejection>,
     Extension(pool): Extension<Arc<PgPool>>,
+    Extension(provider): Extension<Arc<dyn IdentityProvider>>,
+    client: ClientInfo,
 ) -> AxumResult<Json<AxumRes>> {
     match payload {
         Ok(payload) => {
             let token = payload.0;
-            let client = reqwest::Client::new();
-            let request = client
-                .post(format!("{}/magic_links/authenticate", BASE_URL.to_string()))
-                .header("Content-Type", "application/json")
-                .basic_auth(PROJECT_ID.to_string(), Some(SECRET_ID.to_string()))
-                .body(serde_json::to_string(&token).unwrap())
-                .send()
-                .await
-                .unwrap();
-
-            let response = request.text().await.unwrap();
-
-            let v: serde_json::Value = serde_json::from_str(&response).unwrap();
-            let code = v["status_code"].as_i64().unwrap();
-            if code == 200 {
-                let token = create_token(&v["user_id"].as_str().unwrap().to_string());
-                match db_create_auth(
-                    &v["user_id"].as_str().unwrap().to_string(),
-                    &"Email".to_string(),
-                    &token,
-                    &pool,
-                )
-                .await
-                {
-                    Ok(_) => {
-                        let ret = serde_json::json!({
-                            "user_id": v["user_id"],
-                            "token": token,
-                        });
-                        Ok(axum::Json(AxumRes{code: 200, result: ret}))
-                    }
-                    Err(e) => {
-                        println!("{:?}", e.to_string());
-                        let ret = serde_json::json!({
-                            "error": format!("{:?}", e),
-                        });
-                        Err(into_reponse(500, ret))
-                    }
-                }
-            } else {
-                println!("{:?}", v["error_message"]);
-                let ret = serde_json::json!({
-                    "error": v["error_message"],
-                });
-                Err(into_reponse(code, ret))
+            match provider.verify_magic_link(&token).await {
+                Ok(verified) => login(&verified.user_id, &"Email".to_string(), &client, &pool).await,
+                Err(e) => Err(e.into()),
             }
         }
         Err(e) => {
@@ -395,54 +379,28 @@ pub async fn email_verify_link(
 pub async fn email_verify_otp(
     payload: Result<Json<StytchOTP>, JsonRejection>,
     Extension(pool): Extension<Arc<PgPool>>,
+    Extension(provider): Extension<Arc<dyn IdentityProvider>>,
+    client: ClientInfo,
 ) -> AxumResult<Json<AxumRes>> {
     match payload {
         Ok(payload) => {
             let token = payload.0;
-            let client = reqwest::Client::new();
-            let request = client
-                .post(format!("{}/otps/authenticate", BASE_URL.to_string()))
-                .header("Content-Type", "application/json")
-                .basic_auth(PROJECT_ID.to_string(), Some(SECRET_ID.to_string()))
-                .body(serde_json::to_string(&token).unwrap())
-                .send()
-                .await
-                .unwrap();
-
-            let response = request.text().await.unwrap();
-
-            let v: serde_json::Value = serde_json::from_str(&response).unwrap();
-            let code = v["status_code"].as_i64().unwrap();
-            if code == 200 {
-                let token = create_token(&v["user_id"].as_str().unwrap().to_string());
-                match db_create_auth(
-                    &v["user_id"].as_str().unwrap().to_string(),
-                    &"Email".to_string(),
-                    &token,
-                    &pool,
-                )
-                .await
-                {
-                    Ok(_) => {
-                        let ret = serde_json::json!({
-                            "user_id": v["user_id"],
-                            "token": token,
-                        });
-                        Ok(axum::Json(AxumRes{code: 200, result: ret}))
-                    }
-                    Err(e) => {
-                        println!("{:?}", e.to_string());
-                        let ret = serde_json::json!({
-                            "error": format!("{:?}", e),
-                        });
-                        Err(into_reponse(500, ret))
+            let keys = otp_keys(&token.method_id, &client);
+            check_attempts(&keys, token.captcha_token.as_ref(), &client, &pool).await?;
+            match provider.verify_otp(&token).await {
+                Ok(verified) => {
+                    record_success(&keys, &pool).await?;
+                    login(&verified.user_id, &"Email".to_string(), &client, &pool).await
+                }
+                Err(e) => {
+                    // only wrong or expired codes count, not provider outages
+                    if e.code < 500 {
+                        record_failure(&keys, &client, &pool).await?;
+                    } else {
+                        release_attempts(&keys, &pool).await?;
                     }
+                    Err(e.into())
                 }
-            } else {
-                let ret = serde_json::json!({
-                    "error": v["error_message"],
-                });
-                Err(into_reponse(code, ret))
             }
         }
         Err(e) => {
@@ -460,54 +418,28 @@ pub async fn email_verify_otp(
 pub async fn phone_verify_otp(
     payload: Result<Json<StytchOTP>, JsonRejection>,
     Extension(pool): Extension<Arc<PgPool>>,
+    Extension(provider): Extension<Arc<dyn IdentityProvider>>,
+    client: ClientInfo,
 ) -> AxumResult<Json<AxumRes>> {
     match payload {
         Ok(payload) => {
             let token = payload.0;
-            let client = reqwest::Client::new();
-            let request = client
-                .post(format!("{}/otps/authenticate", BASE_URL.to_string()))
-                .header("Content-Type", "application/json")
-                .basic_auth(PROJECT_ID.to_string(), Some(SECRET_ID.to_string()))
-                .body(serde_json::to_string(&token).unwrap())
-                .send()
-                .await
-                .unwrap();
-
-            let response = request.text().await.unwrap();
-
-            let v: serde_json::Value = serde_json::from_str(&response).unwrap();
-            let code = v["status_code"].as_i64().unwrap();
-            if code == 200 {
-                let token = create_token(&v["user_id"].as_str().unwrap().to_string());
-                match db_create_auth(
-                    &v["user_id"].as_str().unwrap().to_string(),
-                    &"Phone".to_string(),
-                    &token,
-                    &pool,
-                )
-                .await
-                {
-                    Ok(_) => {
-                        let ret = serde_json::json!({
-                            "user_id": v["user_id"],
-                            "token": token,                            
-                        });
-                        Ok(axum::Json(AxumRes{code: 200, result: ret}))
-                    }
-                    Err(e) => {
-                        println!("{:?}", e.to_string());
-                        let ret = serde_json::json!({
-                            "error": format!("{:?}", e),
-                        });
-                        Err(into_reponse(500, ret))
+            let keys = otp_keys(&token.method_id, &client);
+            check_attempts(&keys, token.captcha_token.as_ref(), &client, &pool).await?;
+            match provider.verify_otp(&token).await {
+                Ok(verified) => {
+                    record_success(&keys, &pool).await?;
+                    login(&verified.user_id, &"Phone".to_string(), &client, &pool).await
+                }
+                Err(e) => {
+                    // only wrong or expired codes count, not provider outages
+                    if e.code < 500 {
+                        record_failure(&keys, &client, &pool).await?;
+                    } else {
+                        release_attempts(&keys, &pool).await?;
                     }
+                    Err(e.into())
                 }
-            } else {
-                let ret = serde_json::json!({
-                    "error": v["error_message"],
-                });
-                Err(into_reponse(code, ret))
             }
         }
         Err(e) => {
@@ -520,20 +452,13 @@ pub async fn phone_verify_otp(
     }
 }
 
-// Google
-// https://api.stytch.com/v1/public/oauth/google/start?public_token=public-token-live-3780acd3-6da2-4987-84e0-abc2207f6508&custom_scopes=https://www.googleapis.com/auth/contacts.readonly
-
-// Outlook
-// https://api.stytch.com/v1/public/oauth/microsoft/start?public_token=public-token-live-3780acd3-6da2-4987-84e0-abc2207f6508&custom_scopes=Contacts.Read
-
-// LinkedIn
-// https://api.stytch.com/v1/public/oauth/linkedin/start?public_token=public-token-live-3780acd3-6da2-4987-84e0-abc2207f6508
-
 #[debug_handler]
 #[handler(method = "POST",tag = "auth_verify")]
 pub async fn oauth_verify(
     payload: Result<Json<StytchAuth>, JsonRejection>,
     Extension(pool): Extension<Arc<PgPool>>,
+    Extension(provider): Extension<Arc<dyn IdentityProvider>>,
+    client: ClientInfo,
 ) -> AxumResult<Json<AxumRes>> {
     match payload {
         Ok(payload) => {
@@ -542,60 +467,21 @@ pub async fn oauth_verify(
                 token: token_info.token,
             };
 
-            let client = reqwest::Client::new();
-            let request = client
-                .post(format!("{}/oauth/authenticate", BASE_URL.to_string()))
-                .header("Content-Type", "application/json")
-                .basic_auth(PROJECT_ID.to_string(), Some(SECRET_ID.to_string()))
-                .body(serde_json::to_string(&token).unwrap())
-                .send()
-                .await
-                .unwrap();
-
-            let response = request.text().await.unwrap();
-
-            let v: serde_json::Value = serde_json::from_str(&response).unwrap();
-            let code = v["status_code"].as_i64().unwrap();
-            if code == 200 {
-                if let Some(user_id) = &token_info.user_id {
-                    let ret = serde_json::json!({
-                        "user_id": user_id,
-                        "id_token": v["provider_values"]["access_token"]
-                    });
-                    Ok(axum::Json(AxumRes{code: 200, result: ret}))
-                } else {
-                    let token = create_token(&v["user_id"].as_str().unwrap().to_string());
-                    match db_create_auth(
-                        &v["user_id"].as_str().unwrap().to_string(),
-                        &v["provider_type"].as_str().unwrap().to_string(),
-                        &token,
-                        &pool,
-                    )
-                    .await
-                    {
-                        Ok(_) => {
-                            let ret = serde_json::json!({
-                                "user_id": v["user_id"],
-                                "token": token,
-                                "id_token": v["provider_values"]["access_token"],
-                                "user": v["user"]
-                            });
-                            Err(into_reponse(200, ret))
-                        }
-                        Err(e) => {
-                            println!("{:?}", e.to_string());
-                            let ret = serde_json::json!({
-                                "error": format!("{:?}", e),
-                            });
-                            Err(into_reponse(500, ret))
-                        }
-                    }
-                }                
-            } else {
+            let oauth = provider.verify_oauth(&token).await?;
+            if let Some(user_id) = &token_info.user_id {
                 let ret = serde_json::json!({
-                    "error": v["error_message"],
+                    "user_id": user_id,
+                    "id_token": oauth.id_token,
                 });
-                Err(into_reponse(code, ret))
+                Ok(axum::Json(AxumRes{code: 200, result: ret}))
+            } else {
+                let mut ret = start_login(&oauth.user_id, &oauth.provider_type, &client, &pool).await?;
+                // provider values are only handed out once the login is complete
+                if ret.get("token").is_some() {
+                    ret["id_token"] = oauth.id_token;
+                    ret["user"] = oauth.user;
+                }
+                Ok(axum::Json(AxumRes{code: 200, result: ret}))
             }
         }
         Err(e) => {
@@ -608,66 +494,110 @@ pub async fn oauth_verify(
     }
 }
 
-// Database
-pub async fn db_create_auth(
-    user_id: &String,
-    provider: &String,
-    token: &Token,
-    pool: &PgPool,
-) -> Result<(), sqlx::Error> {
-    let _ = sqlx::query!(
-        "INSERT INTO auth (user_id, access_token, refresh_token, provider_type) VALUES ($1, $2, $3, $4) ON CONFLICT (user_id) DO UPDATE SET access_token = $2, refresh_token = $3, provider_type = $4",
-        user_id,
-        token.access_token,
-        token.refresh_token,
-	    provider,        
-    ).execute(pool).await?;
-    Ok(())
+pub(crate) fn internal_error(e: impl std::fmt::Debug) -> ResponseError {
+    println!("{:?}", e);
+    into_reponse(500, serde_json::json!({ "error": format!("{:?}", e) }))
 }
 
-pub async fn db_create_shopify_auth(
+pub(crate) fn bad_request(e: JsonRejection) -> ResponseError {
+    println!("{:?}", e.to_string());
+    into_reponse(400, serde_json::json!({ "error": format!("{:?}", e) }))
+}
+
+pub(crate) async fn login(
     user_id: &String,
-    shopify: &Shopify,
+    provider_type: &String,
+    client: &ClientInfo,
     pool: &PgPool,
-) -> Result<(), sqlx::Error> {
-    let _ = sqlx::query!(
-        "INSERT INTO shopify_auth (user_id, token, email) VALUES ($1, $2, $3) ON CONFLICT (user_id) DO UPDATE SET token = $2, email = $3",
-        user_id,
-        shopify.token,
-        shopify.email,        
-    ).execute(pool).await?;
-    Ok(())
+) -> AxumResult<Json<AxumRes>> {
+    let ret = start_login(user_id, provider_type, client, pool).await?;
+    Ok(axum::Json(AxumRes{code: 200, result: ret}))
 }
 
-pub async fn db_update_token(
-    user_id: &String, 
-    access_token: &String, 
+// Called once the first factor is verified: either asks for the second factor or finishes the login.
+// `user_id` is the id given by the provider, sessions are issued for the account it is linked to.
+pub(crate) async fn start_login(
+    user_id: &String,
+    provider_type: &String,
+    client: &ClientInfo,
     pool: &PgPool,
-) -> Result<(), sqlx::Error> {
-    let _ = sqlx::query!("UPDATE auth SET access_token = $1 WHERE user_id = $2", access_token, user_id).execute(pool).await?;
-    Ok(())
+) -> AxumResult<serde_json::Value> {
+    let account_id = resolve_account(user_id, provider_type, pool)
+        .await
+        .map_err(internal_error)?;
+    if let Some(challenge) = mfa_challenge(&account_id, provider_type, pool).await? {
+        return Ok(challenge);
+    }
+    issue_session(&account_id, provider_type, client, pool).await
 }
 
-pub async fn db_check_token(
-    req: &CheckTokenRequest,
+// Issues a token pair for a verified user and records the session, unless the account is being erased.
+pub(crate) async fn issue_session(
+    user_id: &String,
+    provider_type: &String,
+    client: &ClientInfo,
     pool: &PgPool,
-) -> Result<String, sqlx::Error> {
-    let row = sqlx::query!("SELECT user_id FROM auth where user_id = $1 AND access_token = $2", req.user_id, req.access_token).fetch_one(pool).await?;
-    Ok(row.user_id)
+) -> AxumResult<serde_json::Value> {
+    if db_account_closed(user_id, pool).await.map_err(internal_error)? {
+        let ret = serde_json::json!({
+            "error": "The account has been deleted",
+        });
+        return Err(into_reponse(403, ret));
+    }
+    let token = create_token(user_id);
+    match db_create_session(user_id, provider_type, &token, client, pool).await {
+        Ok(session_id) => {
+            // Login history is best effort, the user is signed in either way
+            if let Err(e) = record_login(user_id, &session_id, provider_type, client, pool).await {
+                println!("Unable to record login for {}: {:?}", user_id, e);
+            }
+            Ok(serde_json::json!({
+                "user_id": user_id,
+                "token": token,
+            }))
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(500, ret))
+        }
+    }
 }
 
-pub async fn db_check_refresh_token(
-    req: &TokenRefreshRequest,
+// Database
+pub async fn db_create_session(
+    user_id: &String,
+    provider: &String,
+    token: &Token,
+    client: &ClientInfo,
     pool: &PgPool,
-) -> Result<String, sqlx::Error> {
-    let row = sqlx::query!("SELECT user_id FROM auth where user_id = $1 AND refresh_token = $2", req.user_id, req.refresh_token).fetch_one(pool).await?;
-    Ok(row.user_id)
+) -> Result<Uuid, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query!(
+        "INSERT INTO sessions (user_id, access_token, provider_type, device, user_agent, ip) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+        user_id,
+        token.access_token,
+        provider,
+        client.device,
+        client.user_agent,
+        client.ip,
+    ).fetch_one(&mut tx).await?;
+    db_insert_refresh_token(&row.id, &token.refresh_token, &mut tx).await?;
+    tx.commit().await?;
+    Ok(row.id)
 }
 
-pub async fn db_get_shopify_token(
-    req: &CheckShopifyToken,
+pub async fn db_check_token(
+    req: &CheckTokenRequest,
     pool: &PgPool,
-) -> Result<String, sqlx::Error> {
-    let row = sqlx::query!("SELECT token FROM shopify_auth where user_id = $1", req.user_id).fetch_one(pool).await?;
-    Ok(row.token)
-}
\ No newline at end of file
+) -> Result<Uuid, sqlx::Error> {
+    let row = sqlx::query!(
+        "UPDATE sessions SET last_seen_at = $1 WHERE user_id = $2 AND access_token = $3 AND revoked_at IS NULL RETURNING id",
+        Utc::now().naive_utc(),
+        req.user_id,
+        req.access_token
+    ).fetch_one(pool).await?;
+    Ok(row.id)
+}
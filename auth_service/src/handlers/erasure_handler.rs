@@ -0,0 +1,163 @@
+use sqlx::PgPool;
+
+use microservice_utils::crypto::{blind_index, into_sqlx_error};
+use microservice_utils::server::erasure::{db_erase_user_rows, Erasure};
+
+// Closes the account before erasing it, so a login in the meantime can't leave a new session behind.
+pub async fn db_close_account(user_id: &String, pool: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO closed_accounts (subject_index) VALUES ($1) ON CONFLICT DO NOTHING")
+        .bind(blind_index(user_id).map_err(into_sqlx_error)?)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn db_account_closed(user_id: &String, pool: &PgPool) -> Result<bool, sqlx::Error> {
+    let row: Option<(String,)> = sqlx::query_as("SELECT subject_index FROM closed_accounts WHERE subject_index = $1")
+        .bind(blind_index(user_id).map_err(into_sqlx_error)?)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.is_some())
+}
+
+// Erases the credentials, sessions, login history and login methods of a deleted account. Impersonations
+// are the admins' audit trail, so they are kept with the user anonymised.
+pub async fn db_erase_user_auth(user_id: &String, pool: &PgPool) -> Result<Erasure, sqlx::Error> {
+    db_erase_user_rows(
+        user_id,
+        &[
+            // Throttling counters are keyed by the user's code ids, emails and phone numbers,
+            // which are only known until their rows are deleted below.
+            "DELETE FROM lockout_events WHERE scope = 'identifier' AND (key = 'mfa:' || $1
+                OR key IN (SELECT 'otp:' || id::text FROM local_codes WHERE user_id = $1)
+                OR key IN (SELECT 'password:' || email FROM password_credentials WHERE user_id = $1))",
+            "DELETE FROM auth_attempts WHERE scope = 'identifier' AND (key = 'mfa:' || $1
+                OR key IN (SELECT 'otp:' || id::text FROM local_codes WHERE user_id = $1)
+                OR key IN (SELECT 'password:' || email FROM password_credentials WHERE user_id = $1))",
+            "DELETE FROM send_throttle WHERE key IN (
+                SELECT 'to:' || lower(email) FROM local_users WHERE user_id = $1 AND email IS NOT NULL
+                UNION SELECT 'to:' || phone_number FROM local_users WHERE user_id = $1 AND phone_number IS NOT NULL
+                UNION SELECT 'to:' || email FROM password_credentials WHERE user_id = $1
+                UNION SELECT 'to:' || lower(email) FROM shopify_auth WHERE user_id = $1 AND email IS NOT NULL)",
+            "DELETE FROM login_events WHERE user_id = $1",
+            "DELETE FROM sessions WHERE user_id = $1",
+            "DELETE FROM local_users WHERE user_id = $1",
+            "DELETE FROM password_credentials WHERE user_id = $1",
+            "DELETE FROM mfa_totp WHERE user_id = $1",
+            "DELETE FROM mfa_recovery_codes WHERE user_id = $1",
+            "DELETE FROM mfa_challenges WHERE user_id = $1",
+            "DELETE FROM share_tokens WHERE user_id = $1",
+            "DELETE FROM shopify_auth WHERE user_id = $1",
+            "DELETE FROM provider_contacts WHERE provider_user_id = $1
+                OR provider_user_id IN (SELECT provider_user_id FROM identities WHERE account_id = $1)",
+            "DELETE FROM identities WHERE account_id = $1",
+            "DELETE FROM accounts a WHERE (a.id = $1 OR a.merged_into = $1)
+                AND NOT EXISTS (SELECT 1 FROM identities i WHERE i.account_id = a.id)",
+            "UPDATE impersonations SET user_id = 'erased' WHERE user_id = $1",
+        ],
+        &[
+            "SELECT count(*) FROM lockout_events WHERE scope = 'identifier' AND key = 'mfa:' || $1",
+            "SELECT count(*) FROM auth_attempts WHERE scope = 'identifier' AND key = 'mfa:' || $1",
+            "SELECT count(*) FROM login_events WHERE user_id = $1",
+            "SELECT count(*) FROM sessions WHERE user_id = $1",
+            "SELECT count(*) FROM local_users WHERE user_id = $1",
+            "SELECT count(*) FROM password_credentials WHERE user_id = $1",
+            "SELECT count(*) FROM mfa_totp WHERE user_id = $1",
+            "SELECT count(*) FROM mfa_recovery_codes WHERE user_id = $1",
+            "SELECT count(*) FROM mfa_challenges WHERE user_id = $1",
+            "SELECT count(*) FROM share_tokens WHERE user_id = $1",
+            "SELECT count(*) FROM shopify_auth WHERE user_id = $1",
+            "SELECT count(*) FROM provider_contacts WHERE provider_user_id = $1",
+            "SELECT count(*) FROM identities WHERE account_id = $1",
+            "SELECT count(*) FROM impersonations WHERE user_id = $1",
+        ],
+        pool,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::auth_handler::login;
+    use crate::test_db::{error_code, test_pool, test_user_id};
+    use microservice_utils::server::client_info::ClientInfo;
+
+    async fn count(query: &str, key: &String, pool: &PgPool) -> i64 {
+        let (count,): (i64,) = sqlx::query_as(query).bind(key).fetch_one(pool).await.unwrap();
+        count
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn erasure_clears_the_throttling_counters() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        let email = format!("{}@example.com", user_id);
+        sqlx::query("INSERT INTO local_users (user_id, email) VALUES ($1, $2)")
+            .bind(&user_id)
+            .bind(&email)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let (code_id,): (uuid::Uuid,) = sqlx::query_as(
+            "INSERT INTO local_codes (user_id, kind, code_hash, expires_at)
+            VALUES ($1, 'email', 'hash', CURRENT_TIMESTAMP) RETURNING id")
+            .bind(&user_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        let otp_key = format!("otp:{}", code_id);
+        let mfa_key = format!("mfa:{}", user_id);
+        let send_key = format!("to:{}", email);
+        let other_key = format!("to:{}@example.com", test_user_id());
+        for key in [&otp_key, &mfa_key] {
+            sqlx::query("INSERT INTO auth_attempts (scope, key, failures) VALUES ('identifier', $1, 3)")
+                .bind(key)
+                .execute(&pool)
+                .await
+                .unwrap();
+            sqlx::query(
+                "INSERT INTO lockout_events (scope, key, lockouts, locked_until)
+                VALUES ('identifier', $1, 1, CURRENT_TIMESTAMP)")
+                .bind(key)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        for key in [&send_key, &other_key] {
+            sqlx::query(
+                "INSERT INTO send_throttle (key, window_start, sent_count, last_sent_at)
+                VALUES ($1, CURRENT_TIMESTAMP, 1, CURRENT_TIMESTAMP)")
+                .bind(key)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        let erasure = db_erase_user_auth(&user_id, &pool).await.unwrap();
+        assert_eq!(erasure.remaining, 0);
+        for key in [&otp_key, &mfa_key] {
+            assert_eq!(count("SELECT count(*) FROM auth_attempts WHERE key = $1", key, &pool).await, 0);
+            assert_eq!(count("SELECT count(*) FROM lockout_events WHERE key = $1", key, &pool).await, 0);
+        }
+        assert_eq!(count("SELECT count(*) FROM send_throttle WHERE key = $1", &send_key, &pool).await, 0);
+        // other users keep theirs
+        assert_eq!(count("SELECT count(*) FROM send_throttle WHERE key = $1", &other_key, &pool).await, 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn closed_accounts_cannot_log_in() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        let client = ClientInfo::default();
+        let provider = "email".to_string();
+        assert_eq!(error_code(login(&user_id, &provider, &client, &pool).await), 200);
+
+        db_close_account(&user_id, &pool).await.unwrap();
+        db_erase_user_auth(&user_id, &pool).await.unwrap();
+        assert_eq!(error_code(login(&user_id, &provider, &client, &pool).await), 403);
+        assert_eq!(count("SELECT count(*) FROM sessions WHERE user_id = $1", &user_id, &pool).await, 0);
+    }
+}
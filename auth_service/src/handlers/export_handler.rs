@@ -0,0 +1,112 @@
+use sqlx::PgPool;
+use tonic::async_trait;
+use tonic::Status;
+
+use microservice_utils::server::export::{csv_file, db_export_rows, export_response};
+use microservice_utils::server::grpc::data_export::data_export_server::DataExport;
+use microservice_utils::server::grpc::data_export::{ExportFile, ExportUserDataRequest, ExportUserDataResponse};
+use microservice_utils::server::service_auth::{authorize, USER_SERVICE};
+
+// gRPC
+pub struct MyDataExport {
+    pool: PgPool,
+}
+
+impl MyDataExport {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl DataExport for MyDataExport {
+    async fn export_user_data(
+        &self,
+        request: tonic::Request<ExportUserDataRequest>,
+    ) -> Result<tonic::Response<ExportUserDataResponse>, Status> {
+
+        authorize(&request, &[USER_SERVICE])?;
+
+        let req: ExportUserDataRequest = request.into_inner();
+        println!("Export User Data {}", req.user_id);
+
+        let files = export_files(&req.user_id, &self.pool)
+            .await
+            .map_err(|e| Status::internal(format!("{:?}", e)))?;
+        Ok(tonic::Response::new(export_response(files)))
+    }
+}
+
+// Tokens, secrets and hashes are left out, only what they were issued for is exported.
+async fn export_files(user_id: &String, pool: &PgPool) -> Result<Vec<ExportFile>, sqlx::Error> {
+    Ok(vec![
+        csv_file(
+            "sessions",
+            &db_export_rows(
+                "SELECT id, provider_type, device, user_agent, ip, created_at, last_seen_at, revoked_at
+                FROM sessions WHERE user_id = $1 ORDER BY created_at",
+                user_id,
+                pool,
+            )
+            .await?,
+        ),
+        csv_file(
+            "login_history",
+            &db_export_rows(
+                "SELECT id, session_id, provider_type, ip, user_agent, device, country, city, flags, created_at
+                FROM login_events WHERE user_id = $1 ORDER BY created_at",
+                user_id,
+                pool,
+            )
+            .await?,
+        ),
+        csv_file(
+            "login_methods",
+            &db_export_rows(
+                "SELECT provider_type, provider_user_id, created_at FROM identities WHERE account_id = $1 ORDER BY created_at",
+                user_id,
+                pool,
+            )
+            .await?,
+        ),
+        csv_file(
+            "two_factor",
+            &db_export_rows(
+                "SELECT confirmed_at, created_at FROM mfa_totp WHERE user_id = $1",
+                user_id,
+                pool,
+            )
+            .await?,
+        ),
+        csv_file(
+            "shopify",
+            &db_export_rows(
+                "SELECT a.shop, a.email, s.scope, s.installed_at, s.uninstalled_at
+                FROM shopify_auth a LEFT JOIN shopify_shops s ON s.shop = a.shop WHERE a.user_id = $1",
+                user_id,
+                pool,
+            )
+            .await?,
+        ),
+        csv_file(
+            "share_tokens",
+            &db_export_rows(
+                "SELECT id, resource_type, resource_id, action, max_uses, uses, expires_at, revoked_at, created_at
+                FROM share_tokens WHERE user_id = $1 ORDER BY created_at",
+                user_id,
+                pool,
+            )
+            .await?,
+        ),
+        csv_file(
+            "impersonations",
+            &db_export_rows(
+                "SELECT id, admin_id, scopes, reason, expires_at, ended_at, created_at
+                FROM impersonations WHERE user_id = $1 ORDER BY created_at",
+                user_id,
+                pool,
+            )
+            .await?,
+        ),
+    ])
+}
@@ -0,0 +1,566 @@
+use axum::{
+    extract::{rejection::JsonRejection, Extension},
+    Json,
+};
+use axum_macros::debug_handler;
+use openapi_rs::openapi_proc_macro::handler;
+use sqlx::types::chrono::Utc;
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use okapi::openapi3::RefOr;
+use openapi_rs::gen::OpenApiGenerator;
+use openapi_rs::OpenApiFromData;
+
+use microservice_utils::crypto::{blind_index, into_sqlx_error, normalize_email, normalize_phone};
+use microservice_utils::events::{enqueue, Event};
+use microservice_utils::server::client_info::ClientInfo;
+use microservice_utils::{
+    jwt::extractor::AuthSession,
+    server::response::{into_reponse, AxumRes, AxumResult},
+};
+
+use crate::handlers::auth_handler::{bad_request, internal_error};
+use crate::handlers::mfa_handler::{db_get_totp, verify_second_factor};
+use crate::models::auth::{StytchOTP, StytchToken};
+use crate::models::identity::{Identity, IdentityId, IdentityProof, ProofMethod};
+use crate::providers::{IdentityProvider, ProviderError};
+use crate::throttle::{check_attempts, otp_keys, record_failure, record_success, release_attempts};
+
+fn missing(field: &str) -> ProviderError {
+    ProviderError::new(400, format!("{} is required for this method", field))
+}
+
+// Verifies the proof with the identity provider, returning the provider user id and type.
+async fn verify_proof(
+    proof: &IdentityProof,
+    provider: &Arc<dyn IdentityProvider>,
+    client: &ClientInfo,
+    pool: &PgPool,
+) -> AxumResult<(String, String)> {
+    match proof.method {
+        ProofMethod::EmailOtp | ProofMethod::PhoneOtp => {
+            let otp = StytchOTP {
+                method_id: proof.method_id.clone().ok_or_else(|| missing("method_id"))?,
+                code: proof.code.clone().ok_or_else(|| missing("code"))?,
+                captcha_token: None,
+            };
+            let keys = otp_keys(&otp.method_id, client);
+            check_attempts(&keys, proof.captcha_token.as_ref(), client, pool).await?;
+            match provider.verify_otp(&otp).await {
+                Ok(verified) => {
+                    record_success(&keys, pool).await?;
+                    let provider_type = match proof.method {
+                        ProofMethod::PhoneOtp => "Phone",
+                        _ => "Email",
+                    };
+                    Ok((verified.user_id, provider_type.to_string()))
+                }
+                Err(e) => {
+                    if e.code < 500 {
+                        record_failure(&keys, client, pool).await?;
+                    } else {
+                        release_attempts(&keys, pool).await?;
+                    }
+                    Err(e.into())
+                }
+            }
+        }
+        ProofMethod::MagicLink => {
+            let token = StytchToken {
+                token: proof.token.clone().ok_or_else(|| missing("token"))?,
+            };
+            let verified = provider.verify_magic_link(&token).await?;
+            Ok((verified.user_id, "Email".to_string()))
+        }
+        ProofMethod::Oauth => {
+            let token = StytchToken {
+                token: proof.token.clone().ok_or_else(|| missing("token"))?,
+            };
+            let oauth = provider.verify_oauth(&token).await?;
+            Ok((oauth.user_id, oauth.provider_type))
+        }
+    }
+}
+
+// API
+#[debug_handler]
+#[handler(method = "GET", tag = "identity")]
+pub async fn list_identities(
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    let identities = db_list_identities(&session.user_id, &pool)
+        .await
+        .map_err(internal_error)?;
+    Ok(axum::Json(AxumRes {
+        code: 200,
+        result: serde_json::json!(&identities),
+    }))
+}
+
+#[debug_handler]
+#[handler(method = "POST", tag = "identity")]
+pub async fn link_identity(
+    payload: Result<Json<IdentityProof>, JsonRejection>,
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+    Extension(provider): Extension<Arc<dyn IdentityProvider>>,
+    client: ClientInfo,
+) -> AxumResult<Json<AxumRes>> {
+    let proof = payload.map_err(bad_request)?.0;
+    let (provider_user_id, provider_type) = verify_proof(&proof, &provider, &client, &pool).await?;
+
+    let owner = db_link_identity(&session.user_id, &provider_type, &provider_user_id, &pool)
+        .await
+        .map_err(internal_error)?;
+    if owner != session.user_id {
+        return Err(into_reponse(
+            400,
+            serde_json::json!({
+                "error": "This login method belongs to another account, merge the accounts instead",
+            }),
+        ));
+    }
+
+    let identities = db_list_identities(&session.user_id, &pool)
+        .await
+        .map_err(internal_error)?;
+    Ok(axum::Json(AxumRes {
+        code: 200,
+        result: serde_json::json!(&identities),
+    }))
+}
+
+#[debug_handler]
+#[handler(method = "DELETE", tag = "identity")]
+pub async fn unlink_identity(
+    payload: Result<Json<IdentityId>, JsonRejection>,
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    let req = payload.map_err(bad_request)?.0;
+
+    let identities = db_list_identities(&session.user_id, &pool)
+        .await
+        .map_err(internal_error)?;
+    if !identities.iter().any(|i| i.id == req.id) {
+        return Err(into_reponse(
+            404,
+            serde_json::json!({ "error": "Login method not found" }),
+        ));
+    }
+
+    if db_unlink_identity(&session.user_id, &req.id, &pool)
+        .await
+        .map_err(internal_error)?
+        == 0
+    {
+        return Err(into_reponse(
+            400,
+            serde_json::json!({ "error": "The last login method of an account can't be removed" }),
+        ));
+    }
+    Ok(axum::Json(AxumRes {
+        code: 200,
+        result: serde_json::json!({ "status": "success" }),
+    }))
+}
+
+/// Merges the account owning the proven login method into the current one. Its login methods
+/// move over right away, the other services move workspaces, contacts and files on `AccountMerged`.
+/// When the other account has two-factor authentication, its code is required as well.
+#[debug_handler]
+#[handler(method = "POST", tag = "identity")]
+pub async fn merge_accounts(
+    payload: Result<Json<IdentityProof>, JsonRejection>,
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+    Extension(provider): Extension<Arc<dyn IdentityProvider>>,
+    client: ClientInfo,
+) -> AxumResult<Json<AxumRes>> {
+    let proof = payload.map_err(bad_request)?.0;
+    let (provider_user_id, provider_type) = verify_proof(&proof, &provider, &client, &pool).await?;
+
+    let source = resolve_account(&provider_user_id, &provider_type, &pool)
+        .await
+        .map_err(internal_error)?;
+    if source == session.user_id {
+        return Err(into_reponse(
+            400,
+            serde_json::json!({ "error": "This login method already belongs to this account" }),
+        ));
+    }
+
+    // The login method alone doesn't give access to an account protected by a second factor.
+    let has_mfa = db_get_totp(&source, true, &pool)
+        .await
+        .map_err(internal_error)?
+        .is_some();
+    if has_mfa {
+        let code = proof.mfa_code.as_ref().ok_or_else(|| {
+            into_reponse(
+                401,
+                serde_json::json!({ "error": "The two-factor code of the other account is required" }),
+            )
+        })?;
+        if !verify_second_factor(&source, code, &client, &pool).await? {
+            return Err(into_reponse(
+                401,
+                serde_json::json!({ "error": "Invalid two-factor code" }),
+            ));
+        }
+    }
+
+    db_merge_accounts(&source, &session.user_id, &pool)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(axum::Json(AxumRes {
+        code: 200,
+        result: serde_json::json!({
+            "status": "success",
+            "merged_user_id": source,
+        }),
+    }))
+}
+
+/// Returns the account a provider identity belongs to, creating both on first login.
+pub(crate) async fn resolve_account(
+    provider_user_id: &String,
+    provider_type: &String,
+    pool: &PgPool,
+) -> Result<String, sqlx::Error> {
+    if let Some(account_id) = db_identity_account(provider_type, provider_user_id, pool).await? {
+        return Ok(account_id);
+    }
+
+    // A new login method of a known provider user joins that user's account,
+    // following it when the account was merged.
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "INSERT INTO accounts (id) VALUES ($1) ON CONFLICT (id) DO NOTHING",
+        provider_user_id
+    )
+    .execute(&mut tx)
+    .await?;
+    let account = sqlx::query!(
+        r#"SELECT COALESCE(merged_into, id) AS "id!" FROM accounts WHERE id = $1"#,
+        provider_user_id
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    let row = sqlx::query!(
+        "INSERT INTO identities (account_id, provider_type, provider_user_id) VALUES ($1, $2, $3)
+        ON CONFLICT (provider_type, provider_user_id) DO UPDATE SET provider_type = EXCLUDED.provider_type
+        RETURNING account_id",
+        account.id,
+        provider_type,
+        provider_user_id
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(row.account_id)
+}
+
+// Database
+pub async fn db_identity_account(
+    provider_type: &String,
+    provider_user_id: &String,
+    pool: &PgPool,
+) -> Result<Option<String>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT account_id FROM identities WHERE provider_type = $1 AND provider_user_id = $2",
+        provider_type,
+        provider_user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| r.account_id))
+}
+
+// Provider user id of an account's login method, e.g. to find its password credentials.
+pub async fn db_account_identity(
+    account_id: &String,
+    provider_type: &String,
+    pool: &PgPool,
+) -> Result<Option<String>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT provider_user_id FROM identities WHERE account_id = $1 AND provider_type = $2 ORDER BY created_at LIMIT 1",
+        account_id,
+        provider_type
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| r.provider_user_id))
+}
+
+pub async fn db_list_identities(
+    account_id: &String,
+    pool: &PgPool,
+) -> Result<Vec<Identity>, sqlx::Error> {
+    let rows = sqlx::query_as!(
+        Identity,
+        "SELECT id, provider_type, provider_user_id, created_at FROM identities WHERE account_id = $1 ORDER BY created_at",
+        account_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+// Links an identity to the account, returning the account it ends up on.
+pub async fn db_link_identity(
+    account_id: &String,
+    provider_type: &String,
+    provider_user_id: &String,
+    pool: &PgPool,
+) -> Result<String, sqlx::Error> {
+    let row = sqlx::query!(
+        "INSERT INTO identities (account_id, provider_type, provider_user_id) VALUES ($1, $2, $3)
+        ON CONFLICT (provider_type, provider_user_id) DO UPDATE SET provider_type = EXCLUDED.provider_type
+        RETURNING account_id",
+        account_id,
+        provider_type,
+        provider_user_id
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(row.account_id)
+}
+
+// Keeps at least one login method on the account.
+pub async fn db_unlink_identity(
+    account_id: &String,
+    id: &Uuid,
+    pool: &PgPool,
+) -> Result<u64, sqlx::Error> {
+    let res = sqlx::query!(
+        "DELETE FROM identities WHERE id = $1 AND account_id = $2
+        AND (SELECT count(*) FROM identities WHERE account_id = $2) > 1",
+        id,
+        account_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected())
+}
+
+// Blind index of an email or a phone number, None when there is nothing to match on.
+pub fn contact_index(contact: &str) -> Result<Option<String>, sqlx::Error> {
+    let contact = if contact.contains('@') {
+        normalize_email(contact)
+    } else {
+        normalize_phone(contact)
+    };
+    if contact.is_empty() {
+        return Ok(None);
+    }
+    blind_index(&contact).map(Some).map_err(into_sqlx_error)
+}
+
+// Called once a login code is sent, the contact is only proven when the provider user signs in.
+pub async fn db_record_contact(
+    provider_user_id: &String,
+    contact: &str,
+    pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    if let Some(index) = contact_index(contact)? {
+        sqlx::query!(
+            "INSERT INTO provider_contacts (provider_user_id, contact_index) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            provider_user_id,
+            index
+        )
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+// Whether the account signed in with the contact, or verified it as the email of its password.
+pub async fn db_owns_contact(account_id: &String, contact: &str, pool: &PgPool) -> Result<bool, sqlx::Error> {
+    let index = match contact_index(contact)? {
+        Some(index) => index,
+        None => return Ok(false),
+    };
+    let row = sqlx::query!(
+        r#"SELECT EXISTS (
+            SELECT 1 FROM identities i JOIN provider_contacts c ON c.provider_user_id = i.provider_user_id
+            WHERE i.account_id = $1 AND c.contact_index = $2
+            UNION ALL
+            SELECT 1 FROM identities i JOIN password_credentials p ON p.user_id = i.provider_user_id
+            WHERE i.account_id = $1 AND i.provider_type = 'Password' AND p.verified_at IS NOT NULL AND p.email = $3
+        ) AS "owner!""#,
+        account_id,
+        index,
+        normalize_email(contact)
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(row.owner)
+}
+
+pub async fn db_merge_accounts(
+    source: &String,
+    target: &String,
+    pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let now = Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "UPDATE identities SET account_id = $1 WHERE account_id = $2",
+        target,
+        source
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE accounts SET merged_into = $1, merged_at = $2 WHERE id = $3 OR merged_into = $3",
+        target,
+        now,
+        source
+    )
+    .execute(&mut tx)
+    .await?;
+    // The merged account can't be used on its own anymore.
+    sqlx::query!(
+        "UPDATE sessions SET revoked_at = $1 WHERE user_id = $2 AND revoked_at IS NULL",
+        now,
+        source
+    )
+    .execute(&mut tx)
+    .await?;
+    // Published once committed, so the other services never move data of an unmerged account.
+    let event = Event::AccountMerged {
+        source_user_id: source.clone(),
+        target_user_id: target.clone(),
+    };
+    enqueue(&event, &mut tx).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::auth_handler::email_auth_otp;
+    use crate::models::auth::Email;
+    use crate::test_db::{enable_totp, test_env, test_pool, test_user_id, totp_code, FakeProvider, TEST_CODE};
+
+    fn proof(method_id: &String, mfa_code: Option<String>) -> IdentityProof {
+        IdentityProof {
+            method: ProofMethod::EmailOtp,
+            method_id: Some(method_id.clone()),
+            code: Some(TEST_CODE.to_string()),
+            token: None,
+            captcha_token: None,
+            mfa_code,
+        }
+    }
+
+    fn session(user_id: &String) -> AuthSession {
+        AuthSession {
+            user_id: user_id.clone(),
+            access_token: String::new(),
+            impersonator: None,
+        }
+    }
+
+    async fn merge(source: &String, target: &String, mfa_code: Option<String>, pool: &Arc<PgPool>) -> bool {
+        let provider: Arc<dyn IdentityProvider> = Arc::new(FakeProvider { user_id: source.clone() });
+        merge_accounts(
+            Ok(Json(proof(source, mfa_code))),
+            session(target),
+            Extension(pool.clone()),
+            Extension(provider),
+            ClientInfo::default(),
+        )
+        .await
+        .is_ok()
+    }
+
+    async fn merged_into(account_id: &String, pool: &PgPool) -> Option<String> {
+        sqlx::query_scalar::<_, Option<String>>("SELECT merged_into FROM accounts WHERE id = $1")
+            .bind(account_id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    async fn outbox_events(source: &String, pool: &PgPool) -> Vec<Event> {
+        let rows: Vec<String> = sqlx::query_scalar("SELECT payload FROM event_outbox ORDER BY id")
+            .fetch_all(pool)
+            .await
+            .unwrap();
+        rows.iter()
+            .map(|payload| serde_json::from_str::<Event>(payload).unwrap())
+            .filter(|event| matches!(event, Event::AccountMerged { source_user_id, .. } if source_user_id == source))
+            .collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn merge_requires_the_second_factor_of_the_other_account() {
+        let pool = Arc::new(test_pool().await);
+        let (source, target) = (test_user_id(), test_user_id());
+        resolve_account(&source, &"Email".to_string(), &pool).await.unwrap();
+        let secret = enable_totp(&source, &pool).await;
+
+        assert!(!merge(&source, &target, None, &pool).await);
+        assert!(!merge(&source, &target, Some("000000".to_string()), &pool).await);
+        assert_eq!(merged_into(&source, &pool).await, None);
+        assert!(outbox_events(&source, &pool).await.is_empty());
+
+        assert!(merge(&source, &target, Some(totp_code(&secret)), &pool).await);
+        assert_eq!(merged_into(&source, &pool).await, Some(target.clone()));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn merge_is_published_through_the_outbox() {
+        let pool = Arc::new(test_pool().await);
+        let (source, target) = (test_user_id(), test_user_id());
+
+        assert!(merge(&source, &target, None, &pool).await);
+        assert_eq!(
+            outbox_events(&source, &pool).await,
+            vec![Event::AccountMerged {
+                source_user_id: source.clone(),
+                target_user_id: target.clone(),
+            }]
+        );
+    }
+
+    #[test]
+    fn contacts_are_indexed_normalized() {
+        test_env();
+        assert_eq!(contact_index(" Jane@Example.com").unwrap(), contact_index("jane@example.com").unwrap());
+        assert_eq!(contact_index("+1 (631) 933-1307").unwrap(), contact_index("16319331307").unwrap());
+        assert_ne!(contact_index("jane@example.com").unwrap(), contact_index("john@example.com").unwrap());
+        assert_eq!(contact_index("@").unwrap(), None);
+        assert_eq!(contact_index("no digits").unwrap(), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn contacts_are_owned_once_signed_in_with() {
+        let pool = Arc::new(test_pool().await);
+        let (account, other) = (test_user_id(), test_user_id());
+        let email = format!("{}@Example.com", account);
+        let provider: Arc<dyn IdentityProvider> = Arc::new(FakeProvider { user_id: account.clone() });
+        let payload = Email { email: email.clone(), expiration_minutes: None, captcha_token: None };
+        email_auth_otp(Ok(Json(payload)), Extension(pool.clone()), Extension(provider), ClientInfo::default())
+            .await
+            .unwrap();
+
+        // a code sent to the address proves nothing yet
+        assert!(!db_owns_contact(&account, &email, &pool).await.unwrap());
+        resolve_account(&account, &"Email".to_string(), &pool).await.unwrap();
+        resolve_account(&other, &"Email".to_string(), &pool).await.unwrap();
+        assert!(db_owns_contact(&account, &email.to_lowercase(), &pool).await.unwrap());
+        assert!(!db_owns_contact(&other, &email, &pool).await.unwrap());
+        assert!(!db_owns_contact(&account, &"+16319331307".to_string(), &pool).await.unwrap());
+    }
+}
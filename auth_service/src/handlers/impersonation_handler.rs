@@ -0,0 +1,358 @@
+use axum::{
+    extract::{rejection::JsonRejection, Extension},
+    Json,
+};
+use axum_macros::debug_handler;
+use chrono::Duration;
+use openapi_rs::openapi_proc_macro::handler;
+use sqlx::types::chrono::{NaiveDateTime, Utc};
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use okapi::openapi3::RefOr;
+use openapi_rs::gen::OpenApiGenerator;
+use openapi_rs::OpenApiFromData;
+
+use microservice_utils::server::client_info::ClientInfo;
+use microservice_utils::{
+    jwt::admin::is_admin,
+    jwt::auth::create_impersonation_token,
+    jwt::extractor::AuthSession,
+    server::response::{into_reponse, AxumRes, AxumResult},
+};
+
+use crate::handlers::auth_handler::{bad_request, internal_error};
+use crate::handlers::lockout_handler::require_admin;
+use crate::models::impersonation::{
+    ImpersonatedRequest, Impersonation, ImpersonationId, StartImpersonation,
+};
+
+pub const READ_SCOPE: &str = "read";
+pub const WRITE_SCOPE: &str = "write";
+const IMPERSONATION_PROVIDER: &str = "Impersonation";
+const IMPERSONATION_LIMIT: i64 = 100;
+const IMPERSONATED_REQUESTS_LIMIT: i64 = 1000;
+
+lazy_static! {
+    static ref DEFAULT_TTL_MINUTES: i64 = std::env::var("IMPERSONATION_TTL_MINUTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30);
+    static ref MAX_TTL_MINUTES: i64 = std::env::var("IMPERSONATION_MAX_TTL_MINUTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(120);
+}
+
+fn is_read(method: &str) -> bool {
+    matches!(method, "GET" | "HEAD" | "OPTIONS")
+}
+
+// Writes need the write scope, and impersonation never changes credentials, sessions or login methods.
+fn impersonation_allows(scopes: &[String], method: &str, path: &str) -> bool {
+    if is_read(method) {
+        scopes.iter().any(|s| s == READ_SCOPE || s == WRITE_SCOPE)
+    } else {
+        scopes.iter().any(|s| s == WRITE_SCOPE) && !path.starts_with("/api/auth/")
+    }
+}
+
+/// Called by `check_token` for every authenticated request. Requests made with an
+/// impersonation token are checked against its scopes and recorded, allowed or not.
+pub(crate) async fn authorize_impersonation(
+    session_id: &Uuid,
+    method: &str,
+    path: &str,
+    pool: &PgPool,
+) -> Result<bool, sqlx::Error> {
+    let impersonation = match db_session_impersonation(session_id, pool).await? {
+        Some(impersonation) => impersonation,
+        None => return Ok(true),
+    };
+    let now = Utc::now().naive_utc();
+    let allowed = impersonation.ended_at.is_none()
+        && impersonation.expires_at > now
+        && impersonation_allows(&impersonation.scopes, method, path);
+    println!(
+        "Impersonation {} by admin {} as user {}: {} {} {}",
+        impersonation.id,
+        impersonation.admin_id,
+        impersonation.user_id,
+        method,
+        path,
+        if allowed { "allowed" } else { "refused" }
+    );
+    db_record_request(&impersonation.id, method, path, allowed, pool).await?;
+    Ok(allowed)
+}
+
+// API
+#[debug_handler]
+#[handler(method = "POST", tag = "admin")]
+pub async fn start_impersonation(
+    payload: Result<Json<StartImpersonation>, JsonRejection>,
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+    client: ClientInfo,
+) -> AxumResult<Json<AxumRes>> {
+    require_admin(&session)?;
+    let req = payload.map_err(bad_request)?.0;
+
+    let refuse = |error: &str| into_reponse(400, serde_json::json!({ "error": error }));
+    if session.impersonator.is_some() {
+        return Err(refuse("Impersonation can't be started while impersonating"));
+    }
+    if req.user_id == session.user_id || is_admin(&req.user_id) {
+        return Err(refuse("Admins can't be impersonated"));
+    }
+    if req.reason.trim().is_empty() {
+        return Err(refuse("A reason is required"));
+    }
+    let scopes = req.scopes.unwrap_or_else(|| vec![READ_SCOPE.to_string()]);
+    if scopes.is_empty() || scopes.iter().any(|s| s != READ_SCOPE && s != WRITE_SCOPE) {
+        return Err(refuse("Scopes must be read and/or write"));
+    }
+    let ttl = Duration::minutes(
+        req.ttl_minutes
+            .unwrap_or(*DEFAULT_TTL_MINUTES)
+            .clamp(1, *MAX_TTL_MINUTES),
+    );
+
+    let token = create_impersonation_token(&req.user_id, &session.user_id, &scopes, ttl);
+    let expires_at = Utc::now().naive_utc() + ttl;
+    let id = db_create_impersonation(
+        &session.user_id,
+        &req.user_id,
+        &scopes,
+        req.reason.trim(),
+        expires_at,
+        &token,
+        &client,
+        &pool,
+    )
+    .await
+    .map_err(internal_error)?;
+
+    Ok(axum::Json(AxumRes {
+        code: 200,
+        result: serde_json::json!({
+            "id": id,
+            "user_id": req.user_id,
+            "token": token,
+            "scopes": scopes,
+            "expires_at": expires_at,
+        }),
+    }))
+}
+
+#[debug_handler]
+#[handler(method = "DELETE", tag = "admin")]
+pub async fn end_impersonation(
+    payload: Result<Json<ImpersonationId>, JsonRejection>,
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    require_admin(&session)?;
+    let req = payload.map_err(bad_request)?.0;
+
+    match db_end_impersonation(&req.id, &pool)
+        .await
+        .map_err(internal_error)?
+    {
+        0 => Err(into_reponse(
+            404,
+            serde_json::json!({ "error": "Impersonation not found" }),
+        )),
+        _ => Ok(axum::Json(AxumRes {
+            code: 200,
+            result: serde_json::json!({ "status": "success" }),
+        })),
+    }
+}
+
+#[debug_handler]
+#[handler(method = "GET", tag = "admin")]
+pub async fn list_impersonations(
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    require_admin(&session)?;
+
+    let impersonations = db_list_impersonations(None, &pool)
+        .await
+        .map_err(internal_error)?;
+    Ok(axum::Json(AxumRes {
+        code: 200,
+        result: serde_json::json!(&impersonations),
+    }))
+}
+
+/// The impersonations of the current user's account, with the requests made during each.
+#[debug_handler]
+#[handler(method = "GET", tag = "auth")]
+pub async fn impersonation_history(
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    let impersonations = db_list_impersonations(Some(&session.user_id), &pool)
+        .await
+        .map_err(internal_error)?;
+    let ids: Vec<Uuid> = impersonations.iter().map(|i| i.id).collect();
+    let requests = db_list_requests(&ids, &pool).await.map_err(internal_error)?;
+
+    let history: Vec<serde_json::Value> = impersonations
+        .iter()
+        .map(|i| {
+            let requests: Vec<&ImpersonatedRequest> = requests
+                .iter()
+                .filter(|r| r.impersonation_id == i.id)
+                .collect();
+            serde_json::json!({
+                "id": i.id,
+                "admin_id": i.admin_id,
+                "scopes": i.scopes,
+                "reason": i.reason,
+                "created_at": i.created_at,
+                "expires_at": i.expires_at,
+                "ended_at": i.ended_at,
+                "requests": requests,
+            })
+        })
+        .collect();
+    Ok(axum::Json(AxumRes {
+        code: 200,
+        result: serde_json::json!(&history),
+    }))
+}
+
+// Database
+#[allow(clippy::too_many_arguments)]
+pub async fn db_create_impersonation(
+    admin_id: &String,
+    user_id: &String,
+    scopes: &[String],
+    reason: &str,
+    expires_at: NaiveDateTime,
+    access_token: &String,
+    client: &ClientInfo,
+    pool: &PgPool,
+) -> Result<Uuid, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    // A session of its own, so the user sees it among their sessions and can revoke it.
+    let session = sqlx::query!(
+        "INSERT INTO sessions (user_id, access_token, provider_type, device, user_agent, ip) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+        user_id,
+        access_token,
+        IMPERSONATION_PROVIDER,
+        client.device,
+        client.user_agent,
+        client.ip,
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    let row = sqlx::query!(
+        "INSERT INTO impersonations (admin_id, user_id, session_id, scopes, reason, expires_at) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+        admin_id,
+        user_id,
+        session.id,
+        scopes,
+        reason,
+        expires_at
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(row.id)
+}
+
+pub async fn db_session_impersonation(
+    session_id: &Uuid,
+    pool: &PgPool,
+) -> Result<Option<Impersonation>, sqlx::Error> {
+    let row = sqlx::query_as!(
+        Impersonation,
+        "SELECT id, admin_id, user_id, scopes, reason, expires_at, ended_at, created_at FROM impersonations WHERE session_id = $1",
+        session_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row)
+}
+
+pub async fn db_record_request(
+    impersonation_id: &Uuid,
+    method: &str,
+    path: &str,
+    allowed: bool,
+    pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let _ = sqlx::query!(
+        "INSERT INTO impersonation_requests (impersonation_id, method, path, allowed) VALUES ($1, $2, $3, $4)",
+        impersonation_id,
+        method,
+        path,
+        allowed
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn db_end_impersonation(id: &Uuid, pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let now = Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query!(
+        "UPDATE impersonations SET ended_at = $1 WHERE id = $2 AND ended_at IS NULL RETURNING session_id",
+        now,
+        id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    let row = match row {
+        Some(row) => row,
+        None => return Ok(0),
+    };
+    sqlx::query!(
+        "UPDATE sessions SET revoked_at = $1 WHERE id = $2 AND revoked_at IS NULL",
+        now,
+        row.session_id
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(1)
+}
+
+// All impersonations for admins, or those of one user.
+pub async fn db_list_impersonations(
+    user_id: Option<&String>,
+    pool: &PgPool,
+) -> Result<Vec<Impersonation>, sqlx::Error> {
+    let rows = sqlx::query_as!(
+        Impersonation,
+        "SELECT id, admin_id, user_id, scopes, reason, expires_at, ended_at, created_at FROM impersonations
+        WHERE $1::TEXT IS NULL OR user_id = $1 ORDER BY created_at DESC LIMIT $2",
+        user_id,
+        IMPERSONATION_LIMIT
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+pub async fn db_list_requests(
+    impersonation_ids: &[Uuid],
+    pool: &PgPool,
+) -> Result<Vec<ImpersonatedRequest>, sqlx::Error> {
+    let rows = sqlx::query_as!(
+        ImpersonatedRequest,
+        "SELECT impersonation_id, method, path, allowed, created_at FROM impersonation_requests
+        WHERE impersonation_id = ANY($1) ORDER BY created_at LIMIT $2",
+        impersonation_ids,
+        IMPERSONATED_REQUESTS_LIMIT
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
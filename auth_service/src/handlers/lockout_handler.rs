@@ -0,0 +1,126 @@
+use axum::{
+    extract::{rejection::JsonRejection, Extension},
+    Json,
+};
+use axum_macros::debug_handler;
+use openapi_rs::openapi_proc_macro::handler;
+use sqlx::types::chrono::Utc;
+use sqlx::PgPool;
+use std::sync::Arc;
+
+use okapi::openapi3::RefOr;
+use openapi_rs::gen::OpenApiGenerator;
+use openapi_rs::OpenApiFromData;
+
+use microservice_utils::{
+    jwt::admin::is_admin,
+    jwt::extractor::AuthSession,
+    server::response::{into_reponse, AxumRes, AxumResult},
+};
+
+use crate::handlers::auth_handler::{bad_request, internal_error};
+use crate::models::lockout::{LockoutEvent, LockoutRelease};
+
+const LOCKOUT_EVENTS_LIMIT: i64 = 200;
+
+pub(crate) fn require_admin(session: &AuthSession) -> AxumResult<()> {
+    if is_admin(&session.user_id) {
+        Ok(())
+    } else {
+        Err(into_reponse(
+            404,
+            serde_json::json!({ "error": "Not found" }),
+        ))
+    }
+}
+
+// API
+#[debug_handler]
+#[handler(method = "GET", tag = "admin")]
+pub async fn list_lockouts(
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    require_admin(&session)?;
+
+    let events = db_list_lockout_events(&pool).await.map_err(internal_error)?;
+    let now = Utc::now().naive_utc();
+    let active: Vec<&LockoutEvent> = events
+        .iter()
+        .filter(|e| e.released_at.is_none() && e.locked_until > now)
+        .collect();
+
+    Ok(axum::Json(AxumRes {
+        code: 200,
+        result: serde_json::json!({
+            "active": active,
+            "events": events,
+        }),
+    }))
+}
+
+#[debug_handler]
+#[handler(method = "DELETE", tag = "admin")]
+pub async fn release_lockout(
+    payload: Result<Json<LockoutRelease>, JsonRejection>,
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    require_admin(&session)?;
+    let req = payload.map_err(bad_request)?.0;
+
+    match db_release_lockout(&req, &session.user_id, &pool)
+        .await
+        .map_err(internal_error)?
+    {
+        0 => Err(into_reponse(
+            404,
+            serde_json::json!({ "error": "Lockout not found" }),
+        )),
+        _ => Ok(axum::Json(AxumRes {
+            code: 200,
+            result: serde_json::json!({ "status": "success" }),
+        })),
+    }
+}
+
+// Database
+pub async fn db_list_lockout_events(pool: &PgPool) -> Result<Vec<LockoutEvent>, sqlx::Error> {
+    let rows = sqlx::query_as!(
+        LockoutEvent,
+        "SELECT id, scope, key, ip, user_agent, lockouts, locked_until, released_by, released_at, created_at
+        FROM lockout_events ORDER BY created_at DESC LIMIT $1",
+        LOCKOUT_EVENTS_LIMIT
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+// Clears the counter and marks its open lockout events as released.
+pub async fn db_release_lockout(
+    req: &LockoutRelease,
+    released_by: &String,
+    pool: &PgPool,
+) -> Result<u64, sqlx::Error> {
+    let now = Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+    let res = sqlx::query!(
+        "UPDATE auth_attempts SET failures = 0, locked_until = NULL WHERE scope = $1 AND key = $2",
+        req.scope,
+        req.key
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE lockout_events SET released_by = $1, released_at = $2 WHERE scope = $3 AND key = $4 AND released_at IS NULL AND locked_until > $2",
+        released_by,
+        now,
+        req.scope,
+        req.key
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(res.rows_affected())
+}
@@ -0,0 +1,306 @@
+use axum::{extract::Extension, Json};
+use axum_macros::debug_handler;
+use openapi_rs::openapi_proc_macro::handler;
+use sqlx::types::chrono::NaiveDateTime;
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use okapi::openapi3::RefOr;
+use openapi_rs::gen::OpenApiGenerator;
+use openapi_rs::OpenApiFromData;
+
+use microservice_utils::{
+    events::{event_producer, notify_user},
+    jwt::extractor::AuthSession,
+    server::client_info::ClientInfo,
+    server::preferences::wants_notification,
+    server::response::{into_reponse, AxumRes, AxumResult},
+    server::users::get_user,
+};
+
+use crate::geoip::{distance_km, lookup, Location};
+use crate::models::login::LoginEvent;
+use microservice_utils::server::sender::{sender_from_env, Channel, Message, MessageSender};
+
+pub const NEW_DEVICE: &str = "new_device";
+pub const NEW_COUNTRY: &str = "new_country";
+pub const IMPOSSIBLE_TRAVEL: &str = "impossible_travel";
+
+// Closer than this is within the accuracy of GeoIP, not travel.
+const MIN_TRAVEL_KM: f64 = 200.0;
+const MAX_LOGIN_HISTORY: i64 = 100;
+
+lazy_static! {
+    // Faster than a plane, allowing for GeoIP placing both ends of the trip off.
+    static ref MAX_TRAVEL_KMH: f64 = std::env::var("LOGIN_MAX_TRAVEL_KMH")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1000.0);
+}
+
+// What the new login is compared with.
+#[derive(sqlx::FromRow, Debug, Clone)]
+struct PreviousLogin {
+    device: Option<String>,
+    country: Option<String>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    created_at: NaiveDateTime,
+}
+
+// API
+#[debug_handler]
+#[handler(method = "GET", tag = "session")]
+pub async fn list_logins(
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    match db_list_logins(&session.user_id, &session.access_token, &pool).await {
+        Ok(result) => Ok(axum::Json(AxumRes {
+            code: 200,
+            result: serde_json::json!(&result),
+        })),
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(500, ret))
+        }
+    }
+}
+
+/// Records a completed login and flags what is unusual about it compared to the user's
+/// earlier logins. The user is alerted about flagged logins in the background.
+pub(crate) async fn record_login(
+    user_id: &String,
+    session_id: &Uuid,
+    provider_type: &String,
+    client: &ClientInfo,
+    pool: &PgPool,
+) -> Result<Vec<String>, sqlx::Error> {
+    let location = client.ip.as_deref().and_then(lookup).unwrap_or_default();
+    let previous = db_previous_logins(user_id, pool).await?;
+    let flags = login_flags(client, &location, &previous);
+
+    sqlx::query(
+        "INSERT INTO login_events (user_id, session_id, provider_type, ip, user_agent, device, country, city, latitude, longitude, flags)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+    )
+    .bind(user_id)
+    .bind(session_id)
+    .bind(provider_type)
+    .bind(&client.ip)
+    .bind(&client.user_agent)
+    .bind(&client.device)
+    .bind(&location.country)
+    .bind(&location.city)
+    .bind(location.latitude)
+    .bind(location.longitude)
+    .bind(&flags)
+    .execute(pool)
+    .await?;
+
+    if !flags.is_empty() {
+        spawn_login_alert(user_id.clone(), provider_type.clone(), client.clone(), location.country.clone(), flags.clone());
+    }
+    Ok(flags)
+}
+
+// A first login has nothing to be compared with, so it is never flagged.
+fn login_flags(client: &ClientInfo, location: &Location, previous: &[PreviousLogin]) -> Vec<String> {
+    let mut flags = Vec::new();
+    if previous.is_empty() {
+        return flags;
+    }
+
+    // Browser updates change the user agent, not the device it is parsed into. A client that
+    // tells nothing about itself is as unknown as a new device.
+    let known_device = match &client.device {
+        Some(device) => previous.iter().any(|p| p.device.as_ref() == Some(device)),
+        None => false,
+    };
+    if !known_device {
+        flags.push(NEW_DEVICE.to_string());
+    }
+
+    if let Some(country) = &location.country {
+        let located = previous.iter().any(|p| p.country.is_some());
+        if located && !previous.iter().any(|p| p.country.as_ref() == Some(country)) {
+            flags.push(NEW_COUNTRY.to_string());
+        }
+    }
+
+    // Against the latest located login only, older ones say nothing about the trip
+    let last = previous.iter().find_map(|p| Some((p.latitude?, p.longitude?, p.created_at)));
+    if let (Some(latitude), Some(longitude), Some((last_latitude, last_longitude, last_at))) =
+        (location.latitude, location.longitude, last)
+    {
+        let km = distance_km((last_latitude, last_longitude), (latitude, longitude));
+        let hours = (sqlx::types::chrono::Utc::now().naive_utc() - last_at).num_seconds().max(1) as f64 / 3600.0;
+        if km > MIN_TRAVEL_KM && km / hours > *MAX_TRAVEL_KMH {
+            flags.push(IMPOSSIBLE_TRAVEL.to_string());
+        }
+    }
+    flags
+}
+
+// Alerts in the app and by email, as the user's security notification preferences allow.
+fn spawn_login_alert(
+    user_id: String,
+    provider_type: String,
+    client: ClientInfo,
+    country: Option<String>,
+    flags: Vec<String>,
+) {
+    tokio::spawn(async move {
+        if wants_notification(&user_id, None, "security", "in_app").await {
+            let message = serde_json::json!({
+                "provider_type": provider_type,
+                "ip": client.ip,
+                "device": client.device,
+                "country": country,
+                "flags": flags,
+            });
+            if let Err(e) = notify_user(&user_id, "suspicious_login", &message, &event_producer()).await {
+                println!("Unable to notify {} of a login: {:?}", user_id, e);
+            }
+        }
+
+        if wants_notification(&user_id, None, "security", "email").await {
+            let email = match get_user(&user_id, &["email"]).await {
+                Ok(profile) if !profile.email.is_empty() => profile.email,
+                Ok(_) => return,
+                Err(e) => {
+                    println!("{:?}", e.to_string());
+                    return;
+                }
+            };
+            let body = format!(
+                "There was a new sign-in to your BHuman account ({}) from {} at {}{}.\n\nIf this was you, you can ignore this email. Otherwise sign out the session from your security settings and secure your login methods.",
+                flags.join(", "),
+                client.device.as_deref().unwrap_or("an unknown device"),
+                client.ip.as_deref().unwrap_or("an unknown address"),
+                country.map(|c| format!(", {}", c)).unwrap_or_default(),
+            );
+            let message = Message {
+                channel: Channel::Email,
+                to: email,
+                subject: "New sign-in to your account".to_string(),
+                body,
+            };
+            if let Err(e) = sender_from_env().send(&message).await {
+                println!("Unable to email {} about a login: {:?}", user_id, e);
+            }
+        }
+    });
+}
+
+// Database
+async fn db_previous_logins(user_id: &String, pool: &PgPool) -> Result<Vec<PreviousLogin>, sqlx::Error> {
+    let rows: Vec<PreviousLogin> = sqlx::query_as(
+        "SELECT device, country, latitude, longitude, created_at
+        FROM login_events WHERE user_id = $1 ORDER BY created_at DESC LIMIT $2",
+    )
+    .bind(user_id)
+    .bind(MAX_LOGIN_HISTORY)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+pub async fn db_list_logins(
+    user_id: &String,
+    access_token: &String,
+    pool: &PgPool,
+) -> Result<Vec<LoginEvent>, sqlx::Error> {
+    let rows: Vec<LoginEvent> = sqlx::query_as(
+        "SELECT l.id, l.session_id, l.provider_type, l.ip, l.user_agent, l.device, l.country, l.city,
+            l.latitude, l.longitude, l.flags, l.created_at, COALESCE(s.access_token = $2, FALSE) AS current
+        FROM login_events l LEFT JOIN sessions s ON s.id = l.session_id
+        WHERE l.user_id = $1 ORDER BY l.created_at DESC LIMIT $3",
+    )
+    .bind(user_id)
+    .bind(access_token)
+    .bind(MAX_LOGIN_HISTORY)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::types::chrono::{Duration, Utc};
+
+    fn previous(device: &str, country: &str, at: (f64, f64), hours_ago: i64) -> PreviousLogin {
+        PreviousLogin {
+            device: Some(device.to_string()),
+            country: Some(country.to_string()),
+            latitude: Some(at.0),
+            longitude: Some(at.1),
+            created_at: Utc::now().naive_utc() - Duration::hours(hours_ago),
+        }
+    }
+
+    fn client(device: Option<&str>) -> ClientInfo {
+        ClientInfo {
+            ip: None,
+            user_agent: Some("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) Version/17.1".to_string()),
+            device: device.map(str::to_string),
+        }
+    }
+
+    fn location(country: &str, at: (f64, f64)) -> Location {
+        Location {
+            country: Some(country.to_string()),
+            city: None,
+            latitude: Some(at.0),
+            longitude: Some(at.1),
+        }
+    }
+
+    const PARIS: (f64, f64) = (48.8566, 2.3522);
+    const LYON: (f64, f64) = (45.764, 4.8357);
+    const NEW_YORK: (f64, f64) = (40.7128, -74.006);
+
+    #[test]
+    fn distances_are_great_circles() {
+        assert_eq!(distance_km(PARIS, PARIS), 0.0);
+        assert!((distance_km(PARIS, NEW_YORK) - 5837.0).abs() < 10.0);
+        assert!((distance_km(PARIS, LYON) - 392.0).abs() < 5.0);
+        assert_eq!(distance_km(PARIS, NEW_YORK), distance_km(NEW_YORK, PARIS));
+    }
+
+    #[test]
+    fn first_logins_are_never_flagged() {
+        assert!(login_flags(&client(None), &location("US", NEW_YORK), &[]).is_empty());
+    }
+
+    #[test]
+    fn devices_are_compared_not_user_agents() {
+        let history = [previous("macOS Desktop", "FR", PARIS, 48)];
+        // a browser update changes the user agent only
+        assert!(login_flags(&client(Some("macOS Desktop")), &location("FR", PARIS), &history).is_empty());
+        assert_eq!(login_flags(&client(Some("Android Mobile")), &location("FR", PARIS), &history), vec![NEW_DEVICE]);
+        // nothing to recognise the device by
+        assert_eq!(login_flags(&client(None), &location("FR", PARIS), &history), vec![NEW_DEVICE]);
+    }
+
+    #[test]
+    fn trips_faster_than_a_plane_are_impossible() {
+        let device = Some("macOS Desktop");
+        let history = [previous("macOS Desktop", "FR", PARIS, 1)];
+        assert_eq!(
+            login_flags(&client(device), &location("US", NEW_YORK), &history),
+            vec![NEW_COUNTRY, IMPOSSIBLE_TRAVEL]
+        );
+        // a day is enough to fly there
+        let history = [previous("macOS Desktop", "FR", PARIS, 24)];
+        assert_eq!(login_flags(&client(device), &location("US", NEW_YORK), &history), vec![NEW_COUNTRY]);
+        // within GeoIP accuracy, however soon
+        let history = [previous("macOS Desktop", "FR", PARIS, 0)];
+        assert!(login_flags(&client(device), &location("FR", (48.9, 2.4)), &history).is_empty());
+    }
+}
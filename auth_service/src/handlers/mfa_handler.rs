@@ -0,0 +1,654 @@
+use axum::{
+    extract::{rejection::JsonRejection, Extension},
+    Json,
+};
+use axum_macros::debug_handler;
+use base32::Alphabet;
+use chrono::Duration;
+use hmac::{Hmac, Mac};
+use openapi_rs::openapi_proc_macro::handler;
+use rand::Rng;
+use sha1::Sha1;
+use sqlx::types::chrono::Utc;
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use okapi::openapi3::RefOr;
+use openapi_rs::gen::OpenApiGenerator;
+use openapi_rs::OpenApiFromData;
+
+use microservice_utils::crypto::{current_prefix, decrypt, encrypt, into_sqlx_error, reencrypt};
+use microservice_utils::events::{enqueue, Event};
+use microservice_utils::server::client_info::ClientInfo;
+use microservice_utils::server::grpc::check_mfa_required;
+use microservice_utils::{
+    jwt::auth::hash_token,
+    jwt::extractor::AuthSession,
+    server::response::{into_reponse, AxumRes, AxumResult, ResponseError},
+};
+
+use crate::handlers::auth_handler::{bad_request, internal_error, issue_session};
+use crate::models::mfa::{MfaChallenge, MfaChallengeCode, MfaCode};
+use crate::providers::random_token;
+use crate::throttle::{check_lockout, mfa_keys, record_failure, record_success};
+
+type HmacSha1 = Hmac<Sha1>;
+
+const TOTP_PERIOD: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+const RECOVERY_CODES: usize = 10;
+const CHALLENGE_EXPIRATION_MINUTES: i64 = 5;
+const MAX_ATTEMPTS: i32 = 5;
+const VERIFY_KIND: &str = "verify";
+const ENROLL_KIND: &str = "enroll";
+
+lazy_static! {
+    static ref MFA_ISSUER: String = std::env::var("MFA_ISSUER").unwrap_or_else(|_| "Hailey".to_owned());
+}
+
+// TOTP (RFC 6238): HMAC-SHA1 over the 30 second time step, dynamically truncated.
+pub(crate) fn totp_at(secret: &[u8], step: u64) -> u32 {
+    let mut mac = HmacSha1::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    binary % 10u32.pow(TOTP_DIGITS)
+}
+
+// Returns the matched time step. One step of clock drift is accepted either way, and
+// steps up to `last_used_step` are rejected so a code can't be replayed.
+fn verify_totp(secret: &str, code: &str, last_used_step: i64) -> Option<i64> {
+    let secret = base32::decode(Alphabet::RFC4648 { padding: false }, secret)?;
+    let code = code.trim();
+    if code.len() != TOTP_DIGITS as usize {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+
+    let now = Utc::now().timestamp() / TOTP_PERIOD;
+    (now - 1..=now + 1).find(|step| *step > last_used_step && totp_at(&secret, *step as u64) == code)
+}
+
+pub(crate) fn generate_secret() -> String {
+    let bytes: [u8; 20] = rand::thread_rng().gen();
+    base32::encode(Alphabet::RFC4648 { padding: false }, &bytes)
+}
+
+fn otpauth_uri(secret: &str, user_id: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{user}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = MFA_ISSUER.to_string(),
+        user = user_id,
+        secret = secret,
+        digits = TOTP_DIGITS,
+        period = TOTP_PERIOD,
+    )
+}
+
+fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = rand::thread_rng();
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let bytes: [u8; 5] = rng.gen();
+            let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("{}-{}", &hex[..5], &hex[5..])
+        })
+        .collect()
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Checks a TOTP code, falling back to an unused recovery code.
+pub async fn check_second_factor(
+    user_id: &String,
+    code: &String,
+    pool: &PgPool,
+) -> Result<bool, sqlx::Error> {
+    if let Some((secret, last_used_step)) = db_get_totp(user_id, true, pool).await? {
+        if let Some(step) = verify_totp(&secret, code, last_used_step) {
+            return db_use_totp_step(user_id, step, pool).await;
+        }
+    }
+    db_use_recovery_code(user_id, &hash_token(&normalize_recovery_code(code)), pool).await
+}
+
+/// `check_second_factor` counting the attempts of the user, who is locked out for a while
+/// after too many wrong codes, whatever challenge or endpoint they came through.
+pub async fn verify_second_factor(
+    user_id: &String,
+    code: &String,
+    client: &ClientInfo,
+    pool: &PgPool,
+) -> AxumResult<bool> {
+    let keys = mfa_keys(user_id);
+    check_lockout(&keys, client, pool).await?;
+    if check_second_factor(user_id, code, pool).await.map_err(internal_error)? {
+        record_success(&keys, pool).await?;
+        Ok(true)
+    } else {
+        record_failure(&keys, client, pool).await?;
+        Ok(false)
+    }
+}
+
+/// Decides whether a login needs a second factor. Users with TOTP get a `verify` challenge,
+/// users in a workspace enforcing 2FA without TOTP get an `enroll` challenge.
+pub async fn mfa_challenge(
+    user_id: &String,
+    provider_type: &String,
+    pool: &PgPool,
+) -> AxumResult<Option<serde_json::Value>> {
+    let enabled = db_get_totp(user_id, true, pool)
+        .await
+        .map_err(internal_error)?
+        .is_some();
+
+    let kind = if enabled {
+        VERIFY_KIND
+    } else if check_mfa_required(user_id).await.map_err(internal_error)? {
+        ENROLL_KIND
+    } else {
+        return Ok(None);
+    };
+
+    let token = random_token();
+    db_create_challenge(user_id, provider_type, kind, &hash_token(&token), pool)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Some(serde_json::json!({
+        "user_id": user_id,
+        "mfa_required": true,
+        "enrollment_required": kind == ENROLL_KIND,
+        "challenge_token": token,
+    })))
+}
+
+// Starts (or restarts) enrolment, None when TOTP is already enabled.
+async fn begin_enrolment(
+    user_id: &String,
+    pool: &PgPool,
+) -> Result<Option<serde_json::Value>, sqlx::Error> {
+    if db_get_totp(user_id, true, pool).await?.is_some() {
+        return Ok(None);
+    }
+    let secret = generate_secret();
+    db_set_pending_totp(user_id, &secret, pool).await?;
+    Ok(Some(serde_json::json!({
+        "secret": secret,
+        "otpauth_uri": otpauth_uri(&secret, user_id),
+    })))
+}
+
+// Confirms enrolment with a first code, returning fresh recovery codes.
+async fn confirm_enrolment(
+    user_id: &String,
+    code: &String,
+    pool: &PgPool,
+) -> Result<Option<Vec<String>>, sqlx::Error> {
+    let step = match db_get_totp(user_id, false, pool).await? {
+        Some((secret, _)) => match verify_totp(&secret, code, 0) {
+            Some(step) => step,
+            None => return Ok(None),
+        },
+        None => return Ok(None),
+    };
+
+    let codes = generate_recovery_codes();
+    let hashes: Vec<String> = codes
+        .iter()
+        .map(|c| hash_token(&normalize_recovery_code(c)))
+        .collect();
+    db_confirm_totp(user_id, step, &hashes, pool).await?;
+    Ok(Some(codes))
+}
+
+fn invalid_code() -> ResponseError {
+    into_reponse(401, serde_json::json!({ "error": "Invalid code" }))
+}
+
+fn invalid_challenge() -> ResponseError {
+    into_reponse(401, serde_json::json!({ "error": "Challenge is invalid or expired" }))
+}
+
+// API
+#[debug_handler]
+#[handler(method = "POST", tag = "mfa")]
+pub async fn totp_enroll(
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    match begin_enrolment(&session.user_id, &pool).await.map_err(internal_error)? {
+        Some(ret) => Ok(axum::Json(AxumRes { code: 200, result: ret })),
+        None => Err(into_reponse(
+            400,
+            serde_json::json!({ "error": "Two-factor authentication is already enabled" }),
+        )),
+    }
+}
+
+#[debug_handler]
+#[handler(method = "POST", tag = "mfa")]
+pub async fn totp_confirm(
+    payload: Result<Json<MfaCode>, JsonRejection>,
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    let req = payload.map_err(bad_request)?.0;
+    match confirm_enrolment(&session.user_id, &req.code, &pool)
+        .await
+        .map_err(internal_error)?
+    {
+        Some(codes) => Ok(axum::Json(AxumRes {
+            code: 200,
+            result: serde_json::json!({ "recovery_codes": codes }),
+        })),
+        None => Err(invalid_code()),
+    }
+}
+
+#[debug_handler]
+#[handler(method = "POST", tag = "mfa")]
+pub async fn totp_disable(
+    payload: Result<Json<MfaCode>, JsonRejection>,
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+    client: ClientInfo,
+) -> AxumResult<Json<AxumRes>> {
+    let req = payload.map_err(bad_request)?.0;
+
+    if check_mfa_required(&session.user_id).await.map_err(internal_error)? {
+        return Err(into_reponse(
+            403,
+            serde_json::json!({ "error": "Two-factor authentication is required by your workspace" }),
+        ));
+    }
+    if !verify_second_factor(&session.user_id, &req.code, &client, &pool).await? {
+        return Err(invalid_code());
+    }
+    db_delete_totp(&session.user_id, &pool)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(axum::Json(AxumRes {
+        code: 200,
+        result: serde_json::json!({ "status": "success" }),
+    }))
+}
+
+#[debug_handler]
+#[handler(method = "POST", tag = "mfa")]
+pub async fn mfa_verify(
+    payload: Result<Json<MfaChallengeCode>, JsonRejection>,
+    Extension(pool): Extension<Arc<PgPool>>,
+    client: ClientInfo,
+) -> AxumResult<Json<AxumRes>> {
+    let req = payload.map_err(bad_request)?.0;
+
+    let (id, user_id, provider_type) =
+        db_get_challenge(&hash_token(&req.challenge_token), VERIFY_KIND, &pool)
+            .await
+            .map_err(internal_error)?
+            .ok_or_else(invalid_challenge)?;
+
+    if !verify_second_factor(&user_id, &req.code, &client, &pool).await? {
+        db_challenge_failed(&id, &pool).await.map_err(internal_error)?;
+        return Err(invalid_code());
+    }
+    if !db_use_challenge(&id, &pool).await.map_err(internal_error)? {
+        return Err(invalid_challenge());
+    }
+
+    let ret = issue_session(&user_id, &provider_type, &client, &pool).await?;
+    Ok(axum::Json(AxumRes { code: 200, result: ret }))
+}
+
+#[debug_handler]
+#[handler(method = "POST", tag = "mfa")]
+pub async fn mfa_enroll(
+    payload: Result<Json<MfaChallenge>, JsonRejection>,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    let req = payload.map_err(bad_request)?.0;
+
+    let (_, user_id, _) = db_get_challenge(&hash_token(&req.challenge_token), ENROLL_KIND, &pool)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(invalid_challenge)?;
+
+    match begin_enrolment(&user_id, &pool).await.map_err(internal_error)? {
+        Some(ret) => Ok(axum::Json(AxumRes { code: 200, result: ret })),
+        None => Err(invalid_challenge()),
+    }
+}
+
+#[debug_handler]
+#[handler(method = "POST", tag = "mfa")]
+pub async fn mfa_enroll_confirm(
+    payload: Result<Json<MfaChallengeCode>, JsonRejection>,
+    Extension(pool): Extension<Arc<PgPool>>,
+    client: ClientInfo,
+) -> AxumResult<Json<AxumRes>> {
+    let req = payload.map_err(bad_request)?.0;
+
+    let (id, user_id, provider_type) =
+        db_get_challenge(&hash_token(&req.challenge_token), ENROLL_KIND, &pool)
+            .await
+            .map_err(internal_error)?
+            .ok_or_else(invalid_challenge)?;
+
+    let codes = match confirm_enrolment(&user_id, &req.code, &pool)
+        .await
+        .map_err(internal_error)?
+    {
+        Some(codes) => codes,
+        None => {
+            db_challenge_failed(&id, &pool).await.map_err(internal_error)?;
+            return Err(invalid_code());
+        }
+    };
+    if !db_use_challenge(&id, &pool).await.map_err(internal_error)? {
+        return Err(invalid_challenge());
+    }
+
+    let mut ret = issue_session(&user_id, &provider_type, &client, &pool).await?;
+    ret["recovery_codes"] = serde_json::json!(codes);
+    Ok(axum::Json(AxumRes { code: 200, result: ret }))
+}
+
+// Database
+pub async fn db_get_totp(
+    user_id: &String,
+    confirmed: bool,
+    pool: &PgPool,
+) -> Result<Option<(String, i64)>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT secret, last_used_step FROM mfa_totp WHERE user_id = $1 AND (confirmed_at IS NOT NULL) = $2",
+        user_id,
+        confirmed
+    )
+    .fetch_optional(pool)
+    .await?;
+    match row {
+        Some(r) => Ok(Some((decrypt(&r.secret).map_err(into_sqlx_error)?, r.last_used_step))),
+        None => Ok(None),
+    }
+}
+
+pub async fn db_set_pending_totp(
+    user_id: &String,
+    secret: &String,
+    pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let secret = encrypt(secret).map_err(into_sqlx_error)?;
+    let _ = sqlx::query!(
+        "INSERT INTO mfa_totp (user_id, secret) VALUES ($1, $2) ON CONFLICT (user_id) DO UPDATE SET secret = $2, created_at = $3 WHERE mfa_totp.confirmed_at IS NULL",
+        user_id,
+        secret,
+        Utc::now().naive_utc()
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn db_confirm_totp(
+    user_id: &String,
+    step: i64,
+    recovery_hashes: &Vec<String>,
+    pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "UPDATE mfa_totp SET confirmed_at = $1, last_used_step = $2 WHERE user_id = $3",
+        Utc::now().naive_utc(),
+        step,
+        user_id
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!("DELETE FROM mfa_recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut tx)
+        .await?;
+    for code_hash in recovery_hashes {
+        sqlx::query!(
+            "INSERT INTO mfa_recovery_codes (user_id, code_hash) VALUES ($1, $2)",
+            user_id,
+            code_hash
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    let event = Event::TwoFactorChanged {
+        user_id: user_id.clone(),
+        enabled: true,
+    };
+    enqueue(&event, &mut tx).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn db_delete_totp(user_id: &String, pool: &PgPool) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!("DELETE FROM mfa_recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut tx)
+        .await?;
+    let deleted = sqlx::query!("DELETE FROM mfa_totp WHERE user_id = $1 RETURNING confirmed_at", user_id)
+        .fetch_optional(&mut tx)
+        .await?;
+    if deleted.and_then(|r| r.confirmed_at).is_some() {
+        let event = Event::TwoFactorChanged {
+            user_id: user_id.clone(),
+            enabled: false,
+        };
+        enqueue(&event, &mut tx).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+// Encrypts the secrets stored before encryption and re-wraps them after a key rotation.
+pub async fn db_reencrypt_totp_secrets(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let rows = sqlx::query!(
+        "SELECT user_id, secret FROM mfa_totp WHERE secret NOT LIKE $1",
+        format!("{}%", current_prefix().map_err(into_sqlx_error)?)
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut updated = 0;
+    for row in rows {
+        if let Some(secret) = reencrypt(&row.secret).map_err(into_sqlx_error)? {
+            let res = sqlx::query!(
+                "UPDATE mfa_totp SET secret = $1 WHERE user_id = $2 AND secret = $3",
+                secret,
+                row.user_id,
+                row.secret
+            )
+            .execute(pool)
+            .await?;
+            updated += res.rows_affected();
+        }
+    }
+    Ok(updated)
+}
+
+pub async fn db_use_totp_step(user_id: &String, step: i64, pool: &PgPool) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query!(
+        "UPDATE mfa_totp SET last_used_step = $1 WHERE user_id = $2 AND last_used_step < $1",
+        step,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected() == 1)
+}
+
+pub async fn db_use_recovery_code(
+    user_id: &String,
+    code_hash: &String,
+    pool: &PgPool,
+) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query!(
+        "UPDATE mfa_recovery_codes SET used_at = $1 WHERE user_id = $2 AND code_hash = $3 AND used_at IS NULL",
+        Utc::now().naive_utc(),
+        user_id,
+        code_hash
+    )
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected() == 1)
+}
+
+pub async fn db_create_challenge(
+    user_id: &String,
+    provider_type: &String,
+    kind: &str,
+    token_hash: &String,
+    pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let _ = sqlx::query!(
+        "INSERT INTO mfa_challenges (user_id, provider_type, kind, token_hash, expires_at) VALUES ($1, $2, $3, $4, $5)",
+        user_id,
+        provider_type,
+        kind,
+        token_hash,
+        (Utc::now() + Duration::minutes(CHALLENGE_EXPIRATION_MINUTES)).naive_utc()
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// Returns (id, user_id, provider_type) of a usable challenge.
+pub async fn db_get_challenge(
+    token_hash: &String,
+    kind: &str,
+    pool: &PgPool,
+) -> Result<Option<(Uuid, String, String)>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT id, user_id, provider_type FROM mfa_challenges WHERE token_hash = $1 AND kind = $2 AND used_at IS NULL AND expires_at > $3 AND attempts < $4",
+        token_hash,
+        kind,
+        Utc::now().naive_utc(),
+        MAX_ATTEMPTS
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| (r.id, r.user_id, r.provider_type)))
+}
+
+pub async fn db_challenge_failed(id: &Uuid, pool: &PgPool) -> Result<(), sqlx::Error> {
+    let _ = sqlx::query!("UPDATE mfa_challenges SET attempts = attempts + 1 WHERE id = $1", id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn db_use_challenge(id: &Uuid, pool: &PgPool) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query!(
+        "UPDATE mfa_challenges SET used_at = $1 WHERE id = $2 AND used_at IS NULL",
+        Utc::now().naive_utc(),
+        id
+    )
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected() == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::{enable_totp, error_code, test_pool, test_user_id, totp_code};
+
+    async fn two_factor_events(user_id: &String, pool: &PgPool) -> Vec<bool> {
+        let rows: Vec<String> = sqlx::query_scalar("SELECT payload FROM event_outbox ORDER BY id")
+            .fetch_all(pool)
+            .await
+            .unwrap();
+        rows.iter()
+            .filter_map(|payload| match serde_json::from_str::<Event>(payload).unwrap() {
+                Event::TwoFactorChanged { user_id: id, enabled } if id == *user_id => Some(enabled),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn secret_is_stored_encrypted() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        let secret = enable_totp(&user_id, &pool).await;
+
+        let stored: String = sqlx::query_scalar("SELECT secret FROM mfa_totp WHERE user_id = $1")
+            .bind(&user_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(stored.starts_with(&current_prefix().unwrap()));
+        assert_eq!(db_get_totp(&user_id, true, &pool).await.unwrap().unwrap().0, secret);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn plaintext_secret_is_encrypted_on_startup() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        let secret = generate_secret();
+        sqlx::query("INSERT INTO mfa_totp (user_id, secret, confirmed_at) VALUES ($1, $2, now())")
+            .bind(&user_id)
+            .bind(&secret)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(db_get_totp(&user_id, true, &pool).await.unwrap().unwrap().0, secret);
+        db_reencrypt_totp_secrets(&pool).await.unwrap();
+        assert_eq!(db_get_totp(&user_id, true, &pool).await.unwrap().unwrap().0, secret);
+        assert!(check_second_factor(&user_id, &totp_code(&secret), &pool).await.unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn wrong_codes_lock_the_user_out() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        let secret = enable_totp(&user_id, &pool).await;
+        let client = ClientInfo::default();
+
+        for _ in 0..5 {
+            assert!(!verify_second_factor(&user_id, &"000000".to_string(), &client, &pool).await.unwrap());
+        }
+        // even the right code, through any challenge
+        let res = verify_second_factor(&user_id, &totp_code(&secret), &client, &pool).await;
+        assert_eq!(error_code(res), 429);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn enabling_and_disabling_is_published() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+
+        enable_totp(&user_id, &pool).await;
+        db_delete_totp(&user_id, &pool).await.unwrap();
+        // a pending enrolment was never enabled
+        db_set_pending_totp(&user_id, &generate_secret(), &pool).await.unwrap();
+        db_delete_totp(&user_id, &pool).await.unwrap();
+
+        assert_eq!(two_factor_events(&user_id, &pool).await, vec![true, false]);
+    }
+}
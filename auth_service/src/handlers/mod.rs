@@ -1 +1,14 @@
-pub mod auth_handler;
\ No newline at end of file
+pub mod auth_handler;
+pub mod erasure_handler;
+pub mod export_handler;
+pub mod identity_handler;
+pub mod impersonation_handler;
+pub mod lockout_handler;
+pub mod login_handler;
+pub mod mfa_handler;
+pub mod oauth_handler;
+pub mod password_handler;
+pub mod session_handler;
+pub mod share_handler;
+pub mod shopify_handler;
+pub mod token_handler;
\ No newline at end of file
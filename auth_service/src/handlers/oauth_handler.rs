@@ -0,0 +1,263 @@
+use axum::{
+    extract::{Extension, Form, TypedHeader},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use headers::authorization::{Basic, Bearer};
+use headers::Authorization;
+use sqlx::PgPool;
+use std::sync::Arc;
+
+use microservice_utils::jwt::auth::decode_token;
+use microservice_utils::jwt::client::{create_client_token, decode_client_token, ClientClaims, CLIENT_TOKEN_TTL_SECONDS};
+use microservice_utils::server::grpc::{check_api_client, verify_api_key};
+use microservice_utils::server::service_auth::{verify_service_token, AUTH_SERVICE};
+
+use crate::models::oauth::{IntrospectRequest, TokenRequest};
+
+// These endpoints follow RFC 6749 / RFC 7662: form encoded requests and plain JSON
+// responses instead of AxumRes, so they are not part of the generated OpenAPI spec.
+
+fn oauth_response(status: StatusCode, body: serde_json::Value) -> Response {
+    (
+        status,
+        [(header::CACHE_CONTROL, "no-store"), (header::PRAGMA, "no-cache")],
+        Json(body),
+    )
+        .into_response()
+}
+
+fn oauth_error(status: StatusCode, error: &str, description: &str) -> Response {
+    oauth_response(
+        status,
+        serde_json::json!({
+            "error": error,
+            "error_description": description,
+        }),
+    )
+}
+
+// Who asks for an introspection.
+#[derive(Debug, Clone, PartialEq)]
+enum Caller {
+    Service,
+    Client(String), // api key client id
+}
+
+// Services may introspect any token, api key clients only the tokens issued to them.
+fn may_introspect(caller: &Caller, claims: Option<&ClientClaims>) -> bool {
+    match (caller, claims) {
+        (Caller::Service, _) => true,
+        (Caller::Client(client_id), Some(claims)) => *client_id == claims.client_id,
+        (Caller::Client(_), None) => false,
+    }
+}
+
+fn inactive() -> Response {
+    oauth_response(StatusCode::OK, serde_json::json!({ "active": false }))
+}
+
+// API
+pub async fn oauth_token(
+    basic: Option<TypedHeader<Authorization<Basic>>>,
+    Form(req): Form<TokenRequest>,
+) -> Response {
+    if req.grant_type != "client_credentials" {
+        return oauth_error(
+            StatusCode::BAD_REQUEST,
+            "unsupported_grant_type",
+            "Only client_credentials is supported",
+        );
+    }
+
+    let (client_id, client_secret) = match (basic, req.client_id, req.client_secret) {
+        (Some(TypedHeader(Authorization(basic))), _, _) => {
+            (basic.username().to_string(), basic.password().to_string())
+        }
+        (None, Some(client_id), Some(client_secret)) => (client_id, client_secret),
+        _ => {
+            return oauth_error(
+                StatusCode::UNAUTHORIZED,
+                "invalid_client",
+                "Client credentials are missing",
+            )
+        }
+    };
+
+    let (user_id, allowed) = match verify_api_key(&client_id, &client_secret).await {
+        Ok(key) => key,
+        Err(e) => {
+            println!("{:?}", e);
+            return oauth_error(
+                StatusCode::UNAUTHORIZED,
+                "invalid_client",
+                "Client authentication failed",
+            );
+        }
+    };
+
+    // Without a scope parameter the token gets every scope of the key.
+    let scopes: Vec<String> = match &req.scope {
+        Some(scope) => scope.split_whitespace().map(|s| s.to_string()).collect(),
+        None => allowed.clone(),
+    };
+    if let Some(scope) = scopes.iter().find(|s| !allowed.contains(s)) {
+        return oauth_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_scope",
+            &format!("Scope {} is not granted to this client", scope),
+        );
+    }
+
+    let (access_token, claims) = create_client_token(&user_id, &client_id, &scopes);
+    oauth_response(
+        StatusCode::OK,
+        serde_json::json!({
+            "access_token": access_token,
+            "token_type": "Bearer",
+            "expires_in": CLIENT_TOKEN_TTL_SECONDS,
+            "scope": claims.scope,
+        }),
+    )
+}
+
+/// Token introspection for other services. Callers authenticate with a service token
+/// (audience `auth_service`) or with api key credentials as HTTP Basic, api key clients
+/// only see the tokens issued to them.
+pub async fn oauth_introspect(
+    basic: Option<TypedHeader<Authorization<Basic>>>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(pool): Extension<Arc<PgPool>>,
+    Form(req): Form<IntrospectRequest>,
+) -> Response {
+    let caller = match (basic, bearer) {
+        (Some(TypedHeader(Authorization(basic))), _) => {
+            let client_id = basic.username().to_string();
+            verify_api_key(&client_id, &basic.password().to_string())
+                .await
+                .ok()
+                .map(|_| Caller::Client(client_id))
+        }
+        (None, Some(TypedHeader(Authorization(bearer)))) => {
+            verify_service_token(bearer.token(), AUTH_SERVICE).ok().map(|_| Caller::Service)
+        }
+        _ => None,
+    };
+    let caller = match caller {
+        Some(caller) => caller,
+        None => {
+            return oauth_error(
+                StatusCode::UNAUTHORIZED,
+                "invalid_client",
+                "Caller authentication failed",
+            )
+        }
+    };
+
+    // Tokens the caller may not see are reported inactive, as if unknown.
+    if let Ok(claims) = decode_client_token(&req.token) {
+        if !may_introspect(&caller, Some(&claims)) {
+            return inactive();
+        }
+        // Deleting the api key revokes its tokens.
+        match check_api_client(&claims.client_id).await {
+            Ok(true) => {}
+            Ok(false) => return inactive(),
+            Err(e) => {
+                println!("{:?}", e);
+                return oauth_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "server_error",
+                    "Unable to check the token",
+                );
+            }
+        }
+        return oauth_response(
+            StatusCode::OK,
+            serde_json::json!({
+                "active": true,
+                "token_type": "Bearer",
+                "client_id": claims.client_id,
+                "sub": claims.sub,
+                "scope": claims.scope,
+                "iat": claims.iat,
+                "exp": claims.exp,
+            }),
+        );
+    }
+    if !may_introspect(&caller, None) {
+        return inactive();
+    }
+
+    // User access tokens are only active while their session is.
+    if let Ok(claims) = decode_token(&req.token) {
+        match db_session_active(&claims.sub, &req.token, &pool).await {
+            Ok(true) => {
+                let mut body = serde_json::json!({
+                    "active": true,
+                    "token_type": "Bearer",
+                    "sub": claims.sub,
+                    "exp": claims.exp,
+                });
+                // Impersonation tokens name the acting admin (RFC 8693 `act`).
+                if let Some(admin_id) = &claims.act {
+                    body["act"] = serde_json::json!({ "sub": admin_id });
+                    body["scope"] = serde_json::json!(claims.scope);
+                }
+                return oauth_response(StatusCode::OK, body);
+            }
+            Ok(false) => {}
+            Err(e) => {
+                println!("{:?}", e.to_string());
+                return oauth_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "server_error",
+                    "Unable to check the token",
+                );
+            }
+        }
+    }
+
+    inactive()
+}
+
+// Database
+pub async fn db_session_active(
+    user_id: &String,
+    access_token: &String,
+    pool: &PgPool,
+) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT id FROM sessions WHERE user_id = $1 AND access_token = $2 AND revoked_at IS NULL",
+        user_id,
+        access_token
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(client_id: &str) -> ClientClaims {
+        create_client_token(&"user".to_string(), &client_id.to_string(), &["contacts:read".to_string()]).1
+    }
+
+    #[test]
+    fn services_may_introspect_every_token() {
+        assert!(may_introspect(&Caller::Service, Some(&claims("a"))));
+        assert!(may_introspect(&Caller::Service, None));
+    }
+
+    #[test]
+    fn clients_may_only_introspect_their_own_tokens() {
+        let caller = Caller::Client("a".to_string());
+        assert!(may_introspect(&caller, Some(&claims("a"))));
+        assert!(!may_introspect(&caller, Some(&claims("b"))));
+        // user access tokens
+        assert!(!may_introspect(&caller, None));
+    }
+}
@@ -0,0 +1,705 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use axum::{
+    extract::{rejection::JsonRejection, Extension},
+    Json,
+};
+use axum_macros::debug_handler;
+use chrono::Duration;
+use openapi_rs::openapi_proc_macro::handler;
+use sqlx::types::chrono::Utc;
+use sqlx::PgPool;
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+use uuid::Uuid;
+
+use okapi::openapi3::RefOr;
+use openapi_rs::gen::OpenApiGenerator;
+use openapi_rs::OpenApiFromData;
+
+use microservice_utils::server::client_info::ClientInfo;
+use microservice_utils::{
+    jwt::auth::hash_token,
+    jwt::extractor::AuthSession,
+    server::response::{into_reponse, AxumRes, AxumResult},
+};
+
+use crate::handlers::auth_handler::{bad_request, internal_error, login};
+use crate::handlers::identity_handler::{db_account_identity, resolve_account};
+use crate::handlers::session_handler::{db_revoke_all_sessions, db_revoke_other_sessions};
+use crate::models::password::{
+    EmailVerification, PasswordChange, PasswordLogin, PasswordPolicy, PasswordReset,
+    PasswordResetRequest,
+};
+use crate::providers::random_token;
+use microservice_utils::server::sender::{Channel, Message, MessageSender};
+use crate::throttle::{check_attempts, check_send, password_keys, record_failure, record_success, release_attempts};
+
+const PROVIDER_TYPE: &str = "Password";
+const RESET_EXPIRATION_MINUTES: i64 = 30;
+const VERIFY_EXPIRATION_MINUTES: i64 = 24 * 60;
+const RESET_KIND: &str = "reset";
+const VERIFY_KIND: &str = "verify";
+
+lazy_static! {
+    static ref PASSWORD_POLICY: PasswordPolicy = PasswordPolicy::from_env();
+    static ref PASSWORD_RESET_URL: String = std::env::var("PASSWORD_RESET_URL")
+        .unwrap_or_else(|_| "http://localhost:3000/reset-password".to_owned());
+    static ref EMAIL_VERIFY_URL: String = std::env::var("EMAIL_VERIFY_URL")
+        .unwrap_or_else(|_| "http://localhost:3000/verify-email".to_owned());
+}
+
+// Checked against unknown emails, so they take as long as a wrong password.
+static DUMMY_HASH: OnceCell<String> = OnceCell::const_new();
+
+fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+fn check_policy(password: &str) -> AxumResult<()> {
+    let errors = PASSWORD_POLICY.check(password);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(into_reponse(400, serde_json::json!({ "error": errors })))
+    }
+}
+
+// Argon2id with the crate defaults, run off the async runtime since it is deliberately slow.
+async fn hash_password(password: String) -> anyhow::Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut rand::rngs::OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| anyhow::anyhow!("Unable to hash password: {}", e))
+    })
+    .await?
+}
+
+async fn verify_password(password: String, password_hash: String) -> anyhow::Result<bool> {
+    tokio::task::spawn_blocking(move || {
+        let parsed = PasswordHash::new(&password_hash)
+            .map_err(|e| anyhow::anyhow!("Invalid password hash: {}", e))?;
+        Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok())
+    })
+    .await?
+}
+
+// Sends a new verification link, the earlier ones stop working.
+async fn send_verification(
+    user_id: &String,
+    email: &String,
+    sender: &Arc<dyn MessageSender>,
+    pool: &PgPool,
+) -> AxumResult<()> {
+    let token = random_token();
+    db_create_email_verification(user_id, &hash_token(&token), pool)
+        .await
+        .map_err(internal_error)?;
+
+    let message = Message {
+        channel: Channel::Email,
+        to: email.clone(),
+        subject: "Verify your email".to_string(),
+        body: format!("{}?token={}", EMAIL_VERIFY_URL.to_string(), token),
+    };
+    sender.send(&message).await.map_err(internal_error)?;
+    Ok(())
+}
+
+// Tells the owner of a verified email that someone tried to register it, instead of telling the
+// caller the email is registered.
+async fn send_already_registered(email: &String, sender: &Arc<dyn MessageSender>) -> AxumResult<()> {
+    let message = Message {
+        channel: Channel::Email,
+        to: email.clone(),
+        subject: "You already have an account".to_string(),
+        body: format!(
+            "Someone tried to register with this email. If it was you, log in with your password or reset it at {}",
+            PASSWORD_RESET_URL.to_string()
+        ),
+    };
+    sender.send(&message).await.map_err(internal_error)?;
+    Ok(())
+}
+
+// The user and whether their email is verified when the password is theirs. Unknown emails go
+// through a password check as well, so the response time doesn't tell whether one is registered.
+async fn check_password(email: &String, password: String, pool: &PgPool) -> AxumResult<Option<(String, bool)>> {
+    let credentials = db_get_credentials_by_email(email, pool)
+        .await
+        .map_err(internal_error)?;
+    let (user_id, password_hash, verified) = match credentials {
+        Some((user_id, password_hash, verified)) => (Some(user_id), password_hash, verified),
+        None => {
+            let dummy = DUMMY_HASH
+                .get_or_try_init(|| hash_password(random_token()))
+                .await
+                .map_err(internal_error)?;
+            (None, dummy.clone(), false)
+        }
+    };
+    let password_ok = verify_password(password, password_hash)
+        .await
+        .map_err(internal_error)?;
+    Ok(user_id.filter(|_| password_ok).map(|user_id| (user_id, verified)))
+}
+
+// API
+/// Registers the password and emails a verification link, logging in is possible once verified.
+/// Registering again an email that wasn't verified replaces its password, so nobody can hold
+/// an address they don't own. A verified email gets a notice instead, the response is the same.
+#[debug_handler]
+#[handler(method = "POST", tag = "password")]
+pub async fn password_register(
+    payload: Result<Json<PasswordLogin>, JsonRejection>,
+    Extension(pool): Extension<Arc<PgPool>>,
+    Extension(sender): Extension<Arc<dyn MessageSender>>,
+    client: ClientInfo,
+) -> AxumResult<Json<AxumRes>> {
+    let req = payload.map_err(bad_request)?.0;
+    let email = normalize_email(&req.email);
+    check_policy(&req.password)?;
+    check_send(&email, &client, &pool).await?;
+
+    let password_hash = hash_password(req.password).await.map_err(internal_error)?;
+    let user_id = Uuid::new_v4().to_string();
+
+    match db_create_credentials(&user_id, &email, &password_hash, &pool)
+        .await
+        .map_err(internal_error)?
+    {
+        Some(user_id) => send_verification(&user_id, &email, &sender, &pool).await?,
+        None => send_already_registered(&email, &sender).await?,
+    }
+
+    Ok(axum::Json(AxumRes {
+        code: 200,
+        result: serde_json::json!({ "status": "verification_sent" }),
+    }))
+}
+
+/// Verifies the email of a registration with the emailed token and logs in.
+#[debug_handler]
+#[handler(method = "POST", tag = "password")]
+pub async fn password_verify_email(
+    payload: Result<Json<EmailVerification>, JsonRejection>,
+    Extension(pool): Extension<Arc<PgPool>>,
+    client: ClientInfo,
+) -> AxumResult<Json<AxumRes>> {
+    let req = payload.map_err(bad_request)?.0;
+
+    let user_id = match db_use_email_verification(&hash_token(&req.token), &pool)
+        .await
+        .map_err(internal_error)?
+    {
+        Some(user_id) => user_id,
+        None => {
+            return Err(into_reponse(
+                401,
+                serde_json::json!({ "error": "Verification token is invalid or expired" }),
+            ))
+        }
+    };
+    login(&user_id, &PROVIDER_TYPE.to_string(), &client, &pool).await
+}
+
+#[debug_handler]
+#[handler(method = "POST", tag = "password")]
+pub async fn password_login(
+    payload: Result<Json<PasswordLogin>, JsonRejection>,
+    Extension(pool): Extension<Arc<PgPool>>,
+    Extension(sender): Extension<Arc<dyn MessageSender>>,
+    client: ClientInfo,
+) -> AxumResult<Json<AxumRes>> {
+    let req = payload.map_err(bad_request)?.0;
+    let email = normalize_email(&req.email);
+
+    // Guesses are limited per email and per ip, like codes
+    let keys = password_keys(&email, &client);
+    check_attempts(&keys, req.captcha_token.as_ref(), &client, &pool).await?;
+    let (user_id, verified) = match check_password(&email, req.password, &pool).await {
+        Ok(Some(found)) => found,
+        Ok(None) => {
+            record_failure(&keys, &client, &pool).await?;
+            return Err(into_reponse(
+                401,
+                serde_json::json!({ "error": "Invalid email or password" }),
+            ));
+        }
+        Err(e) => {
+            release_attempts(&keys, &pool).await?;
+            return Err(e);
+        }
+    };
+    record_success(&keys, &pool).await?;
+
+    if verified {
+        login(&user_id, &PROVIDER_TYPE.to_string(), &client, &pool).await
+    } else {
+        // Registered before verification was required, or the link expired.
+        if check_send(&email, &client, &pool).await.is_ok() {
+            send_verification(&user_id, &email, &sender, &pool).await?;
+        }
+        Err(into_reponse(
+            403,
+            serde_json::json!({
+                "error": "Verify your email first, a new link was sent",
+                "verification_required": true,
+            }),
+        ))
+    }
+}
+
+#[debug_handler]
+#[handler(method = "POST", tag = "password")]
+pub async fn password_reset_request(
+    payload: Result<Json<PasswordResetRequest>, JsonRejection>,
+    Extension(pool): Extension<Arc<PgPool>>,
+    Extension(sender): Extension<Arc<dyn MessageSender>>,
+    client: ClientInfo,
+) -> AxumResult<Json<AxumRes>> {
+    let req = payload.map_err(bad_request)?.0;
+    let email = normalize_email(&req.email);
+    check_send(&email, &client, &pool).await?;
+
+    // Answer the same way whether or not the email is registered.
+    let credentials = db_get_credentials_by_email(&email, &pool)
+        .await
+        .map_err(internal_error)?;
+    if let Some((user_id, _, _)) = credentials {
+        let token = random_token();
+        db_create_password_reset(&user_id, &hash_token(&token), &pool)
+            .await
+            .map_err(internal_error)?;
+
+        let message = Message {
+            channel: Channel::Email,
+            to: email,
+            subject: "Reset your password".to_string(),
+            body: format!("{}?token={}", PASSWORD_RESET_URL.to_string(), token),
+        };
+        sender.send(&message).await.map_err(internal_error)?;
+    }
+
+    Ok(axum::Json(AxumRes {
+        code: 200,
+        result: serde_json::json!({ "status": "success" }),
+    }))
+}
+
+#[debug_handler]
+#[handler(method = "POST", tag = "password")]
+pub async fn password_reset(
+    payload: Result<Json<PasswordReset>, JsonRejection>,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    let req = payload.map_err(bad_request)?.0;
+    check_policy(&req.password)?;
+
+    let user_id = match db_use_password_reset(&hash_token(&req.token), &pool)
+        .await
+        .map_err(internal_error)?
+    {
+        Some(user_id) => user_id,
+        None => {
+            return Err(into_reponse(
+                401,
+                serde_json::json!({ "error": "Reset token is invalid or expired" }),
+            ))
+        }
+    };
+
+    let password_hash = hash_password(req.password).await.map_err(internal_error)?;
+    db_update_password(&user_id, &password_hash, &pool)
+        .await
+        .map_err(internal_error)?;
+    // The reset link was received at the email, which is verified with it.
+    db_set_email_verified(&user_id, &pool)
+        .await
+        .map_err(internal_error)?;
+    // Whoever knew the old password should not stay logged in.
+    let account_id = resolve_account(&user_id, &PROVIDER_TYPE.to_string(), &pool)
+        .await
+        .map_err(internal_error)?;
+    db_revoke_all_sessions(&account_id, &pool)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(axum::Json(AxumRes {
+        code: 200,
+        result: serde_json::json!({ "status": "success" }),
+    }))
+}
+
+#[debug_handler]
+#[handler(method = "POST", tag = "password")]
+pub async fn password_change(
+    payload: Result<Json<PasswordChange>, JsonRejection>,
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    let req = payload.map_err(bad_request)?.0;
+
+    // Credentials belong to the password identity linked to the account.
+    let password_user_id = db_account_identity(&session.user_id, &PROVIDER_TYPE.to_string(), &pool)
+        .await
+        .map_err(internal_error)?
+        .unwrap_or_else(|| session.user_id.clone());
+    let password_hash = match db_get_credentials_by_user(&password_user_id, &pool)
+        .await
+        .map_err(internal_error)?
+    {
+        Some(password_hash) => password_hash,
+        None => {
+            return Err(into_reponse(
+                400,
+                serde_json::json!({ "error": "No password is set for this account" }),
+            ))
+        }
+    };
+
+    if !verify_password(req.current_password, password_hash)
+        .await
+        .map_err(internal_error)?
+    {
+        return Err(into_reponse(
+            401,
+            serde_json::json!({ "error": "Current password is incorrect" }),
+        ));
+    }
+    check_policy(&req.new_password)?;
+
+    let new_hash = hash_password(req.new_password).await.map_err(internal_error)?;
+    db_update_password(&password_user_id, &new_hash, &pool)
+        .await
+        .map_err(internal_error)?;
+    db_revoke_other_sessions(&session.user_id, &session.access_token, &pool)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(axum::Json(AxumRes {
+        code: 200,
+        result: serde_json::json!({ "status": "success" }),
+    }))
+}
+
+// Database
+// Returns the user the credentials belong to, None when the email is already registered
+// and verified. An unverified registration gets the new password.
+pub async fn db_create_credentials(
+    user_id: &String,
+    email: &String,
+    password_hash: &String,
+    pool: &PgPool,
+) -> Result<Option<String>, sqlx::Error> {
+    let row = sqlx::query!(
+        "INSERT INTO password_credentials (user_id, email, password_hash) VALUES ($1, $2, $3)
+        ON CONFLICT (email) DO UPDATE SET password_hash = EXCLUDED.password_hash, updated_at = $4
+        WHERE password_credentials.verified_at IS NULL
+        RETURNING user_id",
+        user_id,
+        email,
+        password_hash,
+        Utc::now().naive_utc()
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| r.user_id))
+}
+
+// user id, password hash and whether the email is verified
+pub async fn db_get_credentials_by_email(
+    email: &String,
+    pool: &PgPool,
+) -> Result<Option<(String, String, bool)>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT user_id, password_hash, verified_at IS NOT NULL AS "verified!" FROM password_credentials WHERE email = $1"#,
+        email
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| (r.user_id, r.password_hash, r.verified)))
+}
+
+pub async fn db_set_email_verified(user_id: &String, pool: &PgPool) -> Result<(), sqlx::Error> {
+    let _ = sqlx::query!(
+        "UPDATE password_credentials SET verified_at = $1 WHERE user_id = $2 AND verified_at IS NULL",
+        Utc::now().naive_utc(),
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn db_get_credentials_by_user(
+    user_id: &String,
+    pool: &PgPool,
+) -> Result<Option<String>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT password_hash FROM password_credentials WHERE user_id = $1",
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| r.password_hash))
+}
+
+pub async fn db_update_password(
+    user_id: &String,
+    password_hash: &String,
+    pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let _ = sqlx::query!(
+        "UPDATE password_credentials SET password_hash = $1, updated_at = $2 WHERE user_id = $3",
+        password_hash,
+        Utc::now().naive_utc(),
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn db_create_password_reset(
+    user_id: &String,
+    token_hash: &String,
+    pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let _ = sqlx::query!(
+        "INSERT INTO password_resets (user_id, token_hash, expires_at, kind) VALUES ($1, $2, $3, $4)",
+        user_id,
+        token_hash,
+        (Utc::now() + Duration::minutes(RESET_EXPIRATION_MINUTES)).naive_utc(),
+        RESET_KIND
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// Consumes a reset token, returning its user when it was valid.
+pub async fn db_use_password_reset(
+    token_hash: &String,
+    pool: &PgPool,
+) -> Result<Option<String>, sqlx::Error> {
+    let now = Utc::now().naive_utc();
+    let row = sqlx::query!(
+        "UPDATE password_resets SET used_at = $1 WHERE token_hash = $2 AND kind = $3 AND used_at IS NULL AND expires_at > $1 RETURNING user_id",
+        now,
+        token_hash,
+        RESET_KIND
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| r.user_id))
+}
+
+// Replaces the pending verification tokens of the user.
+pub async fn db_create_email_verification(
+    user_id: &String,
+    token_hash: &String,
+    pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "DELETE FROM password_resets WHERE user_id = $1 AND kind = $2 AND used_at IS NULL",
+        user_id,
+        VERIFY_KIND
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO password_resets (user_id, token_hash, expires_at, kind) VALUES ($1, $2, $3, $4)",
+        user_id,
+        token_hash,
+        (Utc::now() + Duration::minutes(VERIFY_EXPIRATION_MINUTES)).naive_utc(),
+        VERIFY_KIND
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+// Consumes a verification token and marks the email verified, returning its user when valid.
+pub async fn db_use_email_verification(
+    token_hash: &String,
+    pool: &PgPool,
+) -> Result<Option<String>, sqlx::Error> {
+    let now = Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query!(
+        "UPDATE password_resets SET used_at = $1 WHERE token_hash = $2 AND kind = $3 AND used_at IS NULL AND expires_at > $1 RETURNING user_id",
+        now,
+        token_hash,
+        VERIFY_KIND
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    let user_id = match row {
+        Some(row) => row.user_id,
+        None => return Ok(None),
+    };
+    sqlx::query!(
+        "UPDATE password_credentials SET verified_at = $1 WHERE user_id = $2 AND verified_at IS NULL",
+        now,
+        user_id
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(Some(user_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::{error_code, test_pool, test_user_id, RecordingSender};
+
+    const PASSWORD: &str = "correct horse battery 9";
+
+    struct Setup {
+        pool: Arc<PgPool>,
+        recorder: Arc<RecordingSender>,
+        sender: Arc<dyn MessageSender>,
+        email: String,
+    }
+
+    async fn setup() -> Setup {
+        let recorder = Arc::new(RecordingSender::default());
+        Setup {
+            pool: Arc::new(test_pool().await),
+            sender: recorder.clone(),
+            recorder,
+            email: format!("{}@example.com", test_user_id()),
+        }
+    }
+
+    fn credentials(email: &str, password: &str) -> Result<Json<PasswordLogin>, JsonRejection> {
+        Ok(Json(PasswordLogin {
+            email: email.to_string(),
+            password: password.to_string(),
+            captcha_token: None,
+        }))
+    }
+
+    async fn register(s: &Setup, password: &str) -> AxumResult<Json<AxumRes>> {
+        // registrations of the same email are throttled like codes
+        sqlx::query("DELETE FROM send_throttle WHERE key = $1")
+            .bind(format!("to:{}", s.email))
+            .execute(&*s.pool)
+            .await
+            .unwrap();
+        password_register(
+            credentials(&s.email, password),
+            Extension(s.pool.clone()),
+            Extension(s.sender.clone()),
+            ClientInfo::default(),
+        )
+        .await
+    }
+
+    async fn log_in(s: &Setup, password: &str) -> AxumResult<Json<AxumRes>> {
+        password_login(
+            credentials(&s.email, password),
+            Extension(s.pool.clone()),
+            Extension(s.sender.clone()),
+            ClientInfo::default(),
+        )
+        .await
+    }
+
+    async fn verify(s: &Setup, token: String) -> AxumResult<Json<AxumRes>> {
+        password_verify_email(
+            Ok(Json(EmailVerification { token })),
+            Extension(s.pool.clone()),
+            ClientInfo::default(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn registration_logs_in_only_once_the_email_is_verified() {
+        let s = setup().await;
+
+        let res = register(&s, PASSWORD).await.unwrap();
+        assert_eq!(res.0.result["status"], "verification_sent");
+        assert!(res.0.result.get("access_token").is_none());
+        assert_eq!(error_code(log_in(&s, PASSWORD).await), 403);
+
+        let token = s.recorder.last_token(&s.email).unwrap();
+        assert_eq!(error_code(verify(&s, token.clone()).await), 200);
+        // a verification link works once
+        assert_eq!(error_code(verify(&s, token).await), 401);
+        assert_eq!(error_code(log_in(&s, PASSWORD).await), 200);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn unverified_registration_can_be_taken_over_by_the_owner() {
+        let s = setup().await;
+
+        register(&s, "squatter password 1").await.unwrap();
+        let squatter_token = s.recorder.last_token(&s.email).unwrap();
+        register(&s, PASSWORD).await.unwrap();
+
+        assert_eq!(error_code(verify(&s, squatter_token).await), 401);
+        verify(&s, s.recorder.last_token(&s.email).unwrap()).await.unwrap();
+        assert_eq!(error_code(log_in(&s, "squatter password 1").await), 401);
+        assert_eq!(error_code(log_in(&s, PASSWORD).await), 200);
+
+        // verified emails stay with their owner, who is told about the attempt
+        let res = register(&s, "squatter password 1").await.unwrap();
+        assert_eq!(res.0.result["status"], "verification_sent");
+        assert_eq!(s.recorder.last_token(&s.email), None);
+        assert_eq!(error_code(log_in(&s, PASSWORD).await), 200);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn wrong_passwords_require_a_captcha() {
+        let s = setup().await;
+        register(&s, PASSWORD).await.unwrap();
+        verify(&s, s.recorder.last_token(&s.email).unwrap()).await.unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(error_code(log_in(&s, "wrong password 1").await), 401);
+        }
+        // the right password doesn't get around it, captchas fail in tests
+        let res = log_in(&s, PASSWORD).await;
+        assert_eq!(error_code(res), 400);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn reset_requests_are_throttled() {
+        let s = setup().await;
+        let request = || {
+            password_reset_request(
+                Ok(Json(PasswordResetRequest { email: s.email.clone() })),
+                Extension(s.pool.clone()),
+                Extension(s.sender.clone()),
+                ClientInfo::default(),
+            )
+        };
+
+        assert_eq!(error_code(request().await), 200);
+        assert_eq!(error_code(request().await), 429);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn unknown_email_is_checked_against_a_dummy_hash() {
+        let s = setup().await;
+
+        assert_eq!(error_code(log_in(&s, PASSWORD).await), 401);
+        let dummy = DUMMY_HASH.get().unwrap();
+        assert!(PasswordHash::new(dummy).is_ok());
+    }
+}
@@ -0,0 +1,179 @@
+use axum::{
+    extract::{rejection::JsonRejection, Extension},
+    Json,
+};
+use axum_macros::debug_handler;
+use openapi_rs::openapi_proc_macro::handler;
+use sqlx::types::chrono::Utc;
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use okapi::openapi3::RefOr;
+use openapi_rs::gen::OpenApiGenerator;
+use openapi_rs::OpenApiFromData;
+
+use microservice_utils::{
+    jwt::extractor::AuthSession,
+    server::response::{into_reponse, AxumRes, AxumResult},
+};
+
+use crate::models::session::{Session, SessionId};
+
+// API
+#[debug_handler]
+#[handler(method = "GET", tag = "session")]
+pub async fn list_sessions(
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    match db_list_sessions(&session.user_id, &session.access_token, &pool).await {
+        Ok(result) => Ok(axum::Json(AxumRes {
+            code: 200,
+            result: serde_json::json!(&result),
+        })),
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(500, ret))
+        }
+    }
+}
+
+#[debug_handler]
+#[handler(method = "DELETE", tag = "session")]
+pub async fn revoke_session(
+    payload: Result<Json<SessionId>, JsonRejection>,
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    match payload {
+        Ok(payload) => {
+            let res = db_revoke_session(&session.user_id, &payload.0.id, &pool).await;
+            match res {
+                Ok(0) => {
+                    let ret = serde_json::json!({
+                        "error": "Session not found",
+                    });
+                    Err(into_reponse(404, ret))
+                }
+                Ok(_) => {
+                    let ret = serde_json::json!({
+                        "status": "success",
+                    });
+                    Ok(axum::Json(AxumRes {
+                        code: 200,
+                        result: ret,
+                    }))
+                }
+                Err(e) => {
+                    println!("{:?}", e.to_string());
+                    let ret = serde_json::json!({
+                        "error": format!("{:?}", e),
+                    });
+                    Err(into_reponse(500, ret))
+                }
+            }
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(400, ret))
+        }
+    }
+}
+
+#[debug_handler]
+#[handler(method = "DELETE", tag = "session")]
+pub async fn revoke_other_sessions(
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    match db_revoke_other_sessions(&session.user_id, &session.access_token, &pool).await {
+        Ok(revoked) => {
+            let ret = serde_json::json!({
+                "status": "success",
+                "revoked": revoked,
+            });
+            Ok(axum::Json(AxumRes {
+                code: 200,
+                result: ret,
+            }))
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(500, ret))
+        }
+    }
+}
+
+// Database
+pub async fn db_list_sessions(
+    user_id: &String,
+    access_token: &String,
+    pool: &PgPool,
+) -> Result<Vec<Session>, sqlx::Error> {
+    let rows = sqlx::query_as!(
+        Session,
+        r#"SELECT id, provider_type, device, user_agent, ip, created_at, last_seen_at, access_token = $2 AS "current!"
+        FROM sessions WHERE user_id = $1 AND revoked_at IS NULL ORDER BY last_seen_at DESC"#,
+        user_id,
+        access_token
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+pub async fn db_revoke_session(
+    user_id: &String,
+    session_id: &Uuid,
+    pool: &PgPool,
+) -> Result<u64, sqlx::Error> {
+    let res = sqlx::query!(
+        "UPDATE sessions SET revoked_at = $1 WHERE id = $2 AND user_id = $3 AND revoked_at IS NULL",
+        Utc::now().naive_utc(),
+        session_id,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected())
+}
+
+pub async fn db_revoke_other_sessions(
+    user_id: &String,
+    access_token: &String,
+    pool: &PgPool,
+) -> Result<u64, sqlx::Error> {
+    let res = sqlx::query!(
+        "UPDATE sessions SET revoked_at = $1 WHERE user_id = $2 AND access_token <> $3 AND revoked_at IS NULL",
+        Utc::now().naive_utc(),
+        user_id,
+        access_token
+    )
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected())
+}
+
+pub async fn db_revoke_all_sessions(
+    user_id: &String,
+    pool: &PgPool,
+) -> Result<u64, sqlx::Error> {
+    let res = sqlx::query!(
+        "UPDATE sessions SET revoked_at = $1 WHERE user_id = $2 AND revoked_at IS NULL",
+        Utc::now().naive_utc(),
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected())
+}
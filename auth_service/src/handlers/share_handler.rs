@@ -0,0 +1,266 @@
+use axum::{
+    extract::{rejection::JsonRejection, Extension},
+    Json,
+};
+use axum_macros::debug_handler;
+use chrono::Duration;
+use openapi_rs::openapi_proc_macro::handler;
+use sqlx::types::chrono::{NaiveDateTime, Utc};
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use okapi::openapi3::RefOr;
+use openapi_rs::gen::OpenApiGenerator;
+use openapi_rs::OpenApiFromData;
+
+use microservice_utils::{
+    jwt::extractor::AuthSession,
+    jwt::share::{create_share_token, is_share_scope, ShareClaims},
+    server::grpc::check_resource_owner,
+    server::response::{into_reponse, AxumRes, AxumResult},
+};
+
+use crate::handlers::auth_handler::{bad_request, internal_error};
+use crate::models::share::{CreateShareToken, ShareTokenId, ShareTokenInfo};
+
+lazy_static! {
+    static ref DEFAULT_TTL_MINUTES: i64 = std::env::var("SHARE_TOKEN_TTL_MINUTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(60 * 24);
+    static ref MAX_TTL_MINUTES: i64 = std::env::var("SHARE_TOKEN_MAX_TTL_MINUTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(60 * 24 * 30);
+}
+
+// API
+#[debug_handler]
+#[handler(method = "POST", tag = "share")]
+pub async fn create_share(
+    payload: Result<Json<CreateShareToken>, JsonRejection>,
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    let req = payload.map_err(bad_request)?.0;
+    if !is_share_scope(&req.resource_type, &req.action) {
+        return Err(into_reponse(
+            400,
+            serde_json::json!({ "error": format!("{} can't be shared for {}", req.resource_type, req.action) }),
+        ));
+    }
+    if req.max_uses.map_or(false, |max| max < 1) {
+        return Err(into_reponse(
+            400,
+            serde_json::json!({ "error": "max_uses must be at least 1" }),
+        ));
+    }
+    // A token would reach whatever its creator can, only owners get to hand that out.
+    if !check_resource_owner(&session.user_id, &req.resource_type, &req.resource_id)
+        .await
+        .map_err(internal_error)?
+    {
+        return Err(into_reponse(
+            404,
+            serde_json::json!({ "error": "Resource not found" }),
+        ));
+    }
+
+    let ttl = Duration::minutes(
+        req.ttl_minutes
+            .unwrap_or(*DEFAULT_TTL_MINUTES)
+            .clamp(1, *MAX_TTL_MINUTES),
+    );
+    let expires_at = Utc::now().naive_utc() + ttl;
+    let id = db_create_share_token(&session.user_id, &req, expires_at, &pool)
+        .await
+        .map_err(internal_error)?;
+    let token = create_share_token(
+        &id.to_string(),
+        &session.user_id,
+        &req.resource_type,
+        &req.resource_id,
+        &req.action,
+        ttl,
+    );
+
+    Ok(axum::Json(AxumRes {
+        code: 200,
+        result: serde_json::json!({
+            "id": id,
+            "token": token,
+            "expires_at": expires_at,
+            "max_uses": req.max_uses,
+        }),
+    }))
+}
+
+#[debug_handler]
+#[handler(method = "GET", tag = "share")]
+pub async fn list_shares(
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    let tokens = db_list_share_tokens(&session.user_id, &pool)
+        .await
+        .map_err(internal_error)?;
+    Ok(axum::Json(AxumRes {
+        code: 200,
+        result: serde_json::json!(&tokens),
+    }))
+}
+
+#[debug_handler]
+#[handler(method = "DELETE", tag = "share")]
+pub async fn revoke_share(
+    payload: Result<Json<ShareTokenId>, JsonRejection>,
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    let req = payload.map_err(bad_request)?.0;
+
+    match db_revoke_share_token(&session.user_id, &req.id, &pool)
+        .await
+        .map_err(internal_error)?
+    {
+        0 => Err(into_reponse(
+            404,
+            serde_json::json!({ "error": "Share token not found" }),
+        )),
+        _ => Ok(axum::Json(AxumRes {
+            code: 200,
+            result: serde_json::json!({ "status": "success" }),
+        })),
+    }
+}
+
+// Database
+pub async fn db_create_share_token(
+    user_id: &String,
+    req: &CreateShareToken,
+    expires_at: NaiveDateTime,
+    pool: &PgPool,
+) -> Result<Uuid, sqlx::Error> {
+    let row = sqlx::query!(
+        "INSERT INTO share_tokens (user_id, resource_type, resource_id, action, max_uses, expires_at) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+        user_id,
+        req.resource_type,
+        req.resource_id,
+        req.action,
+        req.max_uses,
+        expires_at
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(row.id)
+}
+
+pub async fn db_list_share_tokens(
+    user_id: &String,
+    pool: &PgPool,
+) -> Result<Vec<ShareTokenInfo>, sqlx::Error> {
+    let rows = sqlx::query_as!(
+        ShareTokenInfo,
+        "SELECT id, resource_type, resource_id, action, max_uses, uses, expires_at, revoked_at, last_used_at, created_at
+        FROM share_tokens WHERE user_id = $1 ORDER BY created_at DESC",
+        user_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+pub async fn db_revoke_share_token(
+    user_id: &String,
+    id: &Uuid,
+    pool: &PgPool,
+) -> Result<u64, sqlx::Error> {
+    let res = sqlx::query!(
+        "UPDATE share_tokens SET revoked_at = $1 WHERE id = $2 AND user_id = $3 AND revoked_at IS NULL",
+        Utc::now().naive_utc(),
+        id,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected())
+}
+
+// Counts a use of a live token matching the claims, None when it's revoked, expired or used up.
+pub async fn db_use_share_token(
+    claims: &ShareClaims,
+    pool: &PgPool,
+) -> Result<Option<String>, sqlx::Error> {
+    let id: Uuid = match claims.jti.parse() {
+        Ok(id) => id,
+        Err(_) => return Ok(None),
+    };
+    let now = Utc::now().naive_utc();
+    let row = sqlx::query!(
+        "UPDATE share_tokens SET uses = uses + 1, last_used_at = $1
+        WHERE id = $2 AND user_id = $3 AND resource_type = $4 AND resource_id = $5 AND action = $6
+            AND revoked_at IS NULL AND expires_at > $1 AND (max_uses IS NULL OR uses < max_uses)
+        RETURNING user_id",
+        now,
+        id,
+        claims.sub,
+        claims.resource_type,
+        claims.resource_id,
+        claims.action
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| r.user_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::{error_code, test_pool, test_user_id};
+    use microservice_utils::jwt::share::{READ_ACTION, VIDEO_RESOURCE};
+
+    fn session(user_id: &String) -> AuthSession {
+        AuthSession {
+            user_id: user_id.clone(),
+            access_token: String::new(),
+            impersonator: None,
+        }
+    }
+
+    fn share(resource_id: &String) -> Result<Json<CreateShareToken>, JsonRejection> {
+        Ok(Json(CreateShareToken {
+            resource_type: VIDEO_RESOURCE.to_string(),
+            resource_id: resource_id.clone(),
+            action: READ_ACTION.to_string(),
+            ttl_minutes: None,
+            max_uses: None,
+        }))
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn owners_can_share_their_resources() {
+        let pool = Arc::new(test_pool().await);
+        let user_id = test_user_id();
+        let video = format!("{}-video", user_id);
+
+        let res = create_share(share(&video), session(&user_id), Extension(pool.clone())).await;
+        assert_eq!(error_code(res), 200);
+        let tokens = db_list_share_tokens(&user_id, &pool).await.unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].resource_id, video);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn resources_of_others_cant_be_shared() {
+        let pool = Arc::new(test_pool().await);
+        let user_id = test_user_id();
+        let video = format!("{}-video", test_user_id());
+
+        let res = create_share(share(&video), session(&user_id), Extension(pool.clone())).await;
+        assert_eq!(error_code(res), 404);
+        assert!(db_list_share_tokens(&user_id, &pool).await.unwrap().is_empty());
+    }
+}
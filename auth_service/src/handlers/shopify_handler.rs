@@ -0,0 +1,646 @@
+use axum::{
+    body::Bytes,
+    extract::{rejection::JsonRejection, Extension, Query},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    Json,
+};
+use axum_macros::debug_handler;
+use chrono::Duration;
+use hmac::{Hmac, Mac};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use openapi_rs::openapi_proc_macro::handler;
+use sha2::Sha256;
+use sqlx::types::chrono::Utc;
+use sqlx::PgPool;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use okapi::openapi3::RefOr;
+use openapi_rs::gen::OpenApiGenerator;
+use openapi_rs::OpenApiFromData;
+
+use microservice_utils::crypto::{current_prefix, decrypt, encrypt, into_sqlx_error, reencrypt};
+use microservice_utils::server::client_info::ClientInfo;
+use microservice_utils::server::response::{into_reponse, AxumRes, AxumResult};
+
+use crate::handlers::auth_handler::{bad_request, internal_error, start_login};
+use crate::handlers::identity_handler::resolve_account;
+use crate::models::shopify::{AccessTokenResponse, SessionTokenClaims, ShopifySessionToken};
+use crate::providers::random_token;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const STATE_TTL_MINUTES: i64 = 10;
+const SHOPIFY_PROVIDER: &str = "Shopify";
+
+pub struct ShopifyApp {
+    pub api_key: String,
+    pub api_secret: String,
+    pub scopes: String,
+    pub redirect_uri: String, // public url of `/api/shopify/callback`
+}
+
+lazy_static! {
+    // None until SHOPIFY_API_KEY, SHOPIFY_API_SECRET and SHOPIFY_REDIRECT_URI are set.
+    static ref SHOPIFY_APP: Option<ShopifyApp> = (|| {
+        Some(ShopifyApp {
+            api_key: std::env::var("SHOPIFY_API_KEY").ok()?,
+            api_secret: std::env::var("SHOPIFY_API_SECRET").ok()?,
+            scopes: std::env::var("SHOPIFY_SCOPES")
+                .unwrap_or_else(|_| "read_customers,read_orders".to_owned()),
+            redirect_uri: std::env::var("SHOPIFY_REDIRECT_URI").ok()?,
+        })
+    })();
+}
+
+fn shopify_app() -> AxumResult<&'static ShopifyApp> {
+    SHOPIFY_APP.as_ref().ok_or_else(|| {
+        into_reponse(
+            500,
+            serde_json::json!({ "error": "The Shopify app is not configured" }),
+        )
+    })
+}
+
+// `{name}.myshopify.com`, so a forged `shop` can't send the code exchange elsewhere.
+fn is_shop_domain(shop: &str) -> bool {
+    match shop.strip_suffix(".myshopify.com") {
+        Some(name) => {
+            !name.is_empty()
+                && !name.starts_with('-')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        }
+        None => false,
+    }
+}
+
+fn hex_decode(value: &str) -> Option<Vec<u8>> {
+    if value.len() % 2 != 0 {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn mac(secret: &str, data: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac
+}
+
+/// Checks the `hmac` Shopify adds to install and callback urls: a hex HMAC-SHA256 of
+/// the other query parameters, sorted and joined as `key=value&...`.
+fn verify_query_hmac(params: &HashMap<String, String>, secret: &str) -> bool {
+    let signature = match params.get("hmac").and_then(|v| hex_decode(v)) {
+        Some(signature) => signature,
+        None => return false,
+    };
+    let message = params
+        .iter()
+        .filter(|(k, _)| k.as_str() != "hmac" && k.as_str() != "signature")
+        .collect::<BTreeMap<_, _>>()
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&");
+    mac(secret, message.as_bytes()).verify_slice(&signature).is_ok()
+}
+
+// Webhooks carry a base64 HMAC-SHA256 of the raw body.
+fn verify_webhook_hmac(headers: &HeaderMap, body: &[u8], secret: &str) -> bool {
+    let signature = headers
+        .get("X-Shopify-Hmac-Sha256")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| base64::decode(v).ok());
+    match signature {
+        Some(signature) => mac(secret, body).verify_slice(&signature).is_ok(),
+        None => false,
+    }
+}
+
+/// Verifies an App Bridge session token and returns its claims. The token must be signed
+/// with the app secret, issued for this app and by the shop it is meant for.
+pub(crate) fn verify_session_token(token: &str) -> AxumResult<SessionTokenClaims> {
+    let app = shopify_app()?;
+    let unauthorized = |e: &str| {
+        into_reponse(
+            400,
+            serde_json::json!({ "error": format!("Invalid Shopify session token: {}", e) }),
+        )
+    };
+
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.validate_nbf = true;
+    validation.leeway = 5;
+    validation.set_audience(&[app.api_key.as_str()]);
+    let claims = decode::<SessionTokenClaims>(
+        token,
+        &DecodingKey::from_secret(app.api_secret.as_bytes()),
+        &validation,
+    )
+    .map_err(|e| unauthorized(&e.to_string()))?
+    .claims;
+
+    let shop = claims.dest.strip_prefix("https://").unwrap_or_default();
+    if !is_shop_domain(shop) {
+        return Err(unauthorized("dest is not a shop"));
+    }
+    if claims.iss.trim_end_matches('/').strip_suffix("/admin") != Some(claims.dest.as_str()) {
+        return Err(unauthorized("issuer doesn't match the shop"));
+    }
+    Ok(claims)
+}
+
+// Shop domain of verified session token claims.
+pub(crate) fn claims_shop(claims: &SessionTokenClaims) -> String {
+    claims.dest.trim_start_matches("https://").to_string()
+}
+
+// Session tokens are only accepted from shops that have the app installed.
+pub(crate) async fn require_installed(shop: &String, pool: &PgPool) -> AxumResult<()> {
+    if db_shop_installed(shop, pool).await.map_err(internal_error)? {
+        Ok(())
+    } else {
+        Err(into_reponse(
+            400,
+            serde_json::json!({ "error": "The app is not installed on this shop" }),
+        ))
+    }
+}
+
+fn plain_error(status: StatusCode, message: &str) -> Response {
+    (status, message.to_string()).into_response()
+}
+
+// API
+// Install, callback and webhook requests come from Shopify and answer with redirects or
+// plain statuses instead of AxumRes, so they are not part of the generated OpenAPI spec.
+pub async fn shopify_install(
+    Query(params): Query<HashMap<String, String>>,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> Response {
+    let app = match shopify_app() {
+        Ok(app) => app,
+        Err(e) => return e.into_response(),
+    };
+    let shop = match params.get("shop") {
+        Some(shop) if is_shop_domain(shop) => shop.to_lowercase(),
+        _ => return plain_error(StatusCode::BAD_REQUEST, "Invalid shop"),
+    };
+    // Installs started from the Shopify admin are signed, ones started by hand are not.
+    if params.contains_key("hmac") && !verify_query_hmac(&params, &app.api_secret) {
+        return plain_error(StatusCode::UNAUTHORIZED, "Invalid hmac");
+    }
+
+    let state = random_token();
+    if let Err(e) = db_create_state(&state, &shop, &pool).await {
+        println!("{:?}", e);
+        return plain_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to start the install");
+    }
+
+    let url = reqwest::Url::parse_with_params(
+        &format!("https://{}/admin/oauth/authorize", shop),
+        &[
+            ("client_id", app.api_key.as_str()),
+            ("scope", app.scopes.as_str()),
+            ("redirect_uri", app.redirect_uri.as_str()),
+            ("state", state.as_str()),
+        ],
+    );
+    match url {
+        Ok(url) => Redirect::to(url.as_str()).into_response(),
+        Err(e) => plain_error(StatusCode::BAD_REQUEST, &e.to_string()),
+    }
+}
+
+pub async fn shopify_callback(
+    Query(params): Query<HashMap<String, String>>,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> Response {
+    let app = match shopify_app() {
+        Ok(app) => app,
+        Err(e) => return e.into_response(),
+    };
+    if !verify_query_hmac(&params, &app.api_secret) {
+        return plain_error(StatusCode::UNAUTHORIZED, "Invalid hmac");
+    }
+    let (shop, code, state) = match (params.get("shop"), params.get("code"), params.get("state")) {
+        (Some(shop), Some(code), Some(state)) if is_shop_domain(shop) => (shop.to_lowercase(), code, state),
+        _ => return plain_error(StatusCode::BAD_REQUEST, "Invalid callback"),
+    };
+
+    match db_consume_state(state, &shop, &pool).await {
+        Ok(true) => {}
+        Ok(false) => return plain_error(StatusCode::UNAUTHORIZED, "Invalid or expired state"),
+        Err(e) => {
+            println!("{:?}", e);
+            return plain_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to check the state");
+        }
+    }
+
+    let token = match exchange_code(&shop, code, app).await {
+        Ok(token) => token,
+        Err(e) => {
+            println!("{:?}", e);
+            return plain_error(StatusCode::BAD_GATEWAY, "Failed to get an access token from Shopify");
+        }
+    };
+    if let Err(e) = db_install_shop(&shop, &token, &pool).await {
+        println!("{:?}", e);
+        return plain_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to store the shop");
+    }
+
+    // Back to the embedded app, which signs in with a session token.
+    Redirect::to(&format!("https://{}/admin/apps/{}", shop, app.api_key)).into_response()
+}
+
+async fn exchange_code(
+    shop: &String,
+    code: &String,
+    app: &ShopifyApp,
+) -> Result<AccessTokenResponse, reqwest::Error> {
+    reqwest::Client::new()
+        .post(format!("https://{}/admin/oauth/access_token", shop))
+        .json(&serde_json::json!({
+            "client_id": app.api_key,
+            "client_secret": app.api_secret,
+            "code": code,
+        }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<AccessTokenResponse>()
+        .await
+}
+
+pub async fn shopify_app_uninstalled(
+    headers: HeaderMap,
+    Extension(pool): Extension<Arc<PgPool>>,
+    body: Bytes,
+) -> Response {
+    let app = match shopify_app() {
+        Ok(app) => app,
+        Err(e) => return e.into_response(),
+    };
+    if !verify_webhook_hmac(&headers, &body, &app.api_secret) {
+        return plain_error(StatusCode::UNAUTHORIZED, "Invalid hmac");
+    }
+    let shop = match headers
+        .get("X-Shopify-Shop-Domain")
+        .and_then(|v| v.to_str().ok())
+        .filter(|shop| is_shop_domain(shop))
+    {
+        Some(shop) => shop.to_lowercase(),
+        None => return plain_error(StatusCode::BAD_REQUEST, "Invalid shop"),
+    };
+
+    match db_uninstall_shop(&shop, &pool).await {
+        Ok(_) => StatusCode::OK.into_response(),
+        Err(e) => {
+            println!("{:?}", e);
+            // Shopify retries webhooks that fail.
+            plain_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to revoke the token")
+        }
+    }
+}
+
+/// Signs in from the embedded app: the session token identifies the shop staff member,
+/// whose account gets linked to the shop.
+#[debug_handler]
+#[handler(method = "POST", tag = "auth")]
+pub async fn shopify_session(
+    payload: Result<Json<ShopifySessionToken>, JsonRejection>,
+    Extension(pool): Extension<Arc<PgPool>>,
+    client: ClientInfo,
+) -> AxumResult<Json<AxumRes>> {
+    let req = payload.map_err(bad_request)?.0;
+    let claims = verify_session_token(&req.session_token)?;
+    let shop = claims_shop(&claims);
+    require_installed(&shop, &pool).await?;
+
+    let staff_id = claims.sub.as_ref().ok_or_else(|| {
+        into_reponse(
+            400,
+            serde_json::json!({ "error": "The session token has no user" }),
+        )
+    })?;
+    let provider_user_id = format!("{}:{}", shop, staff_id);
+    let provider_type = SHOPIFY_PROVIDER.to_string();
+
+    let account_id = resolve_account(&provider_user_id, &provider_type, &pool)
+        .await
+        .map_err(internal_error)?;
+    db_link_shop(&account_id, &shop, None, &pool)
+        .await
+        .map_err(internal_error)?;
+
+    let result = start_login(&provider_user_id, &provider_type, &client, &pool).await?;
+    Ok(axum::Json(AxumRes { code: 200, result }))
+}
+
+// Database
+pub async fn db_create_state(state: &String, shop: &String, pool: &PgPool) -> Result<(), sqlx::Error> {
+    let now = Utc::now().naive_utc();
+    let _ = sqlx::query!(
+        "DELETE FROM shopify_oauth_states WHERE expires_at < $1",
+        now
+    )
+    .execute(pool)
+    .await?;
+    let _ = sqlx::query!(
+        "INSERT INTO shopify_oauth_states (state, shop, expires_at) VALUES ($1, $2, $3)",
+        state,
+        shop,
+        now + Duration::minutes(STATE_TTL_MINUTES)
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// A state can be used once, for the shop it was created for.
+pub async fn db_consume_state(state: &String, shop: &String, pool: &PgPool) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!(
+        "DELETE FROM shopify_oauth_states WHERE state = $1 AND shop = $2 AND expires_at > $3 RETURNING state",
+        state,
+        shop,
+        Utc::now().naive_utc()
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.is_some())
+}
+
+pub async fn db_install_shop(
+    shop: &String,
+    token: &AccessTokenResponse,
+    pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let access_token = encrypt(&token.access_token).map_err(into_sqlx_error)?;
+    let _ = sqlx::query!(
+        "INSERT INTO shopify_shops (shop, access_token, scope, installed_at) VALUES ($1, $2, $3, $4)
+        ON CONFLICT (shop) DO UPDATE SET access_token = $2, scope = $3, installed_at = $4, uninstalled_at = NULL",
+        shop,
+        access_token,
+        token.scope,
+        Utc::now().naive_utc()
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn db_uninstall_shop(shop: &String, pool: &PgPool) -> Result<(), sqlx::Error> {
+    let _ = sqlx::query!(
+        "UPDATE shopify_shops SET access_token = NULL, uninstalled_at = $1 WHERE shop = $2",
+        Utc::now().naive_utc(),
+        shop
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn db_shop_installed(shop: &String, pool: &PgPool) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT shop FROM shopify_shops WHERE shop = $1 AND access_token IS NOT NULL",
+        shop
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.is_some())
+}
+
+pub async fn db_link_shop(
+    user_id: &String,
+    shop: &String,
+    email: Option<&String>,
+    pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let _ = sqlx::query!(
+        "INSERT INTO shopify_auth (user_id, shop, email) VALUES ($1, $2, $3)
+        ON CONFLICT (user_id) DO UPDATE SET shop = $2, email = COALESCE($3, shopify_auth.email), legacy_token = NULL",
+        user_id,
+        shop,
+        email
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// The shop and access token linked to a user, directly or through one of the account's login methods.
+// Users not linked to an installed shop yet get the token they posted before the install flow, with no shop.
+pub async fn db_get_shopify_token(
+    user_id: &String,
+    pool: &PgPool,
+) -> Result<(String, String), sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT COALESCE(s.shop, '') AS "shop!", COALESCE(s.access_token, a.legacy_token) AS "access_token!"
+        FROM shopify_auth a
+        LEFT JOIN shopify_shops s ON s.shop = a.shop AND s.access_token IS NOT NULL
+        WHERE COALESCE(s.access_token, a.legacy_token) IS NOT NULL
+            AND (a.user_id = $1 OR a.user_id IN (SELECT provider_user_id FROM identities WHERE account_id = $1))
+        ORDER BY s.access_token IS NULL
+        LIMIT 1"#,
+        user_id
+    )
+    .fetch_one(pool)
+    .await?;
+    let token = decrypt(&row.access_token).map_err(into_sqlx_error)?;
+    Ok((row.shop, token))
+}
+
+// Moves shop access tokens to the current master key.
+pub async fn db_reencrypt_shopify_tokens(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"SELECT shop, access_token AS "access_token!" FROM shopify_shops WHERE access_token NOT LIKE $1"#,
+        format!("{}%", current_prefix().map_err(into_sqlx_error)?)
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut updated = 0;
+    for row in rows {
+        if let Some(token) = reencrypt(&row.access_token).map_err(into_sqlx_error)? {
+            let res = sqlx::query!(
+                "UPDATE shopify_shops SET access_token = $1 WHERE shop = $2 AND access_token = $3",
+                token,
+                row.shop,
+                row.access_token
+            )
+            .execute(pool)
+            .await?;
+            updated += res.rows_affected();
+        }
+    }
+
+    // Tokens posted before the install flow, encrypting any stored before encryption.
+    let rows = sqlx::query!(
+        r#"SELECT id, legacy_token AS "legacy_token!" FROM shopify_auth WHERE legacy_token NOT LIKE $1"#,
+        format!("{}%", current_prefix().map_err(into_sqlx_error)?)
+    )
+    .fetch_all(pool)
+    .await?;
+    for row in rows {
+        if let Some(token) = reencrypt(&row.legacy_token).map_err(into_sqlx_error)? {
+            let res = sqlx::query!(
+                "UPDATE shopify_auth SET legacy_token = $1 WHERE id = $2 AND legacy_token = $3",
+                token,
+                row.id,
+                row.legacy_token
+            )
+            .execute(pool)
+            .await?;
+            updated += res.rows_affected();
+        }
+    }
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::auth_handler::{shopify_auth_otp, shopify_verify_otp};
+    use crate::models::auth::{Shopify, ShopifyOTP};
+    use crate::providers::IdentityProvider;
+    use crate::test_db::{
+        test_pool, test_user_id, FakeProvider, TEST_CODE, TEST_SHOPIFY_API_KEY, TEST_SHOPIFY_API_SECRET,
+    };
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    async fn installed_shop(pool: &PgPool) -> String {
+        let shop = format!("{}.myshopify.com", &test_user_id()[..13]);
+        let token = AccessTokenResponse {
+            access_token: format!("shop-token-{}", shop),
+            scope: "read_customers".to_string(),
+        };
+        db_install_shop(&shop, &token, pool).await.unwrap();
+        shop
+    }
+
+    fn session_token(shop: &str) -> String {
+        let now = Utc::now().timestamp() as usize;
+        let claims = SessionTokenClaims {
+            iss: format!("https://{}/admin", shop),
+            dest: format!("https://{}", shop),
+            aud: TEST_SHOPIFY_API_KEY.to_string(),
+            sub: Some("1".to_string()),
+            exp: now + 60,
+            nbf: now,
+            iat: now,
+            jti: test_user_id(),
+            sid: None,
+        };
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(TEST_SHOPIFY_API_SECRET.as_bytes())).unwrap()
+    }
+
+    async fn linked_shop(user_id: &String, pool: &PgPool) -> Option<String> {
+        sqlx::query_scalar::<_, Option<String>>("SELECT shop FROM shopify_auth WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await
+            .unwrap()
+            .flatten()
+    }
+
+    fn otp(user_id: &String, code: &str, shop: &str) -> ShopifyOTP {
+        ShopifyOTP {
+            code: code.to_string(),
+            method_id: user_id.clone(),
+            session_token: session_token(shop),
+            email: Some(format!("{}@example.com", user_id)),
+            captcha_token: None,
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn requesting_a_code_does_not_link_the_shop() {
+        let pool = Arc::new(test_pool().await);
+        let shop = installed_shop(&pool).await;
+        let user_id = test_user_id();
+        let provider: Arc<dyn IdentityProvider> = Arc::new(FakeProvider { user_id: user_id.clone() });
+
+        let payload = Shopify {
+            email: format!("{}@example.com", user_id),
+            session_token: session_token(&shop),
+        };
+        shopify_auth_otp(Ok(Json(payload)), Extension(pool.clone()), Extension(provider), ClientInfo::default())
+            .await
+            .unwrap();
+        assert_eq!(linked_shop(&user_id, &pool).await, None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn verified_code_links_the_shop() {
+        let pool = Arc::new(test_pool().await);
+        let shop = installed_shop(&pool).await;
+        let user_id = test_user_id();
+        let provider: Arc<dyn IdentityProvider> = Arc::new(FakeProvider { user_id: user_id.clone() });
+
+        shopify_verify_otp(Ok(Json(otp(&user_id, TEST_CODE, &shop))), Extension(pool.clone()), Extension(provider), ClientInfo::default())
+            .await
+            .unwrap();
+        assert_eq!(linked_shop(&user_id, &pool).await, Some(shop.clone()));
+        let (token_shop, token) = db_get_shopify_token(&user_id, &pool).await.unwrap();
+        assert_eq!(token_shop, shop);
+        assert_eq!(token, format!("shop-token-{}", shop));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn wrong_code_does_not_link_the_shop() {
+        let pool = Arc::new(test_pool().await);
+        let shop = installed_shop(&pool).await;
+        let user_id = test_user_id();
+        let provider: Arc<dyn IdentityProvider> = Arc::new(FakeProvider { user_id: user_id.clone() });
+
+        let res = shopify_verify_otp(Ok(Json(otp(&user_id, "000000", &shop))), Extension(pool.clone()), Extension(provider), ClientInfo::default()).await;
+        assert!(res.is_err());
+        assert_eq!(linked_shop(&user_id, &pool).await, None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn legacy_token_is_served_until_the_user_is_linked() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        // stored in plaintext, before encryption
+        sqlx::query("INSERT INTO shopify_auth (user_id, legacy_token, email) VALUES ($1, 'legacy-token', 'a@example.com')")
+            .bind(&user_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            db_get_shopify_token(&user_id, &pool).await.unwrap(),
+            ("".to_string(), "legacy-token".to_string())
+        );
+
+        db_reencrypt_shopify_tokens(&pool).await.unwrap();
+        let stored: String = sqlx::query_scalar("SELECT legacy_token FROM shopify_auth WHERE user_id = $1")
+            .bind(&user_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(stored.starts_with(&current_prefix().unwrap()));
+        assert_eq!(db_get_shopify_token(&user_id, &pool).await.unwrap().1, "legacy-token");
+
+        let shop = installed_shop(&pool).await;
+        db_link_shop(&user_id, &shop, None, &pool).await.unwrap();
+        assert_eq!(
+            db_get_shopify_token(&user_id, &pool).await.unwrap(),
+            (shop.clone(), format!("shop-token-{}", shop))
+        );
+        let legacy: Option<String> = sqlx::query_scalar("SELECT legacy_token FROM shopify_auth WHERE user_id = $1")
+            .bind(&user_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(legacy, None);
+    }
+}
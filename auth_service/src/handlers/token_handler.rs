@@ -0,0 +1,241 @@
+use axum::{
+    extract::{rejection::JsonRejection, Extension},
+    Json,
+};
+use axum_macros::debug_handler;
+use openapi_rs::openapi_proc_macro::handler;
+use sqlx::types::chrono::Utc;
+use sqlx::{PgConnection, PgPool};
+use std::fmt;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use okapi::openapi3::RefOr;
+use openapi_rs::gen::OpenApiGenerator;
+use openapi_rs::OpenApiFromData;
+
+use microservice_utils::{
+    jwt::auth::{create_token, decode_token, hash_token, Token},
+    jwt::extractor::AuthSession,
+    server::response::{into_reponse, AxumRes, AxumResult},
+};
+
+use crate::models::session::RefreshToken;
+
+#[derive(Debug)]
+pub enum RefreshError {
+    Invalid(String),
+    Reused,
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for RefreshError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefreshError::Invalid(e) => write!(f, "{}", e),
+            RefreshError::Reused => write!(f, "Refresh token was already used, session revoked"),
+            RefreshError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for RefreshError {
+    fn from(e: sqlx::Error) -> Self {
+        RefreshError::Database(e)
+    }
+}
+
+/// Exchanges a refresh token for a new token pair.
+///
+/// Refresh tokens are single use. Replaying one that was already exchanged means it
+/// leaked, so the session it belongs to is revoked together with all its tokens.
+pub async fn rotate_refresh_token(
+    refresh_token: &String,
+    pool: &PgPool,
+) -> Result<Token, RefreshError> {
+    let claims = decode_token(refresh_token).map_err(|e| RefreshError::Invalid(e.to_string()))?;
+
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query!(
+        "SELECT rt.id, rt.session_id, rt.used_at, s.user_id, s.revoked_at FROM refresh_tokens rt JOIN sessions s ON s.id = rt.session_id WHERE rt.token_hash = $1 FOR UPDATE",
+        hash_token(refresh_token)
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .ok_or_else(|| RefreshError::Invalid("Refresh token does not exist".to_string()))?;
+
+    if row.user_id != claims.sub || row.revoked_at.is_some() {
+        return Err(RefreshError::Invalid("Refresh token is revoked".to_string()));
+    }
+
+    if row.used_at.is_some() {
+        println!("Refresh token reuse detected, revoking session {:?}", row.session_id);
+        db_revoke_session_by_id(&row.session_id, &mut tx).await?;
+        tx.commit().await?;
+        return Err(RefreshError::Reused);
+    }
+
+    let token = create_token(&row.user_id);
+    sqlx::query!(
+        "UPDATE refresh_tokens SET used_at = $1 WHERE id = $2",
+        Utc::now().naive_utc(),
+        row.id
+    )
+    .execute(&mut tx)
+    .await?;
+    db_insert_refresh_token(&row.session_id, &token.refresh_token, &mut tx).await?;
+    sqlx::query!(
+        "UPDATE sessions SET access_token = $1, last_seen_at = $2 WHERE id = $3",
+        token.access_token,
+        Utc::now().naive_utc(),
+        row.session_id
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(token)
+}
+
+// API
+#[debug_handler]
+#[handler(method = "POST", tag = "session")]
+pub async fn refresh(
+    payload: Result<Json<RefreshToken>, JsonRejection>,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    match payload {
+        Ok(payload) => match rotate_refresh_token(&payload.0.refresh_token, &pool).await {
+            Ok(token) => Ok(axum::Json(AxumRes {
+                code: 200,
+                result: serde_json::json!(&token),
+            })),
+            Err(RefreshError::Database(e)) => {
+                println!("{:?}", e.to_string());
+                let ret = serde_json::json!({
+                    "error": format!("{:?}", e),
+                });
+                Err(into_reponse(500, ret))
+            }
+            Err(e) => {
+                let ret = serde_json::json!({
+                    "error": e.to_string(),
+                });
+                Err(into_reponse(401, ret))
+            }
+        },
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(400, ret))
+        }
+    }
+}
+
+#[debug_handler]
+#[handler(method = "POST", tag = "session")]
+pub async fn logout(
+    session: AuthSession,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    match db_logout(&session.user_id, &session.access_token, &pool).await {
+        Ok(_) => {
+            let ret = serde_json::json!({
+                "status": "success",
+            });
+            Ok(axum::Json(AxumRes {
+                code: 200,
+                result: ret,
+            }))
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(500, ret))
+        }
+    }
+}
+
+// Database
+pub async fn db_insert_refresh_token(
+    session_id: &Uuid,
+    refresh_token: &String,
+    conn: &mut PgConnection,
+) -> Result<(), sqlx::Error> {
+    let _ = sqlx::query!(
+        "INSERT INTO refresh_tokens (session_id, token_hash) VALUES ($1, $2)",
+        session_id,
+        hash_token(refresh_token)
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn db_revoke_session_by_id(
+    session_id: &Uuid,
+    conn: &mut PgConnection,
+) -> Result<(), sqlx::Error> {
+    let _ = sqlx::query!(
+        "UPDATE sessions SET revoked_at = $1 WHERE id = $2 AND revoked_at IS NULL",
+        Utc::now().naive_utc(),
+        session_id
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn db_logout(
+    user_id: &String,
+    access_token: &String,
+    pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let _ = sqlx::query!(
+        "UPDATE sessions SET revoked_at = $1 WHERE user_id = $2 AND access_token = $3 AND revoked_at IS NULL",
+        Utc::now().naive_utc(),
+        user_id,
+        access_token
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::{test_pool, test_user_id};
+    use sqlx::Executor;
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn refresh_tokens_from_before_rotation_are_kept() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        let token = create_token(&user_id);
+
+        // a session stored before refresh_tokens, then the migration
+        pool.execute("ALTER TABLE sessions ADD COLUMN IF NOT EXISTS refresh_token TEXT")
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO sessions (user_id, access_token, refresh_token, provider_type) VALUES ($1, $2, $3, 'stytch')")
+            .bind(&user_id)
+            .bind(&token.access_token)
+            .bind(&token.refresh_token)
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../schema.sql")).await.unwrap();
+
+        assert!(rotate_refresh_token(&token.refresh_token, &pool).await.is_ok());
+        assert!(matches!(
+            rotate_refresh_token(&token.refresh_token, &pool).await,
+            Err(RefreshError::Reused)
+        ));
+    }
+}
@@ -1,13 +1,53 @@
 use axum::{
     extract::Extension,
-    routing::{get, post},
+    routing::{delete, get, post},
     Router,
 };
 use dotenv::dotenv;
 use handlers::auth_handler::{
     email_auth_link_spec, email_auth_otp_spec, email_verify_link_spec, email_verify_otp_spec,
     oauth_verify_spec, phone_auth_otp_spec, phone_verify_otp_spec, shopify_auth_otp_spec,
+    shopify_verify_otp_spec,
 };
+use handlers::session_handler::{
+    list_sessions, list_sessions_spec, revoke_other_sessions, revoke_other_sessions_spec,
+    revoke_session, revoke_session_spec,
+};
+use handlers::login_handler::{list_logins, list_logins_spec};
+use handlers::identity_handler::{
+    link_identity, link_identity_spec, list_identities, list_identities_spec, merge_accounts,
+    merge_accounts_spec, unlink_identity, unlink_identity_spec,
+};
+use handlers::impersonation_handler::{
+    end_impersonation, end_impersonation_spec, impersonation_history, impersonation_history_spec,
+    list_impersonations, list_impersonations_spec, start_impersonation, start_impersonation_spec,
+};
+use handlers::erasure_handler::{db_close_account, db_erase_user_auth};
+use handlers::export_handler::MyDataExport;
+use handlers::lockout_handler::{
+    list_lockouts, list_lockouts_spec, release_lockout, release_lockout_spec,
+};
+use handlers::mfa_handler::{
+    db_reencrypt_totp_secrets, mfa_enroll, mfa_enroll_confirm, mfa_enroll_confirm_spec, mfa_enroll_spec, mfa_verify,
+    mfa_verify_spec, totp_confirm, totp_confirm_spec, totp_disable, totp_disable_spec,
+    totp_enroll, totp_enroll_spec,
+};
+use handlers::oauth_handler::{oauth_introspect, oauth_token};
+use handlers::password_handler::{
+    password_change, password_change_spec, password_login, password_login_spec,
+    password_register, password_register_spec, password_reset, password_reset_request,
+    password_reset_request_spec, password_reset_spec, password_verify_email,
+    password_verify_email_spec,
+};
+use handlers::share_handler::{
+    create_share, create_share_spec, list_shares, list_shares_spec, revoke_share,
+    revoke_share_spec,
+};
+use handlers::shopify_handler::{
+    db_reencrypt_shopify_tokens, shopify_app_uninstalled, shopify_callback, shopify_install,
+    shopify_session, shopify_session_spec,
+};
+use handlers::token_handler::{logout, logout_spec, refresh, refresh_spec};
 use shuttle_service::error::CustomError;
 use sqlx::{Executor, PgPool};
 use std::{env, ffi::OsStr, net::SocketAddr, sync::Arc};
@@ -20,15 +60,30 @@ use tower_http::{
 
 use crate::handlers::auth_handler::{
     email_auth_link, email_auth_otp, email_verify_link, email_verify_otp, oauth_verify,
-    phone_auth_otp, phone_verify_otp, shopify_auth_otp, MyAuthService,
+    phone_auth_otp, phone_verify_otp, shopify_auth_otp, shopify_verify_otp, MyAuthService,
 };
 
+use microservice_utils::server::audit::audit_impersonation;
 use microservice_utils::{open_api::gen::{generate_openapi_spec, Spec, GenSpec}, server::spa::SpaRouter};
 use microservice_utils::server::hybrid::hybrid;
+use microservice_utils::server::grpc_support::{grpc_web, health_service, reflection_service};
+use microservice_utils::server::service_auth::{check_service_config, service_interceptor, AUTH_SERVICE};
 use microservice_utils::server::error_404::error_404;
+use microservice_utils::events::{event_producer, spawn_outbox_relay, start_event_consumer, Event};
+use microservice_utils::server::erasure::report_erasure;
+use microservice_utils::server::grpc::data_export::data_export_server::DataExportServer;
+use microservice_utils::crypto::check_keys;
+use microservice_utils::server::sender::sender_from_env;
+use crate::providers::check_provider_config;
+use crate::throttle::check_captcha_secret;
 
+pub mod geoip;
 pub mod handlers;
 pub mod models;
+pub mod providers;
+pub mod throttle;
+#[cfg(test)]
+mod test_db;
 
 pub mod auth_service {
     tonic::include_proto!("auth_service");
@@ -36,6 +91,9 @@ pub mod auth_service {
 
 use auth_service::auth_service_server::AuthServiceServer;
 
+pub(crate) const FILE_DESCRIPTOR_SET: &[u8] =
+    tonic::include_file_descriptor_set!("auth_service_descriptor");
+
 #[macro_use]
 extern crate lazy_static;
 
@@ -50,19 +108,39 @@ lazy_static! {
 #[tokio::main]
 pub async fn main() {
     dotenv().expect("Failed to read .env file");
+    check_service_config().expect("Invalid service token config");
     lazy_static::initialize(&DATABASE_URL);
+    check_keys().expect("Invalid encryption keys");
+    check_captcha_secret().expect("Missing captcha secret");
+    check_provider_config().expect("Invalid identity provider config");
+    let reflection = reflection_service(&[FILE_DESCRIPTOR_SET]).expect("Invalid gRPC reflection descriptors");
 
     let pool = PgPool::connect(&std::env::var("DATABASE_URL").unwrap())
         .await
         .unwrap();
 
+    spawn_reencrypt_job(&pool);
+    spawn_outbox_relay(&pool);
+    start_events(&pool);
     let axum_make_service = create_app(&pool);
 
     let grpc_service = tonic::transport::Server::builder()
-        .add_service(AuthServiceServer::new(MyAuthService::new(pool)))
+        .add_service(grpc_web(AuthServiceServer::with_interceptor(
+            MyAuthService::new(pool.clone()),
+            service_interceptor(AUTH_SERVICE),
+        )))
+        .add_service(grpc_web(DataExportServer::with_interceptor(
+            MyDataExport::new(pool),
+            service_interceptor(AUTH_SERVICE),
+        )))
+        .add_service(health_service::<AuthServiceServer<MyAuthService>>().await)
+        .add_service(reflection)
         .into_service();
 
-    let hybrid_make_service = hybrid(axum_make_service.into_make_service(), grpc_service);
+    let hybrid_make_service = hybrid(
+        axum_make_service.into_make_service_with_connect_info::<SocketAddr>(),
+        grpc_service,
+    );
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 4004));
     println!("Listening on http://{}", addr);
@@ -75,15 +153,59 @@ pub async fn main() {
 
 #[shuttle_service::main]
 async fn axum(pool: PgPool) -> shuttle_service::ShuttleAxum {
+    // refuse to start without valid encryption keys, a captcha secret, identity provider
+    // credentials or a service signing key
+    check_keys()?;
+    check_captcha_secret()?;
+    check_provider_config()?;
+    check_service_config()?;
     pool.execute(include_str!("../schema.sql"))
         .await
         .map_err(CustomError::new)?;
 
+    spawn_reencrypt_job(&pool);
+    spawn_outbox_relay(&pool);
+    start_events(&pool);
     let app = create_app(&pool);
     let sync_wrapper = SyncWrapper::new(app);
     Ok(sync_wrapper)
 }
 
+// Re-encrypts secrets still wrapped with an older master key after a rotation.
+fn spawn_reencrypt_job(pool: &PgPool) {
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        match db_reencrypt_shopify_tokens(&pool).await {
+            Ok(count) => println!("Re-encrypted {} shopify tokens", count),
+            Err(e) => println!("{:?}", e.to_string()),
+        }
+        match db_reencrypt_totp_secrets(&pool).await {
+            Ok(count) => println!("Re-encrypted {} totp secrets", count),
+            Err(e) => println!("{:?}", e.to_string()),
+        }
+    });
+}
+
+fn start_events(pool: &PgPool) {
+    let pool = pool.clone();
+    let producer = event_producer();
+    start_event_consumer("auth_service", move |event| {
+        let pool = pool.clone();
+        let producer = producer.clone();
+        async move {
+            match event {
+                Event::UserDeletionRequested { deletion_id, user_id, .. } => {
+                    db_close_account(&user_id, &pool).await?;
+                    let erasure = db_erase_user_auth(&user_id, &pool).await?;
+                    report_erasure(&deletion_id, &user_id, AUTH_SERVICE, erasure, &producer).await?;
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+    });
+}
+
 fn create_app(pool: &PgPool) -> Router {
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var(
@@ -109,6 +231,10 @@ fn create_app(pool: &PgPool) -> Router {
             route: "/api/auth/shopify".into(),
             gen: Box::new(shopify_auth_otp_spec),
         },
+        Spec {
+            route: "/api/auth/shopify/session".into(),
+            gen: Box::new(shopify_session_spec),
+        },
         Spec {
             route: "/api/verify/email_link".into(),
             gen: Box::new(email_verify_link_spec),
@@ -123,18 +249,144 @@ fn create_app(pool: &PgPool) -> Router {
         },
         Spec {
             route: "/api/verify/shopify".into(),
-            gen: Box::new(email_verify_otp_spec),
+            gen: Box::new(shopify_verify_otp_spec),
         },
         Spec {
             route: "/api/verify/oauth".into(),
             gen: Box::new(oauth_verify_spec),
         },
+        Spec {
+            route: "/api/auth/sessions".into(),
+            gen: Box::new(list_sessions_spec),
+        },
+        Spec {
+            route: "/api/auth/sessions".into(),
+            gen: Box::new(revoke_session_spec),
+        },
+        Spec {
+            route: "/api/auth/sessions/others".into(),
+            gen: Box::new(revoke_other_sessions_spec),
+        },
+        Spec {
+            route: "/api/auth/logins".into(),
+            gen: Box::new(list_logins_spec),
+        },
+        Spec {
+            route: "/api/auth/refresh".into(),
+            gen: Box::new(refresh_spec),
+        },
+        Spec {
+            route: "/api/auth/logout".into(),
+            gen: Box::new(logout_spec),
+        },
+        Spec {
+            route: "/api/auth/password/register".into(),
+            gen: Box::new(password_register_spec),
+        },
+        Spec {
+            route: "/api/auth/password/verify".into(),
+            gen: Box::new(password_verify_email_spec),
+        },
+        Spec {
+            route: "/api/auth/password/login".into(),
+            gen: Box::new(password_login_spec),
+        },
+        Spec {
+            route: "/api/auth/password/reset_request".into(),
+            gen: Box::new(password_reset_request_spec),
+        },
+        Spec {
+            route: "/api/auth/password/reset".into(),
+            gen: Box::new(password_reset_spec),
+        },
+        Spec {
+            route: "/api/auth/password/change".into(),
+            gen: Box::new(password_change_spec),
+        },
+        Spec {
+            route: "/api/auth/mfa/totp/enroll".into(),
+            gen: Box::new(totp_enroll_spec),
+        },
+        Spec {
+            route: "/api/auth/mfa/totp/confirm".into(),
+            gen: Box::new(totp_confirm_spec),
+        },
+        Spec {
+            route: "/api/auth/mfa/totp/disable".into(),
+            gen: Box::new(totp_disable_spec),
+        },
+        Spec {
+            route: "/api/auth/mfa/verify".into(),
+            gen: Box::new(mfa_verify_spec),
+        },
+        Spec {
+            route: "/api/auth/mfa/enroll".into(),
+            gen: Box::new(mfa_enroll_spec),
+        },
+        Spec {
+            route: "/api/auth/mfa/enroll/confirm".into(),
+            gen: Box::new(mfa_enroll_confirm_spec),
+        },
+        Spec {
+            route: "/api/auth/identities".into(),
+            gen: Box::new(list_identities_spec),
+        },
+        Spec {
+            route: "/api/auth/identities".into(),
+            gen: Box::new(link_identity_spec),
+        },
+        Spec {
+            route: "/api/auth/identities".into(),
+            gen: Box::new(unlink_identity_spec),
+        },
+        Spec {
+            route: "/api/auth/accounts/merge".into(),
+            gen: Box::new(merge_accounts_spec),
+        },
+        Spec {
+            route: "/api/auth/admin/lockouts".into(),
+            gen: Box::new(list_lockouts_spec),
+        },
+        Spec {
+            route: "/api/auth/admin/lockouts".into(),
+            gen: Box::new(release_lockout_spec),
+        },
+        Spec {
+            route: "/api/auth/admin/impersonations".into(),
+            gen: Box::new(list_impersonations_spec),
+        },
+        Spec {
+            route: "/api/auth/admin/impersonations".into(),
+            gen: Box::new(start_impersonation_spec),
+        },
+        Spec {
+            route: "/api/auth/admin/impersonations".into(),
+            gen: Box::new(end_impersonation_spec),
+        },
+        Spec {
+            route: "/api/auth/impersonations".into(),
+            gen: Box::new(impersonation_history_spec),
+        },
+        Spec {
+            route: "/api/auth/share_tokens".into(),
+            gen: Box::new(list_shares_spec),
+        },
+        Spec {
+            route: "/api/auth/share_tokens".into(),
+            gen: Box::new(create_share_spec),
+        },
+        Spec {
+            route: "/api/auth/share_tokens".into(),
+            gen: Box::new(revoke_share_spec),
+        },
     ];
 
     generate_openapi_spec(specs).expect("failed to generate openapi spec");
 
     let pool_arc = Arc::new(pool.clone());
-
+    let sender = sender_from_env();
+    // checked by `check_provider_config` at startup
+    let provider = providers::provider_from_env(pool, sender.clone()).expect("Invalid identity provider config");
     let cors = CorsLayer::new()
         .allow_methods(Any)
         .allow_origin(Any)
@@ -149,6 +401,7 @@ fn create_app(pool: &PgPool) -> Router {
         )
         .layer(ConcurrencyLimitLayer::new(64))
         .layer(cors)
+        .layer(axum::middleware::from_fn(audit_impersonation))
         .into_inner();
 
     let app = Router::new()
@@ -157,13 +410,53 @@ fn create_app(pool: &PgPool) -> Router {
         .route("/api/auth/email", post(email_auth_otp))
         .route("/api/auth/phone", post(phone_auth_otp))
         .route("/api/auth/shopify", post(shopify_auth_otp))
+        .route("/api/auth/shopify/session", post(shopify_session))
+        .route("/api/shopify/install", get(shopify_install))
+        .route("/api/shopify/callback", get(shopify_callback))
+        .route("/api/shopify/webhooks/app_uninstalled", post(shopify_app_uninstalled))
         .route("/api/verify/email_link", post(email_verify_link))
         .route("/api/verify/email", post(email_verify_otp))
         .route("/api/verify/phone", post(phone_verify_otp))
-        .route("/api/verify/shopify", post(email_verify_otp))
+        .route("/api/verify/shopify", post(shopify_verify_otp))
         .route("/api/verify/oauth", post(oauth_verify))
+        .route("/api/auth/sessions", get(list_sessions).delete(revoke_session))
+        .route("/api/auth/sessions/others", delete(revoke_other_sessions))
+        .route("/api/auth/logins", get(list_logins))
+        .route("/api/auth/refresh", post(refresh))
+        .route("/api/auth/logout", post(logout))
+        .route("/api/auth/password/register", post(password_register))
+        .route("/api/auth/password/verify", post(password_verify_email))
+        .route("/api/auth/password/login", post(password_login))
+        .route("/api/auth/password/reset_request", post(password_reset_request))
+        .route("/api/auth/password/reset", post(password_reset))
+        .route("/api/auth/password/change", post(password_change))
+        .route("/api/auth/mfa/totp/enroll", post(totp_enroll))
+        .route("/api/auth/mfa/totp/confirm", post(totp_confirm))
+        .route("/api/auth/mfa/totp/disable", post(totp_disable))
+        .route("/api/auth/mfa/verify", post(mfa_verify))
+        .route("/api/auth/mfa/enroll", post(mfa_enroll))
+        .route("/api/auth/mfa/enroll/confirm", post(mfa_enroll_confirm))
+        .route(
+            "/api/auth/identities",
+            get(list_identities).post(link_identity).delete(unlink_identity),
+        )
+        .route("/api/auth/accounts/merge", post(merge_accounts))
+        .route("/api/auth/admin/lockouts", get(list_lockouts).delete(release_lockout))
+        .route(
+            "/api/auth/admin/impersonations",
+            get(list_impersonations).post(start_impersonation).delete(end_impersonation),
+        )
+        .route("/api/auth/impersonations", get(impersonation_history))
+        .route(
+            "/api/auth/share_tokens",
+            get(list_shares).post(create_share).delete(revoke_share),
+        )
+        .route("/oauth/token", post(oauth_token))
+        .route("/oauth/introspect", post(oauth_introspect))
         .fallback(get(error_404))
         .layer(Extension(pool_arc))
+        .layer(Extension(provider))
+        .layer(Extension(sender))
         .layer(middleware_stack);
 
     return app;
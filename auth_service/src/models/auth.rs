@@ -5,19 +5,26 @@ use serde::Serialize;
 #[derive(Debug, Clone, PartialEq,JsonSchema, Serialize, Deserialize)]
 pub struct Email {
     pub email: String,
-    pub expiration_minutes: Option<u32>
+    pub expiration_minutes: Option<u32>,
+    // Only checked after repeated failures, never forwarded to the identity provider.
+    #[serde(default, skip_serializing)]
+    pub captcha_token: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq,JsonSchema, Serialize, Deserialize)]
 pub struct Shopify {
     pub email: String,
-    pub token: String,
+    // App Bridge session token proving the shop the user signs in from.
+    pub session_token: String,
 }
 
 #[derive(Debug, Clone, PartialEq,JsonSchema, Serialize, Deserialize)]
 pub struct PhoneNumber {
     pub phone_number: String,
-    pub expiration_minutes: Option<u32>
+    pub expiration_minutes: Option<u32>,
+    // Only checked after repeated failures, never forwarded to the identity provider.
+    #[serde(default, skip_serializing)]
+    pub captcha_token: Option<String>,
 }
 
 impl PhoneNumber {
@@ -50,4 +57,18 @@ pub struct StytchToken {
 pub struct StytchOTP {
     pub code: String,
     pub method_id: String,
-}
\ No newline at end of file
+    // Only checked after repeated failures, never forwarded to the identity provider.
+    #[serde(default, skip_serializing)]
+    pub captcha_token: Option<String>,
+}
+#[derive(Debug, Clone, PartialEq,JsonSchema, Serialize, Deserialize)]
+pub struct ShopifyOTP {
+    pub code: String,
+    pub method_id: String,
+    // The same session token the code was requested with.
+    pub session_token: String,
+    pub email: Option<String>,
+    // Only checked after repeated failures, never forwarded to the identity provider.
+    #[serde(default, skip_serializing)]
+    pub captcha_token: Option<String>,
+}
@@ -0,0 +1,52 @@
+use schemars::JsonSchema;
+use schemars::schema::Schema;
+use schemars::schema_for_value;
+use serde::Deserialize;
+use serde::Serialize;
+use sqlx::types::chrono::NaiveDateTime;
+use uuid::Uuid;
+
+#[derive(sqlx::FromRow)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Identity {
+    pub id: Uuid,
+    pub provider_type: String,
+    pub provider_user_id: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IdentityId {
+    pub id: Uuid,
+}
+
+impl JsonSchema for IdentityId {
+    fn schema_name() -> String {
+        "IdentityId".into()
+    }
+
+    fn json_schema(_gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        let root_schema = schema_for_value!(IdentityId::default());
+        Schema::Object(root_schema.schema)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProofMethod {
+    EmailOtp,
+    PhoneOtp,
+    MagicLink,
+    Oauth,
+}
+
+/// Proves control over a login method, obtained through the usual `/api/auth/*` send endpoints.
+#[derive(Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct IdentityProof {
+    pub method: ProofMethod,
+    pub method_id: Option<String>, // otp methods
+    pub code: Option<String>,      // otp methods
+    pub token: Option<String>,     // magic link and oauth
+    pub captcha_token: Option<String>,
+    pub mfa_code: Option<String>,  // merge, when the other account has two-factor authentication
+}
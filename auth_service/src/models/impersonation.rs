@@ -0,0 +1,54 @@
+use schemars::JsonSchema;
+use schemars::schema::Schema;
+use schemars::schema_for_value;
+use serde::Deserialize;
+use serde::Serialize;
+use sqlx::types::chrono::NaiveDateTime;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct StartImpersonation {
+    pub user_id: String,
+    pub reason: String,              // shown to the user in their impersonation history
+    pub scopes: Option<Vec<String>>, // "read" and/or "write", read-only by default
+    pub ttl_minutes: Option<i64>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImpersonationId {
+    pub id: Uuid,
+}
+
+impl JsonSchema for ImpersonationId {
+    fn schema_name() -> String {
+        "ImpersonationId".into()
+    }
+
+    fn json_schema(_gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        let root_schema = schema_for_value!(ImpersonationId::default());
+        Schema::Object(root_schema.schema)
+    }
+}
+
+#[derive(sqlx::FromRow)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Impersonation {
+    pub id: Uuid,
+    pub admin_id: String,
+    pub user_id: String,
+    pub scopes: Vec<String>,
+    pub reason: String,
+    pub expires_at: NaiveDateTime,
+    pub ended_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(sqlx::FromRow)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImpersonatedRequest {
+    pub impersonation_id: Uuid,
+    pub method: String,
+    pub path: String,
+    pub allowed: bool,
+    pub created_at: NaiveDateTime,
+}
@@ -0,0 +1,26 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use sqlx::types::chrono::NaiveDateTime;
+use uuid::Uuid;
+
+#[derive(sqlx::FromRow)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LockoutEvent {
+    pub id: Uuid,
+    pub scope: String,
+    pub key: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub lockouts: i32,
+    pub locked_until: NaiveDateTime,
+    pub released_by: Option<String>,
+    pub released_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct LockoutRelease {
+    pub scope: String, // "identifier" or "ip"
+    pub key: String,
+}
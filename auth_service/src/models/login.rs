@@ -0,0 +1,22 @@
+use serde::Deserialize;
+use serde::Serialize;
+use sqlx::types::chrono::NaiveDateTime;
+use uuid::Uuid;
+
+#[derive(sqlx::FromRow)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoginEvent {
+    pub id: Uuid,
+    pub session_id: Option<Uuid>,
+    pub provider_type: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub device: Option<String>,
+    pub country: Option<String>,
+    pub city: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub flags: Vec<String>, // new_device, new_country, impossible_travel
+    pub created_at: NaiveDateTime,
+    pub current: bool, // issued the session making the request
+}
@@ -0,0 +1,19 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct MfaCode {
+    pub code: String, // TOTP code or recovery code
+}
+
+#[derive(Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct MfaChallenge {
+    pub challenge_token: String,
+}
+
+#[derive(Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct MfaChallengeCode {
+    pub challenge_token: String,
+    pub code: String,
+}
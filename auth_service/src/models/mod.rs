@@ -1 +1,11 @@
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+pub mod identity;
+pub mod impersonation;
+pub mod lockout;
+pub mod login;
+pub mod mfa;
+pub mod oauth;
+pub mod password;
+pub mod session;
+pub mod share;
+pub mod shopify;
\ No newline at end of file
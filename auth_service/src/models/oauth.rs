@@ -0,0 +1,17 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+// OAuth2 bodies are form encoded (RFC 6749 / RFC 7662)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    pub scope: Option<String>,         // space separated
+    pub client_id: Option<String>,     // unless sent as HTTP Basic
+    pub client_secret: Option<String>, // unless sent as HTTP Basic
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IntrospectRequest {
+    pub token: String,
+    pub token_type_hint: Option<String>,
+}
@@ -0,0 +1,86 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct PasswordLogin {
+    pub email: String,
+    pub password: String,
+    // Only checked after repeated failures.
+    #[serde(default, skip_serializing)]
+    pub captcha_token: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct PasswordResetRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct PasswordReset {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct EmailVerification {
+    pub token: String,
+}
+
+#[derive(Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct PasswordChange {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// Password rules, read from `PASSWORD_MIN_LENGTH` and `PASSWORD_REQUIRE_{UPPERCASE,LOWERCASE,DIGIT,SYMBOL}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub require_uppercase: bool,
+    pub require_lowercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+}
+
+fn env_flag(key: &str, default: bool) -> bool {
+    std::env::var(key)
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(default)
+}
+
+impl PasswordPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            min_length: std::env::var("PASSWORD_MIN_LENGTH")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(12),
+            require_uppercase: env_flag("PASSWORD_REQUIRE_UPPERCASE", false),
+            require_lowercase: env_flag("PASSWORD_REQUIRE_LOWERCASE", false),
+            require_digit: env_flag("PASSWORD_REQUIRE_DIGIT", true),
+            require_symbol: env_flag("PASSWORD_REQUIRE_SYMBOL", false),
+        }
+    }
+
+    /// Returns every rule the password breaks, empty when it is accepted.
+    pub fn check(&self, password: &str) -> Vec<String> {
+        let mut errors = Vec::new();
+        if password.chars().count() < self.min_length {
+            errors.push(format!("Password must be at least {} characters", self.min_length));
+        }
+        if self.require_uppercase && !password.chars().any(|c| c.is_uppercase()) {
+            errors.push("Password must contain an uppercase letter".to_string());
+        }
+        if self.require_lowercase && !password.chars().any(|c| c.is_lowercase()) {
+            errors.push("Password must contain a lowercase letter".to_string());
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            errors.push("Password must contain a digit".to_string());
+        }
+        if self.require_symbol && password.chars().all(|c| c.is_alphanumeric()) {
+            errors.push("Password must contain a symbol".to_string());
+        }
+        errors
+    }
+}
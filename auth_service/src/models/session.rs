@@ -0,0 +1,41 @@
+use schemars::JsonSchema;
+use schemars::schema::Schema;
+use schemars::schema_for_value;
+use serde::Deserialize;
+use serde::Serialize;
+use sqlx::types::chrono::NaiveDateTime;
+use uuid::Uuid;
+
+#[derive(sqlx::FromRow)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub id: Uuid,
+    pub provider_type: String,
+    pub device: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    pub current: bool,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionId {
+    pub id: Uuid,
+}
+
+impl JsonSchema for SessionId {
+    fn schema_name() -> String {
+        "SessionId".into()
+    }
+
+    fn json_schema(_gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        let root_schema = schema_for_value!(SessionId::default());
+        Schema::Object(root_schema.schema)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct RefreshToken {
+    pub refresh_token: String,
+}
@@ -0,0 +1,47 @@
+use schemars::JsonSchema;
+use schemars::schema::Schema;
+use schemars::schema_for_value;
+use serde::Deserialize;
+use serde::Serialize;
+use sqlx::types::chrono::NaiveDateTime;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct CreateShareToken {
+    pub resource_type: String, // "video", "ws_board" or "folder"
+    pub resource_id: String,
+    pub action: String,        // "read", or "upload" for folders
+    pub ttl_minutes: Option<i64>,
+    pub max_uses: Option<i32>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShareTokenId {
+    pub id: Uuid,
+}
+
+impl JsonSchema for ShareTokenId {
+    fn schema_name() -> String {
+        "ShareTokenId".into()
+    }
+
+    fn json_schema(_gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        let root_schema = schema_for_value!(ShareTokenId::default());
+        Schema::Object(root_schema.schema)
+    }
+}
+
+#[derive(sqlx::FromRow)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShareTokenInfo {
+    pub id: Uuid,
+    pub resource_type: String,
+    pub resource_id: String,
+    pub action: String,
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    pub expires_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
@@ -0,0 +1,30 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+
+// App Bridge session token, from `shopify.idToken()` in the embedded app.
+#[derive(Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct ShopifySessionToken {
+    pub session_token: String,
+}
+
+// Claims of an App Bridge session token, signed with the app secret.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionTokenClaims {
+    pub iss: String,  // https://{shop}/admin
+    pub dest: String, // https://{shop}
+    pub aud: String,  // the app's api key
+    pub sub: Option<String>, // shop staff member id, missing for some app extensions
+    pub exp: usize,
+    pub nbf: usize,
+    pub iat: usize,
+    pub jti: String,
+    pub sid: Option<String>,
+}
+
+// Response of the authorization code exchange.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccessTokenResponse {
+    pub access_token: String,
+    pub scope: String,
+}
@@ -0,0 +1,254 @@
+use std::sync::Arc;
+
+use chrono::Duration;
+use microservice_utils::jwt::auth::hash_token;
+use rand::Rng;
+use sqlx::types::chrono::{NaiveDateTime, Utc};
+use sqlx::PgPool;
+use tonic::async_trait;
+use uuid::Uuid;
+
+use microservice_utils::server::sender::{Channel, Message, MessageSender};
+use super::{random_token, IdentityProvider, OAuthUser, ProviderError, SentCode, VerifiedUser};
+use crate::models::auth::{Email, PhoneNumber, StytchOTP, StytchToken};
+
+const OTP_KIND: &str = "otp";
+const MAGIC_LINK_KIND: &str = "magic_link";
+const DEFAULT_EXPIRATION_MINUTES: u32 = 5;
+const MAX_ATTEMPTS: i32 = 5;
+
+lazy_static! {
+    static ref MAGIC_LINK_URL: String = std::env::var("LOCAL_MAGIC_LINK_URL")
+        .unwrap_or_else(|_| "http://localhost:3000/authenticate".to_owned());
+}
+
+/// Self-hosted provider: users, OTPs and magic links live in our own database
+/// and messages go out through a `MessageSender`. OAuth is not supported.
+pub struct LocalProvider {
+    pool: PgPool,
+    sender: Arc<dyn MessageSender>,
+}
+
+impl LocalProvider {
+    pub fn new(pool: PgPool, sender: Arc<dyn MessageSender>) -> Self {
+        Self { pool, sender }
+    }
+
+    async fn send(&self, message: Message) -> Result<(), ProviderError> {
+        self.sender.send(&message).await.map_err(|e| {
+            println!("{:?}", e);
+            ProviderError::new(502, format!("{:?}", e))
+        })
+    }
+
+    async fn create_otp(
+        &self,
+        user_id: &String,
+        expiration_minutes: Option<u32>,
+    ) -> Result<(Uuid, String), ProviderError> {
+        let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+        let method_id =
+            db_create_code(user_id, OTP_KIND, &hash_token(&code), expires_at(expiration_minutes), &self.pool)
+                .await?;
+        Ok((method_id, code))
+    }
+}
+
+fn expires_at(expiration_minutes: Option<u32>) -> NaiveDateTime {
+    let minutes = expiration_minutes.unwrap_or(DEFAULT_EXPIRATION_MINUTES);
+    (Utc::now() + Duration::minutes(minutes as i64)).naive_utc()
+}
+
+#[async_trait]
+impl IdentityProvider for LocalProvider {
+    async fn send_magic_link(&self, email: &Email) -> Result<SentCode, ProviderError> {
+        let (user_id, user_created) = db_user_by_email(&email.email, &self.pool).await?;
+
+        let token = random_token();
+        let method_id = db_create_code(
+            &user_id,
+            MAGIC_LINK_KIND,
+            &hash_token(&token),
+            expires_at(email.expiration_minutes),
+            &self.pool,
+        )
+        .await?;
+
+        self.send(Message {
+            channel: Channel::Email,
+            to: email.email.clone(),
+            subject: "Your login link".to_string(),
+            body: format!("{}?token={}", MAGIC_LINK_URL.to_string(), token),
+        })
+        .await?;
+
+        Ok(SentCode {
+            user_created,
+            method_id: method_id.to_string(),
+            user_id,
+        })
+    }
+
+    async fn send_email_otp(&self, email: &Email) -> Result<SentCode, ProviderError> {
+        let (user_id, user_created) = db_user_by_email(&email.email, &self.pool).await?;
+        let (method_id, code) = self.create_otp(&user_id, email.expiration_minutes).await?;
+
+        self.send(Message {
+            channel: Channel::Email,
+            to: email.email.clone(),
+            subject: "Your login code".to_string(),
+            body: format!("Your login code is {}", code),
+        })
+        .await?;
+
+        Ok(SentCode {
+            user_created,
+            method_id: method_id.to_string(),
+            user_id,
+        })
+    }
+
+    async fn send_sms_otp(&self, phone: &PhoneNumber) -> Result<SentCode, ProviderError> {
+        let (user_id, user_created) = db_user_by_phone(&phone.phone_number, &self.pool).await?;
+        let (method_id, code) = self.create_otp(&user_id, phone.expiration_minutes).await?;
+
+        self.send(Message {
+            channel: Channel::Sms,
+            to: phone.phone_number.clone(),
+            subject: String::new(),
+            body: format!("Your login code is {}", code),
+        })
+        .await?;
+
+        Ok(SentCode {
+            user_created,
+            method_id: method_id.to_string(),
+            user_id,
+        })
+    }
+
+    async fn verify_magic_link(&self, token: &StytchToken) -> Result<VerifiedUser, ProviderError> {
+        let row = sqlx::query!(
+            "SELECT id, user_id, expires_at FROM local_codes WHERE code_hash = $1 AND kind = $2 AND used_at IS NULL",
+            hash_token(&token.token),
+            MAGIC_LINK_KIND
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| ProviderError::new(401, "Magic link is invalid"))?;
+
+        if row.expires_at < Utc::now().naive_utc() {
+            return Err(ProviderError::new(401, "Magic link is expired"));
+        }
+        db_use_code(&row.id, &self.pool).await?;
+
+        Ok(VerifiedUser {
+            user_id: row.user_id,
+        })
+    }
+
+    async fn verify_otp(&self, otp: &StytchOTP) -> Result<VerifiedUser, ProviderError> {
+        let method_id = Uuid::parse_str(&otp.method_id)
+            .map_err(|_| ProviderError::new(400, "Invalid method id"))?;
+
+        let row = sqlx::query!(
+            "SELECT id, user_id, code_hash, attempts, expires_at FROM local_codes WHERE id = $1 AND kind = $2 AND used_at IS NULL",
+            method_id,
+            OTP_KIND
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| ProviderError::new(401, "Code is invalid"))?;
+
+        if row.expires_at < Utc::now().naive_utc() || row.attempts >= MAX_ATTEMPTS {
+            return Err(ProviderError::new(401, "Code is expired"));
+        }
+        if row.code_hash != hash_token(&otp.code) {
+            sqlx::query!("UPDATE local_codes SET attempts = attempts + 1 WHERE id = $1", row.id)
+                .execute(&self.pool)
+                .await?;
+            return Err(ProviderError::new(401, "Code is invalid"));
+        }
+        db_use_code(&row.id, &self.pool).await?;
+
+        Ok(VerifiedUser {
+            user_id: row.user_id,
+        })
+    }
+
+    async fn verify_oauth(&self, _token: &StytchToken) -> Result<OAuthUser, ProviderError> {
+        Err(ProviderError::new(400, "OAuth is not supported by the local identity provider"))
+    }
+}
+
+// Database
+async fn db_user_by_email(email: &String, pool: &PgPool) -> Result<(String, bool), sqlx::Error> {
+    let created = sqlx::query!(
+        "INSERT INTO local_users (user_id, email) VALUES ($1, $2) ON CONFLICT (email) DO NOTHING RETURNING user_id",
+        Uuid::new_v4().to_string(),
+        email.to_lowercase()
+    )
+    .fetch_optional(pool)
+    .await?;
+    if let Some(row) = created {
+        return Ok((row.user_id, true));
+    }
+
+    let row = sqlx::query!("SELECT user_id FROM local_users WHERE email = $1", email.to_lowercase())
+        .fetch_one(pool)
+        .await?;
+    Ok((row.user_id, false))
+}
+
+async fn db_user_by_phone(phone_number: &String, pool: &PgPool) -> Result<(String, bool), sqlx::Error> {
+    let created = sqlx::query!(
+        "INSERT INTO local_users (user_id, phone_number) VALUES ($1, $2) ON CONFLICT (phone_number) DO NOTHING RETURNING user_id",
+        Uuid::new_v4().to_string(),
+        phone_number
+    )
+    .fetch_optional(pool)
+    .await?;
+    if let Some(row) = created {
+        return Ok((row.user_id, true));
+    }
+
+    let row = sqlx::query!("SELECT user_id FROM local_users WHERE phone_number = $1", phone_number)
+        .fetch_one(pool)
+        .await?;
+    Ok((row.user_id, false))
+}
+
+async fn db_create_code(
+    user_id: &String,
+    kind: &str,
+    code_hash: &String,
+    expires_at: NaiveDateTime,
+    pool: &PgPool,
+) -> Result<Uuid, sqlx::Error> {
+    let row = sqlx::query!(
+        "INSERT INTO local_codes (user_id, kind, code_hash, expires_at) VALUES ($1, $2, $3, $4) RETURNING id",
+        user_id,
+        kind,
+        code_hash,
+        expires_at
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(row.id)
+}
+
+// Marks a code as used, failing when a concurrent request already used it.
+async fn db_use_code(id: &Uuid, pool: &PgPool) -> Result<(), ProviderError> {
+    let res = sqlx::query!(
+        "UPDATE local_codes SET used_at = $1 WHERE id = $2 AND used_at IS NULL",
+        Utc::now().naive_utc(),
+        id
+    )
+    .execute(pool)
+    .await?;
+    if res.rows_affected() == 1 {
+        Ok(())
+    } else {
+        Err(ProviderError::new(401, "Code was already used"))
+    }
+}
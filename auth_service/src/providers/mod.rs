@@ -0,0 +1,137 @@
+use std::sync::Arc;
+
+use microservice_utils::server::response::{into_reponse, ResponseError};
+use rand::Rng;
+use serde::Serialize;
+use sqlx::PgPool;
+use tonic::async_trait;
+
+use crate::models::auth::{Email, PhoneNumber, StytchOTP, StytchToken};
+
+pub mod local;
+pub mod stytch;
+
+use local::LocalProvider;
+use microservice_utils::server::sender::MessageSender;
+use stytch::StytchProvider;
+
+/// Error returned by a provider, `code` is used as the response code.
+#[derive(Debug, Clone)]
+pub struct ProviderError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl ProviderError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<ProviderError> for ResponseError {
+    fn from(e: ProviderError) -> Self {
+        into_reponse(e.code, serde_json::json!({ "error": e.message }))
+    }
+}
+
+impl From<sqlx::Error> for ProviderError {
+    fn from(e: sqlx::Error) -> Self {
+        println!("{:?}", e.to_string());
+        ProviderError::new(500, format!("{:?}", e))
+    }
+}
+
+impl From<reqwest::Error> for ProviderError {
+    fn from(e: reqwest::Error) -> Self {
+        println!("{:?}", e.to_string());
+        ProviderError::new(502, format!("{:?}", e))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SentCode {
+    pub user_created: bool,
+    pub method_id: String,
+    pub user_id: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifiedUser {
+    pub user_id: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OAuthUser {
+    pub user_id: String,
+    pub provider_type: String,
+    pub id_token: serde_json::Value,
+    pub user: serde_json::Value,
+}
+
+/// Sends and verifies the login codes used by the auth endpoints.
+#[async_trait]
+pub trait IdentityProvider: Send + Sync {
+    async fn send_magic_link(&self, email: &Email) -> Result<SentCode, ProviderError>;
+    async fn send_email_otp(&self, email: &Email) -> Result<SentCode, ProviderError>;
+    async fn send_sms_otp(&self, phone: &PhoneNumber) -> Result<SentCode, ProviderError>;
+    async fn verify_magic_link(&self, token: &StytchToken) -> Result<VerifiedUser, ProviderError>;
+    async fn verify_otp(&self, otp: &StytchOTP) -> Result<VerifiedUser, ProviderError>;
+    async fn verify_oauth(&self, token: &StytchToken) -> Result<OAuthUser, ProviderError>;
+}
+
+/// Random hex token for links sent by email.
+pub fn random_token() -> String {
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ProviderKind {
+    Stytch,
+    Local,
+}
+
+fn provider_kind(value: Option<&str>) -> anyhow::Result<ProviderKind> {
+    match value {
+        None | Some("stytch") => Ok(ProviderKind::Stytch),
+        Some("local") => Ok(ProviderKind::Local),
+        Some(other) => Err(anyhow::anyhow!("Unknown IDENTITY_PROVIDER {:?}, expected stytch or local", other)),
+    }
+}
+
+fn provider_kind_from_env() -> anyhow::Result<ProviderKind> {
+    provider_kind(std::env::var("IDENTITY_PROVIDER").ok().as_deref())
+}
+
+/// Fails when `IDENTITY_PROVIDER` is unknown, or is Stytch without its credentials.
+pub fn check_provider_config() -> anyhow::Result<()> {
+    match provider_kind_from_env()? {
+        ProviderKind::Stytch => StytchProvider::from_env().map(|_| ()),
+        ProviderKind::Local => Ok(()),
+    }
+}
+
+/// Picks the provider from `IDENTITY_PROVIDER` (`stytch` by default, or `local`).
+pub fn provider_from_env(pool: &PgPool, sender: Arc<dyn MessageSender>) -> anyhow::Result<Arc<dyn IdentityProvider>> {
+    Ok(match provider_kind_from_env()? {
+        ProviderKind::Local => Arc::new(LocalProvider::new(pool.clone(), sender)),
+        ProviderKind::Stytch => Arc::new(StytchProvider::from_env()?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_providers_are_refused() {
+        assert_eq!(provider_kind(None).unwrap(), ProviderKind::Stytch);
+        assert_eq!(provider_kind(Some("stytch")).unwrap(), ProviderKind::Stytch);
+        assert_eq!(provider_kind(Some("local")).unwrap(), ProviderKind::Local);
+        assert!(provider_kind(Some("Local")).is_err());
+        assert!(provider_kind(Some("")).is_err());
+    }
+}
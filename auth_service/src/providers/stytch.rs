@@ -0,0 +1,123 @@
+use serde::Serialize;
+use tonic::async_trait;
+
+use super::{IdentityProvider, OAuthUser, ProviderError, SentCode, VerifiedUser};
+use crate::models::auth::{Email, PhoneNumber, StytchOTP, StytchToken};
+
+lazy_static! {
+    static ref BASE_URL: String = "https://api.stytch.com/v1".to_owned();
+}
+
+fn required_var(name: &str) -> anyhow::Result<String> {
+    std::env::var(name)
+        .ok()
+        .filter(|v| !v.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("{} is not set", name))
+}
+
+// Google
+// https://api.stytch.com/v1/public/oauth/google/start?public_token=public-token-live-3780acd3-6da2-4987-84e0-abc2207f6508&custom_scopes=https://www.googleapis.com/auth/contacts.readonly
+
+// Outlook
+// https://api.stytch.com/v1/public/oauth/microsoft/start?public_token=public-token-live-3780acd3-6da2-4987-84e0-abc2207f6508&custom_scopes=Contacts.Read
+
+// LinkedIn
+// https://api.stytch.com/v1/public/oauth/linkedin/start?public_token=public-token-live-3780acd3-6da2-4987-84e0-abc2207f6508
+
+pub struct StytchProvider {
+    client: reqwest::Client,
+    project_id: String,
+    secret: String,
+}
+
+impl StytchProvider {
+    /// Reads the credentials of the Stytch project from `STYTCH_PROJECT_ID` and `STYTCH_SECRET`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::new(),
+            project_id: required_var("STYTCH_PROJECT_ID")?,
+            secret: required_var("STYTCH_SECRET")?,
+        })
+    }
+
+    async fn post<T: Serialize + ?Sized>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> Result<serde_json::Value, ProviderError> {
+        let response = self
+            .client
+            .post(format!("{}{}", BASE_URL.to_string(), path))
+            .basic_auth(&self.project_id, Some(&self.secret))
+            .json(body)
+            .send()
+            .await?;
+
+        let v: serde_json::Value = response.json().await?;
+        let code = v["status_code"].as_i64().unwrap_or(500);
+        if code == 200 {
+            Ok(v)
+        } else {
+            let message = v["error_message"].as_str().unwrap_or("Stytch request failed");
+            println!("{:?}", message);
+            Err(ProviderError::new(code, message))
+        }
+    }
+}
+
+fn string_field(v: &serde_json::Value, field: &str) -> Result<String, ProviderError> {
+    v[field]
+        .as_str()
+        .map(|s| s.to_string())
+        .ok_or_else(|| ProviderError::new(502, format!("Stytch response is missing {}", field)))
+}
+
+fn sent_code(v: &serde_json::Value, method_field: &str) -> Result<SentCode, ProviderError> {
+    Ok(SentCode {
+        user_created: v["user_created"].as_bool().unwrap_or(false),
+        method_id: string_field(v, method_field)?,
+        user_id: string_field(v, "user_id")?,
+    })
+}
+
+#[async_trait]
+impl IdentityProvider for StytchProvider {
+    async fn send_magic_link(&self, email: &Email) -> Result<SentCode, ProviderError> {
+        let v = self.post("/magic_links/email/login_or_create", email).await?;
+        sent_code(&v, "email_id")
+    }
+
+    async fn send_email_otp(&self, email: &Email) -> Result<SentCode, ProviderError> {
+        let v = self.post("/otps/email/login_or_create", email).await?;
+        sent_code(&v, "email_id")
+    }
+
+    async fn send_sms_otp(&self, phone: &PhoneNumber) -> Result<SentCode, ProviderError> {
+        let v = self.post("/otps/sms/login_or_create", phone).await?;
+        sent_code(&v, "phone_id")
+    }
+
+    async fn verify_magic_link(&self, token: &StytchToken) -> Result<VerifiedUser, ProviderError> {
+        let v = self.post("/magic_links/authenticate", token).await?;
+        Ok(VerifiedUser {
+            user_id: string_field(&v, "user_id")?,
+        })
+    }
+
+    async fn verify_otp(&self, otp: &StytchOTP) -> Result<VerifiedUser, ProviderError> {
+        let v = self.post("/otps/authenticate", otp).await?;
+        Ok(VerifiedUser {
+            user_id: string_field(&v, "user_id")?,
+        })
+    }
+
+    async fn verify_oauth(&self, token: &StytchToken) -> Result<OAuthUser, ProviderError> {
+        let v = self.post("/oauth/authenticate", token).await?;
+        Ok(OAuthUser {
+            user_id: string_field(&v, "user_id")?,
+            provider_type: string_field(&v, "provider_type")?,
+            id_token: v["provider_values"]["access_token"].clone(),
+            user: v["user"].clone(),
+        })
+    }
+}
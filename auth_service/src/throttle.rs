@@ -0,0 +1,467 @@
+use chrono::Duration;
+use microservice_utils::server::client_info::ClientInfo;
+use microservice_utils::server::response::{into_reponse, AxumResult};
+use sqlx::types::chrono::{NaiveDateTime, Utc};
+use sqlx::PgPool;
+
+use crate::handlers::auth_handler::internal_error;
+
+pub const IDENTIFIER_SCOPE: &str = "identifier";
+pub const IP_SCOPE: &str = "ip";
+
+fn env_i64(name: &str, default: i64) -> i64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+lazy_static! {
+    // Failures within ATTEMPT_WINDOW_MINUTES that lock a key.
+    static ref MAX_FAILURES: i64 = env_i64("ATTEMPT_MAX_FAILURES", 5);
+    // Failures after which a captcha has to be solved.
+    static ref CAPTCHA_AFTER_FAILURES: i64 = env_i64("CAPTCHA_AFTER_FAILURES", 3);
+    static ref ATTEMPT_WINDOW_MINUTES: i64 = env_i64("ATTEMPT_WINDOW_MINUTES", 15);
+    // The first lockout lasts LOCKOUT_BASE_SECONDS and doubles with every following one.
+    static ref LOCKOUT_BASE_SECONDS: i64 = env_i64("LOCKOUT_BASE_SECONDS", 60);
+    static ref LOCKOUT_MAX_SECONDS: i64 = env_i64("LOCKOUT_MAX_SECONDS", 86400);
+    static ref SEND_MIN_INTERVAL_SECONDS: i64 = env_i64("SEND_MIN_INTERVAL_SECONDS", 30);
+    static ref SEND_MAX_PER_HOUR: i64 = env_i64("SEND_MAX_PER_HOUR", 5);
+    static ref SEND_MAX_PER_HOUR_PER_IP: i64 = env_i64("SEND_MAX_PER_HOUR_PER_IP", 20);
+    // Required, the service refuses to start without it (see `check_captcha_secret`).
+    static ref CAPTCHA_SECRET: Option<String> = std::env::var("CAPTCHA_SECRET").ok().filter(|s| !s.is_empty());
+    static ref CAPTCHA_VERIFY_URL: String = std::env::var("CAPTCHA_VERIFY_URL")
+        .unwrap_or_else(|_| "https://hcaptcha.com/siteverify".to_owned());
+}
+
+/// Fails without a captcha secret, captchas couldn't be verified.
+pub fn check_captcha_secret() -> anyhow::Result<()> {
+    match CAPTCHA_SECRET.as_ref() {
+        Some(_) => Ok(()),
+        None => Err(anyhow::anyhow!("CAPTCHA_SECRET is not set")),
+    }
+}
+
+/// A counter of verification attempts, counted before verifying.
+#[derive(Debug, Clone)]
+pub struct AttemptKey {
+    pub scope: &'static str,
+    pub key: String,
+}
+
+impl AttemptKey {
+    pub fn identifier(key: impl Into<String>) -> Self {
+        Self {
+            scope: IDENTIFIER_SCOPE,
+            key: key.into(),
+        }
+    }
+
+    pub fn ip(client: &ClientInfo) -> Option<Self> {
+        client.ip.clone().map(|ip| Self { scope: IP_SCOPE, key: ip })
+    }
+}
+
+// Counter of the second factor codes of a user, whatever the challenge or endpoint.
+pub fn mfa_keys(user_id: &String) -> Vec<AttemptKey> {
+    vec![AttemptKey::identifier(format!("mfa:{}", user_id))]
+}
+
+// Counters for an OTP verification: the method id and the caller's ip.
+pub fn otp_keys(method_id: &String, client: &ClientInfo) -> Vec<AttemptKey> {
+    let mut keys = vec![AttemptKey::identifier(format!("otp:{}", method_id))];
+    keys.extend(AttemptKey::ip(client));
+    keys
+}
+
+// Counters for a password login: the email and the caller's ip.
+pub fn password_keys(email: &String, client: &ClientInfo) -> Vec<AttemptKey> {
+    let mut keys = vec![AttemptKey::identifier(format!("password:{}", email))];
+    keys.extend(AttemptKey::ip(client));
+    keys
+}
+
+fn lockout_seconds(lockouts: i32) -> i64 {
+    let factor = 2i64.saturating_pow(lockouts.max(0) as u32);
+    LOCKOUT_BASE_SECONDS.saturating_mul(factor).min(*LOCKOUT_MAX_SECONDS)
+}
+
+/// Counts the attempt on every key before it is verified, so concurrent attempts can't
+/// all pass the check, and rejects it while a key is locked or out of attempts. A captcha is
+/// required once a key has seen `CAPTCHA_AFTER_FAILURES` recent attempts.
+///
+/// The caller reports the outcome with `record_success`, `record_failure` or `release_attempts`.
+pub async fn check_attempts(
+    keys: &[AttemptKey],
+    captcha_token: Option<&String>,
+    client: &ClientInfo,
+    pool: &PgPool,
+) -> AxumResult<()> {
+    let captcha_required = count_attempts(keys, client, pool).await?;
+    if captcha_required && !verify_captcha(captcha_token, client).await {
+        release_attempts(keys, pool).await?;
+        return Err(into_reponse(
+            400,
+            serde_json::json!({
+                "error": "Captcha required",
+                "captcha_required": true,
+            }),
+        ));
+    }
+    Ok(())
+}
+
+/// `check_attempts` without a captcha, for second factors: the lockout alone leaves no
+/// chance to guess a code, and the user already passed the first factor.
+pub async fn check_lockout(keys: &[AttemptKey], client: &ClientInfo, pool: &PgPool) -> AxumResult<()> {
+    count_attempts(keys, client, pool).await.map(|_| ())
+}
+
+// Counts the attempt, returning whether a captcha is required.
+async fn count_attempts(keys: &[AttemptKey], client: &ClientInfo, pool: &PgPool) -> AxumResult<bool> {
+    let now = Utc::now().naive_utc();
+    let window_start = now - Duration::minutes(*ATTEMPT_WINDOW_MINUTES);
+    let mut captcha_required = false;
+
+    for (i, key) in keys.iter().enumerate() {
+        let counted = db_add_attempt(key, now, window_start, pool)
+            .await
+            .map_err(internal_error)?;
+        let locked_until = match counted {
+            // more concurrent attempts than allowed, the key is locked from now on
+            Some((attempts, _)) if attempts as i64 > *MAX_FAILURES => {
+                db_lock(key, now, client, pool).await.map_err(internal_error)?
+            }
+            Some((attempts, _)) => {
+                if attempts as i64 > *CAPTCHA_AFTER_FAILURES {
+                    captcha_required = true;
+                }
+                None
+            }
+            None => db_locked_until(key, pool).await.map_err(internal_error)?,
+        };
+        if let Some(locked_until) = locked_until.filter(|until| *until > now) {
+            release_attempts(&keys[..i], pool).await?;
+            return Err(into_reponse(
+                429,
+                serde_json::json!({
+                    "error": "Too many attempts, try again later",
+                    "retry_after": (locked_until - now).num_seconds().max(1),
+                    "captcha_required": true,
+                }),
+            ));
+        }
+    }
+    Ok(captcha_required)
+}
+
+async fn verify_captcha(captcha_token: Option<&String>, client: &ClientInfo) -> bool {
+    let secret = match CAPTCHA_SECRET.as_ref() {
+        Some(secret) => secret,
+        None => return false,
+    };
+    let token = match captcha_token {
+        Some(token) => token,
+        None => return false,
+    };
+
+    let mut form = vec![("secret", secret.clone()), ("response", token.clone())];
+    if let Some(ip) = &client.ip {
+        form.push(("remoteip", ip.clone()));
+    }
+    let res = reqwest::Client::new()
+        .post(CAPTCHA_VERIFY_URL.to_string())
+        .form(&form)
+        .send()
+        .await;
+    match res {
+        Ok(res) => match res.json::<serde_json::Value>().await {
+            Ok(v) => v["success"].as_bool().unwrap_or(false),
+            Err(e) => {
+                println!("{:?}", e.to_string());
+                false
+            }
+        },
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            false
+        }
+    }
+}
+
+/// The attempt failed. A key that used up `MAX_FAILURES` attempts is locked for a duration
+/// that doubles with every lockout and the lockout is recorded in `lockout_events`.
+pub async fn record_failure(keys: &[AttemptKey], client: &ClientInfo, pool: &PgPool) -> AxumResult<()> {
+    let now = Utc::now().naive_utc();
+    for key in keys {
+        db_lock(key, now, client, pool).await.map_err(internal_error)?;
+    }
+    Ok(())
+}
+
+// A successful verification clears the identifier counters; ip counters only get the
+// attempt back, they expire.
+pub async fn record_success(keys: &[AttemptKey], pool: &PgPool) -> AxumResult<()> {
+    for key in keys {
+        if key.scope == IDENTIFIER_SCOPE {
+            db_clear_attempt(key, pool).await.map_err(internal_error)?;
+        } else {
+            db_release_attempt(key, pool).await.map_err(internal_error)?;
+        }
+    }
+    Ok(())
+}
+
+// The attempt couldn't be verified, e.g. the provider is down, and doesn't count.
+pub async fn release_attempts(keys: &[AttemptKey], pool: &PgPool) -> AxumResult<()> {
+    for key in keys {
+        db_release_attempt(key, pool).await.map_err(internal_error)?;
+    }
+    Ok(())
+}
+
+/// Limits how often codes are sent to a destination and from an ip, so the send
+/// endpoints can't be used to spam arbitrary numbers or inboxes.
+pub async fn check_send(destination: &String, client: &ClientInfo, pool: &PgPool) -> AxumResult<()> {
+    // The minimum interval only applies per destination, many clients can share an ip.
+    let mut keys = vec![(
+        format!("to:{}", destination),
+        *SEND_MAX_PER_HOUR,
+        *SEND_MIN_INTERVAL_SECONDS,
+    )];
+    if let Some(ip) = &client.ip {
+        keys.push((format!("ip:{}", ip), *SEND_MAX_PER_HOUR_PER_IP, 0));
+    }
+
+    for (key, max_per_hour, min_interval_seconds) in keys {
+        let allowed = db_count_send(&key, max_per_hour, min_interval_seconds, pool)
+            .await
+            .map_err(internal_error)?;
+        if !allowed {
+            return Err(into_reponse(
+                429,
+                serde_json::json!({ "error": "Too many codes requested, try again later" }),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Database
+// Counts an attempt unless the key is locked, returning the attempts in the current window
+// (this one included) and the lockouts so far. Lockouts are forgiven after a day without attempts.
+async fn db_add_attempt(
+    key: &AttemptKey,
+    now: NaiveDateTime,
+    window_start: NaiveDateTime,
+    pool: &PgPool,
+) -> Result<Option<(i32, i32)>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"INSERT INTO auth_attempts (scope, key, failures, last_failure_at) VALUES ($1, $2, 1, $3)
+        ON CONFLICT (scope, key) DO UPDATE SET
+            failures = CASE WHEN auth_attempts.last_failure_at < $4 THEN 1 ELSE auth_attempts.failures + 1 END,
+            lockouts = CASE WHEN auth_attempts.last_failure_at < $5 THEN 0 ELSE auth_attempts.lockouts END,
+            last_failure_at = $3
+        WHERE auth_attempts.locked_until IS NULL OR auth_attempts.locked_until <= $3
+        RETURNING failures, lockouts"#,
+        key.scope,
+        key.key,
+        now,
+        window_start,
+        now - Duration::days(1)
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| (r.failures, r.lockouts)))
+}
+
+async fn db_locked_until(key: &AttemptKey, pool: &PgPool) -> Result<Option<NaiveDateTime>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT locked_until FROM auth_attempts WHERE scope = $1 AND key = $2",
+        key.scope,
+        key.key
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.and_then(|r| r.locked_until))
+}
+
+// Locks the key once it used up its attempts, returning until when. Only one of concurrent
+// callers locks it, the others see it locked.
+async fn db_lock(
+    key: &AttemptKey,
+    now: NaiveDateTime,
+    client: &ClientInfo,
+    pool: &PgPool,
+) -> Result<Option<NaiveDateTime>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query!(
+        "SELECT failures, lockouts, locked_until FROM auth_attempts WHERE scope = $1 AND key = $2 FOR UPDATE",
+        key.scope,
+        key.key
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    let row = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+    if row.locked_until.filter(|until| *until > now).is_some() {
+        return Ok(row.locked_until);
+    }
+    if (row.failures as i64) < *MAX_FAILURES {
+        return Ok(None);
+    }
+
+    let locked_until = now + Duration::seconds(lockout_seconds(row.lockouts));
+    let lockouts = row.lockouts + 1;
+    sqlx::query!(
+        "UPDATE auth_attempts SET failures = 0, lockouts = $1, locked_until = $2 WHERE scope = $3 AND key = $4",
+        lockouts,
+        locked_until,
+        key.scope,
+        key.key
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO lockout_events (scope, key, ip, user_agent, lockouts, locked_until) VALUES ($1, $2, $3, $4, $5, $6)",
+        key.scope,
+        key.key,
+        client.ip,
+        client.user_agent,
+        lockouts,
+        locked_until
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(Some(locked_until))
+}
+
+async fn db_release_attempt(key: &AttemptKey, pool: &PgPool) -> Result<(), sqlx::Error> {
+    let _ = sqlx::query!(
+        "UPDATE auth_attempts SET failures = GREATEST(failures - 1, 0) WHERE scope = $1 AND key = $2",
+        key.scope,
+        key.key
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn db_clear_attempt(key: &AttemptKey, pool: &PgPool) -> Result<(), sqlx::Error> {
+    let _ = sqlx::query!(
+        "UPDATE auth_attempts SET failures = 0, locked_until = NULL WHERE scope = $1 AND key = $2",
+        key.scope,
+        key.key
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// Counts a send, returning false without counting it when the key is throttled.
+async fn db_count_send(
+    key: &String,
+    max_per_hour: i64,
+    min_interval_seconds: i64,
+    pool: &PgPool,
+) -> Result<bool, sqlx::Error> {
+    let now = Utc::now().naive_utc();
+    let row = sqlx::query!(
+        r#"INSERT INTO send_throttle (key, window_start, sent_count, last_sent_at) VALUES ($1, $2, 1, $2)
+        ON CONFLICT (key) DO UPDATE SET
+            window_start = CASE WHEN send_throttle.window_start < $3 THEN $2 ELSE send_throttle.window_start END,
+            sent_count = CASE WHEN send_throttle.window_start < $3 THEN 1 ELSE send_throttle.sent_count + 1 END,
+            last_sent_at = $2
+        WHERE send_throttle.last_sent_at <= $4
+            AND (send_throttle.window_start < $3 OR send_throttle.sent_count < $5)
+        RETURNING key"#,
+        key,
+        now,
+        now - Duration::hours(1),
+        now - Duration::seconds(min_interval_seconds),
+        max_per_hour as i32
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::{test_pool, test_user_id};
+
+    async fn failures(key: &AttemptKey, pool: &PgPool) -> i32 {
+        sqlx::query_scalar("SELECT failures FROM auth_attempts WHERE scope = $1 AND key = $2")
+            .bind(key.scope)
+            .bind(&key.key)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn concurrent_attempts_cannot_skip_the_captcha() {
+        let pool = test_pool().await;
+        let keys = vec![AttemptKey::identifier(test_user_id())];
+        let client = ClientInfo::default();
+
+        let attempts = (0..10).map(|_| check_attempts(&keys, None, &client, &pool));
+        let passed = futures_util::future::join_all(attempts)
+            .await
+            .into_iter()
+            .filter(|res| res.is_ok())
+            .count();
+        assert_eq!(passed as i64, *CAPTCHA_AFTER_FAILURES);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn key_out_of_attempts_is_locked_before_verifying() {
+        let pool = test_pool().await;
+        let key = AttemptKey::identifier(test_user_id());
+        let client = ClientInfo::default();
+        sqlx::query("INSERT INTO auth_attempts (scope, key, failures) VALUES ($1, $2, $3)")
+            .bind(key.scope)
+            .bind(&key.key)
+            .bind(*MAX_FAILURES as i32)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert!(check_attempts(&[key.clone()], None, &client, &pool).await.is_err());
+        assert!(db_locked_until(&key, &pool).await.unwrap().is_some());
+        let lockouts: i64 = sqlx::query_scalar("SELECT count(*) FROM lockout_events WHERE key = $1")
+            .bind(&key.key)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(lockouts, 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn unverified_attempts_are_given_back() {
+        let pool = test_pool().await;
+        let keys = vec![AttemptKey::identifier(test_user_id())];
+        let client = ClientInfo::default();
+
+        check_attempts(&keys, None, &client, &pool).await.unwrap();
+        assert_eq!(failures(&keys[0], &pool).await, 1);
+        release_attempts(&keys, &pool).await.unwrap();
+        assert_eq!(failures(&keys[0], &pool).await, 0);
+    }
+
+    #[tokio::test]
+    async fn captcha_fails_closed() {
+        crate::test_db::test_env();
+        let client = ClientInfo::default();
+        assert!(!verify_captcha(None, &client).await);
+        // the verification endpoint can't be reached
+        assert!(!verify_captcha(Some(&"token".to_string()), &client).await);
+    }
+}
@@ -20,12 +20,23 @@ CREATE TABLE IF NOT EXISTS generic_contacts (
     provider TEXT NOT NULL,
     name TEXT,
     photo TEXT,
-    phone_numbers TEXT[],
-    email_addresses TEXT[],
+    phone_numbers TEXT[], -- encrypted
+    email_addresses TEXT[], -- encrypted
+    phone_index TEXT[], -- blind indexes of the phone numbers
+    email_index TEXT[], -- blind indexes of the email addresses
+    search_index TEXT[], -- blind indexes of the n-grams of both, for partial matches
     FOREIGN KEY(user_id)
         REFERENCES contacts(user_id)
 );
 
+ALTER TABLE generic_contacts ADD COLUMN IF NOT EXISTS phone_index TEXT[];
+ALTER TABLE generic_contacts ADD COLUMN IF NOT EXISTS email_index TEXT[];
+ALTER TABLE generic_contacts ADD COLUMN IF NOT EXISTS search_index TEXT[];
+
+CREATE INDEX IF NOT EXISTS generic_contacts_phone_index_idx ON generic_contacts USING GIN (phone_index);
+CREATE INDEX IF NOT EXISTS generic_contacts_email_index_idx ON generic_contacts USING GIN (email_index);
+CREATE INDEX IF NOT EXISTS generic_contacts_search_index_idx ON generic_contacts USING GIN (search_index);
+
 CREATE TABLE IF NOT EXISTS tag_name (
     id uuid DEFAULT uuid_generate_v4(),
     user_id TEXT NOT NULL,
@@ -122,4 +133,70 @@ CREATE TABLE IF NOT EXISTS shopify_customer_addresses (
     zip TEXT NOT NULL,    
     FOREIGN KEY(address_customer_id)
         REFERENCES shopify_contacts(customer_id)
-);
\ No newline at end of file
+);
+
+-- Row-level security: rows are only visible to the tenant set by microservice_utils::server::tenant
+-- (app.current_user / app.current_workspace). Without a tenant in the transaction nothing is
+-- visible; internal paths that cross tenants use a service transaction (app.service_role).
+-- Superusers and BYPASSRLS roles skip policies entirely, so the service has to connect as a
+-- regular role for this to take effect.
+CREATE OR REPLACE FUNCTION app_current_user() RETURNS TEXT AS $$
+    SELECT NULLIF(current_setting('app.current_user', true), '')
+$$ LANGUAGE SQL STABLE;
+
+CREATE OR REPLACE FUNCTION app_current_workspace() RETURNS TEXT AS $$
+    SELECT NULLIF(current_setting('app.current_workspace', true), '')
+$$ LANGUAGE SQL STABLE;
+
+CREATE OR REPLACE FUNCTION app_service_role() RETURNS BOOLEAN AS $$
+    SELECT COALESCE(current_setting('app.service_role', true), '') <> ''
+$$ LANGUAGE SQL STABLE;
+
+ALTER TABLE contacts ENABLE ROW LEVEL SECURITY;
+ALTER TABLE contacts FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON contacts;
+CREATE POLICY tenant_isolation ON contacts
+    USING (app_service_role() OR user_id = app_current_user())
+    WITH CHECK (app_service_role() OR user_id = app_current_user());
+
+ALTER TABLE generic_contacts ENABLE ROW LEVEL SECURITY;
+ALTER TABLE generic_contacts FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON generic_contacts;
+CREATE POLICY tenant_isolation ON generic_contacts
+    USING (app_service_role() OR user_id = app_current_user())
+    WITH CHECK (app_service_role() OR user_id = app_current_user());
+
+ALTER TABLE tag_name ENABLE ROW LEVEL SECURITY;
+ALTER TABLE tag_name FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON tag_name;
+CREATE POLICY tenant_isolation ON tag_name
+    USING (app_service_role() OR user_id = app_current_user())
+    WITH CHECK (app_service_role() OR user_id = app_current_user());
+
+ALTER TABLE tag_contacts ENABLE ROW LEVEL SECURITY;
+ALTER TABLE tag_contacts FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON tag_contacts;
+CREATE POLICY tenant_isolation ON tag_contacts
+    USING (app_service_role() OR user_id = app_current_user())
+    WITH CHECK (app_service_role() OR user_id = app_current_user());
+
+ALTER TABLE shopify_contacts ENABLE ROW LEVEL SECURITY;
+ALTER TABLE shopify_contacts FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON shopify_contacts;
+CREATE POLICY tenant_isolation ON shopify_contacts
+    USING (app_service_role() OR user_id = app_current_user())
+    WITH CHECK (app_service_role() OR user_id = app_current_user());
+
+ALTER TABLE shopify_customer_orders ENABLE ROW LEVEL SECURITY;
+ALTER TABLE shopify_customer_orders FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON shopify_customer_orders;
+CREATE POLICY tenant_isolation ON shopify_customer_orders
+    USING (app_service_role() OR EXISTS (SELECT 1 FROM shopify_contacts c WHERE c.customer_id = order_customer_id))
+    WITH CHECK (app_service_role() OR EXISTS (SELECT 1 FROM shopify_contacts c WHERE c.customer_id = order_customer_id));
+
+ALTER TABLE shopify_customer_addresses ENABLE ROW LEVEL SECURITY;
+ALTER TABLE shopify_customer_addresses FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON shopify_customer_addresses;
+CREATE POLICY tenant_isolation ON shopify_customer_addresses
+    USING (app_service_role() OR EXISTS (SELECT 1 FROM shopify_contacts c WHERE c.customer_id = address_customer_id))
+    WITH CHECK (app_service_role() OR EXISTS (SELECT 1 FROM shopify_contacts c WHERE c.customer_id = address_customer_id));
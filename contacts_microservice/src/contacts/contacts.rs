@@ -10,6 +10,7 @@ use serde::Deserialize;
 use serde::Serialize;
 use serde::Deserializer;
 
+use microservice_utils::crypto::decrypt_all;
 use serde_json::Value;
 use shopify::{customer::Customer, customer_address::CustomerAddress, order::Order};
 use sqlx::types::Json;
@@ -74,6 +75,19 @@ pub struct GenericContact {
     pub email_addresses: Option<Vec<String>>,
 }
 
+impl GenericContact {
+    // Phone numbers and email addresses are stored encrypted, see `db_upsert_generic_contact`.
+    pub fn decrypt(mut self) -> anyhow::Result<Self> {
+        if let Some(phone_numbers) = &self.phone_numbers {
+            self.phone_numbers = Some(decrypt_all(phone_numbers)?);
+        }
+        if let Some(email_addresses) = &self.email_addresses {
+            self.email_addresses = Some(decrypt_all(email_addresses)?);
+        }
+        Ok(self)
+    }
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize)]
 pub struct GoogleContacts {
     pub contacts: Vec<GenericContact>,
@@ -0,0 +1,99 @@
+use sqlx::PgPool;
+use tonic::async_trait;
+use tonic::Status;
+
+use microservice_utils::server::export::{csv_file, db_export_rows, decrypt_fields, export_response};
+use microservice_utils::server::grpc::data_export::data_export_server::DataExport;
+use microservice_utils::server::grpc::data_export::{ExportFile, ExportUserDataRequest, ExportUserDataResponse};
+use microservice_utils::server::service_auth::{authorize, USER_SERVICE};
+
+// gRPC
+pub struct MyDataExport {
+    pool: PgPool
+}
+
+impl MyDataExport {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool
+        }
+    }
+}
+
+#[async_trait]
+impl DataExport for MyDataExport {
+    async fn export_user_data(
+        &self,
+        request: tonic::Request<ExportUserDataRequest>,
+    ) -> Result<tonic::Response<ExportUserDataResponse>, Status> {
+
+        authorize(&request, &[USER_SERVICE])?;
+
+        let req: ExportUserDataRequest = request.into_inner();
+        println!("Export User Data {}", req.user_id);
+
+        let files = export_files(&req.user_id, &self.pool)
+            .await
+            .map_err(|e| Status::internal(format!("{:?}", e)))?;
+        Ok(tonic::Response::new(export_response(files)))
+    }
+}
+
+async fn export_files(user_id: &String, pool: &PgPool) -> anyhow::Result<Vec<ExportFile>> {
+    // Blind indexes are left out, they are only useful with the master key.
+    let mut contacts = db_export_rows(
+        "SELECT id, identifier, provider, name, photo, phone_numbers, email_addresses
+        FROM generic_contacts WHERE user_id = $1 ORDER BY id",
+        user_id,
+        pool,
+    ).await?;
+    decrypt_fields(&mut contacts, &["phone_numbers", "email_addresses"])?;
+
+    Ok(vec![
+        csv_file("contacts", &contacts),
+        csv_file(
+            "tags",
+            &db_export_rows("SELECT id, name FROM tag_name WHERE user_id = $1 ORDER BY name", user_id, pool).await?,
+        ),
+        csv_file(
+            "tag_contacts",
+            &db_export_rows(
+                "SELECT tag_id, identifier FROM tag_contacts WHERE user_id = $1 ORDER BY id",
+                user_id,
+                pool,
+            ).await?,
+        ),
+        csv_file(
+            "shopify_customers",
+            &db_export_rows(
+                "SELECT customer_id, first_name, last_name, customer_email, customer_phone, accepts_marketing,
+                verified_email, tax_exempt, note, currency, orders_count, total_spent, last_order_id, created_at, updated_at
+                FROM shopify_contacts WHERE user_id = $1 ORDER BY created_at",
+                user_id,
+                pool,
+            ).await?,
+        ),
+        csv_file(
+            "shopify_orders",
+            &db_export_rows(
+                "SELECT o.order_id, o.order_customer_id, o.order_name, o.order_number, o.order_email,
+                o.financial_status, o.fulfillment_status, o.order_currency, o.subtotal_price, o.total_tax,
+                o.total_price, o.line_items, o.cancel_reason, o.cancelled_at, o.processed_at, o.order_created_at
+                FROM shopify_customer_orders o JOIN shopify_contacts c ON c.customer_id = o.order_customer_id
+                WHERE c.user_id = $1 ORDER BY o.processed_at",
+                user_id,
+                pool,
+            ).await?,
+        ),
+        csv_file(
+            "shopify_addresses",
+            &db_export_rows(
+                "SELECT a.address_customer_id, a.company, a.address1, a.address2, a.city, a.zip,
+                a.province, a.country_name FROM shopify_customer_addresses a
+                JOIN shopify_contacts c ON c.customer_id = a.address_customer_id WHERE c.user_id = $1",
+                user_id,
+                pool,
+            ).await?,
+        ),
+    ])
+}
@@ -13,7 +13,7 @@ use shopify::customer_address::CustomerAddress;
 use shopify::order::{Currency, Order};
 use sqlx::types::chrono::DateTime;
 use sqlx::types::chrono::Utc;
-use sqlx::PgPool;
+use sqlx::postgres::{PgConnection, PgPool};
 use std::str::FromStr;
 use std::sync::Arc;
 use std::fmt::Write;
@@ -28,9 +28,16 @@ use crate::{
         contacts::{ContactQuery, ContactRes, ContactSync},
     },
 };
+use microservice_utils::server::service_auth::{authorize, AUTH_SERVICE};
 use microservice_utils::server::response::{AxumRes,into_reponse, AxumResult};
 use microservice_utils::server::grpc::{get_shopify_token};
+use microservice_utils::server::erasure::{db_erase_user_rows, Erasure};
+use microservice_utils::server::tenant::{begin_service_tx, begin_tenant_tx};
 use microservice_utils::jwt::extractor::AuthToken;
+use microservice_utils::crypto::{
+    blind_index, blind_ngrams, current_prefix, decrypt_all, encrypt_all, into_sqlx_error,
+    normalize_email, normalize_phone, reencrypt,
+};
 
 use crate::contacts::contacts::{Contact, ContactModel, Provider, GenericContact, GoogleContacts, OutlookContacts};
 
@@ -59,6 +66,8 @@ impl AddressBookService for MyAddressBookService {
         &self,
         request: tonic::Request<ShopifyOrder>,
     ) -> Result<tonic::Response<ShopifyResponse>, tonic::Status> {
+        authorize(&request, &[AUTH_SERVICE])?;
+
         let req: ShopifyOrder = request.into_inner();
         println!("Adding Shopify Order {:?}", req);
 
@@ -76,6 +85,8 @@ impl AddressBookService for MyAddressBookService {
         &self,
         request: tonic::Request<ShopifyDataRequest>,
     ) -> Result<tonic::Response<ShopifyDataResponse>, tonic::Status> {
+        authorize(&request, &[AUTH_SERVICE])?;
+
         let req: ShopifyDataRequest = request.into_inner();
         println!("Requesting Shopify Orders {:?}", req);
 
@@ -101,6 +112,8 @@ impl AddressBookService for MyAddressBookService {
         &self,
         request: tonic::Request<ShopifyOrdersRedact>,
     ) -> Result<tonic::Response<ShopifyResponse>, tonic::Status> {
+        authorize(&request, &[AUTH_SERVICE])?;
+
         let req: ShopifyOrdersRedact = request.into_inner();
         println!("Deleting Shopify Orders {:?}", req);
 
@@ -393,28 +406,79 @@ pub async fn sync_google_contacts(
     contacts: &GoogleContacts,
     pool: &PgPool,
 ) -> Result<(), sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     let _ = sqlx::query!(
         "INSERT INTO contacts (user_id, phone, email) VALUES ($1, $2, $3) ON CONFLICT (user_id) DO NOTHING",
         user_id,
         phone,
         email,
     )
-    .execute(pool)
+    .execute(&mut tx)
     .await?;
 
     for contact in &contacts.contacts {
-        let _ = sqlx::query!(
-            "INSERT INTO generic_contacts (identifier, user_id, provider, name, photo, phone_numbers, email_addresses) VALUES ($1, $2, $3, $4, $5, $6, $7) 
-            ON CONFLICT (identifier) DO UPDATE SET name = $4, photo = $5, phone_numbers = $6, email_addresses = $7",
-            contact.identifier,
-            user_id,
-            "google".to_string(),
-            contact.name,
-            contact.photo,
-            &contact.phone_numbers.as_ref().map_or(Vec::new(), |f| f.to_vec()),
-            &contact.email_addresses.as_ref().map_or(Vec::new(), |f| f.to_vec()),
-        ).execute(pool).await?;
+        db_upsert_generic_contact(user_id, &"google".to_string(), contact, &mut tx).await?;
     }
+    tx.commit().await?;
+    Ok(())
+}
+
+// Blind indexes of plain phone numbers and emails: exact ones for each value, and the n-grams
+// of all of them for partial matches.
+fn contact_indexes(
+    phone_numbers: &[String],
+    email_addresses: &[String],
+) -> anyhow::Result<(Vec<String>, Vec<String>, Vec<String>)> {
+    let phones: Vec<String> = phone_numbers.iter().map(|p| normalize_phone(p)).collect();
+    let emails: Vec<String> = email_addresses.iter().map(|e| normalize_email(e)).collect();
+    let phone_index = phones.iter().map(|p| blind_index(p)).collect::<anyhow::Result<Vec<String>>>()?;
+    let email_index = emails.iter().map(|e| blind_index(e)).collect::<anyhow::Result<Vec<String>>>()?;
+    let mut search_index = Vec::new();
+    for value in phones.iter().chain(emails.iter()) {
+        search_index.extend(blind_ngrams(value)?);
+    }
+    search_index.sort();
+    search_index.dedup();
+    Ok((phone_index, email_index, search_index))
+}
+
+// Blind index of a normalized query, None when nothing is left to match on.
+fn exact_index(value: &str) -> anyhow::Result<Option<String>> {
+    if value.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(blind_index(value)?))
+    }
+}
+
+// Phone numbers and emails are encrypted, the blind indexes allow searching them.
+pub async fn db_upsert_generic_contact(
+    user_id: &String,
+    provider: &String,
+    contact: &GenericContact,
+    conn: &mut PgConnection,
+) -> Result<(), sqlx::Error> {
+    let phone_numbers = contact.phone_numbers.as_ref().map_or(Vec::new(), |f| f.to_vec());
+    let email_addresses = contact.email_addresses.as_ref().map_or(Vec::new(), |f| f.to_vec());
+    let (phone_index, email_index, search_index) =
+        contact_indexes(&phone_numbers, &email_addresses).map_err(into_sqlx_error)?;
+
+    let _ = sqlx::query(
+        "INSERT INTO generic_contacts (identifier, user_id, provider, name, photo, phone_numbers, email_addresses, phone_index, email_index, search_index) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) 
+        ON CONFLICT (identifier) DO UPDATE SET name = $4, photo = $5, phone_numbers = $6, email_addresses = $7, phone_index = $8, email_index = $9, search_index = $10",
+    )
+    .bind(&contact.identifier)
+    .bind(user_id)
+    .bind(provider)
+    .bind(&contact.name)
+    .bind(&contact.photo)
+    .bind(encrypt_all(&phone_numbers).map_err(into_sqlx_error)?)
+    .bind(encrypt_all(&email_addresses).map_err(into_sqlx_error)?)
+    .bind(phone_index)
+    .bind(email_index)
+    .bind(search_index)
+    .execute(conn)
+    .await?;
     Ok(())
 }
 
@@ -425,13 +489,14 @@ pub async fn sync_shopify_contacts(
     contacts: &Vec<Contact>,
     pool: &PgPool,
 ) -> Result<(), sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     let _ = sqlx::query!(
         "INSERT INTO contacts (user_id, phone, email) VALUES ($1, $2, $3) ON CONFLICT (user_id) DO NOTHING",
         user_id,
         phone,
         email,
     )
-    .execute(pool)
+    .execute(&mut tx)
     .await?;
 
     for contact in contacts {
@@ -485,7 +550,7 @@ pub async fn sync_shopify_contacts(
             contact.customer.verified_email,
             contact.customer.tax_exempt
         )
-        .execute(pool)
+        .execute(&mut tx)
         .await?;
 
         for address in addresses {
@@ -511,15 +576,16 @@ pub async fn sync_shopify_contacts(
                 address.province_code,
                 address.zip
             )
-            .execute(pool)
+            .execute(&mut tx)
             .await?;
         }
 
         for order in orders {
-            let _ = insert_shopify_order(pool, order).await?;
+            let _ = db_insert_shopify_order(&mut tx, order).await?;
         }
     }
 
+    tx.commit().await?;
     Ok(())
 }
 
@@ -530,28 +596,20 @@ pub async fn sync_outlook_contacts(
     contacts: &OutlookContacts,
     pool: &PgPool,
 ) -> Result<(), sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     let _ = sqlx::query!(
         "INSERT INTO contacts (user_id, phone, email) VALUES ($1, $2, $3) ON CONFLICT (user_id) DO NOTHING",
         user_id,
         phone,
         email,
     )
-    .execute(pool)
+    .execute(&mut tx)
     .await?;
 
     for contact in &contacts.contacts {
-        let _ = sqlx::query!(
-            "INSERT INTO generic_contacts (identifier, user_id, provider, name, photo, phone_numbers, email_addresses) VALUES ($1, $2, $3, $4, $5, $6, $7) 
-            ON CONFLICT (identifier) DO UPDATE SET name = $4, photo = $5, phone_numbers = $6, email_addresses = $7",
-            contact.identifier,
-            user_id,
-            "outlook".to_string(),
-            contact.name,
-            contact.photo,
-            &contact.phone_numbers.as_ref().map_or(Vec::new(), |f| f.to_vec()),
-            &contact.email_addresses.as_ref().map_or(Vec::new(), |f| f.to_vec()),
-        ).execute(pool).await?;
+        db_upsert_generic_contact(user_id, &"outlook".to_string(), contact, &mut tx).await?;
     }
+    tx.commit().await?;
     Ok(())
 }
 
@@ -559,13 +617,15 @@ pub async fn get_shopify_contacts(
     user_id: &String,
     pool: &PgPool,
 ) -> Result<ContactRes, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     let rows = sqlx::query!(
         "SELECT contacts.phone,contacts.email,shopify_contacts.*,shopify_customer_addresses.*,shopify_customer_orders.* FROM contacts INNER JOIN shopify_contacts
         ON contacts.user_id = shopify_contacts.user_id INNER JOIN shopify_customer_orders ON shopify_customer_orders.order_customer_id = shopify_contacts.customer_id INNER JOIN shopify_customer_addresses ON shopify_customer_addresses.address_customer_id = shopify_contacts.customer_id  WHERE contacts.user_id = $1;",
         user_id
     )
-    .fetch_all(pool)
+    .fetch_all(&mut tx)
     .await?;
+    tx.commit().await?;
     let mut phone = String::new();
     if rows[0].phone.is_some() {
         phone = rows[0].phone.as_ref().unwrap().to_string();
@@ -679,32 +739,28 @@ pub async fn get_shopify_contacts(
     Ok(res)
 }
 
+// Shopify's privacy webhooks reach us from auth_service, not on behalf of a signed in user.
 pub async fn request_shopify_data(
     pool: &PgPool,
     customer_id: Option<i64>,
     orders: Vec<i64>,
 ) -> Result<Vec<ContactModel>, sqlx::Error> {
-    let mut orders_str = String::new();
-    for order in orders {
-        orders_str.push_str(&format!("{},", order));
-    }
-    orders_str.pop();
-
-    let query = match customer_id {
-        Some(id) => format!(
+    let mut tx = begin_service_tx(pool, "shopify_data_request").await?;
+    let contact = match customer_id {
+        Some(id) => sqlx::query_as::<_, ContactModel>(
             "SELECT * FROM shopify_contacts sc INNER JOIN shopify_customer_orders sco ON sco.order_customer_id = sc.customer_id 
-            WHERE sc.customer_id = {} AND sco.order_id IN ({})",
-            id,orders_str
-        ),
-        None => format!(
-            "SELECT * FROM shopify_customer_orders WHERE order_id IN ({})",
-            orders_str
-        ),
+            WHERE sc.customer_id = $1 AND sco.order_id = ANY($2)",
+        )
+        .bind(id)
+        .bind(orders)
+        .fetch_all(&mut tx)
+        .await?,
+        None => sqlx::query_as::<_, ContactModel>("SELECT * FROM shopify_customer_orders WHERE order_id = ANY($1)")
+            .bind(orders)
+            .fetch_all(&mut tx)
+            .await?,
     };
-
-    let contact = sqlx::query_as::<_, ContactModel>(&query)
-        .fetch_all(pool)
-        .await?;
+    tx.commit().await?;
     Ok(contact)
 }
 
@@ -713,28 +769,26 @@ pub async fn delete_shopify_orders(
     customer_id: Option<i64>,
     orders: Vec<i64>,
 ) -> Result<(), sqlx::Error> {
-    let mut orders_str = String::new();
-    for order in orders {
-        orders_str.push_str(&format!("{},", order));
-    }
-    orders_str.pop();
-
-    let query = match customer_id {
-        Some(id) => format!(
-            "DELETE FROM shopify_customer_orders WHERE order_id IN ({}) AND customer_id = {}",
-            orders_str, id
-        ),
-        None => format!(
-            "DELETE FROM shopify_customer_orders WHERE order_id IN ({})",
-            orders_str
-        ),
-    };
-
-    let _ = sqlx::query(&query).execute(pool).await?;
+    let mut tx = begin_service_tx(pool, "shopify_redact").await?;
+    let _ = sqlx::query(
+        "DELETE FROM shopify_customer_orders WHERE order_id = ANY($1) AND ($2::bigint IS NULL OR order_customer_id = $2)",
+    )
+    .bind(orders)
+    .bind(customer_id)
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
     Ok(())
 }
 
 pub async fn insert_shopify_order(pool: &PgPool, order: &Order) -> Result<(), sqlx::Error> {
+    let mut tx = begin_service_tx(pool, "shopify_order").await?;
+    db_insert_shopify_order(&mut tx, order).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn db_insert_shopify_order(conn: &mut PgConnection, order: &Order) -> Result<(), sqlx::Error> {
     let _ = sqlx::query!(
         "INSERT INTO shopify_customer_orders (order_id,order_customer_id,
             app_id,
@@ -823,7 +877,7 @@ pub async fn insert_shopify_order(pool: &PgPool, order: &Order) -> Result<(), sq
             DateTime::<Utc>::from_str(&order.updated_at).expect("failed to parse date").naive_utc(),
             String::new()
     )
-    .execute(pool)
+    .execute(conn)
     .await?;
 
     Ok(())
@@ -834,25 +888,52 @@ pub async fn get_generic_contacts(
     params: &ContactQuery,
     pool: &PgPool,
 ) -> Result<ContactRes, sqlx::Error> {
-
-    let contact = sqlx::query!("SELECT * FROM contacts WHERE user_id = $1", user_id).fetch_one(pool).await?;
-    let row = sqlx::query!("SELECT count(*) OVER() AS total FROM generic_contacts WHERE user_id = $1 AND provider = $2", user_id, params.provider.to_string().to_lowercase()).fetch_one(pool).await?;
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let contact = sqlx::query!("SELECT * FROM contacts WHERE user_id = $1", user_id).fetch_one(&mut tx).await?;
+    let row = sqlx::query!("SELECT count(*) OVER() AS total FROM generic_contacts WHERE user_id = $1 AND provider = $2", user_id, params.provider.to_string().to_lowercase()).fetch_one(&mut tx).await?;
     let total = row.total.unwrap();
 
-    let mut query = "SELECT *, count(*) OVER() AS total FROM generic_contacts WHERE ".to_string();
-    write!(query, "user_id = '{}' AND provider = '{}' ", user_id, params.provider.to_string().to_lowercase()).unwrap();
-
-    if let Some(q) = &params.query {
-        if q.len() > 0 {
-            write!(query, "AND (LOWER(name) LIKE LOWER('%{}%') OR ARRAY_TO_STRING(phone_numbers, ',') LIKE '%{}%' OR ARRAY_TO_STRING(email_addresses, ',') LIKE '%{}%') LIMIT 50", 
-            q, q, q).unwrap();
-        }        
-    } else {
-        write!(query, "OFFSET {} LIMIT {}", params.page * params.size, params.size).unwrap();
-    }
-    
-    let str_query: &str = &query[..];
-    let contacts = sqlx::query_as::<_, GenericContact>(str_query).fetch_all(pool).await?;
+    let provider = params.provider.to_string().to_lowercase();
+    let rows = match &params.query {
+        Some(q) if q.len() > 0 => {
+            // Encrypted phone numbers and emails match through their blind indexes: exactly, or on
+            // all the n-grams of the query when it is long enough to have some.
+            let phone_q = normalize_phone(q);
+            let email_q = normalize_email(q);
+            sqlx::query_as::<_, GenericContact>(
+                "SELECT identifier, name, photo, phone_numbers, email_addresses FROM generic_contacts WHERE user_id = $1 AND provider = $2 
+                AND (LOWER(name) LIKE LOWER($3) OR $4 = ANY(phone_index) OR $5 = ANY(email_index)
+                    OR (cardinality($6::text[]) > 0 AND search_index @> $6)
+                    OR (cardinality($7::text[]) > 0 AND search_index @> $7)) LIMIT 50",
+            )
+            .bind(user_id)
+            .bind(&provider)
+            .bind(format!("%{}%", q))
+            .bind(exact_index(&phone_q).map_err(into_sqlx_error)?)
+            .bind(exact_index(&email_q).map_err(into_sqlx_error)?)
+            .bind(blind_ngrams(&phone_q).map_err(into_sqlx_error)?)
+            .bind(blind_ngrams(&email_q).map_err(into_sqlx_error)?)
+            .fetch_all(&mut tx)
+            .await?
+        }
+        _ => {
+            sqlx::query_as::<_, GenericContact>(
+                "SELECT identifier, name, photo, phone_numbers, email_addresses FROM generic_contacts WHERE user_id = $1 AND provider = $2 OFFSET $3 LIMIT $4",
+            )
+            .bind(user_id)
+            .bind(&provider)
+            .bind(params.page * params.size)
+            .bind(params.size)
+            .fetch_all(&mut tx)
+            .await?
+        }
+    };
+    tx.commit().await?;
+    let contacts = rows
+        .into_iter()
+        .map(|c| c.decrypt())
+        .collect::<anyhow::Result<Vec<GenericContact>>>()
+        .map_err(into_sqlx_error)?;
 
     let res = ContactRes {
         user_id: user_id.to_string(),
@@ -870,14 +951,253 @@ pub async fn get_generic_contact_by_identifier(
     identifier: &String,
     pool: &PgPool,
 ) -> Result<GenericContact, sqlx::Error> {
-    let row = sqlx::query!("SELECT * FROM generic_contacts WHERE user_id = $1 AND identifier = $2", user_id, identifier).fetch_one(pool).await?;
-    let contact = GenericContact {
-        identifier: row.identifier,
-        name: row.name,
-        photo: row.photo,
-        phone_numbers: row.phone_numbers,
-        email_addresses: row.email_addresses,
-    };
-    Ok(contact)
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let contact = sqlx::query_as::<_, GenericContact>(
+        "SELECT identifier, name, photo, phone_numbers, email_addresses FROM generic_contacts WHERE user_id = $1 AND identifier = $2",
+    )
+    .bind(user_id)
+    .bind(identifier)
+    .fetch_one(&mut tx)
+    .await?;
+    tx.commit().await?;
+    contact.decrypt().map_err(into_sqlx_error)
+}
+
+// Moves contact phone numbers and emails to the current master key, encrypting any stored before encryption,
+// and fills in the blind indexes of contacts stored before them.
+pub async fn db_reencrypt_generic_contacts(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let mut tx = begin_service_tx(pool, "reencrypt_contacts").await?;
+    let rows: Vec<(i32, Vec<String>, Vec<String>)> = sqlx::query_as(
+        "SELECT id, COALESCE(phone_numbers, '{}'), COALESCE(email_addresses, '{}') FROM generic_contacts 
+        WHERE phone_index IS NULL OR email_index IS NULL OR search_index IS NULL
+            OR EXISTS (SELECT 1 FROM UNNEST(ARRAY_CAT(phone_numbers, email_addresses)) v WHERE v NOT LIKE $1)",
+    )
+    .bind(format!("{}%", current_prefix().map_err(into_sqlx_error)?))
+    .fetch_all(&mut tx)
+    .await?;
+
+    let mut updated = 0;
+    for (id, phone_numbers, email_addresses) in rows {
+        let rotate = |values: &Vec<String>| -> anyhow::Result<Vec<String>> {
+            values
+                .iter()
+                .map(|v| Ok(reencrypt(v)?.unwrap_or_else(|| v.clone())))
+                .collect()
+        };
+        let new_phone_numbers = rotate(&phone_numbers).map_err(into_sqlx_error)?;
+        let new_email_addresses = rotate(&email_addresses).map_err(into_sqlx_error)?;
+        let (phone_index, email_index, search_index) = contact_indexes(
+            &decrypt_all(&phone_numbers).map_err(into_sqlx_error)?,
+            &decrypt_all(&email_addresses).map_err(into_sqlx_error)?,
+        )
+        .map_err(into_sqlx_error)?;
+
+        // Skipped when the contact was synced again in the meantime.
+        let res = sqlx::query(
+            "UPDATE generic_contacts SET phone_numbers = $1, email_addresses = $2, phone_index = $3, email_index = $4, search_index = $5 
+            WHERE id = $6 AND COALESCE(phone_numbers, '{}') = $7 AND COALESCE(email_addresses, '{}') = $8",
+        )
+        .bind(new_phone_numbers)
+        .bind(new_email_addresses)
+        .bind(phone_index)
+        .bind(email_index)
+        .bind(search_index)
+        .bind(id)
+        .bind(phone_numbers)
+        .bind(email_addresses)
+        .execute(&mut tx)
+        .await?;
+        updated += res.rows_affected();
+    }
+    tx.commit().await?;
+    Ok(updated)
+}
+
+// Moves the address book of a merged account, keeping the target's own phone and email.
+pub async fn db_merge_user_contacts(source: &String, target: &String, pool: &PgPool) -> Result<(), sqlx::Error> {
+    let mut tx = begin_service_tx(pool, "merge_user_contacts").await?;
+    sqlx::query(
+        "INSERT INTO contacts (user_id, phone, email) SELECT $2, phone, email FROM contacts WHERE user_id = $1 ON CONFLICT (user_id) DO NOTHING",
+    )
+    .bind(source)
+    .bind(target)
+    .execute(&mut tx)
+    .await?;
+    for table in ["generic_contacts", "tag_name", "tag_contacts", "shopify_contacts"] {
+        sqlx::query(&format!("UPDATE {} SET user_id = $2 WHERE user_id = $1", table))
+            .bind(source)
+            .bind(target)
+            .execute(&mut tx)
+            .await?;
+    }
+    sqlx::query("DELETE FROM contacts WHERE user_id = $1")
+        .bind(source)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+// Erases the address book of a deleted account, children before the rows they reference.
+pub async fn db_erase_user_contacts(user_id: &String, pool: &PgPool) -> Result<Erasure, sqlx::Error> {
+    db_erase_user_rows(
+        user_id,
+        &[
+            "DELETE FROM shopify_customer_orders WHERE order_customer_id IN (SELECT customer_id FROM shopify_contacts WHERE user_id = $1)",
+            "DELETE FROM shopify_customer_addresses WHERE address_customer_id IN (SELECT customer_id FROM shopify_contacts WHERE user_id = $1)",
+            "DELETE FROM shopify_contacts WHERE user_id = $1",
+            "DELETE FROM tag_contacts WHERE user_id = $1",
+            "DELETE FROM tag_name WHERE user_id = $1",
+            "DELETE FROM generic_contacts WHERE user_id = $1",
+            "DELETE FROM contacts WHERE user_id = $1",
+        ],
+        &[
+            "SELECT count(*) FROM shopify_contacts WHERE user_id = $1",
+            "SELECT count(*) FROM tag_contacts WHERE user_id = $1",
+            "SELECT count(*) FROM tag_name WHERE user_id = $1",
+            "SELECT count(*) FROM generic_contacts WHERE user_id = $1",
+            "SELECT count(*) FROM contacts WHERE user_id = $1",
+        ],
+        pool,
+    )
+    .await
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contacts::contacts::ContactQuery;
+    use crate::test_db::{tenant_test_tx, test_pool, test_user_id};
+
+    async fn create_address_book(user_id: &String, pool: &PgPool) {
+        let mut tx = begin_tenant_tx(pool, user_id, None).await.unwrap();
+        sqlx::query("INSERT INTO contacts (user_id, phone, email) VALUES ($1, '', '')")
+            .bind(user_id)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+    }
+
+    async fn search(user_id: &String, q: &str, pool: &PgPool) -> Vec<String> {
+        let params = ContactQuery {
+            provider: Provider::Google,
+            page: 0,
+            size: 50,
+            query: Some(q.to_string()),
+        };
+        let res = get_generic_contacts(user_id, &params, pool).await.unwrap();
+        let contacts: Vec<GenericContact> = serde_json::from_value(res.contacts).unwrap();
+        contacts.into_iter().map(|c| c.identifier).collect()
+    }
+
+    fn contact(identifier: &String) -> GenericContact {
+        GenericContact {
+            identifier: identifier.clone(),
+            name: Some("Jane".to_string()),
+            photo: None,
+            phone_numbers: Some(vec!["+1 (631) 933-1307".to_string()]),
+            email_addresses: Some(vec!["Jane.Doe@Example.com".to_string()]),
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn encrypted_fields_match_partially() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        create_address_book(&user_id, &pool).await;
+        let identifier = test_user_id();
+        let mut tx = begin_tenant_tx(&pool, &user_id, None).await.unwrap();
+        db_upsert_generic_contact(&user_id, &"google".to_string(), &contact(&identifier), &mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+
+        assert_eq!(search(&user_id, "933-13", &pool).await, vec![identifier.clone()]);
+        assert_eq!(search(&user_id, "doe@example", &pool).await, vec![identifier.clone()]);
+        assert_eq!(search(&user_id, "+1 631 933 1307", &pool).await, vec![identifier.clone()]);
+        assert!(search(&user_id, "777", &pool).await.is_empty());
+        assert!(search(&user_id, "smith@", &pool).await.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn reencrypt_backfills_indexes() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        create_address_book(&user_id, &pool).await;
+        let identifier = test_user_id();
+        // stored before encryption and blind indexes
+        let mut tx = begin_tenant_tx(&pool, &user_id, None).await.unwrap();
+        sqlx::query(
+            "INSERT INTO generic_contacts (identifier, user_id, provider, name, phone_numbers, email_addresses) VALUES ($1, $2, 'google', 'Jane', $3, $4)",
+        )
+        .bind(&identifier)
+        .bind(&user_id)
+        .bind(vec!["+1 (631) 933-1307".to_string()])
+        .bind(vec!["jane.doe@example.com".to_string()])
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+        assert!(search(&user_id, "933-13", &pool).await.is_empty());
+
+        db_reencrypt_generic_contacts(&pool).await.unwrap();
+        assert_eq!(search(&user_id, "933-13", &pool).await, vec![identifier.clone()]);
+        assert_eq!(search(&user_id, "jane.doe@example.com", &pool).await, vec![identifier.clone()]);
+        let stored = get_generic_contact_by_identifier(&user_id, &identifier, &pool).await.unwrap();
+        assert_eq!(stored.phone_numbers, Some(vec!["+1 (631) 933-1307".to_string()]));
+
+        let mut tx = begin_tenant_tx(&pool, &user_id, None).await.unwrap();
+        let (phones,): (Vec<String>,) = sqlx::query_as("SELECT phone_numbers FROM generic_contacts WHERE identifier = $1")
+            .bind(&identifier)
+            .fetch_one(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert!(phones[0].starts_with(&current_prefix().unwrap()));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn contacts_of_other_tenants_are_out_of_reach() {
+        let pool = test_pool().await;
+        let (owner, other) = (test_user_id(), test_user_id());
+        create_address_book(&owner, &pool).await;
+        create_address_book(&other, &pool).await;
+        let identifier = test_user_id();
+        let mut tx = begin_tenant_tx(&pool, &owner, None).await.unwrap();
+        db_upsert_generic_contact(&owner, &"google".to_string(), &contact(&identifier), &mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+
+        // The service's upsert, which finds the existing contact by its identifier alone
+        let mut tx = tenant_test_tx(&pool, &other, None).await;
+        let overwrite = GenericContact { name: Some("Taken".to_string()), ..contact(&identifier) };
+        assert!(db_upsert_generic_contact(&other, &"google".to_string(), &overwrite, &mut tx).await.is_err());
+        tx.rollback().await.unwrap();
+
+        // And the queries without any user_id filter
+        let mut tx = tenant_test_tx(&pool, &other, None).await;
+        let rows: Vec<(String,)> = sqlx::query_as("SELECT identifier FROM generic_contacts WHERE identifier = $1")
+            .bind(&identifier)
+            .fetch_all(&mut tx)
+            .await
+            .unwrap();
+        assert!(rows.is_empty());
+        let renamed = sqlx::query("UPDATE generic_contacts SET name = 'Taken' WHERE identifier = $1")
+            .bind(&identifier)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(renamed.rows_affected(), 0);
+        let deleted = sqlx::query("DELETE FROM generic_contacts WHERE identifier = $1")
+            .bind(&identifier)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(deleted.rows_affected(), 0);
+        tx.commit().await.unwrap();
+
+        let stored = get_generic_contact_by_identifier(&owner, &identifier, &pool).await.unwrap();
+        assert_eq!(stored.name, Some("Jane".to_string()));
+    }
+}
@@ -1,4 +1,5 @@
 pub mod contacts;
 pub mod shopify;
 pub mod param;
-pub mod contacts_handler;
\ No newline at end of file
+pub mod contacts_handler;
+pub mod contacts_export;
\ No newline at end of file
@@ -6,7 +6,6 @@ use axum_macros::debug_handler;
 use openapi_rs::openapi_proc_macro::handler;
 use sqlx::PgPool;
 use std::sync::Arc;
-use std::fmt::Write;
 use uuid::Uuid;
 
 use okapi::openapi3::RefOr;
@@ -23,7 +22,9 @@ use crate::{
     tags::tags_handler::db_get_tag_by_id,
 };
 use microservice_utils::server::response::{AxumRes,into_reponse, AxumResult};
+use microservice_utils::server::tenant::begin_tenant_tx;
 use microservice_utils::jwt::extractor::AuthToken;
+use microservice_utils::crypto::into_sqlx_error;
 
 // API
 #[debug_handler]
@@ -118,6 +119,7 @@ pub async fn db_add_to_tag(
     params: &TagPeople,
     pool: &PgPool,
 ) -> Result<TagPeopleResult, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     // Check contact identifier
     let _ = get_generic_contact_by_identifier(&user_id, &params.identifier, &pool).await?;
 
@@ -125,8 +127,9 @@ pub async fn db_add_to_tag(
     let _ = db_get_tag_by_id(&user_id, &params.tag_id, &pool).await?;
 
     let _ = sqlx::query!("INSERT INTO tag_contacts (user_id, tag_id, identifier) VALUES ($1, $2, $3) RETURNING *", 
-        user_id, params.tag_id, params.identifier).fetch_one(pool).await?;  
+        user_id, params.tag_id, params.identifier).fetch_one(&mut tx).await?;  
         
+    tx.commit().await?;
     Ok(db_get_from_tag(&user_id, &params.tag_id, &pool).await?)
 }
 
@@ -135,13 +138,21 @@ pub async fn db_get_from_tag(
     tag_id: &Uuid,
     pool: &PgPool,
 ) -> Result<TagPeopleResult, sqlx::Error> {
-    let mut query = "SELECT jt.* FROM (SELECT tag_id, ARRAY_AGG(DISTINCT identifier) AS ids FROM tag_contacts WHERE ".to_string();
-    write!(query, "user_id = '{}' ", user_id).unwrap();
-    write!(query, "AND tag_id = '{}' ", tag_id).unwrap();
-    write!(query, "GROUP BY tag_id) q LEFT JOIN generic_contacts jt ON jt.identifier = ANY(q.ids)").unwrap();
-
-    let str_query: &str = &query[..];
-    let contacts = sqlx::query_as::<_, GenericContact>(str_query).fetch_all(pool).await?;
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let contacts = sqlx::query_as::<_, GenericContact>(
+        "SELECT jt.* FROM (SELECT tag_id, ARRAY_AGG(DISTINCT identifier) AS ids FROM tag_contacts WHERE user_id = $1 AND tag_id = $2 
+        GROUP BY tag_id) q LEFT JOIN generic_contacts jt ON jt.identifier = ANY(q.ids)",
+    )
+    .bind(user_id)
+    .bind(tag_id)
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
+    let contacts = contacts
+        .into_iter()
+        .map(|c| c.decrypt())
+        .collect::<anyhow::Result<Vec<GenericContact>>>()
+        .map_err(into_sqlx_error)?;
 
     let res = TagPeopleResult {
         user_id: user_id.to_string(),
@@ -156,6 +167,7 @@ pub async fn db_delete_from_tag(
     params: &TagPeople,
     pool: &PgPool,
 ) -> Result<TagPeopleResult, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     // Check contact identifier
     let _ = get_generic_contact_by_identifier(&user_id, &params.identifier, &pool).await?;
 
@@ -163,8 +175,9 @@ pub async fn db_delete_from_tag(
     let _ = db_get_tag_by_id(&user_id, &params.tag_id, &pool).await?;
 
     let _ = sqlx::query!("DELETE FROM tag_contacts WHERE user_id = $1 AND tag_id = $2 AND identifier = $3", 
-        user_id, params.tag_id, params.identifier).execute(pool).await?; 
+        user_id, params.tag_id, params.identifier).execute(&mut tx).await?; 
         
+    tx.commit().await?;
     Ok(db_get_from_tag(&user_id, &params.tag_id, &pool).await?)
 }
 
@@ -173,7 +186,9 @@ pub async fn db_delete_from_tag_by_id(
     tag_id: &Uuid,
     pool: &PgPool,
 ) -> Result<(), sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     let _ = sqlx::query!("DELETE FROM tag_contacts WHERE user_id = $1 AND tag_id = $2", 
-        user_id, tag_id).execute(pool).await?;        
+        user_id, tag_id).execute(&mut tx).await?;        
+    tx.commit().await?;
     Ok(())
-}
\ No newline at end of file
+}
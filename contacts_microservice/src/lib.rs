@@ -13,21 +13,34 @@ use tower_http::{
     cors::{Any, CorsLayer},
     trace::{DefaultMakeSpan, TraceLayer},
 };
+use microservice_utils::server::audit::audit_impersonation;
 use microservice_utils::{open_api::gen::{generate_openapi_spec, Spec, GenSpec}, server::spa::SpaRouter};
 use microservice_utils::server::hybrid::hybrid;
+use microservice_utils::server::grpc_support::{grpc_web, health_service, reflection_service};
+use microservice_utils::server::service_auth::{check_service_config, service_interceptor, CONTACTS_SERVICE};
 use microservice_utils::server::error_404::error_404;
 
 pub mod tags;
 pub mod groups;
 pub mod contacts;
+#[cfg(test)]
+mod test_db;
 
 use crate::contacts::contacts_handler::{sync_contacts_spec, get_contacts_spec};
 use crate::tags::tags_handler::{create_tag_spec, update_tag_spec, get_tag_spec, delete_tag_spec};
 use crate::groups::groups_handler::{add_to_tag_spec, get_from_tag_spec, delete_from_tag_spec};
 
-use crate::contacts::contacts_handler::{get_contacts, sync_contacts};
+use crate::contacts::contacts_handler::{
+    db_erase_user_contacts, db_merge_user_contacts, db_reencrypt_generic_contacts, get_contacts,
+    sync_contacts,
+};
+use microservice_utils::events::{event_producer, start_event_consumer, Event};
+use microservice_utils::server::erasure::report_erasure;
 use crate::tags::tags_handler::{create_tag, update_tag, get_tag, delete_tag};
 use crate::groups::groups_handler::{add_to_tag, get_from_tag, delete_from_tag};
+use crate::contacts::contacts_export::MyDataExport;
+use microservice_utils::server::grpc::data_export::data_export_server::DataExportServer;
+use microservice_utils::crypto::check_keys;
 use crate::{
     contacts::contacts_handler::{
         address_book_service::address_book_service_server::AddressBookServiceServer,
@@ -35,6 +48,9 @@ use crate::{
     },
 };
 
+pub(crate) const FILE_DESCRIPTOR_SET: &[u8] =
+    tonic::include_file_descriptor_set!("contacts_microservice_descriptor");
+
 #[macro_use]
 extern crate lazy_static;
 
@@ -49,22 +65,37 @@ lazy_static! {
 #[tokio::main]
 pub async fn main() {
     dotenv().expect("Failed to read .env file");
+    check_service_config().expect("Invalid service token config");
     lazy_static::initialize(&DATABASE_URL);
+    check_keys().expect("Invalid encryption keys");
+    let reflection = reflection_service(&[FILE_DESCRIPTOR_SET]).expect("Invalid gRPC reflection descriptors");
 
     let pool = PgPool::connect(&std::env::var("DATABASE_URL").unwrap())
         .await
         .unwrap();
 
+    spawn_reencrypt_job(&pool);
+    start_events(&pool);
     let axum_make_service = create_app(&pool);
     // addres book service
     let grpc_service = tonic::transport::Server::builder()
-        .add_service(AddressBookServiceServer::new(MyAddressBookService::new(
-            pool,
+        .add_service(grpc_web(AddressBookServiceServer::with_interceptor(
+            MyAddressBookService::new(pool.clone()),
+            service_interceptor(CONTACTS_SERVICE),
         )))
+        .add_service(grpc_web(DataExportServer::with_interceptor(
+            MyDataExport::new(pool),
+            service_interceptor(CONTACTS_SERVICE),
+        )))
+        .add_service(health_service::<AddressBookServiceServer<MyAddressBookService>>().await)
+        .add_service(reflection)
         .into_service();
 
     // addres book service
-    let hybrid_make_service = hybrid(axum_make_service.into_make_service(), grpc_service);
+    let hybrid_make_service = hybrid(
+        axum_make_service.into_make_service_with_connect_info::<SocketAddr>(),
+        grpc_service,
+    );
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 4003));
     println!("Listening on http://{}", addr);
@@ -77,15 +108,53 @@ pub async fn main() {
 
 #[shuttle_service::main]
 async fn axum(pool: PgPool) -> shuttle_service::ShuttleAxum {
+    // refuse to start without valid encryption keys or a service signing key
+    check_keys()?;
+    check_service_config()?;
     pool.execute(include_str!("../schema.sql"))
         .await
         .map_err(CustomError::new)?;
 
+    spawn_reencrypt_job(&pool);
+    start_events(&pool);
     let app = create_app(&pool);
     let sync_wrapper = SyncWrapper::new(app);
     Ok(sync_wrapper)
 }
 
+// Re-encrypts contact details still wrapped with an older master key after a rotation.
+fn spawn_reencrypt_job(pool: &PgPool) {
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        match db_reencrypt_generic_contacts(&pool).await {
+            Ok(count) => println!("Re-encrypted {} contacts", count),
+            Err(e) => println!("{:?}", e.to_string()),
+        }
+    });
+}
+
+fn start_events(pool: &PgPool) {
+    let pool = pool.clone();
+    let producer = event_producer();
+    start_event_consumer("contacts_service", move |event| {
+        let pool = pool.clone();
+        let producer = producer.clone();
+        async move {
+            match event {
+                Event::AccountMerged { source_user_id, target_user_id } => {
+                    db_merge_user_contacts(&source_user_id, &target_user_id, &pool).await?;
+                }
+                Event::UserDeletionRequested { deletion_id, user_id, .. } => {
+                    let erasure = db_erase_user_contacts(&user_id, &pool).await?;
+                    report_erasure(&deletion_id, &user_id, CONTACTS_SERVICE, erasure, &producer).await?;
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+    });
+}
+
 fn create_app(pool: &PgPool) -> Router {
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var(
@@ -141,6 +210,7 @@ fn create_app(pool: &PgPool) -> Router {
         )
         .layer(ConcurrencyLimitLayer::new(64))
         .layer(cors)
+        .layer(axum::middleware::from_fn(audit_impersonation))
         .into_inner();
 
     let app = Router::new()
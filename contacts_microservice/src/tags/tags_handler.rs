@@ -6,7 +6,6 @@ use axum_macros::debug_handler;
 use openapi_rs::openapi_proc_macro::handler;
 use sqlx::PgPool;
 use std::sync::Arc;
-use std::fmt::Write;
 use uuid::Uuid;
 
 use okapi::openapi3::RefOr;
@@ -19,6 +18,7 @@ use crate::{
     groups::groups_handler::db_delete_from_tag_by_id,
 };
 use microservice_utils::server::response::{AxumRes,into_reponse, AxumResult};
+use microservice_utils::server::tenant::begin_tenant_tx;
 use microservice_utils::jwt::extractor::AuthToken;
 
 // API
@@ -136,7 +136,9 @@ pub async fn db_create_tag(
     params: &CreateTag,
     pool: &PgPool,
 ) -> Result<TagInfo, sqlx::Error> {
-    let tag = sqlx::query_as!(TagInfo, r#"INSERT INTO tag_name (user_id, name) VALUES ($1, $2) RETURNING *"#, user_id, params.name).fetch_one(pool).await?;    
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let tag = sqlx::query_as!(TagInfo, r#"INSERT INTO tag_name (user_id, name) VALUES ($1, $2) RETURNING *"#, user_id, params.name).fetch_one(&mut tx).await?;    
+    tx.commit().await?;
     Ok(tag)
 }
 
@@ -145,12 +147,14 @@ pub async fn db_update_tag(
     params: &UpdateTag,
     pool: &PgPool,
 ) -> Result<TagInfo, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     let tag = sqlx::query_as!(TagInfo, 
         r#"UPDATE tag_name SET name = $1 WHERE user_id = $2 AND id = $3 RETURNING *"#,
             params.name,
             user_id,
             params.id,
-    ).fetch_one(pool).await?;
+    ).fetch_one(&mut tx).await?;
+    tx.commit().await?;
     Ok(tag)
 }
 
@@ -158,11 +162,12 @@ pub async fn db_get_tag(
     user_id: &String,
     pool: &PgPool,
 ) -> Result<Vec<TagInfo>, sqlx::Error> {
-    let mut query = "SELECT * FROM tag_name WHERE ".to_string();
-    write!(query, "user_id = '{}' ", user_id).unwrap();
-
-    let str_query: &str = &query[..];
-    let tags = sqlx::query_as::<_, TagInfo>(str_query).fetch_all(pool).await?;
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let tags = sqlx::query_as::<_, TagInfo>("SELECT * FROM tag_name WHERE user_id = $1")
+        .bind(user_id)
+        .fetch_all(&mut tx)
+        .await?;
+    tx.commit().await?;
     Ok(tags)
 }
 
@@ -171,7 +176,9 @@ pub async fn db_get_tag_by_id(
     tag_id: &Uuid,
     pool: &PgPool,
 ) -> Result<TagInfo, sqlx::Error> {
-    let tag = sqlx::query_as!(TagInfo, r#"SELECT * FROM tag_name WHERE user_id = $1 AND id = $2"#, user_id, tag_id).fetch_one(pool).await?;
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let tag = sqlx::query_as!(TagInfo, r#"SELECT * FROM tag_name WHERE user_id = $1 AND id = $2"#, user_id, tag_id).fetch_one(&mut tx).await?;
+    tx.commit().await?;
     Ok(tag)  
 }
 
@@ -180,7 +187,9 @@ pub async fn db_delete_tag(
     params: &RequiredId,
     pool: &PgPool,
 ) -> Result<(), sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     let _ = db_delete_from_tag_by_id(&user_id, &params.id, &pool).await?;
-    let _ = sqlx::query!("DELETE FROM tag_name WHERE user_id = $1 AND id = $2", user_id, params.id).execute(pool).await?;
+    let _ = sqlx::query!("DELETE FROM tag_name WHERE user_id = $1 AND id = $2", user_id, params.id).execute(&mut tx).await?;
+    tx.commit().await?;
     Ok(())
 }
@@ -0,0 +1,52 @@
+// Database of the tests that need one: TEST_DATABASE_URL, with schema.sql applied once per run.
+// Tests use their own user ids, so they don't see each other's rows.
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use std::sync::Once;
+use tokio::sync::OnceCell;
+use uuid::Uuid;
+
+use microservice_utils::server::tenant::begin_tenant_tx;
+
+static SCHEMA: OnceCell<()> = OnceCell::const_new();
+static ENV: Once = Once::new();
+
+// Tests may connect as a superuser, which row-level security doesn't apply to, so
+// `tenant_test_tx` switches to this role.
+const TENANT_TEST_ROLE_SQL: &str = r#"
+DO $$ BEGIN CREATE ROLE tenant_test NOLOGIN; EXCEPTION WHEN duplicate_object OR unique_violation THEN NULL; END $$;
+GRANT ALL ON ALL TABLES IN SCHEMA public TO tenant_test;
+GRANT ALL ON ALL SEQUENCES IN SCHEMA public TO tenant_test;
+"#;
+
+// Keys read once per process, set before the first test reads them.
+pub(crate) fn test_env() {
+    ENV.call_once(|| {
+        std::env::set_var("MASTER_KEY_V1", "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=");
+        std::env::set_var("BLIND_INDEX_KEY", "CQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQk=");
+    });
+}
+
+pub(crate) async fn test_pool() -> PgPool {
+    test_env();
+    let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL");
+    let pool = PgPool::connect(&url).await.unwrap();
+    SCHEMA
+        .get_or_init(|| async {
+            pool.execute(r#"CREATE EXTENSION IF NOT EXISTS "uuid-ossp";"#).await.unwrap();
+            pool.execute(include_str!("../schema.sql")).await.unwrap();
+            pool.execute(TENANT_TEST_ROLE_SQL).await.unwrap();
+        })
+        .await;
+    pool
+}
+
+pub(crate) fn test_user_id() -> String {
+    format!("test-{}", Uuid::new_v4())
+}
+
+// A tenant transaction the row-level security policies of schema.sql apply to.
+pub(crate) async fn tenant_test_tx<'a>(pool: &'a PgPool, user_id: &str, workspace_id: Option<&str>) -> Transaction<'a, Postgres> {
+    let mut tx = begin_tenant_tx(pool, user_id, workspace_id).await.unwrap();
+    sqlx::query("SET LOCAL ROLE tenant_test").execute(&mut tx).await.unwrap();
+    tx
+}
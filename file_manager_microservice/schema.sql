@@ -15,3 +15,27 @@ CREATE TABLE IF NOT EXISTS files (
 
     primary key(id)
 );
+
+-- Row-level security: rows are only visible to the tenant set by microservice_utils::server::tenant
+-- (app.current_user / app.current_workspace). Without a tenant in the transaction nothing is
+-- visible; internal paths that cross tenants use a service transaction (app.service_role).
+-- Superusers and BYPASSRLS roles skip policies entirely, so the service has to connect as a
+-- regular role for this to take effect.
+CREATE OR REPLACE FUNCTION app_current_user() RETURNS TEXT AS $$
+    SELECT NULLIF(current_setting('app.current_user', true), '')
+$$ LANGUAGE SQL STABLE;
+
+CREATE OR REPLACE FUNCTION app_current_workspace() RETURNS TEXT AS $$
+    SELECT NULLIF(current_setting('app.current_workspace', true), '')
+$$ LANGUAGE SQL STABLE;
+
+CREATE OR REPLACE FUNCTION app_service_role() RETURNS BOOLEAN AS $$
+    SELECT COALESCE(current_setting('app.service_role', true), '') <> ''
+$$ LANGUAGE SQL STABLE;
+
+ALTER TABLE files ENABLE ROW LEVEL SECURITY;
+ALTER TABLE files FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON files;
+CREATE POLICY tenant_isolation ON files
+    USING (app_service_role() OR user_id = app_current_user())
+    WITH CHECK (app_service_role() OR user_id = app_current_user());
@@ -5,13 +5,17 @@ use sqlx::PgPool;
 use axum::extract::Extension;
 use std::sync::Arc;
 use axum_macros::debug_handler;
+use microservice_utils::server::erasure::{db_erase_user_rows, Erasure};
+use microservice_utils::server::tenant::{begin_service_tx, begin_tenant_tx};
 
 #[debug_handler]
-pub async fn get_file_info(file_id: String, pool: &Arc<PgPool>) -> Result<AmFile, sqlx::Error> {
+pub async fn get_file_info(user_id: &String, file_id: String, pool: &Arc<PgPool>) -> Result<AmFile, sqlx::Error> {
     let file_id: i32 = file_id.parse().unwrap();
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     let file = sqlx::query_as!(AmFile, r#"SELECT * FROM files WHERE id = $1"#, file_id)
-        .fetch_one(pool)
+        .fetch_one(&mut tx)
         .await?;
+    tx.commit().await?;
 
     Ok(file)
 }
@@ -24,36 +28,40 @@ pub async fn _create_new_file(
     file_size: i32,
 ) -> Result<i32, sqlx::Error> {
     let pid = 1;
+    let mut tx = begin_tenant_tx(pool, &user_id, None).await?;
     let query_res = sqlx::query_as(
-        "INSERT INTO files(pid, user_id, name, path, size) VALUES ((1), (2), (3), (4), (5)) RETURNING id;")
+        "INSERT INTO files(pid, user_id, name, path, size) VALUES ($1, $2, $3, $4, $5) RETURNING id;")
         .bind(pid)
-        .bind(user_id)
+        .bind(&user_id)
         .bind(file_name)
         .bind(file_path)
         .bind(file_size).
-        fetch_one(pool).await?;
+        fetch_one(&mut tx).await?;
+    tx.commit().await?;
 
     let row: (i32,) = query_res;
     Ok(row.0)
 }
 
 pub async fn _get_root_directory_id(pool: &PgPool, user_id: String) -> Result<i32, sqlx::Error> {
-    let row: (i32,) = sqlx::query_as("SELECT name FROM files WHERE id = (1)")
-        .bind(user_id)
-        .fetch_one(pool)
+    let mut tx = begin_tenant_tx(pool, &user_id, None).await?;
+    let row: (i32,) = sqlx::query_as("SELECT id FROM files WHERE user_id = $1 AND pid = 0")
+        .bind(&user_id)
+        .fetch_one(&mut tx)
         .await?;
+    tx.commit().await?;
     Ok(row.0)
 }
 
 pub async fn _get_sub_directories(
     pool: &PgPool,
+    user_id: String,
     parent_folder_id: i32,
 ) -> Result<i32, sqlx::Error> {
-    let str_query = format!(
-        "SELECT * FROM files WHERE pid = {} AND deleted = 0",
-        parent_folder_id
-    );
-    let mut rows = sqlx::query(&str_query).fetch(pool);
+    let mut tx = begin_tenant_tx(pool, &user_id, None).await?;
+    let mut rows = sqlx::query("SELECT * FROM files WHERE pid = $1 AND deleted = 0")
+        .bind(parent_folder_id)
+        .fetch(&mut tx);
 
     while let Some(_) = rows.try_next().await? {
         // println!("{}", row.0);
@@ -61,3 +69,49 @@ pub async fn _get_sub_directories(
 
     Ok(0)
 }
+
+// Moves the files of a merged account. Its root folder becomes a folder in the target's root,
+// or the target's root when the target has no files yet.
+pub async fn db_merge_user_files(pool: &PgPool, source: &String, target: &String) -> Result<(), sqlx::Error> {
+    let mut tx = begin_service_tx(pool, "merge_user_files").await?;
+    let target_root: Option<(i32,)> = sqlx::query_as("SELECT id FROM files WHERE user_id = $1 AND pid = 0;")
+        .bind(target)
+        .fetch_optional(&mut tx)
+        .await?;
+    if let Some((root_id,)) = target_root {
+        sqlx::query("UPDATE files SET pid = $1, name = $2 WHERE user_id = $3 AND pid = 0;")
+            .bind(root_id)
+            .bind("Merged account")
+            .bind(source)
+            .execute(&mut tx)
+            .await?;
+    }
+    sqlx::query("UPDATE files SET user_id = $1 WHERE user_id = $2;")
+        .bind(target)
+        .bind(source)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+// Paths of the stored files of a user, folders have no object in S3.
+pub async fn db_list_user_file_paths(pool: &PgPool, user_id: &String) -> Result<Vec<String>, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let rows: Vec<(String,)> = sqlx::query_as("SELECT path FROM files WHERE user_id = $1 AND is_folder = 0;")
+        .bind(user_id)
+        .fetch_all(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(rows.into_iter().map(|row| row.0).collect())
+}
+
+pub async fn db_erase_user_files(pool: &PgPool, user_id: &String) -> Result<Erasure, sqlx::Error> {
+    db_erase_user_rows(
+        user_id,
+        &["DELETE FROM files WHERE user_id = $1"],
+        &["SELECT count(*) FROM files WHERE user_id = $1"],
+        pool,
+    )
+    .await
+}
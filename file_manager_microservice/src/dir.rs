@@ -9,8 +9,9 @@ use axum::{
 use microservice_utils::{
     jwt::extractor::AuthToken,
     server::response::{into_response, AxumRes, AxumResult},
+    server::tenant::begin_tenant_tx,
 };
-use sqlx::postgres::PgPool;
+use sqlx::postgres::{PgConnection, PgPool};
 use std::sync::Arc;
 
 /*pub fn get_sub_directory() {}
@@ -21,10 +22,12 @@ pub fn delete_folder() {}*/
 
 // #[debug_handler]
 async fn db_test_has_root_directory(pool: &PgPool, user_id: String) -> Result<i32, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, &user_id, None).await?;
     let row: (i32,) = sqlx::query_as("SELECT id FROM files WHERE user_id = $1 AND pid = 0;")
-        .bind(user_id)
-        .fetch_one(pool)
+        .bind(&user_id)
+        .fetch_one(&mut tx)
         .await?;
+    tx.commit().await?;
 
     Ok(row.0)
 }
@@ -67,12 +70,16 @@ pub async fn db_create_new_directory(
     pid: i32,
     name: String,
 ) -> Result<AmFile, sqlx::Error> {
-    let query_str = format!("INSERT INTO files(pid, user_id, name, path, size, status, is_folder) VALUES ({},{},\'{}\',\'{}\',{},{},{}) RETURNING *;",
-        pid, user_id, name, "", 0, 0, 1);
-
-    let file = sqlx::query_as::<_, AmFile>(&query_str)
-        .fetch_one(pool)
-        .await?;
+    let mut tx = begin_tenant_tx(pool, &user_id, None).await?;
+    let file = sqlx::query_as::<_, AmFile>(
+        "INSERT INTO files(pid, user_id, name, path, size, status, is_folder) VALUES ($1, $2, $3, '', 0, 0, 1) RETURNING *;",
+    )
+    .bind(pid)
+    .bind(&user_id)
+    .bind(name)
+    .fetch_one(&mut tx)
+    .await?;
+    tx.commit().await?;
 
     Ok(file)
 }
@@ -97,13 +104,13 @@ async fn db_get_sub_directories(
         }
     }
 
-    let query_str = format!(
-        "SELECT * FROM files WHERE user_id = {} AND pid = {};",
-        user_id, parent_folder_id
-    );
-    let files = sqlx::query_as::<_, AmFile>(&query_str)
-        .fetch_all(pool)
+    let mut tx = begin_tenant_tx(pool, &user_id, None).await?;
+    let files = sqlx::query_as::<_, AmFile>("SELECT * FROM files WHERE user_id = $1 AND pid = $2;")
+        .bind(&user_id)
+        .bind(parent_folder_id)
+        .fetch_all(&mut tx)
         .await?;
+    tx.commit().await?;
 
     Ok(files)
 }
@@ -132,29 +139,29 @@ pub async fn get_sub_directory(
 }
 
 async fn db_test_folder_permission(
-    pool: &PgPool,
+    conn: &mut PgConnection,
     user_id: String,
     folder_id: i32,
 ) -> Result<(i32, i32), sqlx::Error> {
-    let query_str = format!(
-        "SELECT id, is_folder FROM files WHERE user_id = {} AND id = {};",
-        user_id, folder_id
-    );
-    let row: (i32, i32) = sqlx::query_as(&query_str).fetch_one(pool).await?;
+    let row: (i32, i32) = sqlx::query_as("SELECT id, is_folder FROM files WHERE user_id = $1 AND id = $2;")
+        .bind(user_id)
+        .bind(folder_id)
+        .fetch_one(conn)
+        .await?;
 
     Ok((row.0, row.1))
 }
 
 async fn db_test_file_folder_permission(
-    pool: &PgPool,
+    conn: &mut PgConnection,
     user_id: String,
     ff_id: i32,
 ) -> Result<(i32, i32), sqlx::Error> {
-    let query_str = format!(
-        "SELECT id, pid FROM files WHERE user_id = {} AND id = {};",
-        user_id, ff_id
-    );
-    let row: (i32, i32) = sqlx::query_as(&query_str).fetch_one(pool).await?;
+    let row: (i32, i32) = sqlx::query_as("SELECT id, pid FROM files WHERE user_id = $1 AND id = $2;")
+        .bind(user_id)
+        .bind(ff_id)
+        .fetch_one(conn)
+        .await?;
 
     Ok((row.0, row.1))
 }
@@ -167,7 +174,19 @@ pub async fn create_new_folder(
     
     let parent_folder_id: i32 = folder_id.parse::<i32>().unwrap();
 
-    if let Err(e) = db_test_folder_permission(&pool, user_id.clone(), parent_folder_id).await {
+    let mut tx = match begin_tenant_tx(&pool, &user_id, None).await {
+        Ok(tx) => tx,
+        Err(e) => {
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            return Err(into_response(500, ret));
+        }
+    };
+
+    let permission = db_test_folder_permission(&mut tx, user_id.clone(), parent_folder_id).await;
+    let _ = tx.rollback().await;
+    if let Err(e) = permission {
         println!("test err: {}", e);
 
         let ret = serde_json::json!({
@@ -191,9 +210,13 @@ pub async fn create_new_folder(
     }
 }
 
-async fn db_move_file(pool: &PgPool, file_id: i32, pid: i32) -> Result<(), sqlx::Error> {
-    let query_str = format!("UPDATE files SET pid = {} WHERE id = {}", pid, file_id);
-    sqlx::query(&query_str).execute(pool).await?;
+async fn db_move_file(conn: &mut PgConnection, user_id: String, file_id: i32, pid: i32) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE files SET pid = $1 WHERE id = $2 AND user_id = $3")
+        .bind(pid)
+        .bind(file_id)
+        .bind(user_id)
+        .execute(conn)
+        .await?;
 
     Ok(())
 }
@@ -207,7 +230,17 @@ pub async fn move_folder_or_file(
     let src_id: i32 = src_folder_id.parse().unwrap();
     let dst_id: i32 = dst_folder_id.parse().unwrap();
 
-    if let Err(e) = db_test_folder_permission(&pool, user_id.clone(), src_id).await {
+    let mut tx = match begin_tenant_tx(&pool, &user_id, None).await {
+        Ok(tx) => tx,
+        Err(e) => {
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            return Err(into_response(500, ret));
+        }
+    };
+
+    if let Err(e) = db_test_folder_permission(&mut tx, user_id.clone(), src_id).await {
         let ret = serde_json::json!({
             "error": format!("{:?}", e),
         });
@@ -215,7 +248,7 @@ pub async fn move_folder_or_file(
     }
 
     // Check if dest is folder
-    let t = db_test_folder_permission(&pool, user_id.clone(), dst_id).await;
+    let t = db_test_folder_permission(&mut tx, user_id.clone(), dst_id).await;
 
     match t {
         Ok(file) => {
@@ -233,27 +266,33 @@ pub async fn move_folder_or_file(
             return Err(into_response(400, ret));
         }
     }
-    println!("he");
-
-    if let Err(e) = db_move_file(&pool, src_id, dst_id).await {
+    if let Err(e) = db_move_file(&mut tx, user_id, src_id, dst_id).await {
         let ret = serde_json::json!({
             "error": format!("{:?}", e),
         });
         return Err(into_response(400, ret));
     }
 
+    if let Err(e) = tx.commit().await {
+        let ret = serde_json::json!({
+            "error": format!("{:?}", e),
+        });
+        return Err(into_response(500, ret));
+    }
+
     Ok(axum::Json(AxumRes {
         code: 200,
         result: "success".to_owned(),
     }))
 }
 
-async fn db_rename_file(pool: &PgPool, file_id: i32, file_name: String) -> Result<(), sqlx::Error> {
-    let query_str = format!(
-        "UPDATE files SET name = \'{}\' WHERE id = {};",
-        file_name, file_id
-    );
-    sqlx::query(&query_str).execute(pool).await?;
+async fn db_rename_file(conn: &mut PgConnection, user_id: String, file_id: i32, file_name: String) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE files SET name = $1 WHERE id = $2 AND user_id = $3;")
+        .bind(file_name)
+        .bind(file_id)
+        .bind(user_id)
+        .execute(conn)
+        .await?;
 
     Ok(())
 }
@@ -266,22 +305,92 @@ pub async fn rename_folder(
     
     let file_id: i32 = file_id.parse().unwrap();
 
-    if let Err(e) = db_test_file_folder_permission(&pool, user_id, file_id).await {
+    let mut tx = match begin_tenant_tx(&pool, &user_id, None).await {
+        Ok(tx) => tx,
+        Err(e) => {
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            return Err(into_response(500, ret));
+        }
+    };
+
+    if let Err(e) = db_test_file_folder_permission(&mut tx, user_id.clone(), file_id).await {
         let ret = serde_json::json!({
             "error": format!("{:?}", e),
         });
         return Err(into_response(400, ret));
     }
 
-    if let Err(e) = db_rename_file(&pool, file_id, file_name).await {
+    if let Err(e) = db_rename_file(&mut tx, user_id, file_id, file_name).await {
         let ret = serde_json::json!({
             "error": format!("{:?}", e),
         });
         return Err(into_response(400, ret));
     }
 
+    if let Err(e) = tx.commit().await {
+        let ret = serde_json::json!({
+            "error": format!("{:?}", e),
+        });
+        return Err(into_response(500, ret));
+    }
+
     Ok(axum::Json(AxumRes {
         code: 200,
         result: "success".to_owned(),
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::{tenant_test_tx, test_pool, test_user_id};
+
+    async fn create_file(user_id: &String, pool: &PgPool) -> i32 {
+        let (id,): (i32,) = sqlx::query_as(
+            "INSERT INTO files (pid, user_id, name, path, size) VALUES (0, $1, 'file', 'file', 0) RETURNING id",
+        )
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        id
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn files_of_other_tenants_are_out_of_reach() {
+        let pool = test_pool().await;
+        let (owner, other) = (test_user_id(), test_user_id());
+        let file_id = create_file(&owner, &pool).await;
+
+        let mut tx = tenant_test_tx(&pool, &other, None).await;
+        // The service's own query, filtering on the owner rather than the caller
+        db_move_file(&mut tx, owner.clone(), file_id, 42).await.unwrap();
+        // And the queries without any user_id filter
+        let rows: Vec<(i32,)> = sqlx::query_as("SELECT id FROM files WHERE id = $1")
+            .bind(file_id)
+            .fetch_all(&mut tx)
+            .await
+            .unwrap();
+        assert!(rows.is_empty());
+        let moved = sqlx::query("UPDATE files SET pid = 42 WHERE id = $1").bind(file_id).execute(&mut tx).await.unwrap();
+        assert_eq!(moved.rows_affected(), 0);
+        let deleted = sqlx::query("DELETE FROM files WHERE id = $1").bind(file_id).execute(&mut tx).await.unwrap();
+        assert_eq!(deleted.rows_affected(), 0);
+        assert!(sqlx::query("INSERT INTO files (pid, user_id, name, path, size) VALUES (0, $1, 'file', 'file', 0)")
+            .bind(&owner)
+            .execute(&mut tx)
+            .await
+            .is_err());
+        tx.rollback().await.unwrap();
+
+        let (pid,): (i32,) = sqlx::query_as("SELECT pid FROM files WHERE id = $1")
+            .bind(file_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(pid, 0);
+    }
+}
@@ -0,0 +1,210 @@
+use axum::{
+    extract::{Extension, Path},
+    response::Redirect,
+};
+use rusoto_core::Region;
+use rusoto_credential::{EnvironmentProvider, ProvideAwsCredentials};
+use rusoto_s3::util::{PreSignedRequest, PreSignedRequestOption};
+use rusoto_s3::{GetObjectRequest, PutObjectRequest, S3Client, S3};
+use sqlx::postgres::PgPool;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::{async_trait, Status};
+use uuid::Uuid;
+
+use microservice_utils::{
+    jwt::extractor::AuthToken,
+    server::export::{csv_file, db_export_rows, export_response},
+    server::grpc::data_export::data_export_server::DataExport,
+    server::grpc::data_export::export_storage_server::ExportStorage,
+    server::grpc::data_export::{
+        ExportUserDataRequest, ExportUserDataResponse, StoreExportRequest, StoreExportResponse,
+    },
+    server::response::{into_reponse, AxumResult},
+    server::service_auth::{authorize, USER_SERVICE},
+    server::tenant::begin_tenant_tx,
+};
+
+use crate::dir::db_get_root_directory_id;
+
+const EXPORTS_DIRECTORY: &str = "exports";
+
+// Download links stay valid for a day.
+const EXPORT_LINK_SECONDS: u64 = 24 * 60 * 60;
+
+fn bucket_name() -> String {
+    std::env::var("S3_BUCKET").unwrap_or("henry-bhuman-bucket".to_string())
+}
+
+// gRPC
+pub struct MyDataExport {
+    pool: PgPool,
+}
+
+impl MyDataExport {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl DataExport for MyDataExport {
+    async fn export_user_data(
+        &self,
+        request: tonic::Request<ExportUserDataRequest>,
+    ) -> Result<tonic::Response<ExportUserDataResponse>, Status> {
+        authorize(&request, &[USER_SERVICE])?;
+
+        let req: ExportUserDataRequest = request.into_inner();
+        println!("Export User Data {}", req.user_id);
+
+        // File contents stay in S3, only their metadata is exported.
+        let files = db_export_rows(
+            "SELECT id, pid, name, size, is_folder, deleted, created_at, updated_at
+            FROM files WHERE user_id = $1 ORDER BY id",
+            &req.user_id,
+            &self.pool,
+        )
+        .await
+        .map_err(|e| Status::internal(format!("{:?}", e)))?;
+
+        Ok(tonic::Response::new(export_response(vec![csv_file("files", &files)])))
+    }
+}
+
+pub struct MyExportStorage {
+    pool: PgPool,
+}
+
+impl MyExportStorage {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl ExportStorage for MyExportStorage {
+    async fn store_export(
+        &self,
+        request: tonic::Request<StoreExportRequest>,
+    ) -> Result<tonic::Response<StoreExportResponse>, Status> {
+        authorize(&request, &[USER_SERVICE])?;
+
+        let req: StoreExportRequest = request.into_inner();
+        println!("Store Export {}", req.user_id);
+
+        let root_id = db_get_root_directory_id(&self.pool, req.user_id.clone())
+            .await
+            .map_err(|e| Status::internal(format!("{:?}", e)))?;
+        let path = format!("{}/{}", EXPORTS_DIRECTORY, Uuid::new_v4());
+        let size = req.content.len() as i32;
+
+        upload_private_to_bucket(&path, &req.name, req.content)
+            .await
+            .map_err(Status::unavailable)?;
+
+        let file_id = db_create_export_file(
+            &self.pool,
+            &req.user_id,
+            &req.name,
+            &path,
+            size,
+            root_id,
+        )
+        .await
+        .map_err(|e| Status::internal(format!("{:?}", e)))?;
+
+        Ok(tonic::Response::new(StoreExportResponse {
+            status: "success".to_string(),
+            file_id,
+        }))
+    }
+}
+
+async fn db_create_export_file(
+    pool: &PgPool,
+    user_id: &String,
+    name: &String,
+    path: &String,
+    size: i32,
+    pid: i32,
+) -> Result<i32, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let row: (i32,) = sqlx::query_as(
+        "INSERT INTO files(pid, user_id, name, path, size) VALUES ($1, $2, $3, $4, $5) RETURNING id;",
+    )
+    .bind(pid)
+    .bind(user_id)
+    .bind(name)
+    .bind(path)
+    .bind(size)
+    .fetch_one(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(row.0)
+}
+
+// Unlike uploads, exports are not public-read, they are only reachable through presigned links.
+async fn upload_private_to_bucket(key: &String, name: &String, content: Vec<u8>) -> Result<(), String> {
+    let s3 = S3Client::new(Region::UsEast1);
+    s3.put_object(PutObjectRequest {
+        key: key.clone(),
+        content_type: Some("application/zip".to_string()),
+        content_disposition: Some(format!("attachment; filename={}", name)),
+        content_length: Some(content.len() as i64),
+        body: Some(content.into()),
+        bucket: bucket_name(),
+        ..Default::default()
+    })
+    .await
+    .map_err(|e| format!("{:?}", e))?;
+
+    Ok(())
+}
+
+/**
+ * Redirects the owner of an export to a short-lived S3 link
+ */
+
+pub async fn download_export(
+    Path(file_id): Path<i32>,
+    AuthToken(user_id): AuthToken,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Redirect> {
+    let db_error = |e: sqlx::Error| into_reponse(400, serde_json::json!({ "error": format!("{:?}", e) }));
+    let mut tx = begin_tenant_tx(&pool, &user_id, None).await.map_err(db_error)?;
+    let file: Option<(String,)> = sqlx::query_as(
+        "SELECT path FROM files WHERE id = $1 AND user_id = $2 AND deleted = 0;",
+    )
+    .bind(file_id)
+    .bind(&user_id)
+    .fetch_optional(&mut tx)
+    .await
+    .map_err(db_error)?;
+    tx.commit().await.map_err(db_error)?;
+
+    let (path,) = match file {
+        Some(file) if file.0.starts_with(EXPORTS_DIRECTORY) => file,
+        _ => return Err(into_reponse(404, serde_json::json!({ "error": "Export not found" }))),
+    };
+
+    let credentials = EnvironmentProvider::default()
+        .credentials()
+        .await
+        .map_err(|e| into_reponse(500, serde_json::json!({ "error": format!("{:?}", e) })))?;
+    let url = GetObjectRequest {
+        bucket: bucket_name(),
+        key: path,
+        ..Default::default()
+    }
+    .get_presigned_url(
+        &Region::UsEast1,
+        &credentials,
+        &PreSignedRequestOption {
+            expires_in: Duration::from_secs(EXPORT_LINK_SECONDS),
+        },
+    );
+
+    Ok(Redirect::temporary(&url))
+}
@@ -1,13 +1,23 @@
 mod ud;
-use ud::{accept_file, create_new_file_on_db, download_from_s3, pull_file};
+use ud::{
+    accept_file, create_new_file_on_db, delete_from_bucket, download_from_s3, pull_file,
+    upload_to_shared_folder,
+};
 mod types;
 use crate::types::AppState;
 mod sock;
 use sock::{media_recording_handler, websocket_handler};
 mod db;
+use db::{db_erase_user_files, db_list_user_file_paths, db_merge_user_files};
 mod dir;
 use dir::{create_new_folder, get_root_directory_id, get_sub_directory, move_folder_or_file, rename_folder};
 mod model;
+mod export;
+use export::{download_export, MyDataExport, MyExportStorage};
+mod share;
+use share::MySharedResources;
+#[cfg(test)]
+mod test_db;
 
 use axum::{
     extract::Extension,
@@ -16,9 +26,19 @@ use axum::{
     Router,
 };
 use dotenv::dotenv;
+use microservice_utils::server::audit::audit_impersonation;
+use microservice_utils::events::{event_producer, start_event_consumer, Event};
+use microservice_utils::server::erasure::report_erasure;
+use microservice_utils::server::grpc::data_export::data_export_server::DataExportServer;
+use microservice_utils::server::grpc::data_export::export_storage_server::ExportStorageServer;
+use microservice_utils::server::grpc::shared_resources::shared_resources_server::SharedResourcesServer;
+use microservice_utils::server::grpc_support::{grpc_web, health_service};
+use microservice_utils::server::hybrid::hybrid;
+use microservice_utils::server::service_auth::{check_service_config, service_interceptor, FILE_MANAGER_SERVICE};
 use sqlx::postgres::PgPoolOptions;
 use std::collections::HashMap;
 use std::{
+    net::SocketAddr,
     sync::{Arc, Mutex},
     time::Duration,
 };
@@ -31,6 +51,7 @@ async fn main() {
 
     // Load .env variables.
     dotenv().expect("Failed to read .env file");
+    check_service_config().expect("Invalid service token config");
 
     // save files to a separte directory to not override files in the current directory
     // tokio::fs::create_dir(UPLOADS_DIRECTORY)
@@ -50,6 +71,29 @@ async fn main() {
 
     println!("Database connected");
 
+    // Files follow their owner when accounts are merged, and go with deleted accounts.
+    let events_pool = pool.clone();
+    let producer = event_producer();
+    start_event_consumer("file_manager_service", move |event| {
+        let pool = events_pool.clone();
+        let producer = producer.clone();
+        async move {
+            match event {
+                Event::AccountMerged { source_user_id, target_user_id } => {
+                    db_merge_user_files(&pool, &source_user_id, &target_user_id).await?;
+                }
+                Event::UserDeletionRequested { deletion_id, user_id, .. } => {
+                    // Objects go first, the rows are how they are found when this is retried.
+                    delete_from_bucket(&db_list_user_file_paths(&pool, &user_id).await?).await?;
+                    let erasure = db_erase_user_files(&pool, &user_id).await?;
+                    report_erasure(&deletion_id, &user_id, FILE_MANAGER_SERVICE, erasure, &producer).await?;
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+    });
+
     // Application shared state
     let (tx, _rx) = broadcast::channel(100);
 
@@ -75,16 +119,36 @@ async fn main() {
         .route("/filemanager/push/:file_id", post(accept_file))
         .route("/filemanager/pull/:file_id", get(pull_file)) // concurrent download
         .route("/filemanager/download/:file_id", get(download_from_s3)) // returns S3 URLs
+        .route("/filemanager/shared/upload/:pid", post(upload_to_shared_folder)) // share links, upload only
+        .route("/filemanager/exports/:file_id", get(download_export)) // data exports, owner only
         .route("/ws/websocket/:token", get(websocket_handler))
         .route("/ws/record/:token", get(media_recording_handler))
         .nest("/filemanager/fs", folder_routes())
         .layer(cors)
+        .layer(axum::middleware::from_fn(audit_impersonation))
         .layer(Extension(app_state))
         .layer(Extension(pool_arc))
-        .layer(Extension(pool));
+        .layer(Extension(pool.clone()));
+
+    // Data exports, called by the user service, and folder owners, called by the auth service
+    let grpc_service = tonic::transport::Server::builder()
+        .add_service(grpc_web(DataExportServer::with_interceptor(
+            MyDataExport::new(pool.clone()),
+            service_interceptor(FILE_MANAGER_SERVICE),
+        )))
+        .add_service(grpc_web(ExportStorageServer::with_interceptor(
+            MyExportStorage::new(pool.clone()),
+            service_interceptor(FILE_MANAGER_SERVICE),
+        )))
+        .add_service(grpc_web(SharedResourcesServer::with_interceptor(
+            MySharedResources::new(pool),
+            service_interceptor(FILE_MANAGER_SERVICE),
+        )))
+        .add_service(health_service::<ExportStorageServer<MyExportStorage>>().await)
+        .into_service();
 
     axum::Server::bind(&"0.0.0.0:4007".parse().unwrap())
-        .serve(app.into_make_service())
+        .serve(hybrid(app.into_make_service_with_connect_info::<SocketAddr>(), grpc_service))
         .await
         .unwrap();
 
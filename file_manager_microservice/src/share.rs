@@ -0,0 +1,47 @@
+use sqlx::postgres::PgPool;
+use std::sync::Arc;
+use tonic::{async_trait, Status};
+
+use microservice_utils::{
+    jwt::share::FOLDER_RESOURCE,
+    server::grpc::shared_resources::shared_resources_server::SharedResources,
+    server::grpc::shared_resources::{CheckOwnerRequest, CheckOwnerResponse},
+    server::service_auth::{authorize, AUTH_SERVICE},
+};
+
+use crate::db::get_file_info;
+
+// gRPC
+pub struct MySharedResources {
+    pool: Arc<PgPool>,
+}
+
+impl MySharedResources {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool: Arc::new(pool) }
+    }
+}
+
+#[async_trait]
+impl SharedResources for MySharedResources {
+    async fn check_owner(
+        &self,
+        request: tonic::Request<CheckOwnerRequest>,
+    ) -> Result<tonic::Response<CheckOwnerResponse>, Status> {
+        authorize(&request, &[AUTH_SERVICE])?;
+
+        let req: CheckOwnerRequest = request.into_inner();
+        let owner = match (req.resource_type.as_str(), req.resource_id.parse::<i32>()) {
+            (FOLDER_RESOURCE, Ok(id)) => match get_file_info(&req.user_id, id.to_string(), &self.pool).await {
+                Ok(folder) => folder.user_id == req.user_id && folder.is_folder == 1 && folder.deleted == 0,
+                Err(sqlx::Error::RowNotFound) => false,
+                Err(e) => return Err(Status::internal(format!("{:?}", e))),
+            },
+            _ => false,
+        };
+        Ok(tonic::Response::new(CheckOwnerResponse {
+            status: "success".to_string(),
+            owner,
+        }))
+    }
+}
@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use std::sync::Mutex;
 use tokio::sync::broadcast;
 use serde::{Deserialize, Serialize};
+use microservice_utils::jwt::share::{ShareScope, FOLDER_RESOURCE, UPLOAD_ACTION};
 
 // Our shared state
 pub struct AppState {
@@ -54,4 +55,12 @@ pub struct VideoChunk {
     pub file_path: String,
     pub data: Vec<u8>,
     pub finish: Option<bool>,
-}
\ No newline at end of file
+}
+// Upload-only access to one folder through a share link, its id in the `pid` path segment.
+pub struct FolderUpload;
+
+impl ShareScope for FolderUpload {
+    const RESOURCE: &'static str = FOLDER_RESOURCE;
+    const ACTION: &'static str = UPLOAD_ACTION;
+    const PARAM: &'static str = "pid";
+}
@@ -16,19 +16,26 @@ use tokio::{fs::File, io::BufWriter};
 use tokio_util::io::{ReaderStream, StreamReader};
 
 use microservice_utils::{
+    events::{event_producer, notify_user},
     jwt::extractor::AuthToken,
+    server::preferences::wants_notification,
+    jwt::share::ShareToken,
     server::response::{into_response, AxumRes, AxumResult},
+    server::tenant::begin_tenant_tx,
 };
 
 use rusoto_core::Region;
 use rusoto_credential::{EnvironmentProvider, ProvideAwsCredentials};
-use rusoto_s3::{PutObjectRequest, S3Client, S3};
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    Delete, DeleteObjectsError, DeleteObjectsRequest, ObjectIdentifier, PutObjectRequest, S3Client, S3,
+};
 // use std::fs::File;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::db::get_file_info;
-use crate::types::{AppState, FileUploadingState};
+use crate::types::{AppState, FileUploadingState, FolderUpload};
 
 const UPLOADS_DIRECTORY: &str = "uploads";
 
@@ -70,12 +77,18 @@ pub async fn create_new_file_record(
     pid: i32,
 ) -> Result<(i32, String), sqlx::Error> {
     let path = format!("{}/{}", UPLOADS_DIRECTORY, Uuid::new_v4()); // Generate path from uuid
-    let query = format!(
-        "INSERT INTO files(pid, user_id, name, path, size) VALUES({}, {}, \'{}\', \'{}\', {}) returning id, path;",
-        pid, user_id, file_name, path, file_size
-    );
-
-    let row: (i32, String) = sqlx::query_as(&query).fetch_one(pool).await?;
+    let mut tx = begin_tenant_tx(pool, &user_id, None).await?;
+    let row: (i32, String) = sqlx::query_as(
+        "INSERT INTO files(pid, user_id, name, path, size) VALUES($1, $2, $3, $4, $5) returning id, path;",
+    )
+    .bind(pid)
+    .bind(&user_id)
+    .bind(file_name)
+    .bind(&path)
+    .bind(file_size as i32)
+    .fetch_one(&mut tx)
+    .await?;
+    tx.commit().await?;
 
     return Ok((row.0, row.1));
 }
@@ -94,7 +107,7 @@ pub async fn accept_file(
     Extension(pool): Extension<Arc<PgPool>>,
 ) {
     let file_path: String;
-    if let Ok(file) = get_file_info(file_id, &pool).await {
+    if let Ok(file) = get_file_info(&user_id, file_id, &pool).await {
         file_path = file.path.clone();
 
         let mut state_list = state.state_list.lock().unwrap();
@@ -124,6 +137,86 @@ pub async fn accept_file(
     }
 }
 
+/**
+ * Accept files into a folder shared with an upload-only link
+ * Each `attach` field becomes a file owned by the user who shared the folder
+ * Max file size: 5GB
+ */
+
+pub async fn upload_to_shared_folder(
+    Path(pid): Path<i32>,
+    share: ShareToken<FolderUpload>,
+    ContentLengthLimit(mut multipart): ContentLengthLimit<Multipart, { 5 * 1024 * 1024 * 1024 }>,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes<Vec<i32>>>> {
+    match get_file_info(&share.user_id, pid.to_string(), &pool).await {
+        Ok(folder) if folder.user_id == share.user_id && folder.is_folder == 1 && folder.deleted == 0 => {}
+        _ => {
+            let ret = serde_json::json!({
+                "error": "Folder not found",
+            });
+            return Err(into_response(404, ret));
+        }
+    }
+
+    let mut file_ids = Vec::new();
+    while let Ok(Some(field)) = multipart.next_field().await {
+        if field.name() != Some("attach") {
+            continue;
+        }
+        let file_name = field.file_name().unwrap_or("upload").to_string();
+        let file_path = format!("{}/{}", UPLOADS_DIRECTORY, Uuid::new_v4());
+        if let Err(e) = stream_to_file(&file_path, field).await {
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            return Err(into_response(400, ret));
+        }
+        let file_size = std::fs::metadata(&file_path).map(|m| m.len() as u128).unwrap_or(0);
+
+        match create_new_file_record(&pool, share.user_id.clone(), file_name, file_size, pid).await {
+            Ok((file_id, path)) => {
+                let _ = std::fs::rename(&file_path, &path);
+                if let Err(_) = upload_to_bucket(path).await {
+                    println!("S3 upload failed!");
+                }
+                file_ids.push(file_id);
+            }
+            Err(e) => {
+                println!("sqlx err! {}", e);
+                let _ = std::fs::remove_file(&file_path);
+                let ret = serde_json::json!({
+                    "error": format!("{:?}", e),
+                });
+                return Err(into_response(500, ret));
+            }
+        }
+    }
+
+    if !file_ids.is_empty() {
+        tokio::spawn(notify_shared_upload(share.user_id.clone(), pid, file_ids.clone()));
+    }
+    Ok(axum::Json(AxumRes {
+        code: 200,
+        result: file_ids,
+    }))
+}
+
+// Tells the owner of a shared folder that files were uploaded to it, unless their uploads
+// notification preferences say otherwise.
+async fn notify_shared_upload(owner_id: String, folder_id: i32, file_ids: Vec<i32>) {
+    if !wants_notification(&owner_id, None, "uploads", "in_app").await {
+        return;
+    }
+    let message = serde_json::json!({
+        "folder_id": folder_id,
+        "file_ids": file_ids,
+    });
+    if let Err(e) = notify_user(&owner_id, "shared_folder_upload", &message, &event_producer()).await {
+        println!("Unable to notify {} of an upload: {:?}", owner_id, e);
+    }
+}
+
 async fn stream_to_file<S, E>(path: &str, stream: S) -> Result<(), io::Error>
 where
     S: Stream<Item = Result<Bytes, E>>,
@@ -192,6 +285,32 @@ pub async fn upload_to_bucket(file_path: String) -> tokio::io::Result<()> {
     Ok(())
 }
 
+// Removes objects from the bucket, in batches of the most S3 accepts per request.
+pub async fn delete_from_bucket(keys: &[String]) -> Result<(), RusotoError<DeleteObjectsError>> {
+    let s3 = S3Client::new(Region::UsEast1);
+    let bucket_name: String =
+        std::env::var("S3_BUCKET").unwrap_or("henry-bhuman-bucket".to_string());
+
+    for chunk in keys.chunks(1000) {
+        s3.delete_objects(DeleteObjectsRequest {
+            bucket: bucket_name.clone(),
+            delete: Delete {
+                objects: chunk
+                    .iter()
+                    .map(|key| ObjectIdentifier {
+                        key: key.clone(),
+                        version_id: None,
+                    })
+                    .collect(),
+                quiet: Some(true),
+            },
+            ..Default::default()
+        })
+        .await?;
+    }
+    Ok(())
+}
+
 #[debug_handler]
 pub async fn pull_file(
     Path(file_id): Path<u32>,
@@ -233,10 +352,10 @@ pub async fn pull_file(
 
 pub async fn download_from_s3(
     Path(file_id): Path<u32>,
-    AuthToken(_user_id): AuthToken,
+    AuthToken(user_id): AuthToken,
     Extension(pool): Extension<Arc<PgPool>>,
 ) -> AxumResult<Json<AxumRes<String>>> {
-    if let Ok(fs) = get_file_info(file_id.to_string(), &pool).await {
+    if let Ok(fs) = get_file_info(&user_id, file_id.to_string(), &pool).await {
         return Ok(axum::Json(AxumRes {
             code: 200,
             result: fs.path,
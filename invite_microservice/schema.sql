@@ -5,10 +5,18 @@ CREATE TABLE IF NOT EXISTS invites (
     user_id TEXT NOT NULL,
     invitor_name TEXT NOT NULL,
     invitee_name TEXT NOT NULL,
-    email TEXT NOT NULL, 
-    phone TEXT NOT NULL, 
+    email TEXT NOT NULL, -- encrypted
+    phone TEXT NOT NULL, -- encrypted
+    email_index TEXT, -- blind index of the email
+    phone_index TEXT, -- blind index of the phone number
     hash TEXT NOT NULL, 
     status INTEGER DEFAULT 0,
     created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
     PRIMARY KEY (id)
-);
\ No newline at end of file
+);
+
+ALTER TABLE invites ADD COLUMN IF NOT EXISTS email_index TEXT;
+ALTER TABLE invites ADD COLUMN IF NOT EXISTS phone_index TEXT;
+
+CREATE INDEX IF NOT EXISTS invites_email_index_idx ON invites (email_index);
+CREATE INDEX IF NOT EXISTS invites_phone_index_idx ON invites (phone_index);
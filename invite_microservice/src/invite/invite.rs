@@ -2,6 +2,8 @@ use schemars::JsonSchema;
 use serde::Deserialize;
 use serde::Serialize;
 
+use microservice_utils::server::users::Profile;
+
 #[derive(Default, Debug, Clone, JsonSchema, PartialEq, Serialize, Deserialize)]
 pub struct SenderInfo {
     pub first_name: String, // invitor's first name
@@ -36,6 +38,7 @@ pub struct InviteCheck {
 #[derive(Default, Debug, Clone, JsonSchema, PartialEq, Serialize, Deserialize)]
 pub struct CheckResult {
     pub invitors: Vec<String>,
+    pub senders: Vec<Profile>, // profiles of the invitors that have one
 }
 
 #[derive(Default, Debug, Clone, JsonSchema, PartialEq, Serialize, Deserialize)]
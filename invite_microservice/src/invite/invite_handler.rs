@@ -5,13 +5,25 @@ use axum::{
 use axum_macros::debug_handler;
 use microservice_utils::server::response::{AxumRes, AxumResult};
 use openapi_rs::openapi_proc_macro::handler;
+use rdkafka::producer::FutureProducer;
+use sqlx::types::chrono::NaiveDateTime;
 use sqlx::PgPool;
 use std::sync::Arc;
 use tiny_id::ShortCodeGenerator;
+use uuid::Uuid;
 
 use crate::invite::invite::{CheckResult, EmailBody, InviteCheck, InviteLink, InviteUser, SmsBody};
+use microservice_utils::events::{publish, Event};
 use microservice_utils::jwt::extractor::AuthToken;
 use microservice_utils::{server::response::into_reponse};
+use microservice_utils::server::preferences::wants_notification;
+use microservice_utils::server::grpc::owns_contact;
+use microservice_utils::server::users::{batch_get_users, get_user};
+use microservice_utils::server::erasure::{db_erase_user_rows, Erasure};
+use microservice_utils::crypto::{
+    blind_index, current_prefix, decrypt, encrypt, into_sqlx_error, normalize_email,
+    normalize_phone, reencrypt,
+};
 
 use okapi::openapi3::RefOr;
 use openapi_rs::gen::OpenApiGenerator;
@@ -26,7 +38,17 @@ pub async fn generate_link(
 ) -> AxumResult<Json<AxumRes>> {
     match payload {
         Ok(payload) => {
-            let invite_info = payload.0;
+            let mut invite_info = payload.0;
+
+            // The sender's name comes from their profile, the payload is only a fallback
+            match get_user(&user_id, &["first_name", "last_name"]).await {
+                Ok(profile) if !profile.first_name.is_empty() => {
+                    invite_info.sender.first_name = profile.first_name;
+                    invite_info.sender.last_name = profile.last_name;
+                }
+                Ok(_) => {}
+                Err(e) => println!("{:?}", e.to_string()),
+            }
 
             let mut generator = ShortCodeGenerator::new_alphanumeric(4);
             let code = generator.next_string();
@@ -39,7 +61,7 @@ pub async fn generate_link(
                     let link = InviteLink { link: url.clone() };
 
                     tokio::spawn(async move {
-                        send_email(&invite_info, &url).await;
+                        send_email(&user_id, &invite_info, &url).await;
                     });
 
                     Ok(axum::Json(AxumRes {
@@ -72,16 +94,47 @@ pub async fn verify_link(
     payload: Result<Json<InviteCheck>, JsonRejection>,
     AuthToken(user_id): AuthToken,
     Extension(pool): Extension<Arc<PgPool>>,
+    Extension(producer): Extension<Arc<FutureProducer>>,
 ) -> AxumResult<Json<AxumRes>> {
     match payload {
         Ok(payload) => {
             let invite_info = payload.0;
 
+            // Invites are only accepted by the owner of the email or phone they were sent to,
+            // as proven to auth_service by signing in with it
+            let owner = owns_contact(&user_id, &invite_info.account).await.map_err(|e| {
+                println!("{:?}", e.to_string());
+                into_reponse(500, serde_json::json!({ "error": format!("{:?}", e) }))
+            })?;
+            if !owner {
+                let ret = serde_json::json!({
+                    "error": "The invite was sent to another account",
+                });
+                return Err(into_reponse(403, ret));
+            }
+
             let update_user = update_invite_user(&invite_info, &pool).await;
 
             match update_user {
-                Ok(invitors) => {
-                    let invitors = CheckResult { invitors: invitors };
+                Ok(invites) => {
+                    // The referral goes to the first invitor user_microservice hears about
+                    for (invitor, invited_at) in &invites {
+                        let event = Event::InviteAccepted {
+                            invitee_id: user_id.clone(),
+                            invitor_id: invitor.clone(),
+                            invited_at: invited_at.timestamp(),
+                        };
+                        if let Err(e) = publish(&event, &producer).await {
+                            println!("Failed to publish {:?}: {:?}", event, e);
+                        }
+                    }
+                    let invitors: Vec<String> = invites.into_iter().map(|(invitor, _)| invitor).collect();
+                    let mut profiles = batch_get_users(&invitors, &[]).await.unwrap_or_else(|e| {
+                        println!("{:?}", e.to_string());
+                        Default::default()
+                    });
+                    let senders = invitors.iter().filter_map(|id| profiles.remove(id)).collect();
+                    let invitors = CheckResult { invitors: invitors, senders: senders };
                     Ok(axum::Json(AxumRes {
                         code: 200,
                         result: serde_json::json!(&invitors),
@@ -106,13 +159,15 @@ pub async fn verify_link(
     }
 }
 
-// Send email
-pub async fn send_email(user: &InviteUser, link: &String) {
+// Send email, and texts, over the channels the sender's invites preferences allow
+pub async fn send_email(sender_id: &String, user: &InviteUser, link: &String) {
     let client = reqwest::Client::new();
+    let email_allowed = wants_notification(sender_id, None, "invites", "email").await;
+    let sms_allowed = wants_notification(sender_id, None, "invites", "sms").await;
 
     for receiver in &user.receivers {
         let message = format!("Hey {},\n\n{} {} invited you to try BHuman, the only app in the world that let's you make personalized videos at scale that look and feel completely real.\n\nWhen you sign up, both you and {} will get 250 videos for free (valued at $50 a piece). Here's a link you can use to make sure they get the credit: {}\n\nAny questions? Reply to this email and I'll give you a ring.\nDon", receiver.first_name, user.sender.first_name, user.sender.last_name, user.sender.first_name, link);
-        if receiver.email.len() > 0 {
+        if email_allowed && receiver.email.len() > 0 {
             let body = EmailBody {
                 From: "don@bhuman.ai".to_string(),
                 To: receiver.email.clone(),
@@ -138,7 +193,7 @@ pub async fn send_email(user: &InviteUser, link: &String) {
 
             println!("Invitation email reponse = {:?}", &response);
         }
-        if receiver.phone.len() > 0 {
+        if sms_allowed && receiver.phone.len() > 0 {
             let body = SmsBody {
                 From: "+16319331307".to_string(),
                 To: receiver.phone.clone(),
@@ -169,33 +224,168 @@ pub async fn add_invite_user(
     pool: &PgPool,
 ) -> Result<(), sqlx::Error> {
     for receiver in &user.receivers {
-        let _ = sqlx::query!(
-            "INSERT INTO invites (user_id, invitor_name, invitee_name, email, phone, hash) VALUES ($1, $2, $3, $4, $5, $6)",
-            user_id,
-            user.sender.first_name,
-            receiver.first_name,
-            receiver.email.clone(),
-            receiver.phone.clone(),
-            hash,
-        ).execute(pool).await?;
+        // email and phone are encrypted, invites are matched on their blind indexes
+        let _ = sqlx::query(
+            "INSERT INTO invites (user_id, invitor_name, invitee_name, email, phone, email_index, phone_index, hash) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        )
+        .bind(user_id)
+        .bind(&user.sender.first_name)
+        .bind(&receiver.first_name)
+        .bind(encrypt(&receiver.email).map_err(into_sqlx_error)?)
+        .bind(encrypt(&receiver.phone).map_err(into_sqlx_error)?)
+        .bind(account_index(&receiver.email, normalize_email).map_err(into_sqlx_error)?)
+        .bind(account_index(&receiver.phone, normalize_phone).map_err(into_sqlx_error)?)
+        .bind(hash)
+        .execute(pool)
+        .await?;
     }
     Ok(())
 }
 
+// Marks the pending invites sent to `user.account` as accepted, returns their invitors and
+// when they were sent. The caller checks that `user.account` belongs to the invitee.
 pub async fn update_invite_user(
     user: &InviteCheck,
     pool: &PgPool,
-) -> Result<Vec<String>, sqlx::Error> {
-    let mut invitors = Vec::new();
+) -> Result<Vec<(String, NaiveDateTime)>, sqlx::Error> {
+    let mut invites = Vec::new();
     if user.account.len() > 0 {
-        let rows = sqlx::query!(
-            "UPDATE invites SET status = 1 WHERE (email = $1 OR phone = $1) AND status = 0 RETURNING user_id",
-            user.account,
-        ).fetch_all(pool).await?;
+        let rows: Vec<(String, NaiveDateTime)> = sqlx::query_as(
+            "UPDATE invites SET status = 1 WHERE (email_index = $1 OR phone_index = $2) AND status = 0 RETURNING user_id, created_at",
+        )
+        .bind(account_index(&user.account, normalize_email).map_err(into_sqlx_error)?)
+        .bind(account_index(&user.account, normalize_phone).map_err(into_sqlx_error)?)
+        .fetch_all(pool)
+        .await?;
 
-        for row in rows {
-            invitors.push(row.user_id);
+        invites.extend(rows);
+    }
+    Ok(invites)
+}
+
+// Blind index of an email or phone number, None when there is nothing to match on.
+fn account_index(value: &String, normalize: fn(&str) -> String) -> anyhow::Result<Option<String>> {
+    let value = normalize(value);
+    if value.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(blind_index(&value)?))
+    }
+}
+
+// Moves invite emails and phones to the current master key, encrypting any stored before encryption,
+// and fills in the blind indexes of invites stored before them.
+pub async fn db_reencrypt_invites(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let prefix = format!("{}%", current_prefix().map_err(into_sqlx_error)?);
+    let rows: Vec<(Uuid, String, String, Option<String>, Option<String>)> = sqlx::query_as(
+        "SELECT id, email, phone, email_index, phone_index FROM invites
+        WHERE email NOT LIKE $1 OR phone NOT LIKE $1 OR email_index IS NULL OR phone_index IS NULL",
+    )
+    .bind(&prefix)
+    .fetch_all(pool)
+    .await?;
+
+    let mut updated = 0;
+    for (id, email, phone, old_email_index, old_phone_index) in rows {
+        let email_index = account_index(&decrypt(&email).map_err(into_sqlx_error)?, normalize_email).map_err(into_sqlx_error)?;
+        let phone_index = account_index(&decrypt(&phone).map_err(into_sqlx_error)?, normalize_phone).map_err(into_sqlx_error)?;
+        let new_email = reencrypt(&email).map_err(into_sqlx_error)?;
+        let new_phone = reencrypt(&phone).map_err(into_sqlx_error)?;
+        // invites without an email or phone keep a NULL index
+        if new_email.is_none() && new_phone.is_none() && email_index == old_email_index && phone_index == old_phone_index {
+            continue;
         }
+
+        let res = sqlx::query(
+            "UPDATE invites SET email = $1, phone = $2, email_index = $3, phone_index = $4 WHERE id = $5 AND email = $6 AND phone = $7",
+        )
+        .bind(new_email.unwrap_or_else(|| email.clone()))
+        .bind(new_phone.unwrap_or_else(|| phone.clone()))
+        .bind(email_index)
+        .bind(phone_index)
+        .bind(id)
+        .bind(email)
+        .bind(phone)
+        .execute(pool)
+        .await?;
+        updated += res.rows_affected();
+    }
+    Ok(updated)
+}
+
+// Erases the invites a deleted account sent.
+pub async fn db_erase_user_invites(user_id: &String, pool: &PgPool) -> Result<Erasure, sqlx::Error> {
+    db_erase_user_rows(
+        user_id,
+        &["DELETE FROM invites WHERE user_id = $1"],
+        &["SELECT count(*) FROM invites WHERE user_id = $1"],
+        pool,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::{test_pool, test_user_id};
+
+    // An invite written before encryption and blind indexes.
+    async fn insert_legacy_invite(user_id: &String, email: &str, phone: &str, pool: &PgPool) -> Uuid {
+        sqlx::query_scalar(
+            "INSERT INTO invites (user_id, invitor_name, invitee_name, email, phone, hash) VALUES ($1, 'Ann', 'Bob', $2, $3, 'hash') RETURNING id",
+        )
+        .bind(user_id)
+        .bind(email)
+        .bind(phone)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn reencrypt_backfills_blind_indexes() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        let email = format!("{}@Example.com", user_id);
+        let id = insert_legacy_invite(&user_id, &email, "+1 (631) 933-1307", &pool).await;
+
+        db_reencrypt_invites(&pool).await.unwrap();
+        let (stored_email, email_index, phone_index): (String, Option<String>, Option<String>) =
+            sqlx::query_as("SELECT email, email_index, phone_index FROM invites WHERE id = $1")
+                .bind(id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert!(stored_email.starts_with(&current_prefix().unwrap()));
+        assert_eq!(decrypt(&stored_email).unwrap(), email);
+        assert_eq!(email_index, Some(blind_index(&normalize_email(&email)).unwrap()));
+        assert_eq!(phone_index, Some(blind_index("16319331307").unwrap()));
+
+        let check = InviteCheck { account: email.to_lowercase(), ..Default::default() };
+        let invitors = update_invite_user(&check, &pool).await.unwrap();
+        assert_eq!(invitors.into_iter().map(|(invitor, _)| invitor).collect::<Vec<_>>(), vec![user_id]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn invites_without_a_phone_are_backfilled_once() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        let id = insert_legacy_invite(&user_id, &format!("{}@example.com", user_id), "", &pool).await;
+
+        db_reencrypt_invites(&pool).await.unwrap();
+        let phone_index: Option<String> = sqlx::query_scalar("SELECT phone_index FROM invites WHERE id = $1")
+            .bind(id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(phone_index, None);
+
+        // other tests may add rows concurrently, so only this invite is checked
+        let before: String = sqlx::query_scalar("SELECT phone FROM invites WHERE id = $1").bind(id).fetch_one(&pool).await.unwrap();
+        db_reencrypt_invites(&pool).await.unwrap();
+        let after: String = sqlx::query_scalar("SELECT phone FROM invites WHERE id = $1").bind(id).fetch_one(&pool).await.unwrap();
+        assert_eq!(before, after);
     }
-    Ok(invitors)
 }
@@ -1,2 +1,3 @@
 pub mod invite;
-pub mod invite_handler;
\ No newline at end of file
+pub mod invite_handler;
+pub mod invite_export;
\ No newline at end of file
@@ -11,6 +11,7 @@ use axum::{
     response::Redirect,
 };
 use invite::invite_handler::{generate_link_spec, verify_link_spec};
+use microservice_utils::server::audit::audit_impersonation;
 use microservice_utils::{open_api::gen::{GenSpec, Spec, generate_openapi_spec}, server::spa::SpaRouter};
 use tower::{limit::ConcurrencyLimitLayer, ServiceBuilder};
 use tower_http::{
@@ -23,11 +24,23 @@ use sync_wrapper::SyncWrapper;
 use shuttle_service::error::CustomError;
 
 pub mod invite;
+#[cfg(test)]
+mod test_db;
 
 use microservice_utils::server::error_404::{
     error_404,
 };
+use microservice_utils::server::grpc::data_export::data_export_server::DataExportServer;
+use microservice_utils::server::grpc_support::{grpc_web, health_service};
+use microservice_utils::server::hybrid::hybrid;
+use microservice_utils::server::service_auth::{check_service_config, service_interceptor, INVITE_SERVICE};
+use crate::invite::invite_export::MyDataExport;
+use microservice_utils::events::{event_producer, start_event_consumer, Event};
+use microservice_utils::server::erasure::report_erasure;
+use microservice_utils::crypto::check_keys;
 use crate::invite::invite_handler::{
+    db_erase_user_invites,
+    db_reencrypt_invites,
     generate_link,
     verify_link,
 };
@@ -46,32 +59,83 @@ lazy_static! {
 #[tokio::main]
 pub async fn main() {
     dotenv().expect("Failed to read .env file");
+    check_service_config().expect("Invalid service token config");
     lazy_static::initialize(&DATABASE_URL);
+    check_keys().expect("Invalid encryption keys");
 
     let pool = PgPool::connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap();
     
+    spawn_reencrypt_job(&pool);
+    start_events(&pool);
     let axum_make_service = create_app(&pool);
+    let grpc_service = tonic::transport::Server::builder()
+        .add_service(grpc_web(DataExportServer::with_interceptor(
+            MyDataExport::new(pool),
+            service_interceptor(INVITE_SERVICE),
+        )))
+        .add_service(health_service::<DataExportServer<MyDataExport>>().await)
+        .into_service();
+
+    let hybrid_make_service = hybrid(
+        axum_make_service.into_make_service_with_connect_info::<SocketAddr>(),
+        grpc_service,
+    );
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 4002));
     println!("Listening on http://{}", addr);
 
     axum_server::bind(addr)
-                .serve(axum_make_service.into_make_service())
+                .serve(hybrid_make_service)
                 .await
                 .unwrap();
 }
 
 #[shuttle_service::main]
 async fn axum(pool: PgPool) -> shuttle_service::ShuttleAxum {
+    // refuse to start without valid encryption keys or a service signing key
+    check_keys()?;
+    check_service_config()?;
     pool.execute(include_str!("../schema.sql"))
         .await
         .map_err(CustomError::new)?;
 
+    spawn_reencrypt_job(&pool);
+    start_events(&pool);
     let app = create_app(&pool);
     let sync_wrapper = SyncWrapper::new(app);
     Ok(sync_wrapper)    
 }
 
+// Re-encrypts invite emails and phones still wrapped with an older master key after a rotation.
+fn spawn_reencrypt_job(pool: &PgPool) {
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        match db_reencrypt_invites(&pool).await {
+            Ok(count) => println!("Re-encrypted {} invites", count),
+            Err(e) => println!("{:?}", e.to_string()),
+        }
+    });
+}
+
+fn start_events(pool: &PgPool) {
+    let pool = pool.clone();
+    let producer = event_producer();
+    start_event_consumer("invite_service", move |event| {
+        let pool = pool.clone();
+        let producer = producer.clone();
+        async move {
+            match event {
+                Event::UserDeletionRequested { deletion_id, user_id, .. } => {
+                    let erasure = db_erase_user_invites(&user_id, &pool).await?;
+                    report_erasure(&deletion_id, &user_id, INVITE_SERVICE, erasure, &producer).await?;
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+    });
+}
+
 fn create_app(pool: &PgPool) -> Router {
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var(
@@ -91,6 +155,7 @@ fn create_app(pool: &PgPool) -> Router {
     generate_openapi_spec(specs).expect("failed to generate openapi spec");
 
     let pool_arc = Arc::new(pool.clone());
+    let producer = Arc::new(event_producer());
 
     let cors = CorsLayer::new()
         .allow_methods(Any)
@@ -106,6 +171,7 @@ fn create_app(pool: &PgPool) -> Router {
         )
         .layer(ConcurrencyLimitLayer::new(64))
         .layer(cors)
+        .layer(axum::middleware::from_fn(audit_impersonation))
         .into_inner();
 
     let app = Router::new()
@@ -115,6 +181,7 @@ fn create_app(pool: &PgPool) -> Router {
         .route("/dl/:id", get(|Path(check_id): Path<String>| async move { Redirect::permanent(&format!("https://frontend_test.bhuman.ai/check-in/{}", check_id)) }))
         .fallback(get(error_404))
         .layer(Extension(pool_arc))
+        .layer(Extension(producer))
         .layer(middleware_stack);
 
     return app;
@@ -0,0 +1,37 @@
+// Database of the tests that need one: TEST_DATABASE_URL, with schema.sql applied once per run.
+// Tests use their own user ids, so they don't see each other's rows.
+use sqlx::{Executor, PgPool};
+use std::sync::Once;
+use tokio::sync::OnceCell;
+use uuid::Uuid;
+
+static SCHEMA: OnceCell<()> = OnceCell::const_new();
+static ENV: Once = Once::new();
+
+// Keys read once per process, set before the first test reads them.
+pub(crate) fn test_env() {
+    ENV.call_once(|| {
+        std::env::set_var("MASTER_KEY_V1", "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=");
+        std::env::set_var("BLIND_INDEX_KEY", "CQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQk=");
+    });
+}
+
+pub(crate) async fn test_pool() -> PgPool {
+    test_env();
+    let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL");
+    let pool = PgPool::connect(&url).await.unwrap();
+    SCHEMA
+        .get_or_init(|| async {
+            // schema.sql starts by dropping invites, which fails on an empty database
+            pool.execute(r#"CREATE EXTENSION IF NOT EXISTS "uuid-ossp"; CREATE TABLE IF NOT EXISTS invites ();"#)
+                .await
+                .unwrap();
+            pool.execute(include_str!("../schema.sql")).await.unwrap();
+        })
+        .await;
+    pool
+}
+
+pub(crate) fn test_user_id() -> String {
+    format!("test-{}", Uuid::new_v4())
+}
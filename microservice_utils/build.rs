@@ -3,9 +3,12 @@ fn main() {
     let user = "./proto/user_service.proto";
     let workspace = "./proto/workspace_service.proto";
     let address_book = "./proto/address_book_service.proto";
+    let api_keygen = "./proto/api_keygen_service.proto";
+    let data_export = "./proto/data_export.proto";
+    let shared_resources = "./proto/shared_resources.proto";
 
     tonic_build::configure()
         .build_server(true)
-        .compile(&[auth,workspace,user,address_book], &["."])
+        .compile(&[auth,workspace,user,address_book,api_keygen,data_export,shared_resources], &["."])
         .unwrap_or_else(|e| panic!("protobuf compile error: {}", e));
 }
\ No newline at end of file
@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, Error};
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use rand::RngCore;
+use sha2::Sha256;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const MASTER_KEY_PREFIX: &str = "MASTER_KEY_V";
+const NGRAM_LEN: usize = 3;
+
+/// Master keys, read from the service secrets (`MASTER_KEY_V1`, `MASTER_KEY_V2`, ...,
+/// base64 encoded 32 byte keys). New values are wrapped with `MASTER_KEY_VERSION`,
+/// or the highest version when it is not set. Older versions stay available for decryption.
+pub struct MasterKeys {
+    keys: BTreeMap<u32, Vec<u8>>,
+    current: u32,
+}
+
+impl MasterKeys {
+    pub fn from_env() -> Result<Self, Error> {
+        let mut keys = BTreeMap::new();
+        for (name, value) in std::env::vars() {
+            if let Some(version) = name.strip_prefix(MASTER_KEY_PREFIX) {
+                let version: u32 = version
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid master key name {}", name))?;
+                let key = base64::decode(value.trim())?;
+                if key.len() != KEY_LEN {
+                    return Err(anyhow!("{} must be {} bytes", name, KEY_LEN));
+                }
+                keys.insert(version, key);
+            }
+        }
+
+        let current = match std::env::var("MASTER_KEY_VERSION") {
+            Ok(v) => v.parse()?,
+            Err(_) => *keys.keys().last().ok_or_else(|| anyhow!("No master key configured"))?,
+        };
+        if !keys.contains_key(&current) {
+            return Err(anyhow!("Master key version {} is not configured", current));
+        }
+        Ok(Self { keys, current })
+    }
+
+    pub fn current_version(&self) -> u32 {
+        self.current
+    }
+
+    fn key(&self, version: u32) -> Result<&Vec<u8>, Error> {
+        self.keys
+            .get(&version)
+            .ok_or_else(|| anyhow!("Master key version {} is not configured", version))
+    }
+}
+
+// `BLIND_INDEX_KEY`, a base64 encoded key of at least 32 bytes.
+fn blind_index_key_from_env() -> Result<Vec<u8>, Error> {
+    let value = std::env::var("BLIND_INDEX_KEY").map_err(|_| anyhow!("BLIND_INDEX_KEY is not set"))?;
+    let key = base64::decode(value.trim())?;
+    if key.len() < KEY_LEN {
+        return Err(anyhow!("BLIND_INDEX_KEY must be at least {} bytes", KEY_LEN));
+    }
+    Ok(key)
+}
+
+lazy_static! {
+    static ref MASTER_KEYS: Result<MasterKeys, String> = MasterKeys::from_env().map_err(|e| e.to_string());
+    static ref BLIND_INDEX_KEY: Result<Vec<u8>, String> = blind_index_key_from_env().map_err(|e| e.to_string());
+}
+
+fn master_keys() -> Result<&'static MasterKeys, Error> {
+    MASTER_KEYS.as_ref().map_err(|e| anyhow!("Invalid master keys: {}", e))
+}
+
+fn blind_index_key() -> Result<&'static Vec<u8>, Error> {
+    BLIND_INDEX_KEY.as_ref().map_err(|e| anyhow!("Invalid blind index key: {}", e))
+}
+
+/// Loads the master keys and the blind index key. Services call it on startup,
+/// so they refuse to start with missing or invalid keys.
+pub fn check_keys() -> Result<(), Error> {
+    master_keys()?;
+    blind_index_key()?;
+    Ok(())
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+// Returns nonce || ciphertext.
+fn seal(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    let cipher = Aes256Gcm::new_from_slice(key).map_err(|e| anyhow!("{:?}", e))?;
+    let nonce = random_bytes(NONCE_LEN);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| anyhow!("Encryption failed"))?;
+    Ok([nonce, ciphertext].concat())
+}
+
+fn open(key: &[u8], sealed: &[u8]) -> Result<Vec<u8>, Error> {
+    if sealed.len() < NONCE_LEN {
+        return Err(anyhow!("Ciphertext is too short"));
+    }
+    let cipher = Aes256Gcm::new_from_slice(key).map_err(|e| anyhow!("{:?}", e))?;
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Decryption failed"))
+}
+
+// Splits `v{version}:{wrapped data key}:{ciphertext}`, None for values stored before encryption.
+fn parse(value: &str) -> Option<(u32, &str, &str)> {
+    let mut parts = value.splitn(3, ':');
+    let version = parts.next()?.strip_prefix('v')?.parse().ok()?;
+    Some((version, parts.next()?, parts.next()?))
+}
+
+/// Encrypts a value with a fresh data key, which is stored next to it wrapped by the current master key.
+pub fn encrypt(plaintext: &str) -> Result<String, Error> {
+    let keys = master_keys()?;
+    let version = keys.current_version();
+    let data_key = random_bytes(KEY_LEN);
+    let wrapped = seal(keys.key(version)?, &data_key)?;
+    let ciphertext = seal(&data_key, plaintext.as_bytes())?;
+    Ok(format!(
+        "v{}:{}:{}",
+        version,
+        base64::encode(wrapped),
+        base64::encode(ciphertext)
+    ))
+}
+
+/// Decrypts a value written by `encrypt`. Plaintext values from before encryption are returned as is.
+pub fn decrypt(value: &str) -> Result<String, Error> {
+    let (version, wrapped, ciphertext) = match parse(value) {
+        Some(parts) => parts,
+        None => return Ok(value.to_string()),
+    };
+    let data_key = open(master_keys()?.key(version)?, &base64::decode(wrapped)?)?;
+    let plaintext = open(&data_key, &base64::decode(ciphertext)?)?;
+    Ok(String::from_utf8(plaintext)?)
+}
+
+pub fn encrypt_all(values: &[String]) -> Result<Vec<String>, Error> {
+    values.iter().map(|v| encrypt(v)).collect()
+}
+
+pub fn decrypt_all(values: &[String]) -> Result<Vec<String>, Error> {
+    values.iter().map(|v| decrypt(v)).collect()
+}
+
+/// Brings a stored value to the current master key, None when it already is.
+/// Only the data key is re-wrapped, plaintext values are encrypted.
+pub fn reencrypt(value: &str) -> Result<Option<String>, Error> {
+    let keys = master_keys()?;
+    let current = keys.current_version();
+    match parse(value) {
+        Some((version, _, _)) if version == current => Ok(None),
+        Some((version, wrapped, ciphertext)) => {
+            let data_key = open(keys.key(version)?, &base64::decode(wrapped)?)?;
+            let wrapped = seal(keys.key(current)?, &data_key)?;
+            Ok(Some(format!("v{}:{}:{}", current, base64::encode(wrapped), ciphertext)))
+        }
+        None => encrypt(value).map(Some),
+    }
+}
+
+// Prefix of values wrapped with the current master key, for finding rows to re-encrypt.
+pub fn current_prefix() -> Result<String, Error> {
+    Ok(format!("v{}:", master_keys()?.current_version()))
+}
+
+/// Keyed hash of a value for exact-match lookups on encrypted columns.
+/// Callers normalize the value first (`normalize_email`, `normalize_phone`).
+pub fn blind_index(value: &str) -> Result<String, Error> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(blind_index_key()?).expect("HMAC accepts any key length");
+    mac.update(value.as_bytes());
+    Ok(mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Blind indexes of every NGRAM_LEN character substring of a normalized value, for substring
+/// search on encrypted columns: a query matches when all of its n-grams are in the value's.
+/// Values shorter than NGRAM_LEN have none.
+pub fn blind_ngrams(value: &str) -> Result<Vec<String>, Error> {
+    let chars: Vec<char> = value.chars().collect();
+    let mut grams = chars
+        .windows(NGRAM_LEN)
+        // separate from exact indexes, so a 3 character value's index is not one of its n-grams
+        .map(|w| blind_index(&format!("ngram:{}", w.iter().collect::<String>())))
+        .collect::<Result<Vec<String>, Error>>()?;
+    grams.sort();
+    grams.dedup();
+    Ok(grams)
+}
+
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+pub fn normalize_phone(phone: &str) -> String {
+    phone.chars().filter(|c| c.is_ascii_digit()).collect()
+}
+
+// Crypto failures inside database functions surface as sqlx errors.
+pub fn into_sqlx_error(e: Error) -> sqlx::Error {
+    sqlx::Error::Decode(e.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_keys() {
+        static KEYS: std::sync::Once = std::sync::Once::new();
+        KEYS.call_once(|| {
+            std::env::set_var("MASTER_KEY_V1", base64::encode([7u8; 32]));
+            std::env::set_var("BLIND_INDEX_KEY", base64::encode([9u8; 32]));
+        });
+    }
+
+    #[test]
+    fn encrypted_values_round_trip() {
+        set_keys();
+        check_keys().unwrap();
+        let value = encrypt("a@example.com").unwrap();
+        assert!(value.starts_with(&current_prefix().unwrap()));
+        assert_eq!(decrypt(&value).unwrap(), "a@example.com");
+        assert_eq!(decrypt("stored before encryption").unwrap(), "stored before encryption");
+        assert_eq!(reencrypt(&value).unwrap(), None);
+    }
+
+    #[test]
+    fn ngrams_of_a_substring_are_contained() {
+        set_keys();
+        let value = blind_ngrams("15551234567").unwrap();
+        let query = blind_ngrams("5123").unwrap();
+        assert_eq!(query.len(), 2);
+        assert!(query.iter().all(|g| value.contains(g)));
+        assert!(!blind_ngrams("999").unwrap().iter().all(|g| value.contains(g)));
+        assert!(blind_ngrams("55").unwrap().is_empty());
+    }
+
+    #[test]
+    fn ngrams_are_not_exact_indexes() {
+        set_keys();
+        assert_ne!(blind_ngrams("abc").unwrap(), vec![blind_index("abc").unwrap()]);
+    }
+}
@@ -0,0 +1,407 @@
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::Error;
+use lazy_static::lazy_static;
+use rdkafka::consumer::{CommitMode, Consumer};
+use rdkafka::message::OwnedHeaders;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::{ClientConfig, Message};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+
+use crate::server::consumer::get_manual_commit_consumer;
+
+// Domain events shared between the services, separate from the websocket messages on bhuman_channel.
+pub const EVENTS_TOPIC: &str = "bhuman_events";
+
+// Messages ai_studio forwards to the websocket clients of `user_id`.
+pub const WS_TOPIC: &str = "bhuman_channel";
+
+// Events a service still failed to handle after HANDLER_ATTEMPTS, with the service's group id
+// and the error in the headers, to be replayed once fixed.
+pub const DEAD_LETTER_TOPIC: &str = "bhuman_events_dead_letter";
+
+const HANDLER_ATTEMPTS: u32 = 5;
+const HANDLER_BACKOFF: Duration = Duration::from_secs(1);
+const OUTBOX_INTERVAL: Duration = Duration::from_secs(1);
+const OUTBOX_BATCH: i64 = 100;
+
+lazy_static! {
+    static ref KAFKA_BROKERS: String =
+        std::env::var("KAFKA_BROKERS").unwrap_or_else(|_| "127.0.0.1:9092".to_owned());
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    // Everything owned by `source_user_id` now belongs to `target_user_id`.
+    AccountMerged {
+        source_user_id: String,
+        target_user_id: String,
+    },
+    // Membership rows are owned by workspace_microservice, user_microservice projects
+    // them into `users.workspace_ids`.
+    WorkspaceMemberAdded {
+        user_id: String,
+        workspace_id: String,
+    },
+    WorkspaceMemberRemoved {
+        user_id: String,
+        workspace_id: String,
+    },
+    // The grace period of an account deletion is over, every service erases the data of
+    // `user_id` and answers with `UserDataErased`. `workspaces` is "transfer" or "delete",
+    // what happens to the workspaces the user owns.
+    UserDeletionRequested {
+        deletion_id: String,
+        user_id: String,
+        workspaces: String,
+    },
+    // `remaining` is what the service still found for the user after erasing, 0 once verified.
+    UserDataErased {
+        deletion_id: String,
+        user_id: String,
+        service: String,
+        erased: i64,
+        remaining: i64,
+    },
+    // `invitee_id` verified an invite sent by `invitor_id` at `invited_at` (unix seconds),
+    // user_microservice grants the referral credits once the invitee has signed up.
+    InviteAccepted {
+        invitee_id: String,
+        invitor_id: String,
+        invited_at: i64,
+    },
+    // auth_service enabled or disabled TOTP, user_microservice keeps `users.two_fator` in sync.
+    TwoFactorChanged {
+        user_id: String,
+        enabled: bool,
+    },
+}
+
+impl Event {
+    // Events about the same user share a partition, so they are handled in order.
+    pub fn key(&self) -> &String {
+        match self {
+            Event::AccountMerged { target_user_id, .. } => target_user_id,
+            Event::WorkspaceMemberAdded { user_id, .. } => user_id,
+            Event::WorkspaceMemberRemoved { user_id, .. } => user_id,
+            Event::UserDeletionRequested { user_id, .. } => user_id,
+            Event::UserDataErased { user_id, .. } => user_id,
+            Event::InviteAccepted { invitee_id, .. } => invitee_id,
+            Event::TwoFactorChanged { user_id, .. } => user_id,
+        }
+    }
+}
+
+pub fn event_producer() -> FutureProducer {
+    ClientConfig::new()
+        .set("bootstrap.servers", KAFKA_BROKERS.as_str())
+        .set("message.timeout.ms", "5000")
+        .create()
+        .expect("Producer creation failed")
+}
+
+pub async fn publish(event: &Event, producer: &FutureProducer) -> Result<(), Error> {
+    let payload = serde_json::to_string(event)?;
+    producer
+        .send(
+            FutureRecord::to(EVENTS_TOPIC)
+                .payload(&payload)
+                .key(event.key())
+                .headers(OwnedHeaders::default()),
+            Duration::from_secs(1),
+        )
+        .await
+        .map_err(|(e, _)| Error::from(e))?;
+    Ok(())
+}
+
+/// Sends a message to the user's open websocket connections, in the format of the
+/// messages workspace_microservice sends on `WS_TOPIC`.
+pub async fn notify_user(
+    user_id: &String,
+    message_type: &str,
+    message: &serde_json::Value,
+    producer: &FutureProducer,
+) -> Result<(), Error> {
+    let payload = serde_json::json!({
+        "user_id": user_id,
+        "message_type": message_type,
+        "message": message.to_string(),
+    })
+    .to_string();
+    producer
+        .send(
+            FutureRecord::to(WS_TOPIC)
+                .payload(&payload)
+                .key(user_id)
+                .headers(OwnedHeaders::default()),
+            Duration::from_secs(1),
+        )
+        .await
+        .map_err(|(e, _)| Error::from(e))?;
+    Ok(())
+}
+
+/// Adds an event to the service's outbox, in the transaction of the change it announces, so it
+/// is published if and only if the change is committed. `spawn_outbox_relay` publishes it.
+///
+/// Services with an outbox create the table in their schema:
+/// `CREATE TABLE IF NOT EXISTS event_outbox (id BIGSERIAL PRIMARY KEY, payload TEXT NOT NULL,
+/// created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP);`
+pub async fn enqueue(event: &Event, conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+    let payload = serde_json::to_string(event).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+    sqlx::query("INSERT INTO event_outbox (payload) VALUES ($1)")
+        .bind(payload)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Sends the pending outbox events in order, removing each once sent, and returns how many were.
+/// Stops at the first failure so later events don't overtake it. Only one replica relays at a time.
+pub async fn relay_outbox<F, Fut>(pool: &PgPool, send: F) -> Result<u64, Error>
+where
+    F: Fn(Event) -> Fut,
+    Fut: Future<Output = Result<(), Error>>,
+{
+    let mut tx = pool.begin().await?;
+    let (locked,): (bool,) = sqlx::query_as("SELECT pg_try_advisory_xact_lock(hashtext('event_outbox'))")
+        .fetch_one(&mut tx)
+        .await?;
+    if !locked {
+        return Ok(0);
+    }
+
+    let rows: Vec<(i64, String)> = sqlx::query_as("SELECT id, payload FROM event_outbox ORDER BY id LIMIT $1")
+        .bind(OUTBOX_BATCH)
+        .fetch_all(&mut tx)
+        .await?;
+    let mut sent = 0;
+    let mut failure = None;
+    for (id, payload) in rows {
+        let event: Event = serde_json::from_str(&payload)?;
+        if let Err(e) = send(event).await {
+            failure = Some(e);
+            break;
+        }
+        sqlx::query("DELETE FROM event_outbox WHERE id = $1")
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        sent += 1;
+    }
+    tx.commit().await?;
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(sent),
+    }
+}
+
+/// Publishes the outbox of the service in the background.
+pub fn spawn_outbox_relay(pool: &PgPool) {
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        let producer = event_producer();
+        loop {
+            let res = relay_outbox(&pool, |event| {
+                let producer = &producer;
+                async move { publish(&event, producer).await }
+            })
+            .await;
+            if let Err(e) = res {
+                println!("Failed to relay the event outbox: {:?}", e);
+            }
+            tokio::time::sleep(OUTBOX_INTERVAL).await;
+        }
+    });
+}
+
+// Runs the handler until it succeeds, waiting longer after each failure. The last error is
+// returned once `attempts` are used up.
+async fn handle_with_retry<F, Fut>(
+    handler: &F,
+    event: &Event,
+    attempts: u32,
+    backoff: Duration,
+) -> Result<(), Error>
+where
+    F: Fn(Event) -> Fut,
+    Fut: Future<Output = Result<(), Error>>,
+{
+    let mut attempt = 1;
+    loop {
+        match handler(event.clone()).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt >= attempts => return Err(e),
+            Err(e) => {
+                println!("Failed to handle {:?} (attempt {}): {:?}", event, attempt, e);
+                tokio::time::sleep(backoff * 2u32.pow(attempt - 1)).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+async fn dead_letter(
+    payload: &str,
+    key: &str,
+    group_id: &str,
+    error: &Error,
+    producer: &FutureProducer,
+) -> Result<(), Error> {
+    let error = format!("{:?}", error);
+    producer
+        .send(
+            FutureRecord::to(DEAD_LETTER_TOPIC)
+                .payload(payload)
+                .key(key)
+                .headers(
+                    OwnedHeaders::new()
+                        .add("group_id", group_id)
+                        .add("error", error.as_str()),
+                ),
+            Duration::from_secs(1),
+        )
+        .await
+        .map_err(|(e, _)| Error::from(e))?;
+    Ok(())
+}
+
+/// Consumes `EVENTS_TOPIC` in the background. Each service uses its own `group_id`
+/// so every service sees every event. Events the service doesn't know are skipped.
+///
+/// Failed events are retried, then moved to `DEAD_LETTER_TOPIC`. The offset is only committed
+/// once the event is handled or dead-lettered, so nothing is lost when the service stops.
+pub fn start_event_consumer<F, Fut>(group_id: &'static str, handler: F)
+where
+    F: Fn(Event) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), Error>> + Send,
+{
+    tokio::spawn(async move {
+        let consumer = get_manual_commit_consumer(KAFKA_BROKERS.as_str(), group_id, &[EVENTS_TOPIC]);
+        let producer = event_producer();
+        loop {
+            match consumer.recv().await {
+                Err(e) => println!("Kafka error: {}", e),
+                Ok(m) => {
+                    let payload = match m.payload_view::<str>() {
+                        Some(Ok(payload)) => payload,
+                        _ => "",
+                    };
+                    if let Ok(event) = serde_json::from_str::<Event>(payload) {
+                        if let Err(e) = handle_with_retry(&handler, &event, HANDLER_ATTEMPTS, HANDLER_BACKOFF).await {
+                            println!("Failed to handle {:?}, moving it to {}: {:?}", event, DEAD_LETTER_TOPIC, e);
+                            while let Err(dl) = dead_letter(payload, event.key(), group_id, &e, &producer).await {
+                                println!("Kafka dead letter error: {:?}", dl);
+                                tokio::time::sleep(HANDLER_BACKOFF).await;
+                            }
+                        }
+                    }
+                    if let Err(e) = consumer.commit_message(&m, CommitMode::Async) {
+                        println!("Kafka commit error: {}", e);
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::Executor;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Mutex;
+
+    fn merged(source: &str) -> Event {
+        Event::AccountMerged {
+            source_user_id: source.to_string(),
+            target_user_id: "target".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn handler_is_retried_until_it_succeeds() {
+        let calls = AtomicU32::new(0);
+        let handler = |_: Event| {
+            let n = calls.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if n < 2 {
+                    Err(Error::msg("not yet"))
+                } else {
+                    Ok(())
+                }
+            }
+        };
+        handle_with_retry(&handler, &merged("a"), 5, Duration::from_millis(1)).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn handler_gives_up_after_the_last_attempt() {
+        let calls = AtomicU32::new(0);
+        let handler = |_: Event| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            async { Err(Error::msg("always")) }
+        };
+        assert!(handle_with_retry(&handler, &merged("a"), 3, Duration::from_millis(1)).await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    // Runs against TEST_DATABASE_URL, with its own outbox table.
+    async fn outbox_pool() -> PgPool {
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL");
+        let pool = PgPool::connect(&url).await.unwrap();
+        pool.execute(
+            "DROP TABLE IF EXISTS event_outbox;
+            CREATE TABLE event_outbox (id BIGSERIAL PRIMARY KEY, payload TEXT NOT NULL, created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP);",
+        )
+        .await
+        .unwrap();
+        pool
+    }
+
+    // Both cases share the table, so they run in one test.
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn outbox_publishes_committed_events_in_order() {
+        let pool = outbox_pool().await;
+
+        let mut tx = pool.begin().await.unwrap();
+        enqueue(&merged("rolled back"), &mut tx).await.unwrap();
+        tx.rollback().await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        for source in ["a", "b", "c"] {
+            enqueue(&merged(source), &mut tx).await.unwrap();
+        }
+        tx.commit().await.unwrap();
+
+        // the second send fails, later events wait for it
+        let sent = Mutex::new(Vec::new());
+        let fail_on = Mutex::new(Some(merged("b")));
+        let res = relay_outbox(&pool, |event| {
+            let fail = fail_on.lock().unwrap().as_ref() == Some(&event);
+            if !fail {
+                sent.lock().unwrap().push(event);
+            }
+            async move { if fail { Err(Error::msg("kafka is down")) } else { Ok(()) } }
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(*sent.lock().unwrap(), vec![merged("a")]);
+
+        *fail_on.lock().unwrap() = None;
+        let count = relay_outbox(&pool, |event| {
+            sent.lock().unwrap().push(event);
+            async { Ok(()) }
+        })
+        .await
+        .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(*sent.lock().unwrap(), vec![merged("a"), merged("b"), merged("c")]);
+        assert_eq!(relay_outbox(&pool, |_| async { Ok(()) }).await.unwrap(), 0);
+    }
+}
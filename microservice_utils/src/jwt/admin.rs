@@ -0,0 +1,15 @@
+use lazy_static::lazy_static;
+
+lazy_static! {
+    // Comma separated user ids allowed to use the admin endpoints.
+    static ref ADMIN_USER_IDS: Vec<String> = std::env::var("ADMIN_USER_IDS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty())
+        .collect();
+}
+
+pub fn is_admin(user_id: &str) -> bool {
+    ADMIN_USER_IDS.iter().any(|id| id == user_id)
+}
@@ -1,5 +1,6 @@
 use anyhow::Error;
 use axum::extract::TypedHeader;
+use http::Method;
 use headers::{authorization::Bearer, Authorization};
 use chrono::Duration;
 use jsonwebtoken::{
@@ -7,7 +8,9 @@ use jsonwebtoken::{
 };
 use serde::Deserialize;
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::ops::Add;
+use uuid::Uuid;
 
 use crate::server::grpc::check_token;
 
@@ -16,6 +19,18 @@ pub struct Claims {
     pub sub: String,
     pub company: String,
     pub exp: usize,
+    #[serde(default)]
+    pub jti: String, // unique per token, so two tokens issued in the same second differ
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<String>, // admin acting as `sub` in an impersonation token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>, // impersonation scopes, space separated
+}
+
+impl Claims {
+    pub fn is_impersonation(&self) -> bool {
+        self.act.is_some()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -38,12 +53,18 @@ pub fn create_token(user_id: &String) -> Token {
         company: "hailey".to_string(),
         exp: chrono::Utc::now().add(Duration::days(7)).timestamp() as usize,
         sub: user_id.to_string(),
+        jti: Uuid::new_v4().to_string(),
+        act: None,
+        scope: None,
     };
 
     let ref_claims = Claims {
         company: "hailey".to_string(),
         exp: chrono::Utc::now().add(Duration::days(30)).timestamp() as usize,
         sub: user_id.to_string(),
+        jti: Uuid::new_v4().to_string(),
+        act: None,
+        scope: None,
     };
 
     let access_token = gen_jwt(&acc_claims);
@@ -54,9 +75,44 @@ pub fn create_token(user_id: &String) -> Token {
     }
 }
 
+/// Access token for an admin acting as a user. It has no refresh token and is only
+/// accepted by auth_service for the scopes it was granted (`read` unless more were given).
+pub fn create_impersonation_token(user_id: &String, admin_id: &String, scopes: &[String], ttl: Duration) -> String {
+    gen_jwt(&Claims {
+        company: "hailey".to_string(),
+        exp: chrono::Utc::now().add(ttl).timestamp() as usize,
+        sub: user_id.to_string(),
+        jti: Uuid::new_v4().to_string(),
+        act: Some(admin_id.to_string()),
+        scope: Some(scopes.join(" ")),
+    })
+}
+
+// Refresh tokens are only stored as this digest.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn decode_token(token: &str) -> Result<Claims, Error> {
+    let validation = Validation::default();
+    let token_data = decode::<Claims>(token, &DecodingKey::from_secret(b"secret"), &validation)
+        .map_err(|e| match *e.kind() {
+            ErrorKind::ExpiredSignature => anyhow::anyhow!("Token is expired"),
+            _ => anyhow::anyhow!("Token is invalid"),
+        })?;
+    Ok(token_data.claims)
+}
+
+/// Validates the bearer token with auth_service. The method and path let auth_service
+/// restrict and audit impersonated requests.
 pub async fn jwt_auth(
     TypedHeader(cookies): TypedHeader<Authorization<Bearer>>,
-) -> Result<String, Error> {
+    method: &Method,
+    path: &str,
+) -> Result<Claims, Error> {
     let token = cookies.0.token();
     let validation = Validation::default();
     let token_data = decode::<Claims>(&token, &DecodingKey::from_secret(b"secret"), &validation)
@@ -70,9 +126,12 @@ pub async fn jwt_auth(
     if user_id.is_empty() {
         Err(Error::msg("User id is empty".to_string()))
     } else {
-        let res = check_token(&user_id.to_string(), &token.to_string()).await;
+        if let Some(admin_id) = &token_data.claims.act {
+            println!("Impersonated request {} {} for user {} by admin {}", method, path, user_id, admin_id);
+        }
+        let res = check_token(&user_id.to_string(), &token.to_string(), method.as_str(), path).await;
         match res {
-            Ok(_) => Ok(user_id.to_string()),
+            Ok(_) => Ok(token_data.claims),
             Err(e) => Err(e),
         }
     }
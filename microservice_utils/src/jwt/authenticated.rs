@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+use axum::{async_trait, extract::RequestParts, TypedHeader};
+use headers::{
+    authorization::{Basic, Bearer},
+    Authorization,
+};
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use okapi::openapi3::{SecurityRequirement, SecurityScheme, SecuritySchemeData};
+use openapi_rs::{
+    gen::OpenApiGenerator,
+    request::{OpenApiFromRequest, RequestHeaderInput},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use super::auth::jwt_auth;
+use super::client::decode_client_token;
+use crate::server::grpc::{check_api_client, verify_api_key};
+
+// Header carrying an api key as `client_id:client_secret`
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+const API_KEY_CACHE_TTL: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    // Key of the cached secret digests, random per process so the cache holds nothing reusable.
+    static ref API_KEY_CACHE_KEY: [u8; 32] = rand::random();
+    // client_id -> (digest of the client_secret, principal, verified at)
+    static ref API_KEY_CACHE: Mutex<HashMap<String, (Vec<u8>, Principal, Instant)>> =
+        Mutex::new(HashMap::new());
+    // client_id -> when its key was last seen, for client-credentials tokens
+    static ref ACTIVE_CLIENT_CACHE: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+}
+
+fn secret_digest(client_secret: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&*API_KEY_CACHE_KEY).expect("HMAC accepts any key length");
+    mac.update(client_secret.as_bytes());
+    mac
+}
+
+// Principal of a key verified less than API_KEY_CACHE_TTL ago, the secret is compared in constant time.
+fn cached_api_key(client_id: &str, client_secret: &str) -> Option<Principal> {
+    let cache = API_KEY_CACHE.lock().unwrap();
+    let (digest, principal, at) = cache.get(client_id)?;
+    if at.elapsed() < API_KEY_CACHE_TTL && secret_digest(client_secret).verify_slice(digest).is_ok() {
+        Some(principal.clone())
+    } else {
+        None
+    }
+}
+
+fn cache_api_key(client_id: &str, client_secret: &str, principal: &Principal) {
+    let digest = secret_digest(client_secret).finalize().into_bytes().to_vec();
+    API_KEY_CACHE
+        .lock()
+        .unwrap()
+        .insert(client_id.to_string(), (digest, principal.clone(), Instant::now()));
+}
+
+// Tokens of a deleted api key stop working within API_KEY_CACHE_TTL, like the key itself.
+async fn client_active(client_id: &String) -> Result<bool, Error> {
+    let seen = ACTIVE_CLIENT_CACHE.lock().unwrap().get(client_id).copied();
+    if seen.filter(|at| at.elapsed() < API_KEY_CACHE_TTL).is_some() {
+        return Ok(true);
+    }
+    let active = check_api_client(client_id).await?;
+    let mut cache = ACTIVE_CLIENT_CACHE.lock().unwrap();
+    if active {
+        cache.insert(client_id.to_string(), Instant::now());
+    } else {
+        cache.remove(client_id);
+    }
+    Ok(active)
+}
+
+// Scopes api keys can be granted, an integration is refused what they don't cover.
+pub const API_KEY_SCOPES: &[&str] = &[
+    "contacts:read",
+    "contacts:write",
+    "files:read",
+    "files:write",
+    "videos:read",
+    "videos:write",
+];
+
+pub fn is_api_key_scope(scope: &str) -> bool {
+    API_KEY_SCOPES.contains(&scope)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum PrincipalKind {
+    User,
+    Integration,
+}
+
+impl Default for PrincipalKind {
+    fn default() -> Self {
+        PrincipalKind::User
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Principal {
+    pub user_id: String,           // user that owns the request (key owner for integrations)
+    pub kind: PrincipalKind,       // user or integration
+    pub client_id: Option<String>, // api key client id for integrations
+    pub scopes: Vec<String>,       // scopes granted to an integration
+    pub impersonator: Option<String>, // admin acting as the user, to show in audit records
+}
+
+impl Principal {
+    pub fn is_user(&self) -> bool {
+        self.kind == PrincipalKind::User
+    }
+
+    // Users act with their full rights, integrations only with the scopes of their key.
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.is_user() || self.scopes.iter().any(|s| s == scope)
+    }
+
+    pub fn require_scope(&self, scope: &str) -> Result<(), Error> {
+        if self.has_scope(scope) {
+            Ok(())
+        } else {
+            Err(Error::msg(format!("Missing scope {}", scope)))
+        }
+    }
+}
+
+/// Accepts a user access token or a client-credentials token (`Authorization: Bearer`), or an
+/// api key sent either as HTTP Basic (`client_id:client_secret`) or in the `X-Api-Key` header.
+#[derive(Default, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Authenticated(pub Principal);
+
+pub async fn api_key_auth(client_id: &String, client_secret: &String) -> Result<Principal, Error> {
+    if let Some(principal) = cached_api_key(client_id, client_secret) {
+        return Ok(principal);
+    }
+
+    let (user_id, scopes) = verify_api_key(client_id, client_secret).await?;
+    let principal = Principal {
+        user_id,
+        kind: PrincipalKind::Integration,
+        client_id: Some(client_id.to_string()),
+        scopes,
+        impersonator: None,
+    };
+
+    cache_api_key(client_id, client_secret, &principal);
+    Ok(principal)
+}
+
+fn rejection(e: impl std::fmt::Debug) -> String {
+    let ret = serde_json::json!({
+        "code": 404,
+        "body": format!("{:?}", e),
+    });
+    ret.to_string()
+}
+
+#[async_trait]
+impl<T> axum::extract::FromRequest<T> for Authenticated
+where
+    T: Send,
+{
+    type Rejection = String;
+
+    async fn from_request(req: &mut RequestParts<T>) -> Result<Self, Self::Rejection> {
+        if let Some(value) = req.headers().get(API_KEY_HEADER) {
+            let value = value.to_str().map_err(rejection)?;
+            let (client_id, client_secret) = value
+                .split_once(':')
+                .ok_or_else(|| rejection("Api key must be client_id:client_secret"))?;
+            return api_key_auth(&client_id.to_string(), &client_secret.to_string())
+                .await
+                .map(Authenticated)
+                .map_err(rejection);
+        }
+
+        if let Ok(TypedHeader(Authorization(basic))) =
+            TypedHeader::<Authorization<Basic>>::from_request(req).await
+        {
+            return api_key_auth(&basic.username().to_string(), &basic.password().to_string())
+                .await
+                .map(Authenticated)
+                .map_err(rejection);
+        }
+
+        let bearer = TypedHeader::<Authorization<Bearer>>::from_request(req)
+            .await
+            .map_err(rejection)?;
+
+        // access tokens from the client-credentials grant are valid while their key exists
+        if let Ok(claims) = decode_client_token(bearer.0.token()) {
+            if !client_active(&claims.client_id).await.map_err(rejection)? {
+                return Err(rejection("Api key was deleted"));
+            }
+            return Ok(Authenticated(Principal {
+                user_id: claims.sub.clone(),
+                kind: PrincipalKind::Integration,
+                client_id: Some(claims.client_id.clone()),
+                scopes: claims.scopes(),
+                impersonator: None,
+            }));
+        }
+
+        let method = req.method().clone();
+        let path = req.uri().path().to_string();
+        jwt_auth(bearer, &method, &path)
+            .await
+            .map(|claims| {
+                Authenticated(Principal {
+                    user_id: claims.sub,
+                    kind: PrincipalKind::User,
+                    client_id: None,
+                    scopes: Vec::new(),
+                    impersonator: claims.act,
+                })
+            })
+            .map_err(rejection)
+    }
+}
+
+impl<T> OpenApiFromRequest<T> for Authenticated
+where
+    T: Send,
+{
+    fn from_request_input(
+        _gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> anyhow::Result<RequestHeaderInput> {
+        let security_scheme = SecurityScheme {
+            description: Some(
+                "Requires an Access Token, or an api key as HTTP Basic / X-Api-Key (client_id:client_secret)"
+                    .to_owned(),
+            ),
+            data: SecuritySchemeData::Http {
+                scheme: "bearer".into(),
+                bearer_format: Some("JWT".into()),
+            },
+            extensions: okapi::map! {},
+        };
+        let mut security_req = SecurityRequirement::new();
+
+        security_req.insert("Bearer".to_owned(), Vec::new());
+
+        Ok(RequestHeaderInput::Security(
+            "Bearer".to_owned(),
+            security_scheme,
+            security_req,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(user_id: &str) -> Principal {
+        Principal {
+            user_id: user_id.to_string(),
+            kind: PrincipalKind::Integration,
+            client_id: Some(format!("client-{}", user_id)),
+            scopes: vec!["contacts:read".to_string()],
+            impersonator: None,
+        }
+    }
+
+    #[test]
+    fn cached_key_needs_the_same_secret() {
+        cache_api_key("client-a", "secret-a", &principal("a"));
+        assert_eq!(cached_api_key("client-a", "secret-a"), Some(principal("a")));
+        assert_eq!(cached_api_key("client-a", "secret-b"), None);
+        assert_eq!(cached_api_key("client-b", "secret-a"), None);
+    }
+
+    #[test]
+    fn cache_does_not_hold_the_secret() {
+        cache_api_key("client-c", "secret-c", &principal("c"));
+        let cache = API_KEY_CACHE.lock().unwrap();
+        let (digest, _, _) = cache.get("client-c").unwrap();
+        assert_ne!(digest.as_slice(), "secret-c".as_bytes());
+        assert_eq!(digest.len(), 32);
+    }
+}
@@ -0,0 +1,62 @@
+use anyhow::Error;
+use chrono::Duration;
+use jsonwebtoken::{decode, encode, errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::ops::Add;
+use uuid::Uuid;
+
+// Marks client-credentials tokens so they can't be mistaken for user tokens.
+pub const CLIENT_TOKEN_USE: &str = "client";
+
+pub const CLIENT_TOKEN_TTL_SECONDS: i64 = 900;
+
+/// Claims of an access token issued through the OAuth2 client-credentials grant.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientClaims {
+    pub sub: String,       // user owning the api key
+    pub client_id: String, // api key client id
+    pub scope: String,     // granted scopes, space separated
+    pub token_use: String,
+    pub iat: usize,
+    pub exp: usize,
+    pub jti: String,
+}
+
+impl ClientClaims {
+    pub fn scopes(&self) -> Vec<String> {
+        self.scope.split_whitespace().map(|s| s.to_string()).collect()
+    }
+}
+
+pub fn create_client_token(user_id: &String, client_id: &String, scopes: &[String]) -> (String, ClientClaims) {
+    let now = chrono::Utc::now();
+    let claims = ClientClaims {
+        sub: user_id.to_string(),
+        client_id: client_id.to_string(),
+        scope: scopes.join(" "),
+        token_use: CLIENT_TOKEN_USE.to_string(),
+        iat: now.timestamp() as usize,
+        exp: now.add(Duration::seconds(CLIENT_TOKEN_TTL_SECONDS)).timestamp() as usize,
+        jti: Uuid::new_v4().to_string(),
+    };
+    let token = encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret("secret".as_ref()),
+    )
+    .unwrap();
+    (token, claims)
+}
+
+pub fn decode_client_token(token: &str) -> Result<ClientClaims, Error> {
+    let validation = Validation::new(Algorithm::HS256);
+    let token_data = decode::<ClientClaims>(token, &DecodingKey::from_secret(b"secret"), &validation)
+        .map_err(|e| match *e.kind() {
+            ErrorKind::ExpiredSignature => anyhow::anyhow!("Token is expired"),
+            _ => anyhow::anyhow!("Token is invalid"),
+        })?;
+    if token_data.claims.token_use != CLIENT_TOKEN_USE {
+        return Err(Error::msg("Token is invalid"));
+    }
+    Ok(token_data.claims)
+}
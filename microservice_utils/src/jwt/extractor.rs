@@ -32,7 +32,9 @@ where
                 });
                 ret.to_string()
             })?;
-        jwt_auth(cookies)
+        let method = req.method().clone();
+        let path = req.uri().path().to_string();
+        jwt_auth(cookies, &method, &path)
             .await
             .map_err(|e| {
                 let ret = serde_json::json!({
@@ -41,7 +43,7 @@ where
                 });
                 ret.to_string()
             })
-            .map(|id| AuthToken(id))
+            .map(|claims| AuthToken(claims.sub))
     }
 }
 
@@ -84,3 +86,61 @@ where
         ))
     }
 }
+
+/// Same as `AuthToken`, but also keeps the access token so handlers can find the current session.
+#[derive(Serialize, Default, Deserialize, JsonSchema)]
+pub struct AuthSession {
+    pub user_id: String,
+    pub access_token: String,
+    pub impersonator: Option<String>, // admin acting as the user, to show in audit records
+}
+
+#[async_trait]
+impl<T> axum::extract::FromRequest<T> for AuthSession
+where
+    T: Send,
+{
+    type Rejection = String;
+
+    async fn from_request(req: &mut RequestParts<T>) -> Result<Self, Self::Rejection> {
+        let cookies = TypedHeader::<Authorization<Bearer>>::from_request(req)
+            .await
+            .map_err(|e| {
+                let ret = serde_json::json!({
+                    "code": 404,
+                    "body": format!("{:?}", e),
+                });
+                ret.to_string()
+            })?;
+        let access_token = cookies.0.token().to_string();
+        let method = req.method().clone();
+        let path = req.uri().path().to_string();
+        jwt_auth(cookies, &method, &path)
+            .await
+            .map_err(|e| {
+                let ret = serde_json::json!({
+                    "code": 404,
+                    "body": format!("{:?}", e),
+                });
+                ret.to_string()
+            })
+            .map(|claims| AuthSession {
+                user_id: claims.sub,
+                access_token,
+                impersonator: claims.act,
+            })
+    }
+}
+
+impl<T> OpenApiFromRequest<T> for AuthSession
+where
+    T: Send,
+{
+    fn from_request_input(
+        gen: &mut OpenApiGenerator,
+        name: String,
+        required: bool,
+    ) -> anyhow::Result<RequestHeaderInput> {
+        <AuthToken as OpenApiFromRequest<T>>::from_request_input(gen, name, required)
+    }
+}
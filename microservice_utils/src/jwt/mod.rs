@@ -1,2 +1,6 @@
 pub mod extractor;
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+pub mod authenticated;
+pub mod client;
+pub mod admin;
+pub mod share;
\ No newline at end of file
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use anyhow::Error;
+use axum::{
+    async_trait,
+    extract::{Path, Query, RequestParts},
+    TypedHeader,
+};
+use chrono::Duration;
+use headers::{authorization::Bearer, Authorization};
+use jsonwebtoken::{decode, encode, errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use openapi_rs::{
+    gen::OpenApiGenerator,
+    request::{OpenApiFromRequest, RequestHeaderInput},
+};
+use serde::{Deserialize, Serialize};
+use std::ops::Add;
+
+use crate::server::grpc::check_share_token;
+use crate::server::response::{into_reponse, ResponseError};
+
+// Marks share tokens so they can't be used as user or client tokens.
+pub const SHARE_TOKEN_USE: &str = "share";
+
+// Query parameter carrying a share token, for links and embeds that can't set headers.
+pub const SHARE_TOKEN_PARAM: &str = "share_token";
+
+pub const VIDEO_RESOURCE: &str = "video";
+pub const WS_BOARD_RESOURCE: &str = "ws_board";
+pub const FOLDER_RESOURCE: &str = "folder";
+
+pub const READ_ACTION: &str = "read";
+pub const UPLOAD_ACTION: &str = "upload";
+
+// Resource and action pairs share tokens can be issued for.
+pub const SHARE_SCOPES: &[(&str, &str)] = &[
+    (VIDEO_RESOURCE, READ_ACTION),
+    (WS_BOARD_RESOURCE, READ_ACTION),
+    (FOLDER_RESOURCE, UPLOAD_ACTION),
+];
+
+pub fn is_share_scope(resource_type: &str, action: &str) -> bool {
+    SHARE_SCOPES.iter().any(|(r, a)| *r == resource_type && *a == action)
+}
+
+/// Claims of a share token: access to one action on one resource of `sub`.
+/// Expiry is in the token, use counts and revocation are checked by auth_service.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareClaims {
+    pub sub: String, // user who shared the resource
+    pub resource_type: String,
+    pub resource_id: String,
+    pub action: String,
+    pub token_use: String,
+    pub iat: usize,
+    pub exp: usize,
+    pub jti: String, // share token id
+}
+
+pub fn create_share_token(
+    id: &String,
+    user_id: &String,
+    resource_type: &String,
+    resource_id: &String,
+    action: &String,
+    ttl: Duration,
+) -> String {
+    let now = chrono::Utc::now();
+    let claims = ShareClaims {
+        sub: user_id.to_string(),
+        resource_type: resource_type.to_string(),
+        resource_id: resource_id.to_string(),
+        action: action.to_string(),
+        token_use: SHARE_TOKEN_USE.to_string(),
+        iat: now.timestamp() as usize,
+        exp: now.add(ttl).timestamp() as usize,
+        jti: id.to_string(),
+    };
+    encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret("secret".as_ref()),
+    )
+    .unwrap()
+}
+
+pub fn decode_share_token(token: &str) -> Result<ShareClaims, Error> {
+    let validation = Validation::new(Algorithm::HS256);
+    let token_data = decode::<ShareClaims>(token, &DecodingKey::from_secret(b"secret"), &validation)
+        .map_err(|e| match *e.kind() {
+            ErrorKind::ExpiredSignature => anyhow::anyhow!("Token is expired"),
+            _ => anyhow::anyhow!("Token is invalid"),
+        })?;
+    if token_data.claims.token_use != SHARE_TOKEN_USE {
+        return Err(Error::msg("Token is invalid"));
+    }
+    Ok(token_data.claims)
+}
+
+/// The resource and action a route serves to share tokens. `PARAM` names the path
+/// or query parameter holding the resource id.
+pub trait ShareScope: Send + Sync {
+    const RESOURCE: &'static str;
+    const ACTION: &'static str;
+    const PARAM: &'static str;
+}
+
+/// Accepts a share token (`Authorization: Bearer` or `?share_token=`) issued for the
+/// route's resource type and action, and for the resource id in the request. Every
+/// accepted request counts as a use of the token.
+///
+/// Handlers keep filtering on `user_id`, so a token only reaches what its creator owns.
+#[derive(Debug)]
+pub struct ShareToken<S> {
+    pub user_id: String,
+    pub resource_id: String,
+    scope: PhantomData<S>,
+}
+
+fn share_rejection(e: impl std::fmt::Display) -> ResponseError {
+    into_reponse(404, serde_json::json!({ "error": e.to_string() }))
+}
+
+#[async_trait]
+impl<T, S> axum::extract::FromRequest<T> for ShareToken<S>
+where
+    T: Send,
+    S: ShareScope,
+{
+    type Rejection = ResponseError;
+
+    async fn from_request(req: &mut RequestParts<T>) -> Result<Self, Self::Rejection> {
+        let query = Query::<HashMap<String, String>>::from_request(req)
+            .await
+            .map(|q| q.0)
+            .unwrap_or_default();
+        let path = Path::<HashMap<String, String>>::from_request(req)
+            .await
+            .map(|p| p.0)
+            .unwrap_or_default();
+
+        let token = match TypedHeader::<Authorization<Bearer>>::from_request(req).await {
+            Ok(TypedHeader(Authorization(bearer))) => bearer.token().to_string(),
+            Err(_) => query
+                .get(SHARE_TOKEN_PARAM)
+                .cloned()
+                .ok_or_else(|| share_rejection("Share token is missing"))?,
+        };
+        let resource_id = path
+            .get(S::PARAM)
+            .or_else(|| query.get(S::PARAM))
+            .cloned()
+            .ok_or_else(|| share_rejection(format!("{} is missing", S::PARAM)))?;
+
+        let claims = decode_share_token(&token).map_err(share_rejection)?;
+        if claims.resource_type != S::RESOURCE
+            || claims.action != S::ACTION
+            || claims.resource_id != resource_id
+        {
+            return Err(share_rejection("Share token is not valid for this resource"));
+        }
+
+        let user_id = check_share_token(&token, S::RESOURCE, &resource_id, S::ACTION)
+            .await
+            .map_err(share_rejection)?;
+        Ok(ShareToken {
+            user_id,
+            resource_id,
+            scope: PhantomData,
+        })
+    }
+}
+
+impl<T, S> OpenApiFromRequest<T> for ShareToken<S>
+where
+    T: Send,
+    S: ShareScope,
+{
+    fn from_request_input(
+        _gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> anyhow::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::None)
+    }
+}
@@ -1,3 +1,5 @@
 pub mod server;
 pub mod jwt;
-pub mod open_api;
\ No newline at end of file
+pub mod open_api;
+pub mod crypto;
+pub mod events;
\ No newline at end of file
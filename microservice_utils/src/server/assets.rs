@@ -0,0 +1,429 @@
+use axum::body::{boxed, Empty, StreamBody};
+use axum::response::Response;
+use futures_util::future::BoxFuture;
+use http::{header, HeaderMap, HeaderValue, Request, StatusCode};
+use percent_encoding::percent_decode;
+use std::{
+    fs::Metadata,
+    io::{self, SeekFrom},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    task::{Context, Poll},
+    time::UNIX_EPOCH,
+};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use tower_service::Service;
+
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+const NO_CACHE: &str = "no-cache";
+
+/// Static file service used by `SpaRouter` for a single mount.
+///
+/// Compared to `ServeDir` it adds strong ETags with `If-None-Match` revalidation,
+/// `Cache-Control` depending on the file name, precompressed `.br`/`.gz` siblings
+/// picked through `Accept-Encoding`, and an optional SPA fallback file for paths
+/// that don't exist inside the mount. Single byte ranges are served as `206 Partial Content`.
+#[derive(Debug, Clone)]
+pub struct AssetService {
+    mount: Arc<Mount>,
+}
+
+#[derive(Debug)]
+struct Mount {
+    dir: Option<PathBuf>,
+    fallback: Option<PathBuf>,
+}
+
+impl AssetService {
+    /// Serve files from `dir`, answering unknown paths with `fallback` when set.
+    pub fn new(dir: PathBuf, fallback: Option<PathBuf>) -> Self {
+        Self {
+            mount: Arc::new(Mount {
+                dir: Some(dir),
+                fallback,
+            }),
+        }
+    }
+
+    /// Serve `file` for every request.
+    pub fn file(file: PathBuf) -> Self {
+        Self {
+            mount: Arc::new(Mount {
+                dir: None,
+                fallback: Some(file),
+            }),
+        }
+    }
+}
+
+impl<B> Service<Request<B>> for AssetService
+where
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Response, io::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let mount = self.mount.clone();
+        let path = req.uri().path().to_owned();
+        let headers = req.headers().clone();
+        Box::pin(async move { serve(&mount, &path, &headers).await })
+    }
+}
+
+async fn serve(mount: &Mount, path: &str, headers: &HeaderMap) -> Result<Response, io::Error> {
+    let resolved = match &mount.dir {
+        Some(dir) => resolve(dir, path).await,
+        None => None,
+    };
+    let file = match resolved {
+        Some(file) => file,
+        None => match &mount.fallback {
+            Some(fallback) => fallback.clone(),
+            None => return Ok(status(StatusCode::NOT_FOUND)),
+        },
+    };
+
+    let (mut content, metadata, encoding) = match open_variant(&file, headers).await {
+        Ok(found) => found,
+        Err(e) if is_not_found(&e) => return Ok(status(StatusCode::NOT_FOUND)),
+        Err(e) => return Err(e),
+    };
+
+    let len = metadata.len();
+    let etag = etag(&metadata, encoding);
+    let cache_control = cache_control(&file);
+    let mime = mime_guess::from_path(&file)
+        .first_raw()
+        .unwrap_or("application/octet-stream");
+
+    let mut builder = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::CACHE_CONTROL, cache_control)
+        .header(header::VARY, "accept-encoding")
+        .header(header::ACCEPT_RANGES, "bytes");
+
+    if if_none_match(headers, &etag) {
+        return Ok(builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(boxed(Empty::new()))
+            .unwrap());
+    }
+
+    builder = builder.header(header::CONTENT_TYPE, mime);
+    if let Some(encoding) = encoding {
+        builder = builder.header(header::CONTENT_ENCODING, encoding);
+    }
+
+    // Streamed from the file, only the announced length in case it grows meanwhile
+    match byte_range(headers, &etag, len) {
+        ByteRange::Full => Ok(builder
+            .header(header::CONTENT_LENGTH, len)
+            .body(boxed(StreamBody::new(ReaderStream::new(content.take(len)))))
+            .unwrap()),
+        ByteRange::Partial(start, end) => {
+            content.seek(SeekFrom::Start(start)).await?;
+            let part = content.take(end - start + 1);
+            Ok(builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len))
+                .header(header::CONTENT_LENGTH, end - start + 1)
+                .body(boxed(StreamBody::new(ReaderStream::new(part))))
+                .unwrap())
+        }
+        ByteRange::Unsatisfiable => Ok(builder
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+            .body(boxed(Empty::new()))
+            .unwrap()),
+    }
+}
+
+// Changes whenever the file is replaced or rewritten, without reading it. Variants get their own.
+fn etag(metadata: &Metadata, encoding: Option<&str>) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| modified.as_nanos())
+        .unwrap_or(0);
+    match encoding {
+        Some(encoding) => format!("\"{:x}-{:x}-{}\"", metadata.len(), modified, encoding),
+        None => format!("\"{:x}-{:x}\"", metadata.len(), modified),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Full,
+    // first and last byte, inclusive
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+// The `Range` asked for, when it's a single byte range and `If-Range` still matches. Multiple
+// ranges get the whole file, which the client has to accept.
+fn byte_range(headers: &HeaderMap, etag: &str, len: u64) -> ByteRange {
+    let range = match headers.get(header::RANGE).and_then(|v| v.to_str().ok()) {
+        Some(range) => range.trim(),
+        None => return ByteRange::Full,
+    };
+    let if_range = headers.get(header::IF_RANGE).and_then(|v| v.to_str().ok());
+    if if_range.map(|v| v.trim() != etag).unwrap_or(false) {
+        return ByteRange::Full;
+    }
+    let spec = match range.strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Full,
+    };
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => (len.saturating_sub(suffix), len.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (start, "") => match start.parse::<u64>() {
+            Ok(start) => (start, len.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+            _ => return ByteRange::Full,
+        },
+    };
+    if len == 0 || start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end)
+}
+
+// Maps the request path onto the mount, rejecting anything that escapes it.
+async fn resolve(base: &Path, path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(path.trim_start_matches('/').as_bytes())
+        .decode_utf8()
+        .ok()?;
+
+    let mut full_path = base.to_path_buf();
+    for component in Path::new(&*decoded).components() {
+        match component {
+            Component::Normal(comp) => full_path.push(comp),
+            Component::CurDir => {}
+            Component::Prefix(_) | Component::RootDir | Component::ParentDir => return None,
+        }
+    }
+
+    let metadata = tokio::fs::metadata(&full_path).await.ok()?;
+    if metadata.is_dir() {
+        full_path.push("index.html");
+        if !tokio::fs::metadata(&full_path).await.ok()?.is_file() {
+            return None;
+        }
+    }
+    Some(full_path)
+}
+
+// Opens `file.br` / `file.gz` when present and accepted by the client.
+async fn open_variant(
+    file: &Path,
+    headers: &HeaderMap,
+) -> Result<(File, Metadata, Option<&'static str>), io::Error> {
+    let accepted = headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+
+    for (encoding, ext) in [("br", "br"), ("gzip", "gz")] {
+        if accepts(accepted, encoding) {
+            let mut name = file.as_os_str().to_owned();
+            name.push(".");
+            name.push(ext);
+            if let Ok((variant, metadata)) = open(Path::new(&name)).await {
+                return Ok((variant, metadata, Some(encoding)));
+            }
+        }
+    }
+
+    let (file, metadata) = open(file).await?;
+    Ok((file, metadata, None))
+}
+
+async fn open(file: &Path) -> Result<(File, Metadata), io::Error> {
+    let file = File::open(file).await?;
+    let metadata = file.metadata().await?;
+    if !metadata.is_file() {
+        return Err(io::Error::from(io::ErrorKind::NotFound));
+    }
+    Ok((file, metadata))
+}
+
+fn accepts(accept_encoding: &str, encoding: &str) -> bool {
+    accept_encoding.split(',').any(|item| {
+        let mut parts = item.trim().split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let rejected = parts.any(|p| {
+            let p = p.trim();
+            p == "q=0" || p == "q=0.0" || p == "q=0.00" || p == "q=0.000"
+        });
+        (name == encoding || name == "*") && !rejected
+    })
+}
+
+fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim())
+        .any(|v| v == "*" || v == etag || v.strip_prefix("W/") == Some(etag))
+}
+
+// The `[name].[contenthash:8].js` / `[name].[contenthash:8].chunk.js` names of the frontend
+// build, which never change content. Dates and versions (`report-20221231.pdf`,
+// `report.20221231.pdf`) are not hashes.
+fn is_hashed(file: &Path) -> bool {
+    let stem = match file.file_stem().and_then(|s| s.to_str()) {
+        Some(stem) => stem.strip_suffix(".chunk").unwrap_or(stem),
+        None => return false,
+    };
+    match stem.rsplit_once('.') {
+        Some((name, hash)) => {
+            !name.is_empty()
+                && hash.len() == 8
+                && hash.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+                && !hash.chars().all(|c| c.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+fn cache_control(file: &Path) -> HeaderValue {
+    let is_html = file.extension().and_then(|e| e.to_str()) == Some("html");
+    if !is_html && is_hashed(file) {
+        HeaderValue::from_static(IMMUTABLE)
+    } else {
+        HeaderValue::from_static(NO_CACHE)
+    }
+}
+
+fn is_not_found(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied
+    )
+}
+
+fn status(code: StatusCode) -> Response {
+    Response::builder()
+        .status(code)
+        .body(boxed(Empty::new()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::HttpBody;
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn range(range: &str) -> HeaderMap {
+        headers(&[(header::RANGE, range)])
+    }
+
+    #[test]
+    fn single_byte_ranges_are_served() {
+        assert_eq!(byte_range(&range("bytes=0-3"), "\"e\"", 10), ByteRange::Partial(0, 3));
+        assert_eq!(byte_range(&range("bytes=4-"), "\"e\"", 10), ByteRange::Partial(4, 9));
+        assert_eq!(byte_range(&range("bytes=-3"), "\"e\"", 10), ByteRange::Partial(7, 9));
+        assert_eq!(byte_range(&range("bytes=8-20"), "\"e\"", 10), ByteRange::Partial(8, 9));
+        assert_eq!(byte_range(&range("bytes=10-"), "\"e\"", 10), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range(&range("bytes=0-1, 4-5"), "\"e\"", 10), ByteRange::Full);
+        assert_eq!(byte_range(&range("items=0-1"), "\"e\"", 10), ByteRange::Full);
+        assert_eq!(byte_range(&HeaderMap::new(), "\"e\"", 10), ByteRange::Full);
+    }
+
+    #[test]
+    fn stale_if_range_gets_the_whole_file() {
+        let stale = headers(&[(header::RANGE, "bytes=0-3"), (header::IF_RANGE, "\"old\"")]);
+        assert_eq!(byte_range(&stale, "\"e\"", 10), ByteRange::Full);
+        let current = headers(&[(header::RANGE, "bytes=0-3"), (header::IF_RANGE, "\"e\"")]);
+        assert_eq!(byte_range(&current, "\"e\"", 10), ByteRange::Partial(0, 3));
+    }
+
+    async fn body(res: Response) -> Vec<u8> {
+        let mut body = res.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        bytes
+    }
+
+    #[test]
+    fn only_content_hashes_are_cached_as_immutable() {
+        for hashed in ["static/js/main.3f2a9c1b.js", "787.0be7d1c4.chunk.js", "main.3f2a9c1b.css"] {
+            assert!(is_hashed(Path::new(hashed)), "{}", hashed);
+        }
+        for plain in [
+            "report-20221231.pdf",
+            "report.20221231.pdf",
+            "app-3f2a9c1b.js",
+            "app.3F2A9C1B.js",
+            "app.3f2a9c1b0.js",
+            ".3f2a9c1b.js",
+            "index.html",
+        ] {
+            assert!(!is_hashed(Path::new(plain)), "{}", plain);
+        }
+    }
+
+    #[tokio::test]
+    async fn files_are_revalidated_and_served_in_ranges() {
+        let dir = std::env::temp_dir().join(format!("assets-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("app.js"), "0123456789").unwrap();
+        let mount = Mount { dir: Some(dir.clone()), fallback: None };
+
+        let res = serve(&mount, "/app.js", &HeaderMap::new()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_LENGTH], "10");
+        let etag = res.headers()[header::ETAG].to_str().unwrap().to_string();
+        assert_eq!(body(res).await, b"0123456789");
+
+        let res = serve(&mount, "/app.js", &headers(&[(header::IF_NONE_MATCH, &etag)])).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        let res = serve(&mount, "/app.js", &range("bytes=2-4")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(body(res).await, b"234");
+
+        let res = serve(&mount, "/app.js", &range("bytes=20-")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+        // rewritten, the etag changes
+        std::fs::write(dir.join("app.js"), "01234567890").unwrap();
+        let res = serve(&mount, "/app.js", &headers(&[(header::IF_NONE_MATCH, &etag)])).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,276 @@
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, RequestParts},
+};
+use lazy_static::lazy_static;
+use openapi_rs::{
+    gen::OpenApiGenerator,
+    request::{OpenApiFromRequest, RequestHeaderInput},
+};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+
+lazy_static! {
+    // Proxies in front of the services, whose forwarding headers are believed (TRUSTED_PROXIES,
+    // comma separated ips and CIDR ranges). The services listen on localhost behind one by default.
+    static ref TRUSTED_PROXIES: Vec<IpRange> = std::env::var("TRUSTED_PROXIES")
+        .unwrap_or("127.0.0.1,::1".to_string())
+        .split(',')
+        .filter_map(IpRange::parse)
+        .collect();
+}
+
+/// Information about the calling client, taken from the request headers.
+///
+/// The services sit behind a proxy, so the ip comes from `X-Forwarded-For` / `X-Real-IP` as
+/// `client_ip` reads them. Clients can name the device with `X-Device-Name`, otherwise it is
+/// derived from the user agent.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub device: Option<String>,
+}
+
+fn header(req_headers: &http::HeaderMap, name: &str) -> Option<String> {
+    req_headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+pub fn device_from_user_agent(user_agent: &str) -> String {
+    let ua = user_agent.to_lowercase();
+    let os = if ua.contains("iphone") || ua.contains("ipad") {
+        "iOS"
+    } else if ua.contains("android") {
+        "Android"
+    } else if ua.contains("windows") {
+        "Windows"
+    } else if ua.contains("mac os") || ua.contains("macintosh") {
+        "macOS"
+    } else if ua.contains("linux") {
+        "Linux"
+    } else {
+        "Unknown"
+    };
+    let kind = if ua.contains("ipad") || ua.contains("tablet") {
+        "Tablet"
+    } else if ua.contains("mobi") || ua.contains("iphone") {
+        "Mobile"
+    } else {
+        "Desktop"
+    };
+    format!("{} {}", os, kind)
+}
+
+/// An ip address or a CIDR range of them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl IpRange {
+    pub fn parse(range: &str) -> Option<Self> {
+        let (address, prefix) = match range.trim().split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix.parse().ok()?)),
+            None => (range.trim(), None),
+        };
+        let network: IpAddr = address.parse().ok()?;
+        let width = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(width);
+        if prefix > width {
+            return None;
+        }
+        Some(IpRange { network, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        // Bits of the address within the prefix
+        let network = |bits: u128, width: u32| bits.checked_shr(width - self.prefix).unwrap_or(0);
+        match (self.network, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                network(u32::from(range) as u128, 32) == network(u32::from(*ip) as u128, 32)
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => network(u128::from(range), 128) == network(u128::from(*ip), 128),
+            _ => false,
+        }
+    }
+}
+
+// An X-Forwarded-For entry, with or without a port.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim();
+    hop.parse()
+        .ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|address| address.ip()))
+}
+
+/// Ip of the client. Each proxy appends the address it was called from to `X-Forwarded-For`,
+/// so the client is its right-most entry that isn't one of the `trusted` proxies: anything left
+/// of it was written by the client itself. A request that didn't come through a trusted proxy
+/// (`peer`) gets no say at all.
+///
+/// Without the peer, e.g. behind shuttle's proxy, nothing tells whether the headers went through
+/// a trusted proxy, so only the right-most hop is believed: the one the platform's proxy appended.
+pub fn client_ip(
+    forwarded_for: Option<&str>,
+    real_ip: Option<&str>,
+    peer: Option<IpAddr>,
+    trusted: &[IpRange],
+) -> Option<String> {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|range| range.contains(ip));
+    let peer = match peer {
+        Some(peer) if !is_trusted(&peer) => return Some(peer.to_string()),
+        Some(peer) => peer,
+        None => {
+            return forwarded_for
+                .and_then(|forwarded_for| forwarded_for.rsplit(',').next())
+                .and_then(parse_hop)
+                .map(|ip| ip.to_string())
+        }
+    };
+
+    match forwarded_for {
+        Some(forwarded_for) => {
+            let mut hops = forwarded_for.split(',').rev().peekable();
+            while let Some(hop) = hops.next() {
+                let ip = parse_hop(hop)?;
+                // Only proxies all the way, the left-most one called the first proxy
+                if !is_trusted(&ip) || hops.peek().is_none() {
+                    return Some(ip.to_string());
+                }
+            }
+            None
+        }
+        None => Some(real_ip.and_then(parse_hop).unwrap_or(peer).to_string()),
+    }
+}
+
+#[async_trait]
+impl<T> axum::extract::FromRequest<T> for ClientInfo
+where
+    T: Send,
+{
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<T>) -> Result<Self, Self::Rejection> {
+        let headers = req.headers();
+
+        let peer = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(address)| address.ip());
+        let ip = client_ip(
+            header(headers, "x-forwarded-for").as_deref(),
+            header(headers, "x-real-ip").as_deref(),
+            peer,
+            &TRUSTED_PROXIES,
+        );
+        let user_agent = header(headers, "user-agent");
+        let device = header(headers, "x-device-name")
+            .or_else(|| user_agent.as_deref().map(device_from_user_agent));
+
+        Ok(ClientInfo {
+            ip,
+            user_agent,
+            device,
+        })
+    }
+}
+
+impl<T> OpenApiFromRequest<T> for ClientInfo
+where
+    T: Send,
+{
+    fn from_request_input(
+        _gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> anyhow::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(ranges: &[&str]) -> Vec<IpRange> {
+        ranges.iter().map(|range| IpRange::parse(range).unwrap()).collect()
+    }
+
+    #[test]
+    fn ranges_contain_their_addresses() {
+        let range = IpRange::parse("10.1.0.0/16").unwrap();
+        assert!(range.contains(&"10.1.200.3".parse().unwrap()));
+        assert!(!range.contains(&"10.2.0.1".parse().unwrap()));
+        assert!(IpRange::parse("0.0.0.0/0").unwrap().contains(&"8.8.8.8".parse().unwrap()));
+        assert!(IpRange::parse("fd00::/8").unwrap().contains(&"fd12::1".parse().unwrap()));
+        assert!(!IpRange::parse("127.0.0.1").unwrap().contains(&"::1".parse().unwrap()));
+        assert_eq!(IpRange::parse("10.0.0.0/33"), None);
+        assert_eq!(IpRange::parse("proxy"), None);
+    }
+
+    #[test]
+    fn the_client_is_the_right_most_untrusted_hop() {
+        let trusted = ranges(&["127.0.0.1", "10.0.0.0/8"]);
+        let proxy = Some("127.0.0.1".parse().unwrap());
+        // the client prepended a forged hop, the proxies appended the real one
+        let forwarded = Some("1.1.1.1, 203.0.113.7, 10.0.0.2");
+        assert_eq!(client_ip(forwarded, None, proxy, &trusted).as_deref(), Some("203.0.113.7"));
+        assert_eq!(client_ip(Some("203.0.113.7:5123"), None, proxy, &trusted).as_deref(), Some("203.0.113.7"));
+        // a client inside the proxies' network
+        assert_eq!(client_ip(Some("10.0.0.9, 10.0.0.2"), None, proxy, &trusted).as_deref(), Some("10.0.0.9"));
+        assert_eq!(client_ip(Some("forged, 10.0.0.2"), None, proxy, &trusted), None);
+    }
+
+    #[test]
+    fn headers_from_untrusted_peers_are_ignored() {
+        let trusted = ranges(&["127.0.0.1"]);
+        let peer = Some("198.51.100.4".parse().unwrap());
+        assert_eq!(client_ip(Some("1.1.1.1"), Some("1.1.1.1"), peer, &trusted).as_deref(), Some("198.51.100.4"));
+        assert_eq!(
+            client_ip(None, Some("203.0.113.7"), Some("127.0.0.1".parse().unwrap()), &trusted).as_deref(),
+            Some("203.0.113.7")
+        );
+        assert_eq!(
+            client_ip(None, None, Some("127.0.0.1".parse().unwrap()), &trusted).as_deref(),
+            Some("127.0.0.1")
+        );
+    }
+
+    #[test]
+    fn without_a_peer_only_the_last_hop_is_believed() {
+        let trusted = ranges(&["127.0.0.1", "10.0.0.0/8"]);
+        // the client can't hide behind hops claiming to be proxies
+        assert_eq!(client_ip(Some("1.1.1.1, 10.0.0.2"), None, None, &trusted).as_deref(), Some("10.0.0.2"));
+        assert_eq!(client_ip(Some("1.1.1.1, 203.0.113.7"), None, None, &trusted).as_deref(), Some("203.0.113.7"));
+        assert_eq!(client_ip(Some("1.1.1.1, forged"), None, None, &trusted), None);
+        // nor name itself without a proxy appending to the header
+        assert_eq!(client_ip(None, Some("203.0.113.7"), None, &trusted), None);
+    }
+
+    async fn client_info(peer: [u8; 4], forwarded_for: &str) -> ClientInfo {
+        let mut request = http::Request::builder()
+            .header("x-forwarded-for", forwarded_for)
+            .body(())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from((peer, 5123))));
+        let mut parts = RequestParts::new(request);
+        <ClientInfo as axum::extract::FromRequest<()>>::from_request(&mut parts)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn clients_calling_the_service_directly_cant_forge_their_ip() {
+        assert_eq!(client_info([198, 51, 100, 4], "203.0.113.7").await.ip.as_deref(), Some("198.51.100.4"));
+        assert_eq!(client_info([127, 0, 0, 1], "203.0.113.7").await.ip.as_deref(), Some("203.0.113.7"));
+    }
+}
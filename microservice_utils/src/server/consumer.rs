@@ -26,13 +26,9 @@ impl ConsumerContext for CustomContext {
     }
 }
 
-pub fn get_consumer(
-    brokers: &str,
-    group_id: &str,
-    topics: &[&str],
-) -> StreamConsumer<CustomContext> {
-    let context = CustomContext;
-    let consumer: LoggingConsumer = ClientConfig::new()
+fn consumer_config(brokers: &str, group_id: &str) -> ClientConfig {
+    let mut config = ClientConfig::new();
+    config
         .set("group.id", group_id)
         .set("bootstrap.servers", brokers)
         .set("enable.partition.eof", "false")
@@ -40,8 +36,13 @@ pub fn get_consumer(
         .set("enable.auto.commit", "true")
         .set("auto.commit.interval.ms", "1000")
         .set("auto.offset.reset", "smallest")
-        .set_log_level(RDKafkaLogLevel::Debug)
-        .create_with_context(context)
+        .set_log_level(RDKafkaLogLevel::Debug);
+    config
+}
+
+fn subscribe(config: &ClientConfig, topics: &[&str]) -> LoggingConsumer {
+    let consumer: LoggingConsumer = config
+        .create_with_context(CustomContext)
         .expect("Consumer creation failed");
 
     consumer
@@ -50,3 +51,23 @@ pub fn get_consumer(
 
     return consumer;
 }
+
+pub fn get_consumer(
+    brokers: &str,
+    group_id: &str,
+    topics: &[&str],
+) -> StreamConsumer<CustomContext> {
+    subscribe(&consumer_config(brokers, group_id), topics)
+}
+
+/// Like `get_consumer`, but the offset of a message is only committed once it is passed to
+/// `commit_message`, so messages that failed are consumed again after a restart.
+pub fn get_manual_commit_consumer(
+    brokers: &str,
+    group_id: &str,
+    topics: &[&str],
+) -> StreamConsumer<CustomContext> {
+    let mut config = consumer_config(brokers, group_id);
+    config.set("enable.auto.commit", "false");
+    subscribe(&config, topics)
+}
@@ -0,0 +1,80 @@
+use anyhow::Error;
+use rdkafka::producer::FutureProducer;
+use sqlx::PgPool;
+
+use crate::events::{publish, Event};
+
+use super::tenant::begin_service_tx;
+use super::service_auth::{
+    AI_STUDIO_SERVICE, API_KEYGEN_SERVICE, AUTH_SERVICE, CONTACTS_SERVICE, FILE_MANAGER_SERVICE,
+    INVITE_SERVICE, WORKSPACE_SERVICE,
+};
+
+// Services erasing user data on UserDeletionRequested, a deletion completes once all of them reported.
+pub const ERASURE_PARTICIPANTS: &[&str] = &[
+    AUTH_SERVICE,
+    WORKSPACE_SERVICE,
+    CONTACTS_SERVICE,
+    INVITE_SERVICE,
+    API_KEYGEN_SERVICE,
+    FILE_MANAGER_SERVICE,
+    AI_STUDIO_SERVICE,
+];
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Erasure {
+    pub erased: i64,
+    pub remaining: i64,
+}
+
+/// Runs `statements` in one transaction, then sums the counts `checks` still return.
+/// Both take the user id as `$1`; statements are expected to be idempotent, as a
+/// deletion is requested again until every service verified it.
+pub async fn db_erase_user_rows(
+    user_id: &String,
+    statements: &[&str],
+    checks: &[&str],
+    pool: &PgPool,
+) -> Result<Erasure, sqlx::Error> {
+    let mut erasure = Erasure::default();
+
+    // Anonymising hands rows to no tenant, which a tenant transaction can't do
+    let mut tx = begin_service_tx(pool, "erase_user").await?;
+    for statement in statements {
+        let res = sqlx::query(statement).bind(user_id).execute(&mut tx).await?;
+        erasure.erased += res.rows_affected() as i64;
+    }
+    tx.commit().await?;
+
+    let mut tx = begin_service_tx(pool, "erase_user").await?;
+    for check in checks {
+        let (count,): (i64,) = sqlx::query_as(check).bind(user_id).fetch_one(&mut tx).await?;
+        erasure.remaining += count;
+    }
+    tx.commit().await?;
+    Ok(erasure)
+}
+
+pub async fn report_erasure(
+    deletion_id: &String,
+    user_id: &String,
+    service: &str,
+    erasure: Erasure,
+    producer: &FutureProducer,
+) -> Result<(), Error> {
+    println!(
+        "Erased {} rows of {} for deletion {}, {} remaining",
+        erasure.erased, user_id, deletion_id, erasure.remaining
+    );
+    publish(
+        &Event::UserDataErased {
+            deletion_id: deletion_id.to_string(),
+            user_id: user_id.to_string(),
+            service: service.to_string(),
+            erased: erasure.erased,
+            remaining: erasure.remaining,
+        },
+        producer,
+    )
+    .await
+}
@@ -0,0 +1,173 @@
+use anyhow::{Context, Error};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::PgPool;
+use tonic::transport::Endpoint;
+
+use crate::crypto::decrypt;
+
+use super::grpc::data_export::{
+    data_export_client::DataExportClient, export_storage_client::ExportStorageClient, ExportFile,
+    ExportUserDataRequest, ExportUserDataResponse, StoreExportRequest,
+};
+use super::tenant::begin_tenant_tx;
+use super::service_auth::{
+    service_request, AI_STUDIO_SERVICE, AUTH_SERVICE, CONTACTS_SERVICE, FILE_MANAGER_SERVICE,
+    INVITE_SERVICE, WORKSPACE_SERVICE,
+};
+
+// Services answering ExportUserData, with their gRPC endpoints.
+pub const EXPORT_SOURCES: &[(&str, &str)] = &[
+    (AUTH_SERVICE, "http://localhost:4004"),
+    (WORKSPACE_SERVICE, "http://localhost:4001"),
+    (CONTACTS_SERVICE, "http://localhost:4003"),
+    (INVITE_SERVICE, "http://localhost:4002"),
+    (FILE_MANAGER_SERVICE, "http://localhost:4007"),
+    (AI_STUDIO_SERVICE, "http://localhost:5000"),
+];
+
+/// Rows of `query` as JSON objects, `$1` being the user id. The query runs as the user's tenant.
+pub async fn db_export_rows(query: &str, user_id: &String, pool: &PgPool) -> Result<Vec<Value>, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let rows: Vec<(String,)> = sqlx::query_as(&format!("SELECT row_to_json(t)::text FROM ({}) t", query))
+        .bind(user_id)
+        .fetch_all(&mut tx)
+        .await?;
+    tx.commit().await?;
+    rows.iter()
+        .map(|(row,)| serde_json::from_str(row).map_err(|e| sqlx::Error::Decode(Box::new(e))))
+        .collect()
+}
+
+/// Decrypts the named columns of exported rows, both single values and arrays.
+pub fn decrypt_fields(rows: &mut [Value], fields: &[&str]) -> Result<(), Error> {
+    for row in rows.iter_mut() {
+        for field in fields {
+            match row.get_mut(*field) {
+                Some(Value::String(value)) => *value = decrypt(value)?,
+                Some(Value::Array(values)) => {
+                    for value in values.iter_mut() {
+                        if let Value::String(v) = value {
+                            *v = decrypt(v)?;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn json_file<T: Serialize>(name: &str, value: &T) -> Result<ExportFile, Error> {
+    Ok(ExportFile {
+        name: format!("{}.json", name),
+        content: serde_json::to_vec_pretty(value)?,
+    })
+}
+
+/// CSV with a column per key of the rows. Nested values are written as JSON.
+pub fn csv_file(name: &str, rows: &[Value]) -> ExportFile {
+    let mut columns: Vec<&String> = Vec::new();
+    for row in rows {
+        if let Value::Object(map) = row {
+            for key in map.keys() {
+                if !columns.contains(&key) {
+                    columns.push(key);
+                }
+            }
+        }
+    }
+
+    let mut csv = String::new();
+    let header: Vec<String> = columns.iter().map(|c| csv_cell(c)).collect();
+    csv.push_str(&header.join(","));
+    csv.push_str("\r\n");
+    for row in rows {
+        let cells: Vec<String> = columns
+            .iter()
+            .map(|c| match row.get(c.as_str()) {
+                None | Some(Value::Null) => String::new(),
+                Some(Value::String(s)) => csv_cell(s),
+                Some(v) => csv_cell(&v.to_string()),
+            })
+            .collect();
+        csv.push_str(&cells.join(","));
+        csv.push_str("\r\n");
+    }
+
+    ExportFile {
+        name: format!("{}.csv", name),
+        content: csv.into_bytes(),
+    }
+}
+
+fn csv_cell(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn export_response(files: Vec<ExportFile>) -> ExportUserDataResponse {
+    ExportUserDataResponse {
+        status: "success".to_string(),
+        files,
+    }
+}
+
+/// Files of every service in `EXPORT_SOURCES`, named `{service}/{file}`. Fails when
+/// any service can't answer, so an export is never silently incomplete.
+pub async fn collect_user_data(user_id: &String) -> Result<Vec<ExportFile>, Error> {
+    let mut files = Vec::new();
+    for (service, address) in EXPORT_SOURCES {
+        let endpoint: Endpoint = address.parse().context("Invalid endpoint")?;
+        let mut grpc = DataExportClient::connect(endpoint)
+            .await
+            .with_context(|| format!("Unable to reach {}", service))?;
+        let res = grpc
+            .export_user_data(service_request(
+                service,
+                ExportUserDataRequest {
+                    user_id: user_id.to_string(),
+                },
+            )?)
+            .await
+            .with_context(|| format!("Unable to export data of {}", service))?;
+
+        for file in res.into_inner().files {
+            files.push(ExportFile {
+                name: format!("{}/{}", service, file.name),
+                content: file.content,
+            });
+        }
+    }
+    Ok(files)
+}
+
+// Keeps a finished export in the user's files, returning its file id.
+pub async fn store_export(user_id: &String, name: &String, content: Vec<u8>) -> Result<i32, Error> {
+    let endpoint: Endpoint = "http://localhost:4007".parse().context("Invalid endpoint")?;
+    let mut grpc = ExportStorageClient::connect(endpoint)
+        .await
+        .context("Unable to establish connection")?;
+    let res = grpc
+        .store_export(service_request(
+            FILE_MANAGER_SERVICE,
+            StoreExportRequest {
+                user_id: user_id.to_string(),
+                name: name.to_string(),
+                content,
+            },
+        )?)
+        .await
+        .context("Unable to store export")?;
+
+    let message = res.into_inner();
+    if message.status == "success" {
+        Ok(message.file_id)
+    } else {
+        Err(Error::msg(message.status))
+    }
+}
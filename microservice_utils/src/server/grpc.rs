@@ -1,7 +1,16 @@
 use anyhow::*;
+use lazy_static::lazy_static;
 use uuid::Uuid;
 use tonic::transport::Endpoint;
 
+use crate::jwt::auth::Token;
+
+use crate::jwt::share::{FOLDER_RESOURCE, VIDEO_RESOURCE, WS_BOARD_RESOURCE};
+
+use super::service_auth::{
+    service_request, AI_STUDIO_SERVICE, API_KEYGEN_SERVICE, AUTH_SERVICE, FILE_MANAGER_SERVICE, WORKSPACE_SERVICE,
+};
+
 pub mod auth_service {
     tonic::include_proto!("auth_service");
 }
@@ -14,109 +23,156 @@ pub mod workspace_service {
     tonic::include_proto!("workspace_service");
 }
 
-use user_service::{user_service_client::UserServiceClient, AddWorkspaceRequest, RemoveWorkspaceRequest};
-use workspace_service::{workspace_service_client::WorkspaceServiceClient, WorkspaceInfo};
-use auth_service::{auth_service_client::AuthServiceClient, CheckTokenRequest, TokenRefreshRequest, CheckShopifyToken};
+pub mod api_keygen_service {
+    tonic::include_proto!("api_keygen_service");
+}
 
+pub mod data_export {
+    tonic::include_proto!("data_export");
+}
+
+pub mod shared_resources {
+    tonic::include_proto!("shared_resources");
+}
 
-pub async fn add_workspace_id(user_id: &String, workspace_id: &Uuid) -> Result<()> {
-    let endpoint: Endpoint = "http://localhost:4000".parse().context("Invalid endpoint")?;
-    let mut grpc = UserServiceClient::connect(endpoint)
+use workspace_service::{workspace_service_client::WorkspaceServiceClient, MembershipsRequest, MfaRequiredRequest, WorkspaceInfo};
+use auth_service::{auth_service_client::AuthServiceClient, CheckTokenRequest, TokenRefreshRequest, CheckShopifyToken, CheckShareTokenRequest, OwnsContactRequest};
+use api_keygen_service::{api_keygen_service_client::ApiKeygenServiceClient, CheckClientRequest, VerifyApiKeyRequest};
+use shared_resources::{shared_resources_client::SharedResourcesClient, CheckOwnerRequest};
+
+lazy_static! {
+    static ref API_KEYGEN_SERVICE_URL: String = std::env::var("API_KEYGEN_SERVICE_URL")
+        .unwrap_or("http://localhost:4005".to_string());
+}
+
+
+pub async fn check_token(user_id: &String, access_token: &String, method: &str, path: &str) -> Result<(), Error> {
+    let endpoint: Endpoint = "http://localhost:4004".parse().context("Invalid endpoint")?;
+    let mut grpc = AuthServiceClient::connect(endpoint)
         .await
         .context("Unable to establish connection")?;
     let res = grpc
-        .add_workspace_id(AddWorkspaceRequest {
-            user_id: user_id.to_string(),
-            workspace_id: workspace_id.to_string(),
-        })
+        .check_token(service_request(
+            AUTH_SERVICE,
+            CheckTokenRequest {
+                user_id: user_id.to_string(),
+                access_token: access_token.to_string(),
+                method: method.to_string(),
+                path: path.to_string(),
+            },
+        )?)
         .await
         .context("Unable to send echo request")?;
 
-    println!("{:?}", res);
-
-    Ok(())
+    let message = res.into_inner();
+    if message.status == "success" {
+        println!("{:?}", message);
+        Ok(())
+    } else {
+        Err(Error::msg("Authentication failed"))
+    }
 }
 
-pub async fn remove_workspace_id(user_id: &String, workspace_id: &Uuid) -> Result<()> {
-    let endpoint: Endpoint = "http://localhost:4000".parse().context("Invalid endpoint")?;
-    let mut grpc = UserServiceClient::connect(endpoint)
+pub async fn refresh_token(user_id: &String, refresh_token: &String) -> Result<Token, Error> {
+    let endpoint: Endpoint = "http://localhost:4004".parse().context("Invalid endpoint")?;
+    let mut grpc = AuthServiceClient::connect(endpoint)
         .await
         .context("Unable to establish connection")?;
     let res = grpc
-        .remove_workspace_id(RemoveWorkspaceRequest {
-            user_id: user_id.to_string(),
-            workspace_id: workspace_id.to_string(),
-        })
+        .refresh_token(service_request(
+            AUTH_SERVICE,
+            TokenRefreshRequest {
+                user_id: user_id.to_string(),
+                refresh_token: refresh_token.to_string(),
+            },
+        )?)
         .await
         .context("Unable to send echo request")?;
-
-    println!("{:?}", res);
-
-    Ok(())
+   
+    let message = res.into_inner();
+    if message.status == "success" {
+        Ok(Token {
+            access_token: message.access_token,
+            refresh_token: message.refresh_token,
+        })
+    } else {
+        Err(Error::msg("Authentication failed"))
+    }
 }
 
-pub async fn check_token(user_id: &String, access_token: &String) -> Result<(), Error> {
+pub async fn get_shopify_token(user_id: &String) -> Result<String, Error> {
     let endpoint: Endpoint = "http://localhost:4004".parse().context("Invalid endpoint")?;
     let mut grpc = AuthServiceClient::connect(endpoint)
         .await
         .context("Unable to establish connection")?;
     let res = grpc
-        .check_token(CheckTokenRequest {
-            user_id: user_id.to_string(),
-            access_token: access_token.to_string(),
-        })
+        .get_shopify_token(service_request(
+            AUTH_SERVICE,
+            CheckShopifyToken {
+                user_id: user_id.to_string(),
+            },
+        )?)
         .await
         .context("Unable to send echo request")?;
 
     let message = res.into_inner();
     if message.status == "success" {
         println!("{:?}", message);
-        Ok(())
+        Ok(message.token)
     } else {
         Err(Error::msg("Authentication failed"))
     }
 }
 
-pub async fn refresh_token(user_id: &String, refresh_token: &Uuid) -> Result<(), Error> {
+// Returns the user who shared the resource, counting a use of the token.
+pub async fn check_share_token(token: &String, resource_type: &str, resource_id: &String, action: &str) -> Result<String, Error> {
     let endpoint: Endpoint = "http://localhost:4004".parse().context("Invalid endpoint")?;
     let mut grpc = AuthServiceClient::connect(endpoint)
         .await
         .context("Unable to establish connection")?;
     let res = grpc
-        .refresh_token(TokenRefreshRequest {
-            user_id: user_id.to_string(),
-            refresh_token: refresh_token.to_string(),
-        })
+        .check_share_token(service_request(
+            AUTH_SERVICE,
+            CheckShareTokenRequest {
+                token: token.to_string(),
+                resource_type: resource_type.to_string(),
+                resource_id: resource_id.to_string(),
+                action: action.to_string(),
+            },
+        )?)
         .await
-        .context("Unable to send echo request")?;
-   
+        .context("Share token is not valid")?;
+
     let message = res.into_inner();
     if message.status == "success" {
-        println!("{:?}", message);
-        Ok(())
+        Ok(message.user_id)
     } else {
-        Err(Error::msg("Authentication failed"))
+        Err(Error::msg("Share token is not valid"))
     }
 }
 
-pub async fn get_shopify_token(user_id: &String) -> Result<String, Error> {
+// Whether the user proved owning the email or phone number by signing in with it.
+pub async fn owns_contact(user_id: &String, contact: &String) -> Result<bool, Error> {
     let endpoint: Endpoint = "http://localhost:4004".parse().context("Invalid endpoint")?;
     let mut grpc = AuthServiceClient::connect(endpoint)
         .await
         .context("Unable to establish connection")?;
     let res = grpc
-        .get_shopify_token(CheckShopifyToken {
-            user_id: user_id.to_string(),
-        })
+        .owns_contact(service_request(
+            AUTH_SERVICE,
+            OwnsContactRequest {
+                user_id: user_id.to_string(),
+                contact: contact.to_string(),
+            },
+        )?)
         .await
-        .context("Unable to send echo request")?;
+        .context("Unable to check the contact")?;
 
     let message = res.into_inner();
     if message.status == "success" {
-        println!("{:?}", message);
-        Ok(message.token)
+        Ok(message.owner)
     } else {
-        Err(Error::msg("Authentication failed"))
+        Err(Error::msg("Unable to check the contact"))
     }
 }
 
@@ -126,10 +182,13 @@ pub async fn check_workspace(user_id: &String, workspace_id: &String) -> Result<
         .await
         .context("Unable to establish connection")?;
     let res = grpc
-        .check_workspace(WorkspaceInfo {
-            user_id: user_id.to_string(),
-            workspace_id: workspace_id.to_string(),
-        })
+        .check_workspace(service_request(
+            WORKSPACE_SERVICE,
+            WorkspaceInfo {
+                user_id: user_id.to_string(),
+                workspace_id: workspace_id.to_string(),
+            },
+        )?)
         .await
         .context("Unable to send echo request")?;
 
@@ -140,4 +199,126 @@ pub async fn check_workspace(user_id: &String, workspace_id: &String) -> Result<
     } else {
         Err(Error::msg("Workspace does not exist"))
     }
-}
\ No newline at end of file
+}
+
+pub async fn check_mfa_required(user_id: &String) -> Result<bool, Error> {
+    let endpoint: Endpoint = "http://localhost:4001".parse().context("Invalid endpoint")?;
+    let mut grpc = WorkspaceServiceClient::connect(endpoint)
+        .await
+        .context("Unable to establish connection")?;
+    let res = grpc
+        .check_mfa_required(service_request(
+            WORKSPACE_SERVICE,
+            MfaRequiredRequest {
+                user_id: user_id.to_string(),
+            },
+        )?)
+        .await
+        .context("Unable to send echo request")?;
+
+    Ok(res.into_inner().required)
+}
+
+// (user_id, workspace_id) pairs for every workspace the users are members of.
+pub async fn list_memberships(user_ids: &[String]) -> Result<Vec<(String, Uuid)>, Error> {
+    let endpoint: Endpoint = "http://localhost:4001".parse().context("Invalid endpoint")?;
+    let mut grpc = WorkspaceServiceClient::connect(endpoint)
+        .await
+        .context("Unable to establish connection")?;
+    let res = grpc
+        .list_memberships(service_request(
+            WORKSPACE_SERVICE,
+            MembershipsRequest {
+                user_ids: user_ids.to_vec(),
+            },
+        )?)
+        .await
+        .context("Unable to list memberships")?;
+
+    let message = res.into_inner();
+    if message.status != "success" {
+        return Err(Error::msg(message.status));
+    }
+    message
+        .memberships
+        .into_iter()
+        .map(|m| Ok((m.user_id, Uuid::parse_str(&m.workspace_id)?)))
+        .collect()
+}
+
+pub async fn verify_api_key(client_id: &String, client_secret: &String) -> Result<(String, Vec<String>), Error> {
+    let endpoint = Endpoint::from_shared(API_KEYGEN_SERVICE_URL.clone()).context("Invalid endpoint")?;
+    let mut grpc = ApiKeygenServiceClient::connect(endpoint)
+        .await
+        .context("Unable to establish connection")?;
+    let res = grpc
+        .verify_api_key(service_request(
+            API_KEYGEN_SERVICE,
+            VerifyApiKeyRequest {
+                client_id: client_id.to_string(),
+                client_secret: client_secret.to_string(),
+            },
+        )?)
+        .await
+        .context("Unable to verify api key")?;
+
+    let message = res.into_inner();
+    if message.status == "success" {
+        Ok((message.user_id, message.scopes))
+    } else {
+        Err(Error::msg("Invalid api key"))
+    }
+}
+
+// Whether the api key `client_id` still exists.
+pub async fn check_api_client(client_id: &String) -> Result<bool, Error> {
+    let endpoint = Endpoint::from_shared(API_KEYGEN_SERVICE_URL.clone()).context("Invalid endpoint")?;
+    let mut grpc = ApiKeygenServiceClient::connect(endpoint)
+        .await
+        .context("Unable to establish connection")?;
+    let res = grpc
+        .check_client(service_request(
+            API_KEYGEN_SERVICE,
+            CheckClientRequest {
+                client_id: client_id.to_string(),
+            },
+        )?)
+        .await
+        .context("Unable to check client")?;
+
+    let message = res.into_inner();
+    if message.status != "success" {
+        return Err(Error::msg(message.status));
+    }
+    Ok(message.active)
+}
+
+// Whether `user_id` owns the resource, asked to the service holding resources of that type.
+pub async fn check_resource_owner(user_id: &String, resource_type: &str, resource_id: &String) -> Result<bool, Error> {
+    let (address, service) = match resource_type {
+        VIDEO_RESOURCE | WS_BOARD_RESOURCE => ("http://localhost:5000", AI_STUDIO_SERVICE),
+        FOLDER_RESOURCE => ("http://localhost:4007", FILE_MANAGER_SERVICE),
+        _ => return Err(Error::msg(format!("{} can't be shared", resource_type))),
+    };
+    let endpoint: Endpoint = address.parse().context("Invalid endpoint")?;
+    let mut grpc = SharedResourcesClient::connect(endpoint)
+        .await
+        .context("Unable to establish connection")?;
+    let res = grpc
+        .check_owner(service_request(
+            service,
+            CheckOwnerRequest {
+                user_id: user_id.to_string(),
+                resource_type: resource_type.to_string(),
+                resource_id: resource_id.to_string(),
+            },
+        )?)
+        .await
+        .context("Unable to check the owner")?;
+
+    let message = res.into_inner();
+    if message.status != "success" {
+        return Err(Error::msg(message.status));
+    }
+    Ok(message.owner)
+}
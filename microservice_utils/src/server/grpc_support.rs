@@ -0,0 +1,54 @@
+use anyhow::Context;
+use lazy_static::lazy_static;
+use tonic::body::BoxBody;
+use tonic::codegen::http::{Request, Response};
+use tonic::transport::{Body, NamedService};
+use tonic_health::server::{health_reporter, Health, HealthServer};
+use tonic_reflection::server::{Builder, ServerReflection, ServerReflectionServer};
+use tonic_web::GrpcWeb;
+use tower::Service;
+
+lazy_static! {
+    // Origins of the web apps allowed to call the services with gRPC-Web (GRPC_WEB_ORIGINS,
+    // comma separated). None by default, browsers on other origins are turned away at preflight.
+    static ref GRPC_WEB_ORIGINS: Vec<String> = std::env::var("GRPC_WEB_ORIGINS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|origin| origin.trim().to_string())
+        .filter(|origin| !origin.is_empty())
+        .collect();
+}
+
+/// Wraps a gRPC service so gRPC-Web clients can call it through the hybrid server, answering
+/// their CORS preflights. gRPC-Web calls are authenticated like any other: the service token of
+/// `service_interceptor`, which browsers never hold. Web apps on GRPC_WEB_ORIGINS reach the
+/// services through a backend attaching one, user requests go to the REST endpoints.
+pub fn grpc_web<S>(service: S) -> GrpcWeb<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>> + NamedService + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>> + Send,
+{
+    tonic_web::config()
+        .allow_origins(GRPC_WEB_ORIGINS.iter().map(String::as_str))
+        .enable(service)
+}
+
+/// Standard `grpc.health.v1` service reporting `S` as serving, for load balancer health checks.
+pub async fn health_service<S: NamedService>() -> HealthServer<impl Health> {
+    let (mut reporter, service) = health_reporter();
+    reporter.set_serving::<S>().await;
+    service
+}
+
+/// Server reflection over the encoded file descriptor sets of the service protos, for grpcurl.
+/// Fails when a set can't be decoded.
+pub fn reflection_service(
+    file_descriptor_sets: &[&'static [u8]],
+) -> anyhow::Result<ServerReflectionServer<impl ServerReflection>> {
+    let mut builder = Builder::configure();
+    for set in file_descriptor_sets {
+        builder = builder.register_encoded_file_descriptor_set(set);
+    }
+    builder.build().context("Invalid file descriptor set")
+}
@@ -5,7 +5,7 @@ use std::task::Poll;
 
 use axum::body::{HttpBody, Body};
 use pin_project::pin_project;
-use tonic::codegen::http::{HeaderMap, Request, Response};
+use tonic::codegen::http::{HeaderMap, Method, Request, Response};
 use tower::Service;
 
 pub fn hybrid<MakeWeb, Grpc>(make_web: MakeWeb, grpc: Grpc) -> HybridMakeService<MakeWeb, Grpc> {
@@ -99,7 +99,7 @@ where
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
-        if req.headers().get("content-type").map(|x| x.as_bytes()) == Some(b"application/grpc") {
+        if is_grpc(&req) {
             HybridFuture::Grpc(self.grpc.call(req))
         } else {
             HybridFuture::Web(self.web.call(req))
@@ -107,6 +107,26 @@ where
     }
 }
 
+/// gRPC calls, of any content type (application/grpc, application/grpc+proto,
+/// application/grpc-web, application/grpc-web-text ...), and the CORS preflights of gRPC-Web
+/// calls, which carry no content type but announce the `x-grpc-web` header.
+fn is_grpc<B>(req: &Request<B>) -> bool {
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_ascii_lowercase)
+    };
+    match *req.method() {
+        Method::OPTIONS => header("access-control-request-headers")
+            .map(|headers| headers.split(',').any(|name| name.trim() == "x-grpc-web"))
+            .unwrap_or(false),
+        _ => header("content-type")
+            .map(|content_type| content_type.starts_with("application/grpc"))
+            .unwrap_or(false),
+    }
+}
+
 #[pin_project(project = HybridBodyProj)]
 pub enum HybridBody<WebBody, GrpcBody> {
     Web(#[pin] WebBody),
@@ -184,4 +204,34 @@ where
             },
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, headers: &[(&str, &str)]) -> Request<()> {
+        let mut builder = Request::builder().method(method).uri("/user_service.UserService/get_user");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn grpc_content_types_go_to_grpc() {
+        for content_type in &["application/grpc", "application/grpc+proto", "application/grpc-web-text"] {
+            assert!(is_grpc(&request(Method::POST, &[("content-type", *content_type)])));
+        }
+        assert!(!is_grpc(&request(Method::POST, &[("content-type", "application/json")])));
+        assert!(!is_grpc(&request(Method::GET, &[])));
+    }
+
+    #[test]
+    fn grpc_web_preflights_go_to_grpc() {
+        let preflight = |headers| request(Method::OPTIONS, &[("access-control-request-headers", headers)]);
+        assert!(is_grpc(&preflight("content-type,X-Grpc-Web, x-user-agent")));
+        assert!(!is_grpc(&preflight("authorization, content-type")));
+        assert!(!is_grpc(&request(Method::OPTIONS, &[])));
+    }
+}
@@ -1,7 +1,18 @@
 pub mod grpc;
 pub mod hybrid;
+pub mod grpc_support;
 pub mod spa;
+pub mod assets;
 pub mod response;
 pub mod error_404;
 pub mod not_found;
 pub mod consumer;
+pub mod service_auth;
+pub mod tenant;
+pub mod client_info;
+pub mod users;
+pub mod export;
+pub mod erasure;
+pub mod preferences;
+pub mod sender;
+pub mod audit;
@@ -16,6 +16,7 @@ impl axum::response::IntoResponse for ResponseError {
             match error {
                 ApiError::NotFound => axum::http::StatusCode::NOT_FOUND,
                 ApiError::BadRequest => axum::http::StatusCode::BAD_REQUEST,
+                ApiError::TooManyRequests => axum::http::StatusCode::TOO_MANY_REQUESTS,
                 ApiError::InternalServerError => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
             }
         } else {
@@ -33,6 +34,8 @@ pub enum ApiError {
     NotFound,
     #[error("bad request")]
     BadRequest,
+    #[error("too many requests")]
+    TooManyRequests,
     #[error("Internal Server error")]
     InternalServerError,
 }
@@ -52,6 +55,7 @@ pub fn into_reponse(code: i64, body: serde_json::Value) -> ResponseError {
     let code = match code {
         404 => ApiError::NotFound,
         400 => ApiError::BadRequest,
+        429 => ApiError::TooManyRequests,
         500 => ApiError::InternalServerError,
         _ => ApiError::InternalServerError
     };
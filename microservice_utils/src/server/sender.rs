@@ -0,0 +1,74 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use serde::Serialize;
+use tonic::async_trait;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum Channel {
+    Email,
+    Sms,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Message {
+    pub channel: Channel,
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers emails and texts: auth_service's codes and links, the notices of the other services.
+#[async_trait]
+pub trait MessageSender: Send + Sync {
+    async fn send(&self, message: &Message) -> anyhow::Result<()>;
+}
+
+/// Prints messages to stdout, for development and tests.
+pub struct LogSender;
+
+#[async_trait]
+impl MessageSender for LogSender {
+    async fn send(&self, message: &Message) -> anyhow::Result<()> {
+        println!("{:?} to {}: {}\n{}", message.channel, message.to, message.subject, message.body);
+        Ok(())
+    }
+}
+
+/// Posts messages as JSON to a delivery service (mailer, sms gateway, ...).
+pub struct WebhookSender {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl WebhookSender {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl MessageSender for WebhookSender {
+    async fn send(&self, message: &Message) -> anyhow::Result<()> {
+        self.client
+            .post(&self.url)
+            .json(message)
+            .send()
+            .await
+            .context("Unable to reach message webhook")?
+            .error_for_status()
+            .context("Message webhook rejected the message")?;
+        Ok(())
+    }
+}
+
+/// Uses `MESSAGE_WEBHOOK_URL` when set, logging otherwise.
+pub fn sender_from_env() -> Arc<dyn MessageSender> {
+    match std::env::var("MESSAGE_WEBHOOK_URL") {
+        Ok(url) if !url.is_empty() => Arc::new(WebhookSender::new(url)),
+        _ => Arc::new(LogSender),
+    }
+}
@@ -16,9 +16,10 @@ use std::{
     path::{Path, PathBuf},
     sync::Arc,
 };
-use tower_http::services::{ServeDir, ServeFile};
 use tower_service::Service;
 
+use super::assets::AssetService;
+
 /// Router for single page applications.
 ///
 /// `SpaRouter` gives a routing setup commonly used for single page applications.
@@ -26,40 +27,44 @@ use tower_service::Service;
 /// # Example
 ///
 /// ```
-/// use axum_extra::routing::SpaRouter;
 /// use axum::{Router, routing::get};
+/// use microservice_utils::server::spa::SpaRouter;
 ///
-/// let spa = SpaRouter::new("/assets", "dist");
-///
-/// let app = Router::new()
+/// let app: Router = Router::new()
 ///     // `SpaRouter` implements `Into<Router>` so it works with `merge`
-///     .merge(spa)
+///     .merge(SpaRouter::new(vec!["/swagger-ui"], vec!["./swagger-ui"]))
 ///     // we can still add other routes
 ///     .route("/api/foo", get(api_foo));
-/// # let _: Router<axum::body::Body> = app;
 ///
 /// async fn api_foo() {}
 /// ```
 ///
 /// With this setup we get this behavior:
 ///
-/// - `GET /` will serve `index.html`
-/// - `GET /assets/app.js` will serve `dist/app.js` assuming that file exists
-/// - `GET /assets/doesnt_exist` will respond with `404 Not Found` assuming no
+/// - `GET /` will serve `swagger-ui/index.html`
+/// - `GET /swagger-ui/app.js` will serve `swagger-ui/app.js` assuming that file exists
+/// - `GET /swagger-ui/doesnt_exist` will respond with `404 Not Found` assuming no
 ///   such file exists
-/// - `GET /some/other/path` will serve `index.html` since there isn't another
+/// - `GET /some/other/path` will serve `swagger-ui/index.html` since there isn't another
 ///   route for it
 /// - `GET /api/foo` will serve the `api_foo` handler function
+///
+/// Files are served with a strong `ETag`, taken from their size and modification time, and
+/// answer `If-None-Match` with `304 Not Modified` and `Range` with the bytes asked for.
+/// Content hashed asset names (`app.3f2a9c1b.js`, `787.0be7d1c4.chunk.js`) are cached as immutable, everything else
+/// (including `index.html`) with `no-cache`. Precompressed `.br`/`.gz` siblings are
+/// served when the client accepts them.
 pub struct SpaRouter<B = Body, T = (), F = fn(io::Error) -> Ready<StatusCode>> {
     paths: Arc<Paths>,
     handle_error: F,
     _marker: PhantomData<fn() -> (B, T)>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Paths {
     assets_path: Vec<String>,
     assets_dir: Vec<PathBuf>,
+    fallbacks: Vec<Option<PathBuf>>,
     index_file: PathBuf,
 }
 
@@ -85,6 +90,7 @@ impl<B> SpaRouter<B, (), fn(io::Error) -> Ready<StatusCode>> {
             paths: Arc::new(Paths {
                 assets_path: assets,
                 assets_dir: path.clone(),
+                fallbacks: vec![None; path.len()],
                 index_file: path
                     .first()
                     .expect("failed to get assets")
@@ -99,30 +105,45 @@ impl<B> SpaRouter<B, (), fn(io::Error) -> Ready<StatusCode>> {
 impl<B, T, F> SpaRouter<B, T, F> {
     /// Set the path to the index file.
     ///
-    /// `path` must be relative to `assets_dir` passed to [`SpaRouter::new`].
+    /// `path` must be relative to the first `assets_dir` passed to [`SpaRouter::new`].
+    pub fn index_file<P>(mut self, path: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        let mut paths = (*self.paths).clone();
+        paths.index_file = paths.assets_dir[0].join(path);
+        self.paths = Arc::new(paths);
+        self
+    }
+
+    /// Serve `index_file` for unknown paths under `serve_assets_at`, so client side
+    /// routes of the app mounted there survive a reload.
+    ///
+    /// `index_file` is relative to the directory of that mount. Fails when nothing is
+    /// served at `serve_assets_at`.
     ///
     /// # Example
     ///
     /// ```
-    /// use axum_extra::routing::SpaRouter;
-    /// use axum::Router;
+    /// use microservice_utils::server::spa::SpaRouter;
     ///
-    /// let spa = SpaRouter::new("/assets", "dist")
-    ///     .index_file("another_file.html");
-    ///
-    /// let app = Router::new().merge(spa);
-    /// # let _: Router<axum::body::Body> = app;
+    /// let spa = SpaRouter::new(vec!["/swagger-ui", "/app"], vec!["./swagger-ui", "./client/build"])
+    ///     .spa_fallback("/app", "index.html")?;
+    /// # Ok::<(), anyhow::Error>(())
     /// ```
-    pub fn index_file<P>(mut self, path: P) -> Self
+    pub fn spa_fallback<P>(mut self, serve_assets_at: &str, index_file: P) -> anyhow::Result<Self>
     where
         P: AsRef<Path>,
     {
-        self.paths = Arc::new(Paths {
-            assets_path: self.paths.assets_path.clone(),
-            assets_dir: self.paths.assets_dir.clone(),
-            index_file: self.paths.assets_dir[0].join(path),
-        });
-        self
+        let mut paths = (*self.paths).clone();
+        let i = paths
+            .assets_path
+            .iter()
+            .position(|x| x == serve_assets_at)
+            .ok_or_else(|| anyhow::anyhow!("No assets are served at {} for the spa fallback", serve_assets_at))?;
+        paths.fallbacks[i] = Some(paths.assets_dir[i].join(index_file));
+        self.paths = Arc::new(paths);
+        Ok(self)
     }
 
     /// Change the function used to handle unknown IO errors.
@@ -131,23 +152,6 @@ impl<B, T, F> SpaRouter<B, T, F> {
     /// `404 Not Found`. The callback given here will be used for other IO errors.
     ///
     /// See [`axum::error_handling::HandleErrorLayer`] for more details.
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// use std::io;
-    /// use axum_extra::routing::SpaRouter;
-    /// use axum::{Router, http::{Method, Uri}};
-    ///
-    /// let spa = SpaRouter::new("/assets", "dist").handle_error(handle_error);
-    ///
-    /// async fn handle_error(method: Method, uri: Uri, err: io::Error) -> String {
-    ///     format!("{} {} failed with {}", method, uri, err)
-    /// }
-    ///
-    /// let app = Router::new().merge(spa);
-    /// # let _: Router<axum::body::Body> = app;
-    /// ```
     pub fn handle_error<T2, F2>(self, f: F2) -> SpaRouter<B, T2, F2> {
         SpaRouter {
             paths: self.paths,
@@ -172,14 +176,18 @@ where
         let mut router = Router::new();
 
         for (i, asset_path) in assets_path.iter().enumerate() {
-            let service = get_service(ServeDir::new(&spa.paths.assets_dir[i]))
-                .handle_error(spa.handle_error.clone());
+            let service = get_service(AssetService::new(
+                spa.paths.assets_dir[i].clone(),
+                spa.paths.fallbacks[i].clone(),
+            ))
+            .handle_error(spa.handle_error.clone());
 
             router = router.nest(asset_path, service);
         }
 
         router.fallback(
-            get_service(ServeFile::new(&spa.paths.index_file)).handle_error(spa.handle_error),
+            get_service(AssetService::file(spa.paths.index_file.clone()))
+                .handle_error(spa.handle_error),
         )
     }
 }
@@ -216,3 +224,17 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spa_fallback_needs_a_known_mount() {
+        let spa = SpaRouter::<Body>::new(vec!["/app"], vec!["./client/build"]);
+        assert!(spa.clone().spa_fallback("/admin", "index.html").is_err());
+
+        let spa = spa.spa_fallback("/app", "index.html").unwrap();
+        assert_eq!(spa.paths.fallbacks[0], Some(PathBuf::from("./client/build/index.html")));
+    }
+}
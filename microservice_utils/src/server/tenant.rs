@@ -0,0 +1,191 @@
+use sqlx::{PgPool, Postgres, Transaction};
+
+/// Begins a transaction scoped to a tenant.
+///
+/// `app.current_user` / `app.current_workspace` are set with `is_local = true`, so they only
+/// live for this transaction. The row-level security policies in the service schemas compare
+/// rows against them, which keeps a query that forgets its `user_id` filter from reaching other
+/// tenants' rows. The policies fail closed: outside a tenant or service transaction no row of a
+/// tenant table is visible or writable.
+pub async fn begin_tenant_tx<'a>(
+    pool: &'a PgPool,
+    user_id: &str,
+    workspace_id: Option<&str>,
+) -> Result<Transaction<'a, Postgres>, sqlx::Error> {
+    if user_id.is_empty() {
+        return Err(sqlx::Error::Protocol("tenant transaction without a user".to_string()));
+    }
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        "SELECT set_config('app.current_user', $1, true), set_config('app.current_workspace', $2, true)",
+    )
+    .bind(user_id)
+    .bind(workspace_id.unwrap_or(""))
+    .execute(&mut tx)
+    .await?;
+    Ok(tx)
+}
+
+/// Begins a transaction for work that isn't done on behalf of one tenant: gRPC calls between
+/// services, event consumers, background jobs and erasure.
+///
+/// Sets `app.service_role`, which the row-level security policies let past, so crossing tenants
+/// has to be asked for here rather than happening when a tenant is forgotten. `reason` names the
+/// caller, it is the value of the setting.
+pub async fn begin_service_tx<'a>(
+    pool: &'a PgPool,
+    reason: &str,
+) -> Result<Transaction<'a, Postgres>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("SELECT set_config('app.service_role', $1, true)")
+        .bind(reason)
+        .execute(&mut tx)
+        .await?;
+    Ok(tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::Executor;
+
+    // As defined in every service schema.
+    const TENANT_FUNCTIONS_SQL: &str = r#"
+CREATE OR REPLACE FUNCTION app_current_user() RETURNS TEXT AS $$
+    SELECT NULLIF(current_setting('app.current_user', true), '')
+$$ LANGUAGE SQL STABLE;
+
+CREATE OR REPLACE FUNCTION app_current_workspace() RETURNS TEXT AS $$
+    SELECT NULLIF(current_setting('app.current_workspace', true), '')
+$$ LANGUAGE SQL STABLE;
+
+CREATE OR REPLACE FUNCTION app_service_role() RETURNS BOOLEAN AS $$
+    SELECT COALESCE(current_setting('app.service_role', true), '') <> ''
+$$ LANGUAGE SQL STABLE;
+"#;
+
+    // Runs against TEST_DATABASE_URL. The connecting role may be a superuser, which skips
+    // row-level security, so each transaction switches to an unprivileged role first.
+    // Each test gets its own table, tests run in parallel.
+    async fn setup(table: &str) -> PgPool {
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL");
+        let pool = PgPool::connect(&url).await.unwrap();
+        pool.execute(TENANT_FUNCTIONS_SQL).await.unwrap();
+        pool.execute(
+            "DO $$ BEGIN CREATE ROLE tenant_test NOLOGIN; EXCEPTION WHEN duplicate_object THEN NULL; END $$",
+        )
+        .await
+        .unwrap();
+        pool.execute(
+            format!(
+                "DROP TABLE IF EXISTS {table};
+                CREATE TABLE {table} (user_id TEXT NOT NULL, body TEXT NOT NULL);
+                ALTER TABLE {table} ENABLE ROW LEVEL SECURITY;
+                ALTER TABLE {table} FORCE ROW LEVEL SECURITY;
+                CREATE POLICY tenant_isolation ON {table}
+                    USING (app_service_role() OR user_id = app_current_user())
+                    WITH CHECK (app_service_role() OR user_id = app_current_user());
+                GRANT ALL ON {table} TO tenant_test;
+                INSERT INTO {table} VALUES ('tenant-a', 'a'), ('tenant-b', 'b');",
+                table = table
+            )
+            .as_str(),
+        )
+        .await
+        .unwrap();
+        pool
+    }
+
+    async fn as_test_role(tx: &mut Transaction<'_, Postgres>) {
+        sqlx::query("SET LOCAL ROLE tenant_test").execute(&mut *tx).await.unwrap();
+    }
+
+    async fn visible(tx: &mut Transaction<'_, Postgres>, table: &str) -> Vec<String> {
+        let rows: Vec<(String,)> = sqlx::query_as(&format!("SELECT user_id FROM {} ORDER BY user_id", table))
+            .fetch_all(&mut *tx)
+            .await
+            .unwrap();
+        rows.into_iter().map(|(user_id,)| user_id).collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn tenant_cannot_read_other_tenants() {
+        let pool = setup("tenant_test_read").await;
+
+        let mut tx = begin_tenant_tx(&pool, "tenant-a", None).await.unwrap();
+        as_test_role(&mut tx).await;
+        assert_eq!(visible(&mut tx, "tenant_test_read").await, vec!["tenant-a"]);
+        let rows: Vec<(String,)> = sqlx::query_as("SELECT body FROM tenant_test_read WHERE user_id = 'tenant-b'")
+            .fetch_all(&mut tx)
+            .await
+            .unwrap();
+        assert!(rows.is_empty());
+        tx.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn tenant_cannot_write_other_tenants() {
+        let pool = setup("tenant_test_write").await;
+
+        let mut tx = begin_tenant_tx(&pool, "tenant-a", None).await.unwrap();
+        as_test_role(&mut tx).await;
+        let res = sqlx::query("UPDATE tenant_test_write SET body = 'x' WHERE user_id = 'tenant-b'")
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(res.rows_affected(), 0);
+        let res = sqlx::query("DELETE FROM tenant_test_write WHERE user_id = 'tenant-b'")
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(res.rows_affected(), 0);
+        tx.rollback().await.unwrap();
+
+        let mut tx = begin_tenant_tx(&pool, "tenant-a", None).await.unwrap();
+        as_test_role(&mut tx).await;
+        let res = sqlx::query("INSERT INTO tenant_test_write VALUES ('tenant-b', 'forged')")
+            .execute(&mut tx)
+            .await;
+        assert!(res.is_err());
+        tx.rollback().await.unwrap();
+
+        // Handing a row over to another tenant is a write to that tenant too
+        let mut tx = begin_tenant_tx(&pool, "tenant-a", None).await.unwrap();
+        as_test_role(&mut tx).await;
+        let res = sqlx::query("UPDATE tenant_test_write SET user_id = 'tenant-b' WHERE user_id = 'tenant-a'")
+            .execute(&mut tx)
+            .await;
+        assert!(res.is_err());
+        tx.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn fails_closed_without_tenant() {
+        let pool = setup("tenant_test_closed").await;
+
+        let mut tx = pool.begin().await.unwrap();
+        as_test_role(&mut tx).await;
+        assert!(visible(&mut tx, "tenant_test_closed").await.is_empty());
+        let res = sqlx::query("INSERT INTO tenant_test_closed VALUES ('tenant-a', 'a')")
+            .execute(&mut tx)
+            .await;
+        assert!(res.is_err());
+        tx.rollback().await.unwrap();
+
+        assert!(begin_tenant_tx(&pool, "", None).await.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn service_role_crosses_tenants() {
+        let pool = setup("tenant_test_service").await;
+
+        let mut tx = begin_service_tx(&pool, "test").await.unwrap();
+        as_test_role(&mut tx).await;
+        assert_eq!(visible(&mut tx, "tenant_test_service").await, vec!["tenant-a", "tenant-b"]);
+        tx.rollback().await.unwrap();
+    }
+}
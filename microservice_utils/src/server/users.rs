@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Error};
+use lazy_static::lazy_static;
+use prost_types::FieldMask;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Status};
+
+use super::grpc::user_service::{
+    user_service_client::UserServiceClient, BatchGetUsersRequest, ChargeCreditsRequest,
+    GetUserRequest, UserProfile,
+};
+use super::service_auth::{service_request, USER_SERVICE};
+
+// Matches the limit of user_microservice, larger lookups are split.
+const MAX_BATCH_USERS: usize = 100;
+const MAX_CACHED_PROFILES: usize = 10_000;
+
+lazy_static! {
+    static ref USER_SERVICE_URL: String = std::env::var("USER_SERVICE_URL")
+        .unwrap_or("http://localhost:4000".to_string());
+    // Shared by every call of the process, a channel multiplexes them over its connection.
+    static ref CHANNEL: Mutex<Option<Channel>> = Mutex::new(None);
+    static ref PROFILE_TTL: Duration = Duration::from_secs(
+        std::env::var("USER_PROFILE_CACHE_SECONDS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(60),
+    );
+    // (user_id, requested fields) -> profile. Each service process keeps its own cache.
+    static ref PROFILES: Mutex<HashMap<(String, String), (Instant, Profile)>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Profile of a user as returned by user_microservice. Fields outside the requested
+/// field mask are empty.
+#[derive(Default, Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct Profile {
+    pub user_id: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub username: String,
+    pub picture: String,
+    pub bio: String,
+    pub email: String,
+    pub phone_number: String,
+}
+
+impl From<UserProfile> for Profile {
+    fn from(p: UserProfile) -> Self {
+        Profile {
+            user_id: p.user_id,
+            first_name: p.first_name,
+            last_name: p.last_name,
+            username: p.username,
+            picture: p.picture,
+            bio: p.bio,
+            email: p.email,
+            phone_number: p.phone_number,
+        }
+    }
+}
+
+fn field_mask(fields: &[&str]) -> Option<FieldMask> {
+    if fields.is_empty() {
+        None
+    } else {
+        Some(FieldMask {
+            paths: fields.iter().map(|f| f.to_string()).collect(),
+        })
+    }
+}
+
+fn cache_key(user_id: &str, fields: &[&str]) -> (String, String) {
+    (user_id.to_string(), fields.join(","))
+}
+
+fn cached(user_id: &str, fields: &[&str]) -> Option<Profile> {
+    let profiles = PROFILES.lock().unwrap();
+    match profiles.get(&cache_key(user_id, fields)) {
+        Some((at, profile)) if at.elapsed() < *PROFILE_TTL => Some(profile.clone()),
+        _ => None,
+    }
+}
+
+fn store(profiles: &[Profile], fields: &[&str]) {
+    let mut cache = PROFILES.lock().unwrap();
+    if cache.len() + profiles.len() > MAX_CACHED_PROFILES {
+        cache.retain(|_, (at, _)| at.elapsed() < *PROFILE_TTL);
+        if cache.len() + profiles.len() > MAX_CACHED_PROFILES {
+            cache.clear();
+        }
+    }
+    let now = Instant::now();
+    for profile in profiles {
+        cache.insert(cache_key(&profile.user_id, fields), (now, profile.clone()));
+    }
+}
+
+fn client() -> Result<UserServiceClient<Channel>, Error> {
+    let mut channel = CHANNEL.lock().unwrap();
+    if channel.is_none() {
+        let endpoint = Endpoint::from_shared(USER_SERVICE_URL.clone()).context("Invalid endpoint")?;
+        *channel = Some(endpoint.connect_lazy().context("Invalid endpoint")?);
+    }
+    Ok(UserServiceClient::new(channel.clone().unwrap()))
+}
+
+/// Calls user_microservice over the shared channel. The channel's connection task lives on
+/// the runtime that created it, a call failing before reaching the service gets a new
+/// channel and is made again, once.
+pub(super) async fn call<M, T, F, Fut>(message: M, rpc: F) -> Result<T, Error>
+where
+    M: Clone,
+    F: Fn(UserServiceClient<Channel>, tonic::Request<M>) -> Fut,
+    Fut: Future<Output = Result<tonic::Response<T>, Status>>,
+{
+    let res = match rpc(client()?, service_request(USER_SERVICE, message.clone())?).await {
+        Err(status) if matches!(status.code(), Code::Unavailable | Code::Unknown) => {
+            *CHANNEL.lock().unwrap() = None;
+            rpc(client()?, service_request(USER_SERVICE, message)?).await
+        }
+        res => res,
+    };
+    Ok(res?.into_inner())
+}
+
+/// Profile of one user, `fields` being the field mask (empty for the default fields).
+pub async fn get_user(user_id: &String, fields: &[&str]) -> Result<Profile, Error> {
+    if let Some(profile) = cached(user_id, fields) {
+        return Ok(profile);
+    }
+
+    let request = GetUserRequest {
+        user_id: user_id.to_string(),
+        field_mask: field_mask(fields),
+    };
+    let res = call(request, |mut grpc, req| async move { grpc.get_user(req).await })
+        .await
+        .context("Unable to get user")?;
+
+    let profile: Profile = res
+        .user
+        .ok_or_else(|| Error::msg("User not found"))?
+        .into();
+    store(&[profile.clone()], fields);
+    Ok(profile)
+}
+
+/// Profiles by user id. Users that don't exist are missing from the map.
+pub async fn batch_get_users(user_ids: &[String], fields: &[&str]) -> Result<HashMap<String, Profile>, Error> {
+    let mut found = HashMap::new();
+    let mut missing = Vec::new();
+    for user_id in user_ids {
+        if found.contains_key(user_id) || missing.contains(user_id) {
+            continue;
+        }
+        match cached(user_id, fields) {
+            Some(profile) => {
+                found.insert(user_id.clone(), profile);
+            }
+            None => missing.push(user_id.clone()),
+        }
+    }
+    if missing.is_empty() {
+        return Ok(found);
+    }
+
+    for chunk in missing.chunks(MAX_BATCH_USERS) {
+        let request = BatchGetUsersRequest {
+            user_ids: chunk.to_vec(),
+            field_mask: field_mask(fields),
+        };
+        let res = call(request, |mut grpc, req| async move { grpc.batch_get_users(req).await })
+            .await
+            .context("Unable to get users")?;
+
+        let profiles: Vec<Profile> = res.users.into_iter().map(Profile::from).collect();
+        store(&profiles, fields);
+        for profile in profiles {
+            found.insert(profile.user_id.clone(), profile);
+        }
+    }
+    Ok(found)
+}
+
+/// Debits `amount` credits for a usage of `user_id` in `workspace_id` (empty for none).
+/// `reference` identifies the usage, charging it again is a no-op. Returns the account
+/// that paid, None when the reference was charged before.
+pub async fn charge_credits(
+    user_id: &String,
+    workspace_id: &String,
+    amount: i64,
+    reference: &String,
+    description: &String,
+) -> Result<Option<String>, Error> {
+    // Charging a reference again is a no-op, so a retried call can't debit twice
+    let request = ChargeCreditsRequest {
+        user_id: user_id.to_string(),
+        workspace_id: workspace_id.to_string(),
+        amount,
+        reference: reference.to_string(),
+        description: description.to_string(),
+    };
+    let message = call(request, |mut grpc, req| async move { grpc.charge_credits(req).await })
+        .await
+        .context("Unable to charge credits")?;
+
+    match message.status.as_str() {
+        "success" => Ok(Some(message.account)),
+        "duplicate" => Ok(None),
+        _ => Err(Error::msg(message.status)),
+    }
+}
@@ -24,5 +24,201 @@ CREATE TABLE IF NOT EXISTS users (
     longitude REAL, 
     last_login_ip TEXT,
     last_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
     PRIMARY KEY (id)
-);
\ No newline at end of file
+);
+
+CREATE TABLE IF NOT EXISTS data_exports (
+    id uuid DEFAULT uuid_generate_v4(),
+    user_id TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending', -- pending, running, ready, failed
+    file_id INTEGER, -- file_manager_microservice file holding the zip
+    error TEXT,
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    completed_at TIMESTAMP(3),
+    PRIMARY KEY (id)
+);
+
+-- Renewed while the export job runs, exports without a recent one were interrupted.
+ALTER TABLE data_exports ADD COLUMN IF NOT EXISTS heartbeat_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP;
+
+-- At most one export in progress per user.
+CREATE UNIQUE INDEX IF NOT EXISTS data_exports_in_progress_idx ON data_exports (user_id)
+    WHERE status IN ('pending', 'running');
+
+-- Account deletions: scheduled for a grace period, then erased by every service (erasing) until
+-- all of them reported nothing left (completed). `user_id` is replaced by its blind index on completion.
+CREATE TABLE IF NOT EXISTS account_deletions (
+    id uuid DEFAULT uuid_generate_v4(),
+    user_id TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'scheduled', -- scheduled, cancelled, erasing, incomplete, failed, completed
+    workspaces TEXT NOT NULL DEFAULT 'transfer', -- owned workspaces: transfer or delete
+    attempts INTEGER NOT NULL DEFAULT 0,
+    requested_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    erase_after TIMESTAMP(3) NOT NULL,
+    published_at TIMESTAMP(3),
+    completed_at TIMESTAMP(3),
+    PRIMARY KEY (id)
+);
+
+-- A failed deletion waits for an admin, the user can't schedule another one meanwhile.
+DROP INDEX IF EXISTS account_deletions_in_progress_idx;
+CREATE UNIQUE INDEX IF NOT EXISTS account_deletions_pending_idx ON account_deletions (user_id)
+    WHERE status IN ('scheduled', 'erasing', 'incomplete', 'failed');
+
+-- What each service erased, and found left afterwards.
+CREATE TABLE IF NOT EXISTS account_deletion_reports (
+    deletion_id uuid NOT NULL REFERENCES account_deletions (id),
+    service TEXT NOT NULL,
+    erased BIGINT NOT NULL,
+    remaining BIGINT NOT NULL,
+    reported_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (deletion_id, service)
+);
+
+CREATE TABLE IF NOT EXISTS erasure_certificates (
+    id uuid DEFAULT uuid_generate_v4(),
+    deletion_id uuid NOT NULL UNIQUE REFERENCES account_deletions (id),
+    subject_index TEXT NOT NULL, -- blind index of the user id
+    services TEXT NOT NULL, -- JSON of the reports
+    requested_at TIMESTAMP(3) NOT NULL,
+    issued_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id)
+);
+
+-- Credits ledger: double-entry, the entries of a transaction sum to zero. Accounts are
+-- user:<user_id>, workspace:<workspace_id> and the system:referrals / system:usage counterparts,
+-- a balance is the sum of the account's entries.
+CREATE TABLE IF NOT EXISTS credit_transactions (
+    id uuid DEFAULT uuid_generate_v4(),
+    kind TEXT NOT NULL, -- referral, usage
+    idempotency_key TEXT NOT NULL UNIQUE, -- a key is posted once, e.g. referral:<invitee> or video:<id>
+    description TEXT NOT NULL,
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id)
+);
+
+CREATE TABLE IF NOT EXISTS credit_entries (
+    id uuid DEFAULT uuid_generate_v4(),
+    transaction_id uuid NOT NULL REFERENCES credit_transactions (id),
+    account TEXT NOT NULL,
+    amount BIGINT NOT NULL,
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id)
+);
+
+CREATE INDEX IF NOT EXISTS credit_entries_account_idx ON credit_entries (account, created_at);
+
+-- One referral per invitee, rewarded once the invitee has signed up. Accounts that existed
+-- before the invite was sent (`invited_at`) aren't referred by it.
+CREATE TABLE IF NOT EXISTS referrals (
+    invitee_id TEXT NOT NULL,
+    invitor_id TEXT NOT NULL,
+    invited_at TIMESTAMP(3) NOT NULL,
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    rewarded_at TIMESTAMP(3),
+    PRIMARY KEY (invitee_id)
+);
+
+ALTER TABLE referrals ADD COLUMN IF NOT EXISTS invited_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP;
+
+-- Previous handles. The 'changed' ones resolve to their user and stay unavailable to others until
+-- they expire. The ones taken from a user ('lost' to an older account, 'invalid' under the current
+-- rules, see user::username::spawn_username_cleanup) expire at once, they are kept to tell the user.
+CREATE TABLE IF NOT EXISTS username_history (
+    username TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    reason TEXT NOT NULL DEFAULT 'changed', -- changed, lost, invalid
+    released_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP(3) NOT NULL,
+    notified_at TIMESTAMP(3),
+    PRIMARY KEY (username, user_id, reason)
+);
+
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM information_schema.columns WHERE table_name = 'username_history' AND column_name = 'reason') THEN
+        ALTER TABLE username_history ADD COLUMN reason TEXT NOT NULL DEFAULT 'changed';
+        ALTER TABLE username_history DROP CONSTRAINT username_history_pkey;
+        ALTER TABLE username_history ADD PRIMARY KEY (username, user_id, reason);
+    END IF;
+END $$;
+ALTER TABLE username_history ADD COLUMN IF NOT EXISTS notified_at TIMESTAMP(3);
+CREATE UNIQUE INDEX IF NOT EXISTS username_history_redirect_idx ON username_history (username) WHERE reason = 'changed';
+
+-- Usernames are stored normalized (see user::username) and unique regardless of case. Handles
+-- set before that are normalized here, and when several users share one the account created
+-- first keeps it; the others lose it.
+UPDATE users SET username = NULLIF(lower(ltrim(trim(username), '@')), '')
+    WHERE username <> lower(ltrim(trim(username), '@'));
+WITH losers AS (
+    SELECT id, user_id, username FROM (
+        SELECT id, user_id, username, row_number() OVER (PARTITION BY lower(username) ORDER BY created_at, id) AS n
+        FROM users WHERE username IS NOT NULL
+    ) ranked WHERE n > 1
+), lost AS (
+    INSERT INTO username_history (username, user_id, reason, expires_at)
+    SELECT lower(username), user_id, 'lost', CURRENT_TIMESTAMP FROM losers
+    ON CONFLICT DO NOTHING
+)
+UPDATE users SET username = NULL WHERE id IN (SELECT id FROM losers);
+CREATE UNIQUE INDEX IF NOT EXISTS users_username_idx ON users (lower(username));
+
+CREATE INDEX IF NOT EXISTS username_history_user_idx ON username_history (user_id);
+
+-- Values a user set for the preference keys of user::preferences, the defaults aren't stored.
+-- A workspace's overrides have its id, the user's own values the nil uuid.
+CREATE TABLE IF NOT EXISTS user_preferences (
+    user_id TEXT NOT NULL,
+    workspace_id uuid NOT NULL DEFAULT '00000000-0000-0000-0000-000000000000',
+    key TEXT NOT NULL,
+    value TEXT NOT NULL, -- JSON
+    version INTEGER NOT NULL, -- version of the key the value was set for
+    updated_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, workspace_id, key)
+);
+
+-- Row-level security: rows are only visible to the tenant set by microservice_utils::server::tenant
+-- (app.current_user / app.current_workspace). Without a tenant in the transaction nothing is
+-- visible; internal paths that cross tenants use a service transaction (app.service_role).
+-- Superusers and BYPASSRLS roles skip policies entirely, so the service has to connect as a
+-- regular role for this to take effect.
+--
+-- Not every table is a tenant table:
+-- - account_deletions, account_deletion_reports and erasure_certificates belong to the deletion
+--   job and the admin endpoints, and outlive the user, whose id is replaced by its blind index.
+-- - credit_transactions and credit_entries are keyed by account: a workspace's balance is shared
+--   by its members and the system accounts by everyone. credits.rs checks membership instead.
+-- - referrals and username_history each relate two users.
+CREATE OR REPLACE FUNCTION app_current_user() RETURNS TEXT AS $$
+    SELECT NULLIF(current_setting('app.current_user', true), '')
+$$ LANGUAGE SQL STABLE;
+
+CREATE OR REPLACE FUNCTION app_current_workspace() RETURNS TEXT AS $$
+    SELECT NULLIF(current_setting('app.current_workspace', true), '')
+$$ LANGUAGE SQL STABLE;
+
+CREATE OR REPLACE FUNCTION app_service_role() RETURNS BOOLEAN AS $$
+    SELECT COALESCE(current_setting('app.service_role', true), '') <> ''
+$$ LANGUAGE SQL STABLE;
+
+ALTER TABLE users ENABLE ROW LEVEL SECURITY;
+ALTER TABLE users FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON users;
+CREATE POLICY tenant_isolation ON users
+    USING (app_service_role() OR user_id = app_current_user())
+    WITH CHECK (app_service_role() OR user_id = app_current_user());
+
+ALTER TABLE data_exports ENABLE ROW LEVEL SECURITY;
+ALTER TABLE data_exports FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON data_exports;
+CREATE POLICY tenant_isolation ON data_exports
+    USING (app_service_role() OR user_id = app_current_user())
+    WITH CHECK (app_service_role() OR user_id = app_current_user());
+
+ALTER TABLE user_preferences ENABLE ROW LEVEL SECURITY;
+ALTER TABLE user_preferences FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON user_preferences;
+CREATE POLICY tenant_isolation ON user_preferences
+    USING (app_service_role() OR user_id = app_current_user())
+    WITH CHECK (app_service_role() OR user_id = app_current_user());
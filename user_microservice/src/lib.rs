@@ -1,10 +1,11 @@
 use axum::{
     extract::Extension,
-    routing::{get, post},
+    routing::{get, post, put},
     Router,
 };
 use dotenv::dotenv;
 use shuttle_service::error::CustomError;
+use sqlx::types::chrono::NaiveDateTime;
 use sqlx::{Executor, PgPool};
 use std::{env, ffi::OsStr, net::SocketAddr, sync::Arc};
 use sync_wrapper::SyncWrapper;
@@ -15,9 +16,41 @@ use tower_http::{
 };
 
 pub mod user;
+#[cfg(test)]
+mod test_db;
 
-use crate::user::user_handler::{create_user, delete_user, get_user, update_user, MyUserService};
+use crate::user::credits::{
+    get_credit_history, get_credit_history_spec, get_credits, get_credits_spec, record_referral,
+};
+use crate::user::deletion::{
+    cancel_deletion, cancel_deletion_spec, get_deletion, get_deletion_spec, get_erasure_certificate,
+    get_erasure_certificate_spec, get_failed_deletions, get_failed_deletions_spec, handle_erasure_report,
+    request_deletion, request_deletion_spec, retry_deletion, retry_deletion_spec, spawn_deletion_job,
+};
+use crate::user::export::{
+    export_user_data, export_user_data_spec, get_user_exports, get_user_exports_spec,
+    spawn_export_sweeper,
+};
+use crate::user::membership::{
+    db_add_workspace_id, db_remove_workspace_id, reconcile_user_workspaces,
+    reconcile_user_workspaces_spec, spawn_reconcile_job,
+};
+use crate::user::preferences::{
+    get_preferences, get_preferences_spec, update_preferences, update_preferences_spec,
+};
+use crate::user::user_handler::{
+    create_user, db_set_two_factor, delete_user, get_user, update_user, MyUserService,
+};
+use crate::user::username::{
+    check_username_available, check_username_available_spec, get_user_by_username,
+    get_user_by_username_spec, spawn_username_cleanup, update_username, update_username_spec,
+};
+use microservice_utils::server::audit::audit_impersonation;
+use microservice_utils::events::{start_event_consumer, Event};
 use microservice_utils::server::{hybrid::hybrid, spa::SpaRouter};
+use microservice_utils::server::grpc_support::{grpc_web, health_service, reflection_service};
+use microservice_utils::server::service_auth::{check_service_config, service_interceptor, USER_SERVICE};
+use microservice_utils::crypto::check_keys;
 use microservice_utils::{
     open_api::gen::{generate_openapi_spec, GenSpec, Spec},
     server::error_404::error_404,
@@ -30,6 +63,9 @@ pub mod user_service {
 
 use user_service::user_service_server::UserServiceServer;
 
+pub(crate) const FILE_DESCRIPTOR_SET: &[u8] =
+    tonic::include_file_descriptor_set!("user_microservice_descriptor");
+
 #[macro_use]
 extern crate lazy_static;
 
@@ -44,19 +80,35 @@ lazy_static! {
 #[tokio::main]
 pub async fn main() {
     dotenv().expect("Failed to read .env file");
+    check_service_config().expect("Invalid service token config");
     lazy_static::initialize(&DATABASE_URL);
+    check_keys().expect("Invalid encryption keys");
+    let reflection = reflection_service(&[FILE_DESCRIPTOR_SET]).expect("Invalid gRPC reflection descriptors");
 
     let pool = PgPool::connect(&std::env::var("DATABASE_URL").unwrap())
         .await
         .unwrap();
 
+    start_events(&pool);
+    spawn_reconcile_job(&pool);
+    spawn_deletion_job(&pool);
+    spawn_username_cleanup(&pool);
+    spawn_export_sweeper(&pool);
     let axum_make_service = create_app(&pool);
 
     let grpc_service = tonic::transport::Server::builder()
-        .add_service(UserServiceServer::new(MyUserService::new(pool)))
+        .add_service(grpc_web(UserServiceServer::with_interceptor(
+            MyUserService::new(pool),
+            service_interceptor(USER_SERVICE),
+        )))
+        .add_service(health_service::<UserServiceServer<MyUserService>>().await)
+        .add_service(reflection)
         .into_service();
 
-    let hybrid_make_service = hybrid(axum_make_service.into_make_service(), grpc_service);
+    let hybrid_make_service = hybrid(
+        axum_make_service.into_make_service_with_connect_info::<SocketAddr>(),
+        grpc_service,
+    );
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 4000));
     println!("Listening on http://{}", addr);
@@ -69,15 +121,54 @@ pub async fn main() {
 
 #[shuttle_service::main]
 async fn axum(pool: PgPool) -> shuttle_service::ShuttleAxum {
+    // refuse to start without valid encryption keys or a service signing key
+    check_keys()?;
+    check_service_config()?;
     pool.execute(include_str!("../schema.sql"))
         .await
         .map_err(CustomError::new)?;
 
+    start_events(&pool);
+    spawn_reconcile_job(&pool);
+    spawn_deletion_job(&pool);
+    spawn_username_cleanup(&pool);
+    spawn_export_sweeper(&pool);
     let app = create_app(&pool);
     let sync_wrapper = SyncWrapper::new(app);
     Ok(sync_wrapper)
 }
 
+// Projects the workspace memberships owned by workspace_microservice into users.workspace_ids,
+// collects the erasure reports of account deletions and records referrals from verified invites.
+fn start_events(pool: &PgPool) {
+    let pool = pool.clone();
+    start_event_consumer("user_service", move |event| {
+        let pool = pool.clone();
+        async move {
+            match event {
+                Event::WorkspaceMemberAdded { user_id, workspace_id } => {
+                    db_add_workspace_id(&user_id, &workspace_id, &pool).await?;
+                }
+                Event::WorkspaceMemberRemoved { user_id, workspace_id } => {
+                    db_remove_workspace_id(&user_id, &workspace_id, &pool).await?;
+                }
+                Event::UserDataErased { deletion_id, service, erased, remaining, .. } => {
+                    handle_erasure_report(&deletion_id, &service, erased, remaining, &pool).await?;
+                }
+                Event::InviteAccepted { invitee_id, invitor_id, invited_at } => {
+                    let invited_at = NaiveDateTime::from_timestamp(invited_at, 0);
+                    record_referral(&invitee_id, &invitor_id, invited_at, &pool).await?;
+                }
+                Event::TwoFactorChanged { user_id, enabled } => {
+                    db_set_two_factor(&user_id, enabled, &pool).await?;
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+    });
+}
+
 fn create_app(pool: &PgPool) -> Router {
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var(
@@ -103,6 +194,70 @@ fn create_app(pool: &PgPool) -> Router {
             route: "/api/user".into(),
             gen: Box::new(delete_user_spec),
         },
+        Spec {
+            route: "/api/user/reconcile_workspaces".into(),
+            gen: Box::new(reconcile_user_workspaces_spec),
+        },
+        Spec {
+            route: "/api/user/export".into(),
+            gen: Box::new(export_user_data_spec),
+        },
+        Spec {
+            route: "/api/user/export".into(),
+            gen: Box::new(get_user_exports_spec),
+        },
+        Spec {
+            route: "/api/user/deletion".into(),
+            gen: Box::new(request_deletion_spec),
+        },
+        Spec {
+            route: "/api/user/deletion".into(),
+            gen: Box::new(get_deletion_spec),
+        },
+        Spec {
+            route: "/api/user/deletion".into(),
+            gen: Box::new(cancel_deletion_spec),
+        },
+        Spec {
+            route: "/api/user/deletion/failed".into(),
+            gen: Box::new(get_failed_deletions_spec),
+        },
+        Spec {
+            route: "/api/user/deletion/retry".into(),
+            gen: Box::new(retry_deletion_spec),
+        },
+        Spec {
+            route: "/api/user/erasure_certificate".into(),
+            gen: Box::new(get_erasure_certificate_spec),
+        },
+        Spec {
+            route: "/api/user/credits".into(),
+            gen: Box::new(get_credits_spec),
+        },
+        Spec {
+            route: "/api/user/credits/transactions".into(),
+            gen: Box::new(get_credit_history_spec),
+        },
+        Spec {
+            route: "/api/user/preferences".into(),
+            gen: Box::new(get_preferences_spec),
+        },
+        Spec {
+            route: "/api/user/preferences".into(),
+            gen: Box::new(update_preferences_spec),
+        },
+        Spec {
+            route: "/api/user/username".into(),
+            gen: Box::new(update_username_spec),
+        },
+        Spec {
+            route: "/api/user/username/available".into(),
+            gen: Box::new(check_username_available_spec),
+        },
+        Spec {
+            route: "/api/user/by_username".into(),
+            gen: Box::new(get_user_by_username_spec),
+        },
     ];
 
     generate_openapi_spec(specs).expect("failed to generate openapi spec");
@@ -123,6 +278,7 @@ fn create_app(pool: &PgPool) -> Router {
         )
         .layer(ConcurrencyLimitLayer::new(64))
         .layer(cors)
+        .layer(axum::middleware::from_fn(audit_impersonation))
         .into_inner();
 
     let app = Router::new()
@@ -134,6 +290,23 @@ fn create_app(pool: &PgPool) -> Router {
                 .get(get_user)
                 .delete(delete_user),
         )
+        .route("/api/user/reconcile_workspaces", post(reconcile_user_workspaces))
+        .route("/api/user/export", post(export_user_data).get(get_user_exports))
+        .route(
+            "/api/user/deletion",
+            post(request_deletion)
+                .get(get_deletion)
+                .delete(cancel_deletion),
+        )
+        .route("/api/user/deletion/failed", get(get_failed_deletions))
+        .route("/api/user/deletion/retry", post(retry_deletion))
+        .route("/api/user/erasure_certificate", get(get_erasure_certificate))
+        .route("/api/user/credits", get(get_credits))
+        .route("/api/user/credits/transactions", get(get_credit_history))
+        .route("/api/user/preferences", get(get_preferences).put(update_preferences))
+        .route("/api/user/username", put(update_username))
+        .route("/api/user/username/available", get(check_username_available))
+        .route("/api/user/by_username", get(get_user_by_username))
         .fallback(get(error_404))
         .layer(Extension(pool_arc))
         .layer(middleware_stack);
@@ -0,0 +1,78 @@
+// Database of the tests that need one: TEST_DATABASE_URL, with schema.sql applied once per run.
+// Tests use their own user ids, so they don't see each other's rows.
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use std::sync::Mutex;
+use tokio::sync::OnceCell;
+use tonic::async_trait;
+use uuid::Uuid;
+
+use microservice_utils::server::sender::{Message, MessageSender};
+use microservice_utils::server::tenant::begin_tenant_tx;
+
+static SCHEMA: OnceCell<()> = OnceCell::const_new();
+
+// Tests may connect as a superuser, which row-level security doesn't apply to, so
+// `tenant_test_tx` switches to this role.
+const TENANT_TEST_ROLE_SQL: &str = r#"
+DO $$ BEGIN CREATE ROLE tenant_test NOLOGIN; EXCEPTION WHEN duplicate_object OR unique_violation THEN NULL; END $$;
+GRANT ALL ON ALL TABLES IN SCHEMA public TO tenant_test;
+GRANT ALL ON ALL SEQUENCES IN SCHEMA public TO tenant_test;
+"#;
+
+pub(crate) async fn test_pool() -> PgPool {
+    let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL");
+    let pool = PgPool::connect(&url).await.unwrap();
+    SCHEMA
+        .get_or_init(|| async {
+            // schema.sql starts by dropping users, which fails on an empty database
+            pool.execute(r#"CREATE EXTENSION IF NOT EXISTS "uuid-ossp"; CREATE TABLE IF NOT EXISTS users ();"#)
+                .await
+                .unwrap();
+            pool.execute(include_str!("../schema.sql")).await.unwrap();
+            pool.execute(TENANT_TEST_ROLE_SQL).await.unwrap();
+        })
+        .await;
+    pool
+}
+
+pub(crate) fn test_user_id() -> String {
+    format!("test-{}", Uuid::new_v4())
+}
+
+// A tenant transaction the row-level security policies of schema.sql apply to.
+pub(crate) async fn tenant_test_tx<'a>(pool: &'a PgPool, user_id: &str, workspace_id: Option<&str>) -> Transaction<'a, Postgres> {
+    let mut tx = begin_tenant_tx(pool, user_id, workspace_id).await.unwrap();
+    sqlx::query("SET LOCAL ROLE tenant_test").execute(&mut tx).await.unwrap();
+    tx
+}
+
+// A valid username of its own for a test user.
+pub(crate) fn test_handle(user_id: &str) -> String {
+    format!("t{}", &user_id.replace('-', "")[4..20])
+}
+
+// Signs up `user_id` at `created_at`.
+pub(crate) async fn create_test_user(user_id: &String, created_at: sqlx::types::chrono::NaiveDateTime, pool: &PgPool) {
+    sqlx::query(
+        "INSERT INTO users (user_id, first_name, last_name, email, phone_number, created_at) VALUES ($1, 'Test', 'User', '', '', $2)",
+    )
+    .bind(user_id)
+    .bind(created_at)
+    .execute(pool)
+    .await
+    .unwrap();
+}
+
+// Keeps the messages instead of delivering them.
+#[derive(Default)]
+pub(crate) struct RecordingSender {
+    pub sent: Mutex<Vec<Message>>,
+}
+
+#[async_trait]
+impl MessageSender for RecordingSender {
+    async fn send(&self, message: &Message) -> anyhow::Result<()> {
+        self.sent.lock().unwrap().push(message.clone());
+        Ok(())
+    }
+}
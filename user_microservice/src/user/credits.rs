@@ -0,0 +1,431 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::extract::{Extension, Query};
+use axum::Json;
+use axum_macros::debug_handler;
+use openapi_rs::openapi_proc_macro::handler;
+use serde::Serialize;
+use sqlx::types::chrono::NaiveDateTime;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use okapi::openapi3::RefOr;
+use openapi_rs::gen::OpenApiGenerator;
+use openapi_rs::OpenApiFromData;
+
+use microservice_utils::{
+    jwt::extractor::AuthToken,
+    server::response::{into_reponse, AxumRes, AxumResult},
+    server::tenant::begin_tenant_tx,
+};
+
+use crate::user::membership::db_is_member;
+use crate::user::param::OptionalId;
+
+// Counterparts of the user and workspace accounts, their balances go negative as credits are
+// granted and come back up as they are used.
+const REFERRALS_ACCOUNT: &str = "system:referrals";
+const USAGE_ACCOUNT: &str = "system:usage";
+
+const MAX_HISTORY_ENTRIES: i64 = 100;
+
+lazy_static! {
+    static ref REFERRAL_REWARD: i64 = std::env::var("REFERRAL_REWARD_CREDITS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(250);
+}
+
+pub fn user_account(user_id: &str) -> String {
+    format!("user:{}", user_id)
+}
+
+pub fn workspace_account(workspace_id: &Uuid) -> String {
+    format!("workspace:{}", workspace_id)
+}
+
+#[derive(sqlx::FromRow, Debug, Clone, Serialize)]
+pub struct CreditEntry {
+    pub transaction_id: Uuid,
+    pub kind: String, // referral, usage
+    pub description: String,
+    pub amount: i64, // positive for credits received
+    pub created_at: NaiveDateTime,
+}
+
+// API
+#[debug_handler]
+#[handler(method = "GET",tag = "user")]
+pub async fn get_credits(
+    AuthToken(user_id): AuthToken,
+    Extension(pool): Extension<Arc<PgPool>>
+) -> AxumResult<Json<AxumRes>> {
+    let workspace_ids = match db_get_workspace_ids(&user_id, &pool).await {
+        Ok(workspace_ids) => workspace_ids,
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            return Err(into_reponse(500, ret));
+        }
+    };
+
+    let mut accounts = vec![user_account(&user_id)];
+    accounts.extend(workspace_ids.iter().map(workspace_account));
+    match db_get_balances(&accounts, &pool).await {
+        Ok(balances) => {
+            let workspaces: Vec<serde_json::Value> = workspace_ids
+                .iter()
+                .map(|id| serde_json::json!({
+                    "workspace_id": id,
+                    "balance": balances.get(&workspace_account(id)).copied().unwrap_or(0),
+                }))
+                .collect();
+            let result = serde_json::json!({
+                "balance": balances.get(&user_account(&user_id)).copied().unwrap_or(0),
+                "workspaces": workspaces,
+            });
+            Ok(axum::Json(AxumRes{code:200, result}))
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(500, ret))
+        }
+    }
+}
+
+/// History of the user's account, or of one of their workspaces with `id`.
+#[debug_handler]
+#[handler(method = "GET",tag = "user")]
+pub async fn get_credit_history(
+    params: Query<OptionalId>,
+    AuthToken(user_id): AuthToken,
+    Extension(pool): Extension<Arc<PgPool>>
+) -> AxumResult<Json<AxumRes>> {
+    let account = match params.id {
+        Some(workspace_id) => match db_is_member(&user_id, &workspace_id, &pool).await {
+            Ok(true) => workspace_account(&workspace_id),
+            Ok(false) => {
+                let ret = serde_json::json!({
+                    "error": "Workspace not found",
+                });
+                return Err(into_reponse(404, ret));
+            }
+            Err(e) => {
+                println!("{:?}", e.to_string());
+                let ret = serde_json::json!({
+                    "error": format!("{:?}", e),
+                });
+                return Err(into_reponse(500, ret));
+            }
+        },
+        None => user_account(&user_id),
+    };
+
+    match db_list_entries(&account, &pool).await {
+        Ok(entries) => {
+            Ok(axum::Json(AxumRes{code:200, result: serde_json::json!(&entries)}))
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(500, ret))
+        }
+    }
+}
+
+// Referrals
+/// Records that `invitor_id` referred `invitee_id` with an invite sent at `invited_at`, the
+/// first invitor wins, then grants the reward if the invitee already signed up. An invitee
+/// whose account is older than the invite wasn't referred by it.
+pub async fn record_referral(
+    invitee_id: &String,
+    invitor_id: &String,
+    invited_at: NaiveDateTime,
+    pool: &PgPool,
+) -> anyhow::Result<bool> {
+    if invitee_id == invitor_id {
+        return Ok(false);
+    }
+
+    let mut tx = begin_tenant_tx(pool, invitee_id, None).await?;
+    sqlx::query!(
+        "INSERT INTO referrals (invitee_id, invitor_id, invited_at) SELECT $1, $2, $3
+        WHERE NOT EXISTS (SELECT 1 FROM users WHERE user_id = $1 AND created_at < $3)
+        ON CONFLICT (invitee_id) DO NOTHING",
+        invitee_id,
+        invitor_id,
+        invited_at
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(grant_referral(invitee_id, pool).await?)
+}
+
+/// Pays the referral of `invitee_id` to both sides once the invitee has a profile. The
+/// transaction is keyed by the invitee, so a referral is rewarded at most once.
+pub async fn grant_referral(invitee_id: &String, pool: &PgPool) -> anyhow::Result<bool> {
+    let mut tx = begin_tenant_tx(pool, invitee_id, None).await?;
+    let referral = sqlx::query_scalar!(
+        "SELECT r.invitor_id FROM referrals r JOIN users u ON u.user_id = r.invitee_id
+        WHERE r.invitee_id = $1 AND r.rewarded_at IS NULL AND u.created_at >= r.invited_at FOR UPDATE OF r",
+        invitee_id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    let invitor_id = match referral {
+        Some(invitor_id) => invitor_id,
+        None => return Ok(false),
+    };
+
+    let reward = *REFERRAL_REWARD;
+    let posted = post_transaction(
+        &mut tx,
+        "referral",
+        &format!("referral:{}", invitee_id),
+        &"Referral reward".to_string(),
+        &[
+            (REFERRALS_ACCOUNT.to_string(), -2 * reward),
+            (user_account(&invitor_id), reward),
+            (user_account(invitee_id), reward),
+        ],
+    )
+    .await?;
+
+    sqlx::query!("UPDATE referrals SET rewarded_at = CURRENT_TIMESTAMP WHERE invitee_id = $1", invitee_id)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query!(
+        "UPDATE users SET referred_by = $2 WHERE user_id = $1 AND referred_by IS NULL",
+        invitee_id,
+        &invitor_id
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(posted.is_some())
+}
+
+// Usage
+/// Debits `amount` for `reference` from the workspace when its balance covers it, from
+/// the user otherwise. Returns the account that paid, None when `reference` was charged before.
+pub async fn charge_credits(
+    user_id: &String,
+    workspace_id: Option<Uuid>,
+    amount: i64,
+    reference: &String,
+    description: &String,
+    pool: &PgPool,
+) -> anyhow::Result<Option<String>> {
+    if amount <= 0 {
+        return Err(anyhow::anyhow!("amount must be positive"));
+    }
+    let workspace_id = match workspace_id {
+        Some(id) if db_is_member(user_id, &id, pool).await? => Some(id),
+        _ => None,
+    };
+
+    let mut tx = pool.begin().await?;
+    let mut account = user_account(user_id);
+    if let Some(workspace_id) = workspace_id {
+        let workspace = workspace_account(&workspace_id);
+        if db_lock_balance(&workspace, &mut tx).await? >= amount {
+            account = workspace;
+        }
+    }
+
+    let posted = post_transaction(
+        &mut tx,
+        "usage",
+        reference,
+        description,
+        &[(account.clone(), -amount), (USAGE_ACCOUNT.to_string(), amount)],
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(posted.map(|_| account))
+}
+
+// Database
+// The ledger isn't under row-level security, see schema.sql: accounts are checked before reaching it.
+/// Posts a transaction whose entries sum to zero. Returns None without posting anything
+/// when `idempotency_key` was posted before.
+async fn post_transaction(
+    tx: &mut Transaction<'_, Postgres>,
+    kind: &str,
+    idempotency_key: &String,
+    description: &String,
+    entries: &[(String, i64)],
+) -> anyhow::Result<Option<Uuid>> {
+    if entries.iter().map(|(_, amount)| amount).sum::<i64>() != 0 {
+        return Err(anyhow::anyhow!("Unbalanced {} transaction {}", kind, idempotency_key));
+    }
+
+    let transaction = sqlx::query_scalar!(
+        r#"INSERT INTO credit_transactions (kind, idempotency_key, description) VALUES ($1, $2, $3)
+        ON CONFLICT (idempotency_key) DO NOTHING RETURNING id AS "id!""#,
+        kind,
+        idempotency_key,
+        description
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let transaction_id = match transaction {
+        Some(transaction) => transaction,
+        None => return Ok(None),
+    };
+
+    for (account, amount) in entries {
+        sqlx::query!(
+            "INSERT INTO credit_entries (transaction_id, account, amount) VALUES ($1, $2, $3)",
+            transaction_id,
+            account,
+            amount
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(Some(transaction_id))
+}
+
+// Serializes debits of the account until the transaction ends, so its balance can't be spent twice.
+async fn db_lock_balance(account: &String, tx: &mut Transaction<'_, Postgres>) -> Result<i64, sqlx::Error> {
+    sqlx::query!("SELECT pg_advisory_xact_lock(hashtext($1))", account)
+        .execute(&mut *tx)
+        .await?;
+    let balance = sqlx::query_scalar!(
+        r#"SELECT COALESCE(SUM(amount), 0)::BIGINT AS "balance!" FROM credit_entries WHERE account = $1"#,
+        account
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    Ok(balance)
+}
+
+async fn db_get_balances(accounts: &[String], pool: &PgPool) -> Result<HashMap<String, i64>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"SELECT account, SUM(amount)::BIGINT AS "balance!" FROM credit_entries
+        WHERE account = ANY($1) GROUP BY account"#,
+        accounts
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().map(|row| (row.account, row.balance)).collect())
+}
+
+async fn db_list_entries(account: &String, pool: &PgPool) -> Result<Vec<CreditEntry>, sqlx::Error> {
+    let entries = sqlx::query_as!(
+        CreditEntry,
+        "SELECT e.transaction_id, t.kind, t.description, e.amount, e.created_at
+        FROM credit_entries e JOIN credit_transactions t ON t.id = e.transaction_id
+        WHERE e.account = $1 ORDER BY e.created_at DESC LIMIT $2",
+        account,
+        MAX_HISTORY_ENTRIES
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(entries)
+}
+
+async fn db_get_workspace_ids(user_id: &String, pool: &PgPool) -> Result<Vec<Uuid>, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let ids = sqlx::query_scalar!("SELECT workspace_ids FROM users WHERE user_id = $1", user_id)
+        .fetch_optional(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(ids.flatten().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::{create_test_user, test_pool, test_user_id};
+    use sqlx::types::chrono::{Duration, Utc};
+
+    async fn balance(account: String, pool: &PgPool) -> i64 {
+        db_get_balances(&[account.clone()], pool).await.unwrap().get(&account).copied().unwrap_or(0)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn referral_is_granted_once_after_sign_up() {
+        let pool = test_pool().await;
+        let (invitor, invitee) = (test_user_id(), test_user_id());
+        let invited_at = Utc::now().naive_utc() - Duration::hours(1);
+
+        // Recorded before the invitee signs up, granted once they do
+        assert!(!record_referral(&invitee, &invitor, invited_at, &pool).await.unwrap());
+        create_test_user(&invitee, Utc::now().naive_utc(), &pool).await;
+        assert!(grant_referral(&invitee, &pool).await.unwrap());
+        assert!(!grant_referral(&invitee, &pool).await.unwrap());
+
+        assert_eq!(balance(user_account(&invitor), &pool).await, *REFERRAL_REWARD);
+        assert_eq!(balance(user_account(&invitee), &pool).await, *REFERRAL_REWARD);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn account_older_than_invite_is_not_referred() {
+        let pool = test_pool().await;
+        let (invitor, invitee) = (test_user_id(), test_user_id());
+        create_test_user(&invitee, Utc::now().naive_utc() - Duration::days(30), &pool).await;
+
+        assert!(!record_referral(&invitee, &invitor, Utc::now().naive_utc(), &pool).await.unwrap());
+        assert!(!grant_referral(&invitee, &pool).await.unwrap());
+        assert_eq!(balance(user_account(&invitor), &pool).await, 0);
+        assert_eq!(balance(user_account(&invitee), &pool).await, 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn self_referral_is_ignored() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        create_test_user(&user_id, Utc::now().naive_utc(), &pool).await;
+
+        assert!(!record_referral(&user_id, &user_id, Utc::now().naive_utc() - Duration::hours(1), &pool).await.unwrap());
+        assert_eq!(balance(user_account(&user_id), &pool).await, 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn charge_is_posted_once_and_balanced() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        let reference = format!("video:{}", Uuid::new_v4());
+        let description = "Generated video".to_string();
+
+        let paid = charge_credits(&user_id, None, 5, &reference, &description, &pool).await.unwrap();
+        assert_eq!(paid, Some(user_account(&user_id)));
+        assert_eq!(charge_credits(&user_id, None, 5, &reference, &description, &pool).await.unwrap(), None);
+        assert_eq!(balance(user_account(&user_id), &pool).await, -5);
+
+        let (sum,): (i64,) = sqlx::query_as(
+            "SELECT SUM(e.amount)::BIGINT FROM credit_entries e JOIN credit_transactions t ON t.id = e.transaction_id
+            WHERE t.idempotency_key = $1")
+            .bind(&reference)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(sum, 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn unbalanced_transaction_is_refused() {
+        let pool = test_pool().await;
+        let mut tx = pool.begin().await.unwrap();
+        let key = format!("test:{}", Uuid::new_v4());
+        let posted = post_transaction(&mut tx, "test", &key, &"Unbalanced".to_string(), &[(user_account("a"), 1)]).await;
+        assert!(posted.is_err());
+        tx.rollback().await.unwrap();
+    }
+}
@@ -0,0 +1,667 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::{rejection::JsonRejection, Extension, Query};
+use axum::Json;
+use axum_macros::debug_handler;
+use openapi_rs::openapi_proc_macro::handler;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::types::chrono::NaiveDateTime;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use okapi::openapi3::RefOr;
+use openapi_rs::gen::OpenApiGenerator;
+use openapi_rs::OpenApiFromData;
+
+use microservice_utils::{
+    crypto::blind_index,
+    events::{event_producer, publish, Event},
+    jwt::{admin::is_admin, extractor::AuthToken},
+    server::erasure::{db_erase_user_rows, ERASURE_PARTICIPANTS},
+    server::response::{into_reponse, AxumRes, AxumResult},
+    server::service_auth::USER_SERVICE,
+};
+
+use crate::user::param::RequiredId;
+
+// What happens to the workspaces the user owns, the first one is the default.
+const WORKSPACE_POLICIES: &[&str] = &["transfer", "delete"];
+
+// A deletion missing reports is requested again after this long, at most MAX_ERASURE_ATTEMPTS times;
+// it is failed afterwards, for an admin to look at and retry.
+const ERASURE_RETRY_MINUTES: i32 = 60;
+const MAX_ERASURE_ATTEMPTS: i32 = 5;
+
+lazy_static! {
+    static ref DELETION_GRACE_DAYS: i32 = std::env::var("ACCOUNT_DELETION_GRACE_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(14);
+    static ref DELETION_POLL_SECS: u64 = std::env::var("ACCOUNT_DELETION_POLL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(60);
+}
+
+#[derive(Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct RequestDeletion {
+    pub workspaces: Option<String>, // transfer (default) or delete
+}
+
+#[derive(sqlx::FromRow, Debug, Clone, Serialize)]
+pub struct AccountDeletion {
+    pub id: Uuid,
+    pub user_id: String,    // blind index of the user id once completed
+    pub status: String,     // scheduled, cancelled, erasing, incomplete, failed, completed
+    pub workspaces: String, // transfer or delete
+    pub attempts: i32,
+    pub requested_at: NaiveDateTime,
+    pub erase_after: NaiveDateTime, // end of the grace period
+    pub completed_at: Option<NaiveDateTime>,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct ErasureReport {
+    pub service: String,
+    pub erased: i64,
+    pub remaining: i64,
+    pub reported_at: NaiveDateTime,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone, Serialize)]
+pub struct ErasureCertificate {
+    pub id: Uuid,
+    pub deletion_id: Uuid,
+    pub subject_index: String, // blind index of the user id
+    pub services: String,      // JSON of the erasure reports
+    pub requested_at: NaiveDateTime,
+    pub issued_at: NaiveDateTime,
+}
+
+// API
+#[debug_handler]
+#[handler(method = "POST",tag = "user")]
+pub async fn request_deletion(
+    payload: Result<Json<RequestDeletion>, JsonRejection>,
+    AuthToken(user_id): AuthToken,
+    Extension(pool): Extension<Arc<PgPool>>
+) -> AxumResult<Json<AxumRes>> {
+    match payload {
+        Ok(payload) => {
+            let workspaces = payload.0.workspaces.unwrap_or(WORKSPACE_POLICIES[0].to_string());
+            schedule_deletion(&user_id, &workspaces, &pool).await
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(400, ret))
+        }
+    }
+}
+
+/// Starts the grace period of an account deletion, the erasure itself is started by
+/// `spawn_deletion_job` once it is over.
+pub async fn schedule_deletion(user_id: &String, workspaces: &String, pool: &PgPool) -> AxumResult<Json<AxumRes>> {
+    if !WORKSPACE_POLICIES.contains(&workspaces.as_str()) {
+        let ret = serde_json::json!({
+            "error": format!("workspaces must be one of {:?}", WORKSPACE_POLICIES),
+        });
+        return Err(into_reponse(400, ret));
+    }
+
+    match db_schedule_deletion(user_id, workspaces, pool).await {
+        Ok(Some(deletion)) => {
+            Ok(axum::Json(AxumRes{code:200, result: serde_json::json!(&deletion)}))
+        }
+        Ok(None) => {
+            let ret = serde_json::json!({
+                "error": "The account is already being deleted",
+            });
+            Err(into_reponse(409, ret))
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(500, ret))
+        }
+    }
+}
+
+#[debug_handler]
+#[handler(method = "GET",tag = "user")]
+pub async fn get_deletion(
+    AuthToken(user_id): AuthToken,
+    Extension(pool): Extension<Arc<PgPool>>
+) -> AxumResult<Json<AxumRes>> {
+    let deletion = match db_get_deletion(&user_id, &pool).await {
+        Ok(Some(deletion)) => deletion,
+        Ok(None) => {
+            let ret = serde_json::json!({
+                "error": "No deletion requested",
+            });
+            return Err(into_reponse(404, ret));
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            return Err(into_reponse(500, ret));
+        }
+    };
+
+    match db_list_reports(&deletion.id, &pool).await {
+        Ok(reports) => {
+            let result = serde_json::json!({
+                "deletion": deletion,
+                "reports": reports,
+            });
+            Ok(axum::Json(AxumRes{code:200, result}))
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(500, ret))
+        }
+    }
+}
+
+#[debug_handler]
+#[handler(method = "DELETE",tag = "user")]
+pub async fn cancel_deletion(
+    AuthToken(user_id): AuthToken,
+    Extension(pool): Extension<Arc<PgPool>>
+) -> AxumResult<Json<AxumRes>> {
+    match db_cancel_deletion(&user_id, &pool).await {
+        Ok(Some(deletion)) => {
+            Ok(axum::Json(AxumRes{code:200, result: serde_json::json!(&deletion)}))
+        }
+        Ok(None) => {
+            let ret = serde_json::json!({
+                "error": "No deletion in its grace period",
+            });
+            Err(into_reponse(409, ret))
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(500, ret))
+        }
+    }
+}
+
+#[debug_handler]
+#[handler(method = "GET",tag = "user")]
+pub async fn get_erasure_certificate(
+    params: Query<RequiredId>,
+    AuthToken(user_id): AuthToken,
+    Extension(pool): Extension<Arc<PgPool>>
+) -> AxumResult<Json<AxumRes>> {
+    if !is_admin(&user_id) {
+        let ret = serde_json::json!({
+            "error": "Not found",
+        });
+        return Err(into_reponse(404, ret));
+    }
+
+    match db_get_certificate(&params.id, &pool).await {
+        Ok(certificate) => {
+            Ok(axum::Json(AxumRes{code:200, result: serde_json::json!(&certificate)}))
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(404, ret))
+        }
+    }
+}
+
+#[debug_handler]
+#[handler(method = "GET",tag = "user")]
+pub async fn get_failed_deletions(
+    AuthToken(user_id): AuthToken,
+    Extension(pool): Extension<Arc<PgPool>>
+) -> AxumResult<Json<AxumRes>> {
+    if !is_admin(&user_id) {
+        let ret = serde_json::json!({
+            "error": "Not found",
+        });
+        return Err(into_reponse(404, ret));
+    }
+
+    let deletions = match db_list_failed_deletions(&pool).await {
+        Ok(deletions) => deletions,
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            return Err(into_reponse(500, ret));
+        }
+    };
+    let mut result = vec![];
+    for deletion in deletions {
+        match db_list_reports(&deletion.id, &pool).await {
+            Ok(reports) => result.push(serde_json::json!({
+                "deletion": deletion,
+                "reports": reports,
+            })),
+            Err(e) => {
+                println!("{:?}", e.to_string());
+                let ret = serde_json::json!({
+                    "error": format!("{:?}", e),
+                });
+                return Err(into_reponse(500, ret));
+            }
+        }
+    }
+    Ok(axum::Json(AxumRes{code:200, result: serde_json::json!(result)}))
+}
+
+#[debug_handler]
+#[handler(method = "POST",tag = "user")]
+pub async fn retry_deletion(
+    params: Query<RequiredId>,
+    AuthToken(user_id): AuthToken,
+    Extension(pool): Extension<Arc<PgPool>>
+) -> AxumResult<Json<AxumRes>> {
+    if !is_admin(&user_id) {
+        let ret = serde_json::json!({
+            "error": "Not found",
+        });
+        return Err(into_reponse(404, ret));
+    }
+
+    match db_retry_deletion(&params.id, &pool).await {
+        Ok(Some(deletion)) => {
+            Ok(axum::Json(AxumRes{code:200, result: serde_json::json!(&deletion)}))
+        }
+        Ok(None) => {
+            let ret = serde_json::json!({
+                "error": "No failed deletion with this id",
+            });
+            Err(into_reponse(409, ret))
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(500, ret))
+        }
+    }
+}
+
+// Saga
+// Asks every service to erase the users whose grace period is over, and again the ones still
+// missing reports after ERASURE_RETRY_MINUTES.
+pub fn spawn_deletion_job(pool: &PgPool) {
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        let producer = event_producer();
+        let mut interval = tokio::time::interval(Duration::from_secs(*DELETION_POLL_SECS));
+        loop {
+            interval.tick().await;
+            let due = match db_start_due_deletions(&pool).await {
+                Ok(due) => due,
+                Err(e) => {
+                    println!("{:?}", e.to_string());
+                    continue;
+                }
+            };
+            for deletion in due {
+                let event = Event::UserDeletionRequested {
+                    deletion_id: deletion.id.to_string(),
+                    user_id: deletion.user_id,
+                    workspaces: deletion.workspaces,
+                };
+                if let Err(e) = publish(&event, &producer).await {
+                    println!("Failed to publish {:?}: {:?}", event, e);
+                }
+            }
+            match db_fail_exhausted_deletions(&pool).await {
+                Ok(failed) => {
+                    for deletion in failed {
+                        println!(
+                            "ALERT: account deletion {} failed after {} attempts, see /api/user/deletion/failed",
+                            deletion.id, deletion.attempts
+                        );
+                    }
+                }
+                Err(e) => println!("{:?}", e.to_string()),
+            }
+        }
+    });
+}
+
+/// Records the report of a participant, then completes the deletion once every
+/// participant verified its erasure: the user's own rows go last, with the certificate.
+pub async fn handle_erasure_report(
+    deletion_id: &String,
+    service: &String,
+    erased: i64,
+    remaining: i64,
+    pool: &PgPool,
+) -> anyhow::Result<()> {
+    if !ERASURE_PARTICIPANTS.contains(&service.as_str()) {
+        return Err(anyhow::anyhow!("Unexpected erasure report from {}", service));
+    }
+    let deletion_id = Uuid::parse_str(deletion_id)?;
+    let deletion = match db_record_report(&deletion_id, service, erased, remaining, pool).await? {
+        Some(deletion) => deletion,
+        None => return Ok(()), // completed or cancelled already
+    };
+
+    let reports = db_list_reports(&deletion.id, pool).await?;
+    let pending = ERASURE_PARTICIPANTS
+        .iter()
+        .any(|participant| !reports.iter().any(|r| r.service == *participant));
+    if pending {
+        return Ok(());
+    }
+    if reports.iter().any(|r| r.remaining > 0) {
+        db_set_deletion_status(&deletion.id, "incomplete", pool).await?;
+        return Ok(());
+    }
+
+    let erasure = db_erase_user_rows(
+        &deletion.user_id,
+        &[
+            "DELETE FROM data_exports WHERE user_id = $1",
+            "DELETE FROM user_preferences WHERE user_id = $1",
+            "DELETE FROM username_history WHERE user_id = $1",
+            "DELETE FROM referrals WHERE invitee_id = $1 OR invitor_id = $1",
+            // Ledger entries stay so transactions keep balancing, only the account is anonymised
+            "UPDATE credit_entries SET account = 'user:erased' WHERE account = 'user:' || $1",
+            "DELETE FROM users WHERE user_id = $1",
+        ],
+        &[
+            "SELECT count(*) FROM data_exports WHERE user_id = $1",
+            "SELECT count(*) FROM user_preferences WHERE user_id = $1",
+            "SELECT count(*) FROM username_history WHERE user_id = $1",
+            "SELECT count(*) FROM referrals WHERE invitee_id = $1 OR invitor_id = $1",
+            "SELECT count(*) FROM credit_entries WHERE account = 'user:' || $1",
+            "SELECT count(*) FROM users WHERE user_id = $1",
+        ],
+        pool,
+    )
+    .await?;
+    db_record_report(&deletion.id, &USER_SERVICE.to_string(), erasure.erased, erasure.remaining, pool).await?;
+    if erasure.remaining > 0 {
+        db_set_deletion_status(&deletion.id, "incomplete", pool).await?;
+        return Ok(());
+    }
+
+    let reports = db_list_reports(&deletion.id, pool).await?;
+    db_issue_certificate(&deletion, &blind_index(&deletion.user_id)?, &serde_json::to_string(&reports)?, pool).await?;
+    println!("Account deletion {} completed", deletion.id);
+    Ok(())
+}
+
+// Database
+// The deletion tables aren't under row-level security, see schema.sql, so these use the pool directly.
+// Returns None when the user already has a deletion in progress.
+async fn db_schedule_deletion(user_id: &String, workspaces: &String, pool: &PgPool) -> Result<Option<AccountDeletion>, sqlx::Error> {
+    let deletion = sqlx::query_as!(
+        AccountDeletion,
+        "INSERT INTO account_deletions (user_id, workspaces, erase_after)
+        VALUES ($1, $2, CURRENT_TIMESTAMP + make_interval(days => $3))
+        ON CONFLICT (user_id) WHERE status IN ('scheduled', 'erasing', 'incomplete', 'failed') DO NOTHING
+        RETURNING id, user_id, status, workspaces, attempts, requested_at, erase_after, completed_at",
+        user_id,
+        workspaces,
+        *DELETION_GRACE_DAYS
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(deletion)
+}
+
+async fn db_get_deletion(user_id: &String, pool: &PgPool) -> Result<Option<AccountDeletion>, sqlx::Error> {
+    let deletion = sqlx::query_as!(
+        AccountDeletion,
+        "SELECT id, user_id, status, workspaces, attempts, requested_at, erase_after, completed_at
+        FROM account_deletions WHERE user_id = $1 ORDER BY requested_at DESC LIMIT 1",
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(deletion)
+}
+
+async fn db_cancel_deletion(user_id: &String, pool: &PgPool) -> Result<Option<AccountDeletion>, sqlx::Error> {
+    let deletion = sqlx::query_as!(
+        AccountDeletion,
+        "UPDATE account_deletions SET status = 'cancelled', completed_at = CURRENT_TIMESTAMP
+        WHERE user_id = $1 AND status = 'scheduled'
+        RETURNING id, user_id, status, workspaces, attempts, requested_at, erase_after, completed_at",
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(deletion)
+}
+
+async fn db_start_due_deletions(pool: &PgPool) -> Result<Vec<AccountDeletion>, sqlx::Error> {
+    let deletions = sqlx::query_as!(
+        AccountDeletion,
+        "UPDATE account_deletions SET status = 'erasing', attempts = attempts + 1, published_at = CURRENT_TIMESTAMP
+        WHERE (status = 'scheduled' AND erase_after <= CURRENT_TIMESTAMP)
+        OR (status IN ('erasing', 'incomplete') AND attempts < $1
+            AND published_at < CURRENT_TIMESTAMP - make_interval(mins => $2))
+        RETURNING id, user_id, status, workspaces, attempts, requested_at, erase_after, completed_at",
+        MAX_ERASURE_ATTEMPTS,
+        ERASURE_RETRY_MINUTES
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(deletions)
+}
+
+// Deletions still missing reports after their last attempt had its time.
+async fn db_fail_exhausted_deletions(pool: &PgPool) -> Result<Vec<AccountDeletion>, sqlx::Error> {
+    let deletions = sqlx::query_as!(
+        AccountDeletion,
+        "UPDATE account_deletions SET status = 'failed'
+        WHERE status IN ('erasing', 'incomplete') AND attempts >= $1
+        AND published_at < CURRENT_TIMESTAMP - make_interval(mins => $2)
+        RETURNING id, user_id, status, workspaces, attempts, requested_at, erase_after, completed_at",
+        MAX_ERASURE_ATTEMPTS,
+        ERASURE_RETRY_MINUTES
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(deletions)
+}
+
+async fn db_list_failed_deletions(pool: &PgPool) -> Result<Vec<AccountDeletion>, sqlx::Error> {
+    let deletions = sqlx::query_as!(
+        AccountDeletion,
+        "SELECT id, user_id, status, workspaces, attempts, requested_at, erase_after, completed_at
+        FROM account_deletions WHERE status = 'failed' ORDER BY requested_at"
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(deletions)
+}
+
+// Starts the attempts over, the next poll requests the erasure again.
+async fn db_retry_deletion(deletion_id: &Uuid, pool: &PgPool) -> Result<Option<AccountDeletion>, sqlx::Error> {
+    let deletion = sqlx::query_as!(
+        AccountDeletion,
+        "UPDATE account_deletions SET status = 'scheduled', attempts = 0, erase_after = CURRENT_TIMESTAMP
+        WHERE id = $1 AND status = 'failed'
+        RETURNING id, user_id, status, workspaces, attempts, requested_at, erase_after, completed_at",
+        deletion_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(deletion)
+}
+
+// Returns the deletion the report belongs to while it is still being erased; a late report
+// can still complete a failed deletion.
+async fn db_record_report(
+    deletion_id: &Uuid,
+    service: &String,
+    erased: i64,
+    remaining: i64,
+    pool: &PgPool,
+) -> Result<Option<AccountDeletion>, sqlx::Error> {
+    let deletion = sqlx::query_as!(
+        AccountDeletion,
+        "SELECT id, user_id, status, workspaces, attempts, requested_at, erase_after, completed_at
+        FROM account_deletions WHERE id = $1 AND status IN ('erasing', 'incomplete', 'failed')",
+        deletion_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    if deletion.is_none() {
+        return Ok(None);
+    }
+
+    sqlx::query!(
+        "INSERT INTO account_deletion_reports (deletion_id, service, erased, remaining) VALUES ($1, $2, $3, $4)
+        ON CONFLICT (deletion_id, service) DO UPDATE SET erased = $3, remaining = $4, reported_at = CURRENT_TIMESTAMP",
+        deletion_id,
+        service,
+        erased,
+        remaining
+    )
+    .execute(pool)
+    .await?;
+    Ok(deletion)
+}
+
+async fn db_list_reports(deletion_id: &Uuid, pool: &PgPool) -> Result<Vec<ErasureReport>, sqlx::Error> {
+    let reports = sqlx::query_as!(
+        ErasureReport,
+        "SELECT service, erased, remaining, reported_at FROM account_deletion_reports
+        WHERE deletion_id = $1 ORDER BY service",
+        deletion_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(reports)
+}
+
+async fn db_set_deletion_status(deletion_id: &Uuid, status: &str, pool: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE account_deletions SET status = $2 WHERE id = $1",
+        deletion_id,
+        status
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// The deletion keeps only the blind index of the user id from here on.
+async fn db_issue_certificate(
+    deletion: &AccountDeletion,
+    subject_index: &String,
+    services: &String,
+    pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "INSERT INTO erasure_certificates (deletion_id, subject_index, services, requested_at)
+        VALUES ($1, $2, $3, $4) ON CONFLICT (deletion_id) DO NOTHING",
+        deletion.id,
+        subject_index,
+        services,
+        deletion.requested_at
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE account_deletions SET status = 'completed', user_id = $2, completed_at = CURRENT_TIMESTAMP WHERE id = $1",
+        deletion.id,
+        subject_index
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn db_get_certificate(deletion_id: &Uuid, pool: &PgPool) -> Result<ErasureCertificate, sqlx::Error> {
+    let certificate = sqlx::query_as!(
+        ErasureCertificate,
+        "SELECT id, deletion_id, subject_index, services, requested_at, issued_at FROM erasure_certificates
+        WHERE deletion_id = $1",
+        deletion_id
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(certificate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::{test_pool, test_user_id};
+
+    async fn exhausted_deletion(user_id: &String, pool: &PgPool) -> Uuid {
+        let (id,): (Uuid,) = sqlx::query_as(
+            "INSERT INTO account_deletions (user_id, status, attempts, erase_after, published_at)
+            VALUES ($1, 'incomplete', $2, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP - make_interval(mins => $3 + 1))
+            RETURNING id")
+            .bind(user_id)
+            .bind(MAX_ERASURE_ATTEMPTS)
+            .bind(ERASURE_RETRY_MINUTES)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        id
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn exhausted_deletions_fail_until_an_admin_retries() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        let id = exhausted_deletion(&user_id, &pool).await;
+
+        let failed = db_fail_exhausted_deletions(&pool).await.unwrap();
+        assert!(failed.iter().any(|d| d.id == id && d.status == "failed"));
+        assert!(db_list_failed_deletions(&pool).await.unwrap().iter().any(|d| d.id == id));
+        // not requested again, and the user can't start another one
+        assert!(!db_start_due_deletions(&pool).await.unwrap().iter().any(|d| d.id == id));
+        assert!(db_schedule_deletion(&user_id, &"transfer".to_string(), &pool).await.unwrap().is_none());
+
+        let retried = db_retry_deletion(&id, &pool).await.unwrap().unwrap();
+        assert_eq!((retried.status.as_str(), retried.attempts), ("scheduled", 0));
+        assert!(db_retry_deletion(&id, &pool).await.unwrap().is_none());
+        let due = db_start_due_deletions(&pool).await.unwrap();
+        assert!(due.iter().any(|d| d.id == id && d.attempts == 1));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn deletions_with_attempts_left_are_not_failed() {
+        let pool = test_pool().await;
+        let id = exhausted_deletion(&test_user_id(), &pool).await;
+        sqlx::query("UPDATE account_deletions SET attempts = $2 WHERE id = $1")
+            .bind(id)
+            .bind(MAX_ERASURE_ATTEMPTS - 1)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let failed = db_fail_exhausted_deletions(&pool).await.unwrap();
+        assert!(!failed.iter().any(|d| d.id == id));
+    }
+}
@@ -0,0 +1,405 @@
+use std::io::{Cursor, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::Extension;
+use axum::Json;
+use axum_macros::debug_handler;
+use openapi_rs::openapi_proc_macro::handler;
+use serde::Serialize;
+use sqlx::types::chrono::NaiveDateTime;
+use sqlx::PgPool;
+use uuid::Uuid;
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+use okapi::openapi3::RefOr;
+use openapi_rs::gen::OpenApiGenerator;
+use openapi_rs::OpenApiFromData;
+
+use microservice_utils::{
+    events::{event_producer, notify_user},
+    jwt::extractor::AuthToken,
+    server::export::{collect_user_data, json_file, store_export},
+    server::grpc::data_export::ExportFile,
+    server::response::{into_reponse, AxumRes, AxumResult},
+    server::sender::{sender_from_env, Channel, Message, MessageSender},
+    server::service_auth::USER_SERVICE,
+    server::tenant::{begin_service_tx, begin_tenant_tx},
+};
+
+use crate::user::preferences::{db_list_preferences, notification_enabled};
+use crate::user::user_handler::db_get_user;
+
+lazy_static! {
+    static ref EXPORT_DOWNLOAD_URL: String = std::env::var("EXPORT_DOWNLOAD_URL")
+        .unwrap_or("https://api.bhuman.ai".to_string());
+}
+
+// A running export proves it's alive this often. Exports that missed their heartbeat for
+// EXPORT_LEASE died with the process running them, whichever instance it was.
+const EXPORT_HEARTBEAT: Duration = Duration::from_secs(30);
+const EXPORT_LEASE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(sqlx::FromRow, Debug, Clone, Serialize)]
+pub struct DataExport {
+    pub id: Uuid,
+    pub status: String, // pending, running, ready, failed
+    pub file_id: Option<i32>,
+    pub error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub completed_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DataExportStatus {
+    #[serde(flatten)]
+    pub export: DataExport,
+    pub download_url: Option<String>,
+}
+
+impl From<DataExport> for DataExportStatus {
+    fn from(export: DataExport) -> Self {
+        Self {
+            download_url: export.file_id.map(download_url),
+            export,
+        }
+    }
+}
+
+fn download_url(file_id: i32) -> String {
+    format!("{}/filemanager/exports/{}", *EXPORT_DOWNLOAD_URL, file_id)
+}
+
+// API
+#[debug_handler]
+#[handler(method = "POST",tag = "user")]
+pub async fn export_user_data(
+    AuthToken(user_id): AuthToken,
+    Extension(pool): Extension<Arc<PgPool>>
+) -> AxumResult<Json<AxumRes>> {
+    match db_create_export(&user_id, &pool).await {
+        Ok(Some(export)) => {
+            spawn_export_job(&pool, user_id, export.id);
+            Ok(axum::Json(AxumRes{code:200, result: serde_json::json!(DataExportStatus::from(export))}))
+        }
+        Ok(None) => {
+            let ret = serde_json::json!({
+                "error": "An export is already in progress",
+            });
+            Err(into_reponse(409, ret))
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(500, ret))
+        }
+    }
+}
+
+#[debug_handler]
+#[handler(method = "GET",tag = "user")]
+pub async fn get_user_exports(
+    AuthToken(user_id): AuthToken,
+    Extension(pool): Extension<Arc<PgPool>>
+) -> AxumResult<Json<AxumRes>> {
+    match db_list_exports(&user_id, &pool).await {
+        Ok(exports) => {
+            let exports: Vec<DataExportStatus> = exports.into_iter().map(DataExportStatus::from).collect();
+            Ok(axum::Json(AxumRes{code:200, result: serde_json::json!(&exports)}))
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(500, ret))
+        }
+    }
+}
+
+// Job
+// Builds the export in the background and tells the user once it's ready.
+fn spawn_export_job(pool: &PgPool, user_id: String, export_id: Uuid) {
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        let _ = db_set_export_status(&user_id, &export_id, "running", None, None, &pool).await;
+        let heartbeat = spawn_export_heartbeat(&pool, user_id.clone(), export_id);
+        let res = run_export(&user_id, &pool).await;
+        heartbeat.abort();
+        match res {
+            Ok(file_id) => {
+                if let Err(e) = db_set_export_status(&user_id, &export_id, "ready", Some(file_id), None, &pool).await {
+                    println!("{:?}", e.to_string());
+                }
+                notify_export_ready(&user_id, &export_id, file_id, &*sender_from_env(), &pool).await;
+            }
+            Err(e) => {
+                println!("Export {} failed: {:?}", export_id, e);
+                let error = format!("{:?}", e);
+                let _ = db_set_export_status(&user_id, &export_id, "failed", None, Some(&error), &pool).await;
+            }
+        }
+    });
+}
+
+fn spawn_export_heartbeat(pool: &PgPool, user_id: String, export_id: Uuid) -> tokio::task::JoinHandle<()> {
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EXPORT_HEARTBEAT);
+        loop {
+            interval.tick().await;
+            if let Err(e) = db_export_heartbeat(&user_id, &export_id, &pool).await {
+                println!("{:?}", e.to_string());
+            }
+        }
+    })
+}
+
+/// Fails the exports whose job died, so they don't block new ones.
+pub fn spawn_export_sweeper(pool: &PgPool) {
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EXPORT_LEASE);
+        loop {
+            interval.tick().await;
+            match db_fail_interrupted_exports(&pool).await {
+                Ok(0) => {}
+                Ok(count) => println!("Marked {} interrupted exports as failed", count),
+                Err(e) => println!("{:?}", e.to_string()),
+            }
+        }
+    });
+}
+
+// Tells the user over the websocket and by email, as their data_export preferences allow.
+async fn notify_export_ready(user_id: &String, export_id: &Uuid, file_id: i32, sender: &dyn MessageSender, pool: &PgPool) {
+    let link = download_url(file_id);
+    if notification_enabled(user_id, "data_export", "in_app", pool).await.unwrap_or(true) {
+        let message = serde_json::json!({
+            "export_id": export_id,
+            "download_url": link,
+        });
+        if let Err(e) = notify_user(user_id, "data_export_ready", &message, &event_producer()).await {
+            println!("Unable to notify {} of their export: {:?}", user_id, e);
+        }
+    }
+    if notification_enabled(user_id, "data_export", "email", pool).await.unwrap_or(true) {
+        let email = match db_get_user(user_id, pool).await {
+            Ok(user) => user.email,
+            Err(e) => {
+                println!("{:?}", e.to_string());
+                return;
+            }
+        };
+        if email.is_empty() {
+            return;
+        }
+        let message = Message {
+            channel: Channel::Email,
+            to: email,
+            subject: "Your data export is ready".to_string(),
+            body: format!(
+                "The export of your BHuman data is ready. Download it within a day at {}",
+                link
+            ),
+        };
+        if let Err(e) = sender.send(&message).await {
+            println!("Unable to email {} about their export: {:?}", user_id, e);
+        }
+    }
+}
+
+async fn run_export(user_id: &String, pool: &PgPool) -> anyhow::Result<i32> {
+    let mut files = vec![
+        json_file(&format!("{}/profile", USER_SERVICE), &db_get_user(user_id, pool).await?)?,
+        json_file(&format!("{}/preferences", USER_SERVICE), &db_list_preferences(user_id, None, pool).await?)?,
+    ];
+    files.extend(collect_user_data(user_id).await?);
+
+    let name = format!("export-{}.zip", sqlx::types::chrono::Utc::now().format("%Y-%m-%d"));
+    store_export(user_id, &name, zip_files(&files)?).await
+}
+
+fn zip_files(files: &[ExportFile]) -> anyhow::Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for file in files {
+        zip.start_file(file.name.as_str(), FileOptions::default())?;
+        zip.write_all(&file.content)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+// Database
+// Returns None when the user already has an export pending or running.
+async fn db_create_export(user_id: &String, pool: &PgPool) -> Result<Option<DataExport>, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let export = sqlx::query_as!(
+        DataExport,
+        "INSERT INTO data_exports (user_id) VALUES ($1)
+        ON CONFLICT (user_id) WHERE status IN ('pending', 'running') DO NOTHING
+        RETURNING id, status, file_id, error, created_at, completed_at",
+        user_id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(export)
+}
+
+// Exports in progress that missed their heartbeat for the lease, those of live jobs are left alone.
+pub async fn db_fail_interrupted_exports(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let mut tx = begin_service_tx(pool, "fail_interrupted_exports").await?;
+    let res = sqlx::query!(
+        "UPDATE data_exports SET status = 'failed', error = 'Interrupted', completed_at = CURRENT_TIMESTAMP
+        WHERE status IN ('pending', 'running') AND heartbeat_at < CURRENT_TIMESTAMP - $1::FLOAT8 * INTERVAL '1 second'",
+        EXPORT_LEASE.as_secs() as f64
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(res.rows_affected())
+}
+
+async fn db_export_heartbeat(user_id: &String, export_id: &Uuid, pool: &PgPool) -> Result<(), sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    sqlx::query!("UPDATE data_exports SET heartbeat_at = CURRENT_TIMESTAMP WHERE id = $1 AND status = 'running'", export_id)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn db_list_exports(user_id: &String, pool: &PgPool) -> Result<Vec<DataExport>, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let exports = sqlx::query_as!(
+        DataExport,
+        "SELECT id, status, file_id, error, created_at, completed_at FROM data_exports
+        WHERE user_id = $1 ORDER BY created_at DESC",
+        user_id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(exports)
+}
+
+async fn db_set_export_status(
+    user_id: &String,
+    export_id: &Uuid,
+    status: &str,
+    file_id: Option<i32>,
+    error: Option<&String>,
+    pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    sqlx::query!(
+        "UPDATE data_exports SET status = $2, file_id = $3, error = $4, heartbeat_at = CURRENT_TIMESTAMP,
+        completed_at = CASE WHEN $2 IN ('ready', 'failed') THEN CURRENT_TIMESTAMP END
+        WHERE id = $1",
+        export_id,
+        status,
+        file_id,
+        error
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::{create_test_user, test_pool, test_user_id, RecordingSender};
+    use crate::user::preferences::{db_set_preferences, find_preference};
+    use microservice_utils::server::preferences::notification_key;
+    use serde_json::Value;
+    use sqlx::types::chrono::Utc;
+
+    async fn export_status(export_id: &Uuid, pool: &PgPool) -> String {
+        let (status,): (String,) = sqlx::query_as("SELECT status FROM data_exports WHERE id = $1")
+            .bind(export_id)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        status
+    }
+
+    async fn user_with_email(pool: &PgPool) -> (String, String) {
+        let user_id = test_user_id();
+        let email = format!("{}@example.com", user_id);
+        create_test_user(&user_id, Utc::now().naive_utc(), pool).await;
+        sqlx::query("UPDATE users SET email = $2 WHERE user_id = $1")
+            .bind(&user_id)
+            .bind(&email)
+            .execute(pool)
+            .await
+            .unwrap();
+        (user_id, email)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn only_exports_past_their_lease_are_interrupted() {
+        let pool = test_pool().await;
+        let live = db_create_export(&test_user_id(), &pool).await.unwrap().unwrap();
+        let dead = db_create_export(&test_user_id(), &pool).await.unwrap().unwrap();
+        sqlx::query("UPDATE data_exports SET status = 'running', heartbeat_at = CURRENT_TIMESTAMP - INTERVAL '1 hour' WHERE id = $1")
+            .bind(dead.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        db_fail_interrupted_exports(&pool).await.unwrap();
+        assert_eq!(export_status(&live.id, &pool).await, "pending");
+        assert_eq!(export_status(&dead.id, &pool).await, "failed");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn heartbeats_keep_running_exports_alive() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        let export = db_create_export(&user_id, &pool).await.unwrap().unwrap();
+        db_set_export_status(&user_id, &export.id, "running", None, None, &pool).await.unwrap();
+        sqlx::query("UPDATE data_exports SET heartbeat_at = CURRENT_TIMESTAMP - INTERVAL '1 hour' WHERE id = $1")
+            .bind(export.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        db_export_heartbeat(&user_id, &export.id, &pool).await.unwrap();
+        db_fail_interrupted_exports(&pool).await.unwrap();
+        assert_eq!(export_status(&export.id, &pool).await, "running");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn ready_exports_are_emailed() {
+        let pool = test_pool().await;
+        let (user_id, email) = user_with_email(&pool).await;
+        let sender = RecordingSender::default();
+
+        notify_export_ready(&user_id, &Uuid::new_v4(), 42, &sender, &pool).await;
+        let sent = sender.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, email);
+        assert!(sent[0].body.contains(&download_url(42)));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn export_emails_follow_preferences() {
+        let pool = test_pool().await;
+        let (user_id, _) = user_with_email(&pool).await;
+        let def = find_preference(&notification_key("data_export", "email")).unwrap();
+        db_set_preferences(&user_id, &Uuid::nil(), &[(def, &Value::from(false))], &pool).await.unwrap();
+        let sender = RecordingSender::default();
+
+        notify_export_ready(&user_id, &Uuid::new_v4(), 42, &sender, &pool).await;
+        assert!(sender.sent.lock().unwrap().is_empty());
+    }
+}
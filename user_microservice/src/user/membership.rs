@@ -0,0 +1,204 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::Extension;
+use axum::Json;
+use axum_macros::debug_handler;
+use openapi_rs::openapi_proc_macro::handler;
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use okapi::openapi3::RefOr;
+use openapi_rs::gen::OpenApiGenerator;
+use openapi_rs::OpenApiFromData;
+
+use microservice_utils::{
+    jwt::{admin::is_admin, extractor::AuthToken},
+    server::grpc::list_memberships,
+    server::response::{into_reponse, AxumRes, AxumResult},
+    server::tenant::{begin_service_tx, begin_tenant_tx},
+};
+
+const RECONCILE_PAGE_SIZE: i64 = 500;
+
+lazy_static! {
+    static ref RECONCILE_INTERVAL_SECS: u64 = std::env::var("WORKSPACE_RECONCILE_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(60 * 60);
+}
+
+// A user whose workspace_ids didn't match their membership rows.
+#[derive(Debug, Clone, Serialize)]
+pub struct MembershipDrift {
+    pub user_id: String,
+    pub added: Vec<Uuid>,
+    pub removed: Vec<Uuid>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReconcileReport {
+    pub users_checked: usize,
+    pub repaired: Vec<MembershipDrift>,
+}
+
+// Runs the reconciliation on startup and then every WORKSPACE_RECONCILE_INTERVAL_SECS.
+pub fn spawn_reconcile_job(pool: &PgPool) {
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(*RECONCILE_INTERVAL_SECS));
+        loop {
+            interval.tick().await;
+            match reconcile_workspaces(&pool).await {
+                Ok(report) => print_report(&report),
+                Err(e) => println!("Workspace reconciliation failed: {:?}", e),
+            }
+        }
+    });
+}
+
+fn print_report(report: &ReconcileReport) {
+    println!(
+        "Reconciled workspaces of {} users, repaired {}",
+        report.users_checked,
+        report.repaired.len()
+    );
+    for drift in &report.repaired {
+        println!(
+            "  {}: added {:?}, removed {:?}",
+            drift.user_id, drift.added, drift.removed
+        );
+    }
+}
+
+/// Compares `users.workspace_ids` with the membership rows of workspace_microservice,
+/// page by page, and overwrites the ids of users that drifted.
+pub async fn reconcile_workspaces(pool: &PgPool) -> anyhow::Result<ReconcileReport> {
+    let mut report = ReconcileReport::default();
+    let mut after = String::new();
+    loop {
+        let users = db_list_workspace_ids(&after, RECONCILE_PAGE_SIZE, pool).await?;
+        let last = match users.last() {
+            Some((user_id, _)) => user_id.clone(),
+            None => break,
+        };
+
+        let user_ids: Vec<String> = users.iter().map(|(user_id, _)| user_id.clone()).collect();
+        let mut expected: HashMap<String, BTreeSet<Uuid>> = HashMap::new();
+        for (user_id, workspace_id) in list_memberships(&user_ids).await? {
+            expected.entry(user_id).or_default().insert(workspace_id);
+        }
+
+        for (user_id, workspace_ids) in users {
+            let actual: BTreeSet<Uuid> = workspace_ids.unwrap_or_default().into_iter().collect();
+            let wanted = expected.remove(&user_id).unwrap_or_default();
+            if actual != wanted {
+                let ids: Vec<Uuid> = wanted.iter().cloned().collect();
+                db_set_workspace_ids(&user_id, &ids, pool).await?;
+                report.repaired.push(MembershipDrift {
+                    added: wanted.difference(&actual).cloned().collect(),
+                    removed: actual.difference(&wanted).cloned().collect(),
+                    user_id,
+                });
+            }
+            report.users_checked += 1;
+        }
+        after = last;
+    }
+    Ok(report)
+}
+
+// API
+#[debug_handler]
+#[handler(method = "POST",tag = "user")]
+pub async fn reconcile_user_workspaces(
+    AuthToken(user_id): AuthToken,
+    Extension(pool): Extension<Arc<PgPool>>
+) -> AxumResult<Json<AxumRes>> {
+    if !is_admin(&user_id) {
+        let ret = serde_json::json!({
+            "error": "Not found",
+        });
+        return Err(into_reponse(404, ret));
+    }
+
+    match reconcile_workspaces(&pool).await {
+        Ok(report) => {
+            print_report(&report);
+            Ok(axum::Json(AxumRes{code:200, result: serde_json::json!(&report)}))
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(500, ret))
+        }
+    }
+}
+
+// Database
+// Projection of WorkspaceMemberAdded, a no-op when the id is already there.
+pub async fn db_add_workspace_id(user_id: &String, workspace_id: &String, pool: &PgPool) -> Result<(), sqlx::Error> {
+    let workspace_id = Uuid::parse_str(workspace_id).map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    sqlx::query!(
+        "UPDATE users SET workspace_ids = array_append(COALESCE(workspace_ids, '{}'), $2)
+        WHERE user_id = $1 AND NOT ($2 = ANY(COALESCE(workspace_ids, '{}')))",
+        user_id,
+        workspace_id
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+// Projection of WorkspaceMemberRemoved, a no-op when the id is already gone.
+pub async fn db_remove_workspace_id(user_id: &String, workspace_id: &String, pool: &PgPool) -> Result<(), sqlx::Error> {
+    let workspace_id = Uuid::parse_str(workspace_id).map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    sqlx::query!("UPDATE users SET workspace_ids = array_remove(workspace_ids, $2) WHERE user_id = $1", user_id, workspace_id)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+// Membership as projected into users.workspace_ids.
+pub async fn db_is_member(user_id: &String, workspace_id: &Uuid, pool: &PgPool) -> Result<bool, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let member = sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM users WHERE user_id = $1 AND $2 = ANY(workspace_ids)) AS "member!""#,
+        user_id,
+        workspace_id
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(member)
+}
+
+async fn db_list_workspace_ids(after: &String, limit: i64, pool: &PgPool) -> Result<Vec<(String, Option<Vec<Uuid>>)>, sqlx::Error> {
+    let mut tx = begin_service_tx(pool, "reconcile_workspaces").await?;
+    let rows = sqlx::query!(
+        "SELECT user_id, workspace_ids FROM users WHERE user_id > $1 ORDER BY user_id LIMIT $2",
+        after,
+        limit
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(rows.into_iter().map(|row| (row.user_id, row.workspace_ids)).collect())
+}
+
+async fn db_set_workspace_ids(user_id: &String, workspace_ids: &[Uuid], pool: &PgPool) -> Result<(), sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    sqlx::query!("UPDATE users SET workspace_ids = $1 WHERE user_id = $2", workspace_ids, user_id)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
@@ -1,2 +1,9 @@
+pub mod credits;
+pub mod deletion;
+pub mod export;
+pub mod membership;
+pub mod param;
+pub mod preferences;
 pub mod user;
-pub mod user_handler;
\ No newline at end of file
+pub mod user_handler;
+pub mod username;
\ No newline at end of file
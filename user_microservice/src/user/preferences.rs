@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::extract::{rejection::JsonRejection, Extension, Query};
+use axum::Json;
+use axum_macros::debug_handler;
+use openapi_rs::openapi_proc_macro::handler;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::types::chrono::NaiveDateTime;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use okapi::openapi3::RefOr;
+use openapi_rs::gen::OpenApiGenerator;
+use openapi_rs::OpenApiFromData;
+
+use microservice_utils::{
+    jwt::extractor::AuthToken,
+    server::preferences::{notification_key, NOTIFICATION_CHANNELS, NOTIFICATION_TYPES},
+    server::response::{into_reponse, AxumRes, AxumResult},
+    server::tenant::begin_tenant_tx,
+};
+
+use crate::user::membership::db_is_member;
+use crate::user::param::OptionalId;
+
+const DIGEST_FREQUENCIES: &[&str] = &["never", "daily", "weekly"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PreferenceType {
+    Bool,
+    Choice(&'static [&'static str]),
+    Locale,
+    Timezone,
+}
+
+/// A preference key. Stored values of another `version` are ignored, so changing what a
+/// key means only takes a version bump.
+#[derive(Debug, Clone)]
+pub struct PreferenceDef {
+    pub key: String,
+    pub kind: PreferenceType,
+    pub version: i32,
+    pub default: Value,
+    pub workspace_override: bool, // whether a workspace can have its own value
+}
+
+lazy_static! {
+    pub static ref PREFERENCES: Vec<PreferenceDef> = {
+        let mut defs = vec![
+            PreferenceDef {
+                key: "locale".to_string(),
+                kind: PreferenceType::Locale,
+                version: 1,
+                default: Value::from("en-US"),
+                workspace_override: false,
+            },
+            PreferenceDef {
+                key: "timezone".to_string(),
+                kind: PreferenceType::Timezone,
+                version: 1,
+                default: Value::from("UTC"),
+                workspace_override: false,
+            },
+            PreferenceDef {
+                key: "digest_frequency".to_string(),
+                kind: PreferenceType::Choice(DIGEST_FREQUENCIES),
+                version: 1,
+                default: Value::from("weekly"),
+                workspace_override: true,
+            },
+        ];
+        for notification in NOTIFICATION_TYPES {
+            for channel in NOTIFICATION_CHANNELS {
+                // Texts cost money and interrupt, only security alerts and the invites the user
+                // sends themselves are texted by default
+                let default = *channel != "sms" || ["security", "invites"].contains(notification);
+                defs.push(PreferenceDef {
+                    key: notification_key(notification, channel),
+                    kind: PreferenceType::Bool,
+                    version: 1,
+                    default: Value::from(default),
+                    workspace_override: true,
+                });
+            }
+        }
+        defs
+    };
+}
+
+pub fn find_preference(key: &str) -> Option<&'static PreferenceDef> {
+    PREFERENCES.iter().find(|def| def.key == key)
+}
+
+impl PreferenceDef {
+    fn validate(&self, value: &Value) -> Result<(), String> {
+        let valid = match self.kind {
+            PreferenceType::Bool => value.is_boolean(),
+            PreferenceType::Choice(choices) => value.as_str().map_or(false, |v| choices.contains(&v)),
+            PreferenceType::Locale => value.as_str().map_or(false, is_locale),
+            PreferenceType::Timezone => value.as_str().map_or(false, |v| v.parse::<chrono_tz::Tz>().is_ok()),
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("Invalid value for {}: {}", self.key, value))
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self.kind {
+            PreferenceType::Bool => "bool",
+            PreferenceType::Choice(_) => "choice",
+            PreferenceType::Locale => "locale",
+            PreferenceType::Timezone => "timezone",
+        }
+    }
+}
+
+// Language with an optional region, e.g. "en" or "pt-BR".
+fn is_locale(value: &str) -> bool {
+    let mut parts = value.split('-');
+    let language = parts.next().unwrap_or_default();
+    let region = parts.next();
+    (2..=3).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_lowercase())
+        && region.map_or(true, |r| r.len() == 2 && r.chars().all(|c| c.is_ascii_uppercase()))
+        && parts.next().is_none()
+}
+
+#[derive(Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct UpdatePreferences {
+    pub workspace_id: Option<Uuid>,            // overrides of this workspace instead of the user's values
+    pub preferences: HashMap<String, Value>, // key -> value, null resets the key
+}
+
+#[derive(sqlx::FromRow, Debug, Clone, Serialize)]
+pub struct StoredPreference {
+    pub workspace_id: Uuid, // nil for the user's own values
+    pub key: String,
+    pub value: String, // JSON
+    pub version: i32,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ResolvedPreference {
+    pub key: String,
+    pub value: Value,
+    pub source: &'static str, // default, user or workspace
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub choices: Option<&'static [&'static str]>,
+    pub default: Value,
+    pub version: i32,
+    pub workspace_override: bool,
+}
+
+/// Resolves every preference of `keys` (all of them when empty): the workspace's value,
+/// then the user's, then the default.
+pub async fn resolve_preferences(
+    user_id: &String,
+    workspace_id: Option<&Uuid>,
+    keys: &[String],
+    pool: &PgPool,
+) -> Result<Vec<ResolvedPreference>, sqlx::Error> {
+    let stored = db_list_preferences(user_id, workspace_id, pool).await?;
+    let defs: Vec<&PreferenceDef> = PREFERENCES
+        .iter()
+        .filter(|def| keys.is_empty() || keys.contains(&def.key))
+        .collect();
+
+    Ok(defs
+        .into_iter()
+        .map(|def| {
+            let value_of = |scope: &Uuid| {
+                stored
+                    .iter()
+                    .find(|p| p.key == def.key && p.workspace_id == *scope && p.version == def.version)
+                    .and_then(|p| serde_json::from_str::<Value>(&p.value).ok())
+                    .filter(|value| def.validate(value).is_ok())
+            };
+            let workspace_value = workspace_id.filter(|_| def.workspace_override).and_then(value_of);
+            let (value, source) = match (workspace_value, value_of(&Uuid::nil())) {
+                (Some(value), _) => (value, "workspace"),
+                (None, Some(value)) => (value, "user"),
+                (None, None) => (def.default.clone(), "default"),
+            };
+            ResolvedPreference {
+                key: def.key.clone(),
+                value,
+                source,
+                kind: def.type_name(),
+                choices: match def.kind {
+                    PreferenceType::Choice(choices) => Some(choices),
+                    _ => None,
+                },
+                default: def.default.clone(),
+                version: def.version,
+                workspace_override: def.workspace_override,
+            }
+        })
+        .collect())
+}
+
+/// Same as `microservice_utils::server::preferences::notification_enabled`, for this service.
+pub async fn notification_enabled(
+    user_id: &String,
+    notification: &str,
+    channel: &str,
+    pool: &PgPool,
+) -> Result<bool, sqlx::Error> {
+    let key = notification_key(notification, channel);
+    let preferences = resolve_preferences(user_id, None, &[key], pool).await?;
+    Ok(preferences.first().and_then(|p| p.value.as_bool()).unwrap_or(true))
+}
+
+// API
+/// Every preference with where its value comes from, `id` being a workspace whose
+/// overrides apply.
+#[debug_handler]
+#[handler(method = "GET",tag = "user")]
+pub async fn get_preferences(
+    params: Query<OptionalId>,
+    AuthToken(user_id): AuthToken,
+    Extension(pool): Extension<Arc<PgPool>>
+) -> AxumResult<Json<AxumRes>> {
+    if let Some(workspace_id) = &params.id {
+        check_member(&user_id, workspace_id, &pool).await?;
+    }
+
+    match resolve_preferences(&user_id, params.id.as_ref(), &[], &pool).await {
+        Ok(preferences) => {
+            Ok(axum::Json(AxumRes{code:200, result: serde_json::json!(&preferences)}))
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(500, ret))
+        }
+    }
+}
+
+#[debug_handler]
+#[handler(method = "PUT",tag = "user")]
+pub async fn update_preferences(
+    payload: Result<Json<UpdatePreferences>, JsonRejection>,
+    AuthToken(user_id): AuthToken,
+    Extension(pool): Extension<Arc<PgPool>>
+) -> AxumResult<Json<AxumRes>> {
+    let update = match payload {
+        Ok(payload) => payload.0,
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            return Err(into_reponse(400, ret));
+        }
+    };
+    if let Some(workspace_id) = &update.workspace_id {
+        check_member(&user_id, workspace_id, &pool).await?;
+    }
+
+    // Nothing is stored unless every value is valid
+    let mut changes = Vec::new();
+    for (key, value) in &update.preferences {
+        let def = match find_preference(key) {
+            Some(def) => def,
+            None => {
+                let ret = serde_json::json!({
+                    "error": format!("Unknown preference {}", key),
+                });
+                return Err(into_reponse(400, ret));
+            }
+        };
+        if update.workspace_id.is_some() && !def.workspace_override {
+            let ret = serde_json::json!({
+                "error": format!("{} can't be set per workspace", key),
+            });
+            return Err(into_reponse(400, ret));
+        }
+        if !value.is_null() {
+            def.validate(value).map_err(|e| into_reponse(400, serde_json::json!({ "error": e })))?;
+        }
+        changes.push((def, value));
+    }
+
+    let workspace_id = update.workspace_id.unwrap_or_else(Uuid::nil);
+    let updated = async {
+        db_set_preferences(&user_id, &workspace_id, &changes, &pool).await?;
+        resolve_preferences(&user_id, update.workspace_id.as_ref(), &[], &pool).await
+    }.await;
+    match updated {
+        Ok(preferences) => {
+            Ok(axum::Json(AxumRes{code:200, result: serde_json::json!(&preferences)}))
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(500, ret))
+        }
+    }
+}
+
+async fn check_member(user_id: &String, workspace_id: &Uuid, pool: &PgPool) -> AxumResult<()> {
+    match db_is_member(user_id, workspace_id, pool).await {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            let ret = serde_json::json!({
+                "error": "Workspace not found",
+            });
+            Err(into_reponse(404, ret))
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(500, ret))
+        }
+    }
+}
+
+// Database
+// The user's own values, and the overrides of `workspace_id`.
+pub async fn db_list_preferences(
+    user_id: &String,
+    workspace_id: Option<&Uuid>,
+    pool: &PgPool,
+) -> Result<Vec<StoredPreference>, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let preferences = sqlx::query_as!(
+        StoredPreference,
+        "SELECT workspace_id, key, value, version, updated_at FROM user_preferences
+        WHERE user_id = $1 AND workspace_id IN ($2, $3) ORDER BY workspace_id, key",
+        user_id,
+        Uuid::nil(),
+        workspace_id.copied().unwrap_or_else(Uuid::nil)
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(preferences)
+}
+
+pub(crate) async fn db_set_preferences(
+    user_id: &String,
+    workspace_id: &Uuid,
+    changes: &[(&PreferenceDef, &Value)],
+    pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    for (def, value) in changes {
+        if value.is_null() {
+            sqlx::query!(
+                "DELETE FROM user_preferences WHERE user_id = $1 AND workspace_id = $2 AND key = $3",
+                user_id,
+                workspace_id,
+                &def.key
+            )
+            .execute(&mut tx)
+            .await?;
+        } else {
+            sqlx::query!(
+                "INSERT INTO user_preferences (user_id, workspace_id, key, value, version) VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (user_id, workspace_id, key) DO UPDATE SET value = $4, version = $5, updated_at = CURRENT_TIMESTAMP",
+                user_id,
+                workspace_id,
+                &def.key,
+                value.to_string(),
+                def.version
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn default_of(notification: &str, channel: &str) -> Value {
+        find_preference(&notification_key(notification, channel)).unwrap().default.clone()
+    }
+
+    #[test]
+    fn only_security_alerts_and_invites_are_texted_by_default() {
+        assert_eq!(default_of("security", "sms"), Value::from(true));
+        assert_eq!(default_of("invites", "sms"), Value::from(true));
+        assert_eq!(default_of("uploads", "sms"), Value::from(false));
+        assert_eq!(default_of("workspace", "sms"), Value::from(false));
+        assert_eq!(default_of("uploads", "in_app"), Value::from(true));
+        assert_eq!(default_of("invites", "email"), Value::from(true));
+    }
+}
@@ -20,14 +20,14 @@ pub struct UpdateUser {
     pub first_name: Option<String>,        // first name
     pub last_name: Option<String>,         // last name
     pub username: Option<String>,          // username
-    pub email: Option<String>,             // email
+    pub email: Option<String>,             // ignored, follows the verified login of auth_service
     pub dob: Option<NaiveDateTime>,        // birthday
-    pub two_fator: Option<bool>,           // two factor auth
+    pub two_fator: Option<bool>,           // ignored, follows the TOTP of auth_service
     pub picture: Option<String>,           // profile picture
     pub gender: Option<String>,            // gender
     pub bio: Option<String>,               // bio
     pub user_account_type: Option<String>, // admin, member, guest
-    pub phone_number: Option<String>,      // phone number
+    pub phone_number: Option<String>,      // ignored, follows the verified login of auth_service
     pub latitude: Option<f32>,             // last known location coordinates
     pub longitude: Option<f32>,
     pub last_login_ip: Option<String>, // last login IP
@@ -58,6 +58,7 @@ pub struct User {
     pub latitude: Option<f32>,             // last known location coordinates
     pub longitude: Option<f32>,
     pub last_login_ip: Option<String>, // last login IP
+    pub created_at: NaiveDateTime,     // sign up date
 }
 
 impl Default for UpdateUser {
@@ -109,6 +110,7 @@ impl Default for User {
             latitude: Some(0.0),
             longitude: Some(0.0),
             last_login_ip: Some(String::new()),
+            created_at: NaiveDate::from_ymd(2016, 7, 8).and_hms(9, 10, 11),
         }
     }
 }
@@ -1,29 +1,53 @@
 use std::sync::Arc;
 use openapi_rs::openapi_proc_macro::handler;
-use uuid::Uuid;
-use sqlx::PgPool;
-use sqlx::types::chrono::NaiveDateTime;
+use sqlx::{PgConnection, PgPool};
 use axum::extract::Extension;
 use axum::{extract::{rejection::JsonRejection}, Json};
 use axum_macros::debug_handler;
 use tonic::async_trait;
 use sqlx::types::chrono::Utc;
-use std::fmt::Write;
+use uuid::Uuid;
 
 use okapi::openapi3::RefOr;
 use openapi_rs::gen::OpenApiGenerator;
 use openapi_rs::OpenApiFromData;
 
 use crate::user_service::user_service_server::UserService;
-use crate::user_service::{AddWorkspaceRequest, AddWorkspaceResponse, RemoveWorkspaceRequest, RemoveWorkspaceResponse};
+use crate::user_service::{
+    BatchGetUsersRequest, BatchGetUsersResponse, ChargeCreditsRequest, ChargeCreditsResponse,
+    GetPreferencesRequest, GetPreferencesResponse, GetUserRequest, GetUserResponse, Preference,
+    UserProfile,
+};
 
+use crate::user::credits::{charge_credits, grant_referral};
+use crate::user::deletion::schedule_deletion;
+use crate::user::preferences::{find_preference, resolve_preferences};
+use crate::user::username::{check_username, db_change_username, UsernameError};
 use crate::user::user::{
     CreateUser,
     UpdateUser,
     User,
 };
+use microservice_utils::server::service_auth::{
+    authorize, ServiceIdentity, AI_STUDIO_SERVICE, ALL_SERVICES, AUTH_SERVICE, INVITE_SERVICE,
+};
+use microservice_utils::server::tenant::{begin_service_tx, begin_tenant_tx};
 use microservice_utils::{jwt::{extractor::AuthToken}, server::response::{into_reponse,AxumResult,AxumRes}};
 
+const MAX_BATCH_USERS: usize = 100;
+
+// Fields a field mask can ask for, the first four are returned when there is no mask.
+const PROFILE_FIELDS: &[&str] = &["first_name", "last_name", "username", "picture", "bio", "email", "phone_number"];
+const DEFAULT_PROFILE_FIELDS: &[&str] = &["first_name", "last_name", "username", "picture"];
+
+// Fields holding contact details, with the services allowed to ask for them: auth_service
+// emails security alerts, invite_microservice matches invites to the email or phone they
+// were sent to.
+const PII_FIELDS: &[(&str, &[&str])] = &[
+    ("email", &[AUTH_SERVICE, INVITE_SERVICE]),
+    ("phone_number", &[INVITE_SERVICE]),
+];
+
 // gRPC
 pub struct MyUserService {
     pool: PgPool
@@ -39,35 +63,182 @@ impl MyUserService {
 
 #[async_trait]
 impl UserService for MyUserService {
-    async fn add_workspace_id(
+    async fn get_user(
         &self,
-        request: tonic::Request<AddWorkspaceRequest>,
-    ) -> Result<tonic::Response<AddWorkspaceResponse>, tonic::Status> {
+        request: tonic::Request<GetUserRequest>,
+    ) -> Result<tonic::Response<GetUserResponse>, tonic::Status> {
+
+        authorize(&request, ALL_SERVICES)?;
+        let caller = caller(&request);
+
+        let req: GetUserRequest = request.into_inner();
+        println!("Get User {}", req.user_id);
+
+        let fields = profile_fields(&req.field_mask, &caller)?;
+        match db_get_user(&req.user_id, &self.pool).await {
+            Ok(user) => {
+                Ok(tonic::Response::new(GetUserResponse {
+                    status: "success".to_string(),
+                    user: Some(to_profile(&user, &fields)),
+                }))
+            }
+            Err(sqlx::Error::RowNotFound) => {
+                Err(tonic::Status::not_found("User not found"))
+            }
+            Err(e) => {
+                Err(tonic::Status::internal(format!("{:?}", e)))
+            }
+        }
+    }
 
-        let req: AddWorkspaceRequest = request.into_inner();
-        println!("Adding workspace {:?}", req);
+    async fn batch_get_users(
+        &self,
+        request: tonic::Request<BatchGetUsersRequest>,
+    ) -> Result<tonic::Response<BatchGetUsersResponse>, tonic::Status> {
 
-        let _ = add_workspace_id(&req.user_id, &req.workspace_id, &self.pool).await;
+        authorize(&request, ALL_SERVICES)?;
+        let caller = caller(&request);
 
-        Ok(tonic::Response::new(AddWorkspaceResponse {
-            status: "Ok".to_string(),
-        }))
+        let req: BatchGetUsersRequest = request.into_inner();
+        println!("Batch Get {} Users", req.user_ids.len());
+
+        if req.user_ids.len() > MAX_BATCH_USERS {
+            return Err(tonic::Status::invalid_argument(format!("At most {} users can be requested at once", MAX_BATCH_USERS)));
+        }
+        let fields = profile_fields(&req.field_mask, &caller)?;
+        match db_batch_get_users(&req.user_ids, &self.pool).await {
+            Ok(users) => {
+                Ok(tonic::Response::new(BatchGetUsersResponse {
+                    status: "success".to_string(),
+                    users: users.iter().map(|user| to_profile(user, &fields)).collect(),
+                }))
+            }
+            Err(e) => {
+                Err(tonic::Status::internal(format!("{:?}", e)))
+            }
+        }
+    }
+
+    async fn charge_credits(
+        &self,
+        request: tonic::Request<ChargeCreditsRequest>,
+    ) -> Result<tonic::Response<ChargeCreditsResponse>, tonic::Status> {
+
+        authorize(&request, &[AI_STUDIO_SERVICE])?;
+
+        let req: ChargeCreditsRequest = request.into_inner();
+        println!("Charge {} Credits {} for {}", req.user_id, req.amount, req.reference);
+
+        let workspace_id = if req.workspace_id.is_empty() {
+            None
+        } else {
+            Some(Uuid::parse_str(&req.workspace_id).map_err(|e| tonic::Status::invalid_argument(e.to_string()))?)
+        };
+        match charge_credits(&req.user_id, workspace_id, req.amount, &req.reference, &req.description, &self.pool).await {
+            Ok(Some(account)) => {
+                Ok(tonic::Response::new(ChargeCreditsResponse {
+                    status: "success".to_string(),
+                    account,
+                }))
+            }
+            Ok(None) => {
+                Ok(tonic::Response::new(ChargeCreditsResponse {
+                    status: "duplicate".to_string(),
+                    account: "".to_string(),
+                }))
+            }
+            Err(e) => {
+                Err(tonic::Status::internal(format!("{:?}", e)))
+            }
+        }
     }
 
-    async fn remove_workspace_id(
+    async fn get_preferences(
         &self,
-        request: tonic::Request<RemoveWorkspaceRequest>,
-    ) -> Result<tonic::Response<RemoveWorkspaceResponse>, tonic::Status> {
+        request: tonic::Request<GetPreferencesRequest>,
+    ) -> Result<tonic::Response<GetPreferencesResponse>, tonic::Status> {
 
-        let req: RemoveWorkspaceRequest = request.into_inner();
-        println!("Removing workspace {:?}", req);
+        authorize(&request, ALL_SERVICES)?;
 
-        let _ = remove_workspace_id(&req.user_id, &req.workspace_id, &self.pool).await;
+        let req: GetPreferencesRequest = request.into_inner();
+        println!("Get Preferences {}", req.user_id);
 
-        Ok(tonic::Response::new(RemoveWorkspaceResponse {
-            status: "Ok".to_string(),
-        }))
+        if let Some(key) = req.keys.iter().find(|key| find_preference(key).is_none()) {
+            return Err(tonic::Status::invalid_argument(format!("Unknown preference {}", key)));
+        }
+        let workspace_id = if req.workspace_id.is_empty() {
+            None
+        } else {
+            Some(Uuid::parse_str(&req.workspace_id).map_err(|e| tonic::Status::invalid_argument(e.to_string()))?)
+        };
+        match resolve_preferences(&req.user_id, workspace_id.as_ref(), &req.keys, &self.pool).await {
+            Ok(preferences) => {
+                Ok(tonic::Response::new(GetPreferencesResponse {
+                    status: "success".to_string(),
+                    preferences: preferences
+                        .into_iter()
+                        .map(|p| Preference { key: p.key, value: p.value.to_string() })
+                        .collect(),
+                }))
+            }
+            Err(e) => {
+                Err(tonic::Status::internal(format!("{:?}", e)))
+            }
+        }
+    }
+}
+
+// Service making the call, as checked by `authorize`.
+fn caller<T>(request: &tonic::Request<T>) -> String {
+    request
+        .extensions()
+        .get::<ServiceIdentity>()
+        .map(|ServiceIdentity(caller)| caller.clone())
+        .unwrap_or_default()
+}
+
+fn profile_fields(mask: &Option<prost_types::FieldMask>, caller: &str) -> Result<Vec<&'static str>, tonic::Status> {
+    let paths = match mask {
+        Some(mask) if !mask.paths.is_empty() => &mask.paths,
+        _ => return Ok(DEFAULT_PROFILE_FIELDS.to_vec()),
+    };
+    paths
+        .iter()
+        .map(|path| {
+            let field = PROFILE_FIELDS
+                .iter()
+                .find(|field| **field == path.as_str())
+                .copied()
+                .ok_or_else(|| tonic::Status::invalid_argument(format!("Unknown field {}", path)))?;
+            match PII_FIELDS.iter().find(|(pii, _)| *pii == field) {
+                Some((_, allowed)) if !allowed.contains(&caller) => Err(tonic::Status::permission_denied(format!(
+                    "{} is not allowed to read {}",
+                    caller, field
+                ))),
+                _ => Ok(field),
+            }
+        })
+        .collect()
+}
+
+fn to_profile(user: &User, fields: &[&str]) -> UserProfile {
+    let mut profile = UserProfile {
+        user_id: user.user_id.clone(),
+        ..Default::default()
+    };
+    for field in fields {
+        match *field {
+            "first_name" => profile.first_name = user.first_name.clone(),
+            "last_name" => profile.last_name = user.last_name.clone(),
+            "username" => profile.username = user.username.clone().unwrap_or_default(),
+            "picture" => profile.picture = user.picture.clone().unwrap_or_default(),
+            "bio" => profile.bio = user.bio.clone().unwrap_or_default(),
+            "email" => profile.email = user.email.clone(),
+            "phone_number" => profile.phone_number = user.phone_number.clone(),
+            _ => {}
+        }
     }
+    profile
 }
 
 // API
@@ -85,6 +256,10 @@ pub async fn create_user(
             let db_user = db_create_user(&user_id, &user_info, &pool).await;
             match db_user {
                 Ok(result) => {
+                    // An invite verified before signing up is rewarded now
+                    if let Err(e) = grant_referral(&user_id, &pool).await {
+                        println!("{:?}", e.to_string());
+                    }
                     Ok(axum::Json(AxumRes{code:200, result: serde_json::json!(&result)}))
                 }
                 Err(e) => {
@@ -117,17 +292,16 @@ pub async fn update_user(
         Ok(payload) => {
             let user_info = payload.0;
 
-            let db_user = db_update_user(&user_id, &user_info, &pool).await;
-            match db_user {
+            match update_profile(&user_id, &user_info, &pool).await {
                 Ok(result) => {
                     Ok(axum::Json(AxumRes{code:200, result: serde_json::json!(&result)}))
                 }
                 Err(e) => {
-                    println!("{:?}", e.to_string());
+                    println!("{:?}", e);
                     let ret = serde_json::json!({
-                        "error": format!("{:?}", e),
-                    });    
-                    Err(into_reponse(500, ret))                     
+                        "error": e.message(),
+                    });
+                    Err(into_reponse(e.status(), ret))
                 }
             }
         }
@@ -162,28 +336,31 @@ pub async fn get_user(
     }
 }
 
+// Deleting an account erases it from every service, after a grace period it can be cancelled in.
+// Owned workspaces are transferred, POST /api/user/deletion lets the user choose.
 #[debug_handler]
 #[handler(method = "DELETE",tag = "user")]
 pub async fn delete_user(
     AuthToken(user_id): AuthToken,
     Extension(pool): Extension<Arc<PgPool>>
 ) -> AxumResult<Json<AxumRes>> {
-    let user = db_delete_user(&user_id, &pool).await;
-    match user {
-        Ok(_) => {
-            let ret = serde_json::json!({
-                "status": "success",
-            });  
-            Ok(axum::Json(AxumRes{code:200, result: serde_json::json!(&ret)}))
-        }
-        Err(e) => {
-            println!("{:?}", e.to_string());
-            let ret = serde_json::json!({
-                "error": format!("{:?}", e),
-            });    
-            Err(into_reponse(500, ret))
-        }
+    schedule_deletion(&user_id, &"transfer".to_string(), &pool).await
+}
+
+/// Applies a profile update, username included, in one transaction: a refused username leaves
+/// the rest of the profile unchanged. Usernames go through the same policy as PUT /api/user/username.
+pub async fn update_profile(user_id: &String, user: &UpdateUser, pool: &PgPool) -> Result<User, UsernameError> {
+    let username = match &user.username {
+        Some(username) => Some(check_username(user_id, username, pool).await?),
+        None => None,
+    };
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    if let Some(username) = &username {
+        db_change_username(user_id, username, &mut tx).await?;
     }
+    let user = db_update_user(user_id, user, &mut tx).await?;
+    tx.commit().await?;
+    Ok(user)
 }
 
 // Database
@@ -199,126 +376,241 @@ pub async fn db_create_user(user_id: &String, user: &CreateUser, pool: &PgPool)
         phone_number = _phone_number.to_string();
     }
 
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
     let user = sqlx::query_as!(User,
         "INSERT INTO users (user_id, first_name, last_name, email, phone_number) 
-        VALUES ($1, $2, $3, $4, $5) ON CONFLICT (user_id) DO UPDATE SET first_name = $2, last_name = $3, email = $4, phone_number = $5 RETURNING *",
+        VALUES ($1, $2, $3, $4, $5) ON CONFLICT (user_id) DO UPDATE SET first_name = $2, last_name = $3 RETURNING *",
         user_id,
         user.first_name,
         user.last_name,
         email,
         phone_number,
-    ).fetch_one(pool).await?;    
+    ).fetch_one(&mut tx).await?;    
+    tx.commit().await?;
     Ok(user)
 }
 
-pub async fn db_update_user(user_id: &String, user: &UpdateUser, pool: &PgPool) -> Result<User, sqlx::Error> {
-    let mut query = "UPDATE users SET ".to_string();
-    write!(query, "last_at = '{:?}' ,", Utc::now().naive_utc() as NaiveDateTime).unwrap();
-
-    if let Some(first_name) = &user.first_name {
-        write!(query, "first_name = '{}' ,", first_name).unwrap();
-    }
-
-    if let Some(last_name) = &user.last_name {
-        write!(query, "last_name = '{}' ,", last_name).unwrap();
-    }
-
-    if let Some(username) = &user.username {
-        write!(query, "username = '{}' ,", username).unwrap();
-    }
-
-    if let Some(email) = &user.email {
-        write!(query, "email = '{}' ,", email).unwrap();
-    }
+// two_fator is left out, auth_service owns it (see `db_set_two_factor`).
+// So are email and phone_number: they only change by signing in with a new verified address.
+pub async fn db_update_user(user_id: &String, user: &UpdateUser, tx: &mut PgConnection) -> Result<User, sqlx::Error> {
+    let out_user = sqlx::query_as::<_, User>(
+        "UPDATE users SET last_at = $2,
+            first_name = COALESCE($3, first_name),
+            last_name = COALESCE($4, last_name),
+            dob = COALESCE($5, dob),
+            picture = COALESCE($6, picture),
+            gender = COALESCE($7, gender),
+            bio = COALESCE($8, bio),
+            user_account_type = COALESCE($9, user_account_type),
+            latitude = COALESCE($10, latitude),
+            longitude = COALESCE($11, longitude),
+            last_login_ip = COALESCE($12, last_login_ip)
+        WHERE user_id = $1 RETURNING *")
+        .bind(user_id)
+        .bind(Utc::now().naive_utc())
+        .bind(&user.first_name)
+        .bind(&user.last_name)
+        .bind(user.dob)
+        .bind(&user.picture)
+        .bind(&user.gender)
+        .bind(&user.bio)
+        .bind(&user.user_account_type)
+        .bind(user.latitude)
+        .bind(user.longitude)
+        .bind(&user.last_login_ip)
+        .fetch_one(&mut *tx)
+        .await?;
+    Ok(out_user)
+}
 
-    if let Some(dob) = &user.dob {
-        write!(query, "dob = '{}' ,", dob).unwrap();
-    }
+// Projection of TwoFactorChanged.
+pub async fn db_set_two_factor(user_id: &String, enabled: bool, pool: &PgPool) -> Result<(), sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    sqlx::query("UPDATE users SET two_fator = $2 WHERE user_id = $1")
+        .bind(user_id)
+        .bind(enabled)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
 
-    if let Some(two_fator) = &user.two_fator {
-        write!(query, "two_fator = '{}' ,", two_fator).unwrap();
-    }
+pub async fn db_get_user(user_id: &String, pool: &PgPool) -> Result<User, sqlx::Error> {
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let user = sqlx::query_as!(User, r#"SELECT * FROM users WHERE user_id = $1"#, user_id).fetch_one(&mut tx).await?;   
+    tx.commit().await?;
+    Ok(user)
+}
 
-    if let Some(picture) = &user.picture {
-        write!(query, "picture = '{}' ,", picture).unwrap();
-    }
+pub async fn db_batch_get_users(user_ids: &[String], pool: &PgPool) -> Result<Vec<User>, sqlx::Error> {
+    let mut tx = begin_service_tx(pool, "batch_get_users").await?;
+    let users = sqlx::query_as::<_, User>("SELECT * FROM users WHERE user_id = ANY($1)")
+        .bind(user_ids)
+        .fetch_all(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(users)
+}
 
-    if let Some(gender) = &user.gender {
-        write!(query, "gender = '{}' ,", gender).unwrap();
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::{create_test_user, tenant_test_tx, test_handle, test_pool, test_user_id};
+    use microservice_utils::server::service_auth::WORKSPACE_SERVICE;
 
-    if let Some(bio) = &user.bio {
-        write!(query, "bio = '{}' ,", bio).unwrap();
+    fn mask(fields: &[&str]) -> Option<prost_types::FieldMask> {
+        Some(prost_types::FieldMask {
+            paths: fields.iter().map(|f| f.to_string()).collect(),
+        })
     }
 
-    if let Some(user_account_type) = &user.user_account_type {
-        write!(query, "user_account_type = '{}' ,", user_account_type).unwrap();
+    #[test]
+    fn contact_details_are_only_returned_to_the_services_needing_them() {
+        assert_eq!(
+            profile_fields(&mask(&["email", "phone_number"]), INVITE_SERVICE).unwrap(),
+            vec!["email", "phone_number"]
+        );
+        assert_eq!(profile_fields(&mask(&["email"]), AUTH_SERVICE).unwrap(), vec!["email"]);
+
+        let refused = profile_fields(&mask(&["phone_number"]), AUTH_SERVICE).unwrap_err();
+        assert_eq!(refused.code(), tonic::Code::PermissionDenied);
+        let refused = profile_fields(&mask(&["first_name", "email"]), WORKSPACE_SERVICE).unwrap_err();
+        assert_eq!(refused.code(), tonic::Code::PermissionDenied);
     }
 
-    if let Some(phone_number) = &user.phone_number {
-        write!(query, "phone_number = '{}' ,", phone_number).unwrap();
+    #[test]
+    fn public_fields_are_returned_to_every_service() {
+        assert_eq!(profile_fields(&None, WORKSPACE_SERVICE).unwrap(), DEFAULT_PROFILE_FIELDS.to_vec());
+        assert_eq!(
+            profile_fields(&mask(&["first_name", "bio"]), AI_STUDIO_SERVICE).unwrap(),
+            vec!["first_name", "bio"]
+        );
+        let unknown = profile_fields(&mask(&["password"]), INVITE_SERVICE).unwrap_err();
+        assert_eq!(unknown.code(), tonic::Code::InvalidArgument);
     }
 
-    if let Some(latitude) = &user.latitude {
-        write!(query, "latitude = '{}' ,", latitude).unwrap();
+    fn update(two_fator: Option<bool>) -> UpdateUser {
+        UpdateUser {
+            first_name: None,
+            last_name: None,
+            username: None,
+            email: None,
+            dob: None,
+            two_fator,
+            picture: None,
+            gender: None,
+            bio: None,
+            user_account_type: None,
+            phone_number: None,
+            latitude: None,
+            longitude: None,
+            last_login_ip: None,
+        }
     }
 
-    if let Some(longitude) = &user.longitude {
-        write!(query, "longitude = '{}' ,", longitude).unwrap();
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn two_factor_follows_auth_service() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        create_test_user(&user_id, Utc::now().naive_utc(), &pool).await;
+
+        db_set_two_factor(&user_id, true, &pool).await.unwrap();
+        assert_eq!(db_get_user(&user_id, &pool).await.unwrap().two_fator, Some(true));
+        // the profile can't claim otherwise
+        update_profile(&user_id, &update(Some(false)), &pool).await.unwrap();
+        assert_eq!(db_get_user(&user_id, &pool).await.unwrap().two_fator, Some(true));
+        db_set_two_factor(&user_id, false, &pool).await.unwrap();
+        assert_eq!(db_get_user(&user_id, &pool).await.unwrap().two_fator, Some(false));
     }
 
-    if let Some(last_login_ip) = &user.last_login_ip {
-        write!(query, "last_login_ip = '{}' ,", last_login_ip).unwrap();
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn contact_details_cant_be_changed_through_the_profile() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        create_test_user(&user_id, Utc::now().naive_utc(), &pool).await;
+        let before = db_get_user(&user_id, &pool).await.unwrap();
+
+        let claimed = UpdateUser {
+            email: Some("someone-else@example.com".to_string()),
+            phone_number: Some("+15550100".to_string()),
+            first_name: Some("Renamed".to_string()),
+            ..update(None)
+        };
+        let user = update_profile(&user_id, &claimed, &pool).await.unwrap();
+        assert_eq!(user.first_name, "Renamed");
+        assert_eq!((user.email, user.phone_number), (before.email, before.phone_number));
     }
 
-    query.pop();
-
-    write!(query, "WHERE user_id = '{}' RETURNING *", user_id).unwrap();
-
-    let str_query: &str = &query[..];
-    let out_user = sqlx::query_as::<_, User>(str_query)
-        .fetch_one(pool)
-        .await?;
-    Ok(out_user)    
-}
-
-pub async fn db_get_user(user_id: &String, pool: &PgPool) -> Result<User, sqlx::Error> {
-    let user = sqlx::query_as!(User, r#"SELECT * FROM users WHERE user_id = $1"#, user_id).fetch_one(pool).await?;   
-    Ok(user)
-}
-
-pub async fn db_delete_user(user_id: &String, pool: &PgPool) -> Result<(), sqlx::Error> {
-    let _ = sqlx::query!("DELETE FROM users WHERE user_id = $1", user_id).execute(pool).await?;
-    Ok(())
-}
-
-pub async fn add_workspace_id(user_id: &String, workspace_id: &String, pool: &PgPool) -> Result<(), sqlx::Error> {
-    let row = sqlx::query!("SELECT workspace_ids FROM users WHERE user_id = $1", user_id).fetch_one(pool).await?;
-    let mut workspace_ids: Vec<Uuid> = Vec::new();
-    if row.workspace_ids.is_some() {
-        workspace_ids.extend(&row.workspace_ids.unwrap());
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn a_refused_username_leaves_the_profile_unchanged() {
+        let pool = test_pool().await;
+        let (holder, user_id) = (test_user_id(), test_user_id());
+        create_test_user(&holder, Utc::now().naive_utc(), &pool).await;
+        create_test_user(&user_id, Utc::now().naive_utc(), &pool).await;
+        let handle = test_handle(&holder);
+        update_profile(&holder, &UpdateUser { username: Some(handle.clone()), ..update(None) }, &pool).await.unwrap();
+
+        let taken = UpdateUser {
+            username: Some(handle.to_uppercase()),
+            first_name: Some("Renamed".to_string()),
+            ..update(None)
+        };
+        assert_eq!(update_profile(&user_id, &taken, &pool).await.unwrap_err(), UsernameError::Taken);
+        assert_eq!(db_get_user(&user_id, &pool).await.unwrap().first_name, "Test");
+
+        let free = UpdateUser {
+            username: Some(test_handle(&user_id)),
+            first_name: Some("Renamed".to_string()),
+            ..update(None)
+        };
+        let user = update_profile(&user_id, &free, &pool).await.unwrap();
+        assert_eq!((user.first_name.as_str(), user.username), ("Renamed", free.username));
     }
 
-    if workspace_ids.iter().any(|&ws| ws.to_string() == *workspace_id) == false {
-        workspace_ids.push(Uuid::parse_str(workspace_id).unwrap());
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn rows_of_other_tenants_are_out_of_reach() {
+        let pool = test_pool().await;
+        let (owner, other) = (test_user_id(), test_user_id());
+        create_test_user(&owner, Utc::now().naive_utc(), &pool).await;
+        create_test_user(&other, Utc::now().naive_utc(), &pool).await;
+        sqlx::query("INSERT INTO user_preferences (user_id, key, value, version) VALUES ($1, 'theme', '\"dark\"', 1)")
+            .bind(&owner)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO data_exports (user_id) VALUES ($1)").bind(&owner).execute(&pool).await.unwrap();
+
+        // Queries filtering on the owner rather than the caller
+        let mut tx = tenant_test_tx(&pool, &other, None).await;
+        for table in ["users", "user_preferences", "data_exports"] {
+            let (count,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) FROM {} WHERE user_id = $1", table))
+                .bind(&owner)
+                .fetch_one(&mut tx)
+                .await
+                .unwrap();
+            assert_eq!(count, 0, "{}", table);
+            let deleted = sqlx::query(&format!("DELETE FROM {} WHERE user_id = $1", table))
+                .bind(&owner)
+                .execute(&mut tx)
+                .await
+                .unwrap();
+            assert_eq!(deleted.rows_affected(), 0, "{}", table);
+        }
+        // And without any
+        let renamed = sqlx::query("UPDATE users SET first_name = 'Taken'").execute(&mut tx).await.unwrap();
+        assert_eq!(renamed.rows_affected(), 1);
+        assert!(sqlx::query("INSERT INTO user_preferences (user_id, key, value, version) VALUES ($1, 'locale', '\"fr\"', 1)")
+            .bind(&owner)
+            .execute(&mut tx)
+            .await
+            .is_err());
+        tx.rollback().await.unwrap();
+
+        let user = db_get_user(&owner, &pool).await.unwrap();
+        assert_eq!(user.first_name, "Test");
     }
-    
-    let _ = sqlx::query!("UPDATE users SET workspace_ids = $1 WHERE user_id = $2", &workspace_ids, user_id).execute(pool).await?;
-    Ok(())
 }
-
-pub async fn remove_workspace_id(user_id: &String, workspace_id: &String, pool: &PgPool) -> Result<(), sqlx::Error> {
-    let row = sqlx::query!("SELECT workspace_ids FROM users WHERE user_id = $1", user_id).fetch_one(pool).await?;
-    let mut workspace_ids: Vec<Uuid> = Vec::new();
-    if row.workspace_ids.is_some() {
-        workspace_ids.extend(&row.workspace_ids.unwrap());
-    }
-
-    let index = workspace_ids.iter().position(|ws| ws.to_string() == *workspace_id).unwrap();
-    if workspace_ids.get(index).is_none() == false {
-        workspace_ids.swap_remove(index);
-    }
-
-    let _ = sqlx::query!("UPDATE users SET workspace_ids = $1 WHERE user_id = $2", &workspace_ids, user_id).execute(pool).await?;
-    Ok(())
-}
\ No newline at end of file
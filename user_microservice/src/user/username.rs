@@ -0,0 +1,539 @@
+use std::sync::Arc;
+
+use axum::extract::{rejection::JsonRejection, Extension, Query};
+use axum::Json;
+use axum_macros::debug_handler;
+use openapi_rs::openapi_proc_macro::handler;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+
+use okapi::openapi3::RefOr;
+use openapi_rs::gen::OpenApiGenerator;
+use openapi_rs::OpenApiFromData;
+
+use microservice_utils::{
+    events::{event_producer, notify_user},
+    jwt::extractor::AuthToken,
+    server::response::{into_reponse, AxumRes, AxumResult},
+    server::sender::{sender_from_env, Channel, Message},
+    server::tenant::{begin_service_tx, begin_tenant_tx},
+};
+
+use crate::user::param::UsernameParam;
+use crate::user::user::User;
+
+const MIN_USERNAME_LENGTH: usize = 3;
+const MAX_USERNAME_LENGTH: usize = 30;
+
+// Handles that would be mistaken for the product, its routes or its staff.
+const RESERVED_USERNAMES: &[&str] = &[
+    "about", "account", "accounts", "admin", "administrator", "api", "app", "auth", "billing",
+    "bhuman", "blog", "credits", "dashboard", "dl", "docs", "download", "help", "home", "invite",
+    "login", "logout", "me", "moderator", "null", "official", "privacy", "root", "security",
+    "settings", "share", "signup", "staff", "support", "system", "terms", "undefined", "user",
+    "users", "workspace", "www",
+];
+
+// Handles containing one of these are refused, BANNED_USERNAME_WORDS adds to the list.
+const BANNED_WORDS: &[&str] = &["fuck", "shit", "nazi", "rape", "porn"];
+
+lazy_static! {
+    static ref EXTRA_BANNED_WORDS: Vec<String> = std::env::var("BANNED_USERNAME_WORDS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|word| word.trim().to_lowercase())
+        .filter(|word| !word.is_empty())
+        .collect();
+    // How long a previous handle keeps resolving to its user, and stays unavailable to others.
+    static ref USERNAME_REDIRECT_DAYS: i32 = std::env::var("USERNAME_REDIRECT_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30);
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UsernameError {
+    Invalid(String),
+    Reserved,
+    Taken,
+    Database(String),
+}
+
+impl UsernameError {
+    pub fn status(&self) -> u16 {
+        match self {
+            UsernameError::Invalid(_) | UsernameError::Reserved => 400,
+            UsernameError::Taken => 409,
+            UsernameError::Database(_) => 500,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            UsernameError::Invalid(reason) => reason.clone(),
+            UsernameError::Reserved => "This username is not allowed".to_string(),
+            UsernameError::Taken => "This username is taken".to_string(),
+            UsernameError::Database(e) => e.clone(),
+        }
+    }
+}
+
+impl From<sqlx::Error> for UsernameError {
+    fn from(e: sqlx::Error) -> Self {
+        UsernameError::Database(format!("{:?}", e))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct ChangeUsername {
+    pub username: String,
+}
+
+/// Handles are compared and stored lowercased, without surrounding spaces or a leading @.
+pub fn normalize_username(username: &str) -> String {
+    username.trim().trim_start_matches('@').to_lowercase()
+}
+
+/// Checks a normalized handle against the allowed characters and the word lists: letters,
+/// digits, `_` and `.`, starting with a letter or digit, without a trailing or doubled dot.
+pub fn validate_username(username: &str) -> Result<(), UsernameError> {
+    if username.len() < MIN_USERNAME_LENGTH || username.len() > MAX_USERNAME_LENGTH {
+        return Err(UsernameError::Invalid(format!(
+            "Usernames are {} to {} characters long",
+            MIN_USERNAME_LENGTH, MAX_USERNAME_LENGTH
+        )));
+    }
+    if !username.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '.') {
+        return Err(UsernameError::Invalid(
+            "Usernames only contain letters, digits, underscores and dots".to_string(),
+        ));
+    }
+    if !username.starts_with(|c: char| c.is_ascii_alphanumeric()) || username.ends_with('.') || username.contains("..") {
+        return Err(UsernameError::Invalid(
+            "Usernames start with a letter or digit and can't end with or repeat a dot".to_string(),
+        ));
+    }
+
+    // Separators don't get around the word lists
+    let bare: String = username.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+    if RESERVED_USERNAMES.contains(&username) || RESERVED_USERNAMES.contains(&bare.as_str()) {
+        return Err(UsernameError::Reserved);
+    }
+    let banned = BANNED_WORDS
+        .iter()
+        .map(|word| word.to_string())
+        .chain(EXTRA_BANNED_WORDS.iter().cloned())
+        .any(|word| bare.contains(&word));
+    if banned {
+        return Err(UsernameError::Reserved);
+    }
+    Ok(())
+}
+
+/// Normalizes and validates `username`, then checks nobody else holds it, now or as a
+/// previous handle that still redirects. Returns the normalized handle.
+pub async fn check_username(user_id: &String, username: &str, pool: &PgPool) -> Result<String, UsernameError> {
+    let username = normalize_username(username);
+    validate_username(&username)?;
+    if db_username_holder(&username, pool).await?.map_or(false, |holder| holder != *user_id) {
+        return Err(UsernameError::Taken);
+    }
+    Ok(username)
+}
+
+/// Gives `user_id` the handle `username`. The previous handle keeps redirecting for
+/// USERNAME_REDIRECT_DAYS.
+pub async fn change_username(user_id: &String, username: &str, pool: &PgPool) -> Result<User, UsernameError> {
+    let username = check_username(user_id, username, pool).await?;
+    let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+    let user = db_change_username(user_id, &username, &mut tx).await?;
+    tx.commit().await?;
+    Ok(user)
+}
+
+// Cleanup
+// Handles stored before the policy that don't pass it anymore are taken from their users, who are
+// told along with the ones schema.sql took a duplicate handle from.
+pub fn spawn_username_cleanup(pool: &PgPool) {
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        match db_release_invalid_usernames(&pool).await {
+            Ok(count) => println!("Released {} invalid usernames", count),
+            Err(e) => println!("{:?}", e.to_string()),
+        }
+        match notify_lost_usernames(&pool).await {
+            Ok(count) => println!("Told {} users about their lost username", count),
+            Err(e) => println!("{:?}", e.to_string()),
+        }
+    });
+}
+
+async fn notify_lost_usernames(pool: &PgPool) -> Result<usize, sqlx::Error> {
+    let lost = db_unnotified_lost_usernames(pool).await?;
+    let producer = event_producer();
+    let sender = sender_from_env();
+    for (username, user_id, reason, email) in &lost {
+        let message = serde_json::json!({
+            "username": username,
+            "reason": reason,
+        });
+        if let Err(e) = notify_user(user_id, "username_released", &message, &producer).await {
+            println!("Unable to notify {} of their username: {:?}", user_id, e);
+        }
+        if !email.is_empty() {
+            let why = if reason == "lost" {
+                "an older account already had the same one"
+            } else {
+                "it doesn't follow the username rules anymore"
+            };
+            let message = Message {
+                channel: Channel::Email,
+                to: email.clone(),
+                subject: "Choose a new username".to_string(),
+                body: format!(
+                    "Your BHuman username @{} was removed because {}. You can pick a new one in your profile settings.",
+                    username, why
+                ),
+            };
+            if let Err(e) = sender.send(&message).await {
+                println!("Unable to email {} about their username: {:?}", user_id, e);
+            }
+        }
+        db_set_lost_username_notified(username, user_id, pool).await?;
+    }
+    Ok(lost.len())
+}
+
+// API
+#[debug_handler]
+#[handler(method = "GET",tag = "user")]
+pub async fn check_username_available(
+    params: Query<UsernameParam>,
+    AuthToken(user_id): AuthToken,
+    Extension(pool): Extension<Arc<PgPool>>
+) -> AxumResult<Json<AxumRes>> {
+    let result = match check_username(&user_id, &params.username, &pool).await {
+        Ok(username) => serde_json::json!({
+            "username": username,
+            "available": true,
+        }),
+        Err(UsernameError::Database(e)) => {
+            println!("{:?}", e);
+            let ret = serde_json::json!({
+                "error": e,
+            });
+            return Err(into_reponse(500, ret));
+        }
+        Err(e) => serde_json::json!({
+            "username": normalize_username(&params.username),
+            "available": false,
+            "reason": e.message(),
+        }),
+    };
+    Ok(axum::Json(AxumRes{code:200, result}))
+}
+
+#[debug_handler]
+#[handler(method = "PUT",tag = "user")]
+pub async fn update_username(
+    payload: Result<Json<ChangeUsername>, JsonRejection>,
+    AuthToken(user_id): AuthToken,
+    Extension(pool): Extension<Arc<PgPool>>
+) -> AxumResult<Json<AxumRes>> {
+    match payload {
+        Ok(payload) => {
+            match change_username(&user_id, &payload.0.username, &pool).await {
+                Ok(user) => {
+                    Ok(axum::Json(AxumRes{code:200, result: serde_json::json!(&user)}))
+                }
+                Err(e) => {
+                    println!("{:?}", e);
+                    let ret = serde_json::json!({
+                        "error": e.message(),
+                    });
+                    Err(into_reponse(e.status(), ret))
+                }
+            }
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(400, ret))
+        }
+    }
+}
+
+/// Public profile of the user holding `username`. A previous handle still redirecting
+/// resolves too, with `redirected_from` set.
+#[debug_handler]
+#[handler(method = "GET",tag = "user")]
+pub async fn get_user_by_username(
+    params: Query<UsernameParam>,
+    AuthToken(_user_id): AuthToken,
+    Extension(pool): Extension<Arc<PgPool>>
+) -> AxumResult<Json<AxumRes>> {
+    let username = normalize_username(&params.username);
+    match db_find_by_username(&username, &pool).await {
+        Ok(Some((user, redirected))) => {
+            let result = serde_json::json!({
+                "user_id": user.user_id,
+                "username": user.username,
+                "first_name": user.first_name,
+                "last_name": user.last_name,
+                "picture": user.picture,
+                "bio": user.bio,
+                "redirected_from": if redirected { Some(username) } else { None },
+            });
+            Ok(axum::Json(AxumRes{code:200, result}))
+        }
+        Ok(None) => {
+            let ret = serde_json::json!({
+                "error": "User not found",
+            });
+            Err(into_reponse(404, ret))
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(500, ret))
+        }
+    }
+}
+
+// Database
+// The user holding the handle, or still redirecting from it. Looks across users, so it
+// runs as the service.
+async fn db_username_holder(username: &String, pool: &PgPool) -> Result<Option<String>, sqlx::Error> {
+    let mut tx = begin_service_tx(pool, "username_holder").await?;
+    let holder = sqlx::query_scalar!(
+        r#"SELECT user_id AS "user_id!" FROM users WHERE lower(username) = $1
+        UNION ALL
+        SELECT user_id FROM username_history WHERE username = $1 AND reason = 'changed' AND expires_at > CURRENT_TIMESTAMP
+        LIMIT 1"#,
+        username
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(holder)
+}
+
+// Runs in the caller's tenant transaction, so a profile update changing the username is all or nothing.
+pub async fn db_change_username(user_id: &String, username: &String, tx: &mut PgConnection) -> Result<User, UsernameError> {
+    let previous = sqlx::query_scalar!("SELECT username FROM users WHERE user_id = $1 FOR UPDATE", user_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+    let previous = match previous {
+        Some(previous) => previous.map(|p| normalize_username(&p)),
+        None => return Err(UsernameError::Database("User not found".to_string())),
+    };
+
+    if previous.as_ref() != Some(username) {
+        if let Some(previous) = &previous {
+            sqlx::query!(
+                "INSERT INTO username_history (username, user_id, expires_at)
+                VALUES ($1, $2, CURRENT_TIMESTAMP + make_interval(days => $3))
+                ON CONFLICT (username) WHERE reason = 'changed'
+                DO UPDATE SET user_id = $2, released_at = CURRENT_TIMESTAMP, expires_at = EXCLUDED.expires_at",
+                previous,
+                user_id,
+                *USERNAME_REDIRECT_DAYS
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        // Taking back one's own previous handle ends its redirect
+        sqlx::query!("DELETE FROM username_history WHERE username = $1 AND reason = 'changed'", username)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    // The unique index settles two users racing for the same handle
+    let user = sqlx::query_as!(User, "UPDATE users SET username = $2 WHERE user_id = $1 RETURNING *", user_id, username)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| match &e {
+            sqlx::Error::Database(db) if db.code().as_deref() == Some("23505") => UsernameError::Taken,
+            _ => UsernameError::from(e),
+        })?;
+    Ok(user)
+}
+
+// Takes away the stored handles failing `validate_username`, keeping them in the history.
+async fn db_release_invalid_usernames(pool: &PgPool) -> Result<usize, sqlx::Error> {
+    let mut tx = begin_service_tx(pool, "release_invalid_usernames").await?;
+    let held: Vec<(String, String)> = sqlx::query!(r#"SELECT user_id, username AS "username!" FROM users WHERE username IS NOT NULL"#)
+        .fetch_all(&mut tx)
+        .await?
+        .into_iter()
+        .map(|row| (row.user_id, row.username))
+        .collect();
+    let invalid: Vec<&(String, String)> = held
+        .iter()
+        .filter(|(_, username)| validate_username(username).is_err())
+        .collect();
+    for (user_id, username) in &invalid {
+        sqlx::query!(
+            "INSERT INTO username_history (username, user_id, reason, expires_at)
+            VALUES ($1, $2, 'invalid', CURRENT_TIMESTAMP) ON CONFLICT DO NOTHING",
+            username,
+            user_id
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!("UPDATE users SET username = NULL WHERE user_id = $1 AND username = $2", user_id, username)
+            .execute(&mut tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(invalid.len())
+}
+
+// (username, user_id, reason, email) of the handles taken from users who weren't told yet.
+async fn db_unnotified_lost_usernames(pool: &PgPool) -> Result<Vec<(String, String, String, String)>, sqlx::Error> {
+    let mut tx = begin_service_tx(pool, "notify_lost_usernames").await?;
+    let rows = sqlx::query!(
+        "SELECT h.username, h.user_id, h.reason, u.email FROM username_history h JOIN users u ON u.user_id = h.user_id
+        WHERE h.reason IN ('lost', 'invalid') AND h.notified_at IS NULL"
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(rows.into_iter().map(|row| (row.username, row.user_id, row.reason, row.email)).collect())
+}
+
+async fn db_set_lost_username_notified(username: &String, user_id: &String, pool: &PgPool) -> Result<(), sqlx::Error> {
+    let mut tx = begin_service_tx(pool, "notify_lost_usernames").await?;
+    sqlx::query!(
+        "UPDATE username_history SET notified_at = CURRENT_TIMESTAMP
+        WHERE username = $1 AND user_id = $2 AND reason IN ('lost', 'invalid')",
+        username,
+        user_id
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+// The user holding the handle, and whether it was found through a redirect.
+async fn db_find_by_username(username: &String, pool: &PgPool) -> Result<Option<(User, bool)>, sqlx::Error> {
+    let mut tx = begin_service_tx(pool, "find_by_username").await?;
+    let user = sqlx::query_as!(User, "SELECT * FROM users WHERE lower(username) = $1", username)
+        .fetch_optional(&mut tx)
+        .await?;
+    if let Some(user) = user {
+        tx.commit().await?;
+        return Ok(Some((user, false)));
+    }
+
+    let user = sqlx::query_as!(
+        User,
+        "SELECT u.* FROM username_history h JOIN users u ON u.user_id = h.user_id
+        WHERE h.username = $1 AND h.reason = 'changed' AND h.expires_at > CURRENT_TIMESTAMP",
+        username
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(user.map(|user| (user, true)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::{create_test_user, test_handle, test_pool, test_user_id};
+    use sqlx::types::chrono::Utc;
+
+    async fn set_username(user_id: &String, username: &str, pool: &PgPool) {
+        let mut tx = begin_service_tx(pool, "test").await.unwrap();
+        sqlx::query("UPDATE users SET username = $2 WHERE user_id = $1")
+            .bind(user_id)
+            .bind(username)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+    }
+
+    async fn history(user_id: &String, pool: &PgPool) -> Vec<(String, String)> {
+        let mut tx = begin_service_tx(pool, "test").await.unwrap();
+        let rows = sqlx::query_as("SELECT username, reason FROM username_history WHERE user_id = $1 ORDER BY released_at")
+            .bind(user_id)
+            .fetch_all(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        rows
+    }
+
+    #[test]
+    fn handles_follow_the_policy() {
+        assert_eq!(normalize_username("  @Jane.Doe "), "jane.doe");
+        assert!(validate_username("jane.doe").is_ok());
+        assert!(validate_username("jd").is_err());
+        assert!(validate_username("jane doe").is_err());
+        assert!(validate_username("jane..doe").is_err());
+        assert_eq!(validate_username("ad.min"), Err(UsernameError::Reserved));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn legacy_invalid_handles_are_released_and_told_once() {
+        let pool = test_pool().await;
+        let (user_id, valid) = (test_user_id(), test_user_id());
+        create_test_user(&user_id, Utc::now().naive_utc(), &pool).await;
+        create_test_user(&valid, Utc::now().naive_utc(), &pool).await;
+        let legacy = format!("{} x", test_handle(&user_id));
+        set_username(&user_id, &legacy, &pool).await;
+        set_username(&valid, &test_handle(&valid), &pool).await;
+
+        db_release_invalid_usernames(&pool).await.unwrap();
+        assert_eq!(history(&user_id, &pool).await, vec![(legacy.clone(), "invalid".to_string())]);
+        assert!(history(&valid, &pool).await.is_empty());
+        // the released handle doesn't redirect to its previous user
+        assert!(db_find_by_username(&legacy, &pool).await.unwrap().is_none());
+        assert_eq!(db_username_holder(&legacy, &pool).await.unwrap(), None);
+
+        let unnotified = db_unnotified_lost_usernames(&pool).await.unwrap();
+        assert!(unnotified.iter().any(|(username, id, _, _)| *username == legacy && *id == user_id));
+        db_set_lost_username_notified(&legacy, &user_id, &pool).await.unwrap();
+        let unnotified = db_unnotified_lost_usernames(&pool).await.unwrap();
+        assert!(!unnotified.iter().any(|(_, id, _, _)| *id == user_id));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn a_lost_handle_can_be_taken_while_changed_ones_redirect() {
+        let pool = test_pool().await;
+        let (loser, other) = (test_user_id(), test_user_id());
+        create_test_user(&loser, Utc::now().naive_utc(), &pool).await;
+        create_test_user(&other, Utc::now().naive_utc(), &pool).await;
+        let lost = test_handle(&loser);
+        let mut tx = begin_service_tx(&pool, "test").await.unwrap();
+        sqlx::query(
+            "INSERT INTO username_history (username, user_id, reason, expires_at) VALUES ($1, $2, 'lost', CURRENT_TIMESTAMP)")
+            .bind(&lost)
+            .bind(&loser)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        // the loser can take it back as a regular handle, then move on from it
+        change_username(&loser, &lost, &pool).await.unwrap();
+        change_username(&loser, &format!("{}b", lost), &pool).await.unwrap();
+        assert_eq!(check_username(&other, &lost, &pool).await, Err(UsernameError::Taken));
+        let (user, redirected) = db_find_by_username(&lost, &pool).await.unwrap().unwrap();
+        assert_eq!((user.user_id, redirected), (loser.clone(), true));
+        assert_eq!(
+            history(&loser, &pool).await,
+            vec![(lost.clone(), "lost".to_string()), (lost.clone(), "changed".to_string())]
+        );
+    }
+}
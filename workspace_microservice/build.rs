@@ -1,10 +1,14 @@
+use std::{env, path::PathBuf};
+
 fn main() {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
     let user = "./proto/user_service.proto";
     let auth = "./proto/auth_service.proto";
     let workspace = "./proto/workspace_service.proto";    
 
     tonic_build::configure()
         .build_server(true)
+        .file_descriptor_set_path(out_dir.join("workspace_microservice_descriptor.bin"))
         .compile(&[user, auth, workspace], &["."])
         .unwrap_or_else(|e| panic!("protobuf compile error: {}", e));
 }
\ No newline at end of file
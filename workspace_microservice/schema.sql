@@ -7,4 +7,52 @@ CREATE TABLE IF NOT EXISTS workspaces (
     role TEXT NOT NULL, 
     created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
     updated_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP
-);
\ No newline at end of file
+);
+
+-- Row-level security: rows are only visible to the tenant set by microservice_utils::server::tenant
+-- (app.current_user / app.current_workspace). Without a tenant in the transaction nothing is
+-- visible; internal paths that cross tenants use a service transaction (app.service_role).
+-- Superusers and BYPASSRLS roles skip policies entirely, so the service has to connect as a
+-- regular role for this to take effect.
+CREATE OR REPLACE FUNCTION app_current_user() RETURNS TEXT AS $$
+    SELECT NULLIF(current_setting('app.current_user', true), '')
+$$ LANGUAGE SQL STABLE;
+
+CREATE OR REPLACE FUNCTION app_current_workspace() RETURNS TEXT AS $$
+    SELECT NULLIF(current_setting('app.current_workspace', true), '')
+$$ LANGUAGE SQL STABLE;
+
+CREATE OR REPLACE FUNCTION app_service_role() RETURNS BOOLEAN AS $$
+    SELECT COALESCE(current_setting('app.service_role', true), '') <> ''
+$$ LANGUAGE SQL STABLE;
+
+ALTER TABLE workspaces ENABLE ROW LEVEL SECURITY;
+ALTER TABLE workspaces FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON workspaces;
+CREATE POLICY tenant_isolation ON workspaces
+    USING (app_service_role() OR (user_id = app_current_user() OR workspace_id::text = app_current_workspace()))
+    WITH CHECK (app_service_role() OR (user_id = app_current_user() OR workspace_id::text = app_current_workspace()));
+
+
+-- Per workspace settings, managed by the workspace owner.
+CREATE TABLE IF NOT EXISTS workspace_settings (
+    workspace_id uuid NOT NULL,
+    mfa_required BOOLEAN NOT NULL DEFAULT FALSE,
+    updated_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (workspace_id)
+);
+
+ALTER TABLE workspace_settings ENABLE ROW LEVEL SECURITY;
+ALTER TABLE workspace_settings FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON workspace_settings;
+CREATE POLICY tenant_isolation ON workspace_settings
+    USING (app_service_role() OR workspace_id::text = app_current_workspace()
+        OR EXISTS (SELECT 1 FROM workspaces w WHERE w.workspace_id = workspace_settings.workspace_id AND w.user_id = app_current_user()))
+    WITH CHECK (app_service_role() OR workspace_id::text = app_current_workspace());
+
+-- Events published after the change announcing them committed, see microservice_utils::events::enqueue.
+CREATE TABLE IF NOT EXISTS event_outbox (
+    id BIGSERIAL PRIMARY KEY,
+    payload TEXT NOT NULL,
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
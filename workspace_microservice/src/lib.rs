@@ -1,6 +1,6 @@
 use axum::{
     extract::Extension,
-    routing::{get, post},
+    routing::{get, post, put},
     Router,
 };
 use dotenv::dotenv;
@@ -15,22 +15,33 @@ use tower_http::{
 };
 
 pub mod producer;
+#[cfg(test)]
+mod test_db;
 pub mod workspace;
 
+use microservice_utils::server::audit::audit_impersonation;
+use microservice_utils::events::{event_producer, spawn_outbox_relay, start_event_consumer, Event};
+use microservice_utils::server::erasure::report_erasure;
 use microservice_utils::server::{error_404::error_404, spa::SpaRouter};
 
 use microservice_utils::open_api::gen::{generate_openapi_spec, GenSpec, Spec};
 use workspace::workspace_handler::{
-    add_to_workspace_spec, create_workspace_spec, delete_workspace_spec, get_workspace_spec,
-    remove_from_workspace_spec, update_workspace_spec,
+    add_to_workspace_spec, create_workspace_spec, delete_workspace_spec,
+    get_workspace_members_spec, get_workspace_spec, remove_from_workspace_spec,
+    set_workspace_mfa_spec, update_workspace_spec,
 };
 
 use crate::producer::producer::get_producer;
+use crate::workspace::workspace_export::MyDataExport;
 use crate::workspace::workspace_handler::{
-    add_to_workspace, create_workspace, delete_workspace, get_workspace, remove_from_workspace,
-    update_workspace, MyWorkspaceService,
+    add_to_workspace, create_workspace, db_merge_user_workspaces, delete_workspace,
+    erase_user_workspaces, get_workspace, get_workspace_members, remove_from_workspace,
+    set_workspace_mfa, update_workspace, MyWorkspaceService,
 };
+use microservice_utils::server::grpc::data_export::data_export_server::DataExportServer;
 use microservice_utils::server::hybrid::hybrid;
+use microservice_utils::server::grpc_support::{grpc_web, health_service, reflection_service};
+use microservice_utils::server::service_auth::{check_service_config, service_interceptor, WORKSPACE_SERVICE};
 
 pub mod workspace_service {
     tonic::include_proto!("workspace_service");
@@ -38,6 +49,9 @@ pub mod workspace_service {
 
 use workspace_service::workspace_service_server::WorkspaceServiceServer;
 
+pub(crate) const FILE_DESCRIPTOR_SET: &[u8] =
+    tonic::include_file_descriptor_set!("workspace_microservice_descriptor");
+
 #[macro_use]
 extern crate lazy_static;
 
@@ -52,18 +66,34 @@ lazy_static! {
 #[tokio::main]
 pub async fn main() {
     dotenv().expect("Failed to read .env file");
+    check_service_config().expect("Invalid service token config");
     lazy_static::initialize(&DATABASE_URL);
+    let reflection = reflection_service(&[FILE_DESCRIPTOR_SET]).expect("Invalid gRPC reflection descriptors");
 
     let pool = PgPool::connect(&std::env::var("DATABASE_URL").unwrap())
         .await
         .unwrap();
+    spawn_outbox_relay(&pool);
+    start_events(&pool);
     let axum_make_service = create_app(pool.clone());
 
     let grpc_service = tonic::transport::Server::builder()
-        .add_service(WorkspaceServiceServer::new(MyWorkspaceService::new(pool)))
+        .add_service(grpc_web(WorkspaceServiceServer::with_interceptor(
+            MyWorkspaceService::new(pool.clone()),
+            service_interceptor(WORKSPACE_SERVICE),
+        )))
+        .add_service(grpc_web(DataExportServer::with_interceptor(
+            MyDataExport::new(pool),
+            service_interceptor(WORKSPACE_SERVICE),
+        )))
+        .add_service(health_service::<WorkspaceServiceServer<MyWorkspaceService>>().await)
+        .add_service(reflection)
         .into_service();
 
-    let hybrid_make_service = hybrid(axum_make_service.into_make_service(), grpc_service);
+    let hybrid_make_service = hybrid(
+        axum_make_service.into_make_service_with_connect_info::<SocketAddr>(),
+        grpc_service,
+    );
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 4001));
     println!("Listening on http://{}", addr);
@@ -76,15 +106,41 @@ pub async fn main() {
 
 #[shuttle_service::main]
 async fn axum(pool: PgPool) -> shuttle_service::ShuttleAxum {
+    // refuse to start without a service signing key
+    check_service_config()?;
     pool.execute(include_str!("../schema.sql"))
         .await
         .map_err(CustomError::new)?;
 
+    spawn_outbox_relay(&pool);
+    start_events(&pool);
     let app = create_app(pool);
     let sync_wrapper = SyncWrapper::new(app);
     Ok(sync_wrapper)
 }
 
+fn start_events(pool: &PgPool) {
+    let pool = pool.clone();
+    let producer = event_producer();
+    start_event_consumer("workspace_service", move |event| {
+        let pool = pool.clone();
+        let producer = producer.clone();
+        async move {
+            match event {
+                Event::AccountMerged { source_user_id, target_user_id } => {
+                    db_merge_user_workspaces(&source_user_id, &target_user_id, &pool).await?;
+                }
+                Event::UserDeletionRequested { deletion_id, user_id, workspaces } => {
+                    let erasure = erase_user_workspaces(&user_id, &workspaces, &pool).await?;
+                    report_erasure(&deletion_id, &user_id, WORKSPACE_SERVICE, erasure, &producer).await?;
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+    });
+}
+
 fn create_app(pool: PgPool) -> Router {
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var(
@@ -118,6 +174,14 @@ fn create_app(pool: PgPool) -> Router {
             route: "/api/workspace_util".into(),
             gen: Box::new(remove_from_workspace_spec),
         },
+        Spec {
+            route: "/api/workspace/mfa".into(),
+            gen: Box::new(set_workspace_mfa_spec),
+        },
+        Spec {
+            route: "/api/workspace/members".into(),
+            gen: Box::new(get_workspace_members_spec),
+        },
     ];
 
     generate_openapi_spec(specs).expect("failed to generate openapi spec");
@@ -140,6 +204,7 @@ fn create_app(pool: PgPool) -> Router {
         )
         .layer(ConcurrencyLimitLayer::new(64))
         .layer(cors)
+        .layer(axum::middleware::from_fn(audit_impersonation))
         .into_inner();
 
     let app = Router::new()
@@ -155,6 +220,8 @@ fn create_app(pool: PgPool) -> Router {
             "/api/workspace_util",
             post(add_to_workspace).delete(remove_from_workspace),
         )
+        .route("/api/workspace/mfa", put(set_workspace_mfa))
+        .route("/api/workspace/members", get(get_workspace_members))
         .fallback(get(error_404))
         .layer(Extension(pool_arc))
         .layer(Extension(producer))
@@ -1,3 +1,4 @@
 pub mod workspace_type;
 pub mod workspace_handler;
+pub mod workspace_export;
 pub mod param;
\ No newline at end of file
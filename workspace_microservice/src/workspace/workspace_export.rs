@@ -0,0 +1,62 @@
+use sqlx::PgPool;
+use tonic::async_trait;
+use tonic::Status;
+
+use microservice_utils::server::export::{csv_file, db_export_rows, export_response};
+use microservice_utils::server::grpc::data_export::data_export_server::DataExport;
+use microservice_utils::server::grpc::data_export::{ExportFile, ExportUserDataRequest, ExportUserDataResponse};
+use microservice_utils::server::service_auth::{authorize, USER_SERVICE};
+
+// gRPC
+pub struct MyDataExport {
+    pool: PgPool
+}
+
+impl MyDataExport {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool
+        }
+    }
+}
+
+#[async_trait]
+impl DataExport for MyDataExport {
+    async fn export_user_data(
+        &self,
+        request: tonic::Request<ExportUserDataRequest>,
+    ) -> Result<tonic::Response<ExportUserDataResponse>, Status> {
+
+        authorize(&request, &[USER_SERVICE])?;
+
+        let req: ExportUserDataRequest = request.into_inner();
+        println!("Export User Data {}", req.user_id);
+
+        let files = export_files(&req.user_id, &self.pool)
+            .await
+            .map_err(|e| Status::internal(format!("{:?}", e)))?;
+        Ok(tonic::Response::new(export_response(files)))
+    }
+}
+
+async fn export_files(user_id: &String, pool: &PgPool) -> Result<Vec<ExportFile>, sqlx::Error> {
+    Ok(vec![
+        csv_file(
+            "workspaces",
+            &db_export_rows(
+                "SELECT workspace_id, name, description, role, created_at, updated_at FROM workspaces WHERE user_id = $1 ORDER BY created_at",
+                user_id,
+                pool,
+            ).await?,
+        ),
+        csv_file(
+            "workspace_settings",
+            &db_export_rows(
+                "SELECT s.workspace_id, s.mfa_required, s.updated_at FROM workspace_settings s
+                JOIN workspaces w ON w.workspace_id = s.workspace_id WHERE w.user_id = $1 AND w.role = 'owner'",
+                user_id,
+                pool,
+            ).await?,
+        ),
+    ])
+}
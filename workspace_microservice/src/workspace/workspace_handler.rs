@@ -1,14 +1,17 @@
 use std::sync::Arc;
+use microservice_utils::events::{enqueue, notify_user, Event};
+use microservice_utils::server::erasure::{db_erase_user_rows, Erasure};
+use microservice_utils::server::service_auth::{authorize, ALL_SERVICES, AUTH_SERVICE, USER_SERVICE};
 use microservice_utils::server::response::{AxumResult, AxumRes};
 use openapi_rs::openapi_proc_macro::handler;
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgPool};
 use sqlx::types::chrono::Utc;
 use sqlx::types::chrono::NaiveDateTime;
 use axum::extract::Extension;
 use axum::{extract::{Query, rejection::JsonRejection}, Json};
 use axum_macros::debug_handler;
 use rdkafka::producer::FutureProducer;
-use std::fmt::Write;
+use serde::Serialize;
 use uuid::Uuid;
 use tonic::async_trait;
 
@@ -22,20 +25,21 @@ use crate::workspace::workspace_type::{
     AddToWorkspace,
     RemoveFromWorkspace,
     Workspace,
+    WorkspaceMember,
+    WorkspaceMfa,
 };
 use crate::workspace::param::{RequiredId, OptionalId};
-use microservice_utils::server::grpc::{
-    add_workspace_id,
-    remove_workspace_id,
-};
 use microservice_utils::{jwt::{extractor::AuthToken}, server::response::into_reponse};
+use microservice_utils::server::tenant::{begin_service_tx, begin_tenant_tx};
+use microservice_utils::server::preferences::wants_notification;
+use microservice_utils::server::users::batch_get_users;
 use crate::producer::{
     producer::produce,
     ws_message::WsMessage,
 };
 
 use crate::workspace_service::workspace_service_server::WorkspaceService;
-use crate::workspace_service::{WorkspaceInfo, WorkspaceStatus};
+use crate::workspace_service::{Membership, MembershipsRequest, MembershipsResponse, MfaRequiredRequest, MfaRequiredResponse, WorkspaceInfo, WorkspaceStatus};
 
 // gRPC
 pub struct MyWorkspaceService {
@@ -57,6 +61,8 @@ impl WorkspaceService for MyWorkspaceService {
         request: tonic::Request<WorkspaceInfo>,
     ) -> Result<tonic::Response<WorkspaceStatus>, tonic::Status> {
         
+        authorize(&request, ALL_SERVICES)?;
+
         let req: WorkspaceInfo = request.into_inner();
         println!("Check Workspace {:?}", req);
 
@@ -79,6 +85,88 @@ impl WorkspaceService for MyWorkspaceService {
             }
         }
     }
+
+    async fn check_mfa_required(
+        &self,
+        request: tonic::Request<MfaRequiredRequest>,
+    ) -> Result<tonic::Response<MfaRequiredResponse>, tonic::Status> {
+
+        authorize(&request, &[AUTH_SERVICE])?;
+
+        let req: MfaRequiredRequest = request.into_inner();
+        println!("Check Mfa Required {:?}", req);
+
+        match db_mfa_required(&req.user_id, &self.pool).await {
+            Ok(required) => {
+                Ok(tonic::Response::new(MfaRequiredResponse {
+                    status: "success".to_string(),
+                    required,
+                }))
+            }
+            Err(e) => {
+                Err(tonic::Status::internal(format!("{:?}", e)))
+            }
+        }
+    }
+
+    async fn list_memberships(
+        &self,
+        request: tonic::Request<MembershipsRequest>,
+    ) -> Result<tonic::Response<MembershipsResponse>, tonic::Status> {
+
+        authorize(&request, &[USER_SERVICE])?;
+
+        let req: MembershipsRequest = request.into_inner();
+        println!("List Memberships of {} users", req.user_ids.len());
+
+        match db_list_memberships(&req.user_ids, &self.pool).await {
+            Ok(rows) => {
+                Ok(tonic::Response::new(MembershipsResponse {
+                    status: "success".to_string(),
+                    memberships: rows
+                        .into_iter()
+                        .map(|(user_id, workspace_id)| Membership {
+                            user_id,
+                            workspace_id: workspace_id.to_string(),
+                        })
+                        .collect(),
+                }))
+            }
+            Err(e) => {
+                Err(tonic::Status::internal(format!("{:?}", e)))
+            }
+        }
+    }
+}
+
+// Tells the member's open apps about a membership change, which they follow whatever the member's
+// preferences. The notice shown to the member can be turned off in them.
+async fn send_membership_change(
+    message_type: &str,
+    action: &str,
+    ws_info: &impl Serialize,
+    member_id: &String,
+    workspace_id: &Uuid,
+    producer: &FutureProducer,
+) {
+    let message = WsMessage {
+        user_id: member_id.clone(),
+        message_type: message_type.to_string(),
+        message: serde_json::to_string(ws_info).unwrap(),
+    };
+    let msg_str = serde_json::to_string(&message).unwrap();
+    let produce = produce(&msg_str, producer).await;
+    println!("Message sent to kafka {:?}", produce);
+
+    if wants_notification(member_id, Some(&workspace_id.to_string()), "workspace", "in_app").await {
+        let notice = serde_json::json!({
+            "action": action,
+            "workspace_id": workspace_id,
+        });
+        if let Err(e) = notify_user(member_id, "workspace_notice", &notice, producer).await {
+            println!("Unable to notify {}: {:?}", member_id, e);
+        }
+    }
 }
 
 // API
@@ -94,12 +182,19 @@ pub async fn create_workspace(
         Ok(payload) => {
             let ws_info = payload.0;
 
-            let create_ws = db_create_workspace(&user_id, &ws_info, &pool).await;
+            let create_ws = async {
+                let mut tx = begin_tenant_tx(&pool, &user_id, None).await?;
+                let ws = db_create_workspace(&user_id, &ws_info, &mut tx).await?;
+                // to user_microservice
+                enqueue(&Event::WorkspaceMemberAdded {
+                    user_id: ws.user_id.clone(),
+                    workspace_id: ws.workspace_id.to_string(),
+                }, &mut tx).await?;
+                tx.commit().await?;
+                Ok::<_, sqlx::Error>(ws)
+            }.await;
             match create_ws {
                 Ok(result) => {
-                    // to grpc
-                    let _ = add_workspace_id(&result.user_id, &result.workspace_id).await;
-
                     Ok(axum::Json(AxumRes{code:200, result:serde_json::json!(&result)}))
                 }
                 Err(e) => {
@@ -132,7 +227,12 @@ pub async fn update_workspace(
         Ok(payload) => {
             let ws_info = payload.0;
 
-            let create_ws = db_update_workspace(&user_id, &ws_info, &pool).await;
+            let create_ws = async {
+                let mut tx = begin_tenant_tx(&pool, &user_id, Some(&ws_info.id.to_string())).await?;
+                let ws = db_update_workspace(&user_id, &ws_info, &mut tx).await?;
+                tx.commit().await?;
+                Ok::<_, sqlx::Error>(ws)
+            }.await;
             match create_ws {
                 Ok(result) => {
                     Ok(axum::Json(AxumRes{code:200, result:serde_json::json!(&result)}))
@@ -163,7 +263,12 @@ pub async fn get_workspace(
     AuthToken(user_id): AuthToken,
     Extension(pool): Extension<Arc<PgPool>>,
 ) -> AxumResult<Json<AxumRes>> {
-    let workspace = db_get_workspace(&user_id, &params, &pool).await;
+    let workspace = async {
+        let mut tx = begin_tenant_tx(&pool, &user_id, None).await?;
+        let ws = db_get_workspace(&user_id, &params, &mut tx).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(ws)
+    }.await;
     match workspace {
         Ok(result) => {
             Ok(axum::Json(AxumRes{code:200, result:serde_json::json!(&result)}))
@@ -189,14 +294,21 @@ pub async fn delete_workspace(
         Ok(payload) => {
             let ws_info = payload.0;
 
-            let users = db_delete_workspace(&user_id, &ws_info, &pool).await;
+            let users = async {
+                let mut tx = begin_tenant_tx(&pool, &user_id, Some(&ws_info.id.to_string())).await?;
+                let users = db_delete_workspace(&user_id, &ws_info, &mut tx).await?;
+                // to user_microservice
+                for peer_id in &users {
+                    enqueue(&Event::WorkspaceMemberRemoved {
+                        user_id: peer_id.clone(),
+                        workspace_id: ws_info.id.to_string(),
+                    }, &mut tx).await?;
+                }
+                tx.commit().await?;
+                Ok::<_, sqlx::Error>(users)
+            }.await;
             match users {
-                Ok(result) => {
-                    // to grpc
-                    for peer_id in result {
-                        let _ = remove_workspace_id(&peer_id, &ws_info.id).await;
-                    }
-
+                Ok(_) => {
                     let ret = serde_json::json!({
                         "status": "success",
                     });  
@@ -233,22 +345,21 @@ pub async fn add_to_workspace(
         Ok(payload) => {
             let ws_info = payload.0;
 
-            let add_ws = db_add_to_workspace(&user_id, &ws_info, &pool).await;
+            let add_ws = async {
+                let mut tx = begin_tenant_tx(&pool, &user_id, Some(&ws_info.id.to_string())).await?;
+                let ws = db_add_to_workspace(&user_id, &ws_info, &mut tx).await?;
+                // to user_microservice
+                enqueue(&Event::WorkspaceMemberAdded {
+                    user_id: ws.user_id.clone(),
+                    workspace_id: ws.workspace_id.to_string(),
+                }, &mut tx).await?;
+                tx.commit().await?;
+                Ok::<_, sqlx::Error>(ws)
+            }.await;
             match add_ws {
                 Ok(result) => {
-                    // to grpc
-                    let _ = add_workspace_id(&result.user_id, &result.workspace_id).await;
-
                     // to broker
-                    let message = WsMessage {
-                        user_id: ws_info.peer_id.clone(),
-                        message_type: "add_workspace".to_string(),
-                        message: serde_json::to_string(&ws_info).unwrap(),
-                    };
-                    let msg_str = serde_json::to_string(&message).unwrap();  
-                
-                    let produce = produce(&msg_str, &producer).await;
-                    println!("Message sent to kafka {:?}", produce);
+                    send_membership_change("add_workspace", "added", &ws_info, &ws_info.peer_id, &ws_info.id, &producer).await;
 
                     // to frontend
                     Ok(axum::Json(AxumRes{code:200, result:serde_json::json!(&result)}))
@@ -284,22 +395,21 @@ pub async fn remove_from_workspace(
         Ok(payload) => {
             let ws_info = payload.0;
 
-            let remove_ws = db_remove_from_workspace(&user_id, &ws_info, &pool).await;
+            let remove_ws = async {
+                let mut tx = begin_tenant_tx(&pool, &user_id, Some(&ws_info.id.to_string())).await?;
+                db_remove_from_workspace(&user_id, &ws_info, &mut tx).await?;
+                // to user_microservice
+                enqueue(&Event::WorkspaceMemberRemoved {
+                    user_id: ws_info.peer_id.clone(),
+                    workspace_id: ws_info.id.to_string(),
+                }, &mut tx).await?;
+                tx.commit().await?;
+                Ok::<_, sqlx::Error>(())
+            }.await;
             match remove_ws {
                 Ok(_) => {
-                    // to grpc
-                    let _ = remove_workspace_id(&ws_info.peer_id, &ws_info.id).await;
-
                     // to broker
-                    let message = WsMessage {
-                        user_id: ws_info.peer_id.clone(),
-                        message_type: "remove_workspace".to_string(),
-                        message: serde_json::to_string(&ws_info).unwrap(),
-                    };
-                    let msg_str = serde_json::to_string(&message).unwrap();  
-
-                    let produce = produce(&msg_str, &producer).await;
-                    println!("Message sent to kafka {:?}", produce);
+                    send_membership_change("remove_workspace", "removed", &ws_info, &ws_info.peer_id, &ws_info.id, &producer).await;
 
                     let ret = serde_json::json!({
                         "status": "success",
@@ -325,8 +435,99 @@ pub async fn remove_from_workspace(
     }    
 }
 
+#[debug_handler]
+#[handler(method = "GET",tag = "workspace")]
+pub async fn get_workspace_members(
+    params: Query<RequiredId>,
+    AuthToken(user_id): AuthToken,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    let members = async {
+        let mut tx = begin_tenant_tx(&pool, &user_id, Some(&params.id.to_string())).await?;
+        let members = db_get_workspace_members(&user_id, &params.id, &mut tx).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(members)
+    }.await;
+    match members {
+        Ok(rows) => {
+            // Members are listed even when profiles can't be loaded
+            let user_ids: Vec<String> = rows.iter().map(|ws| ws.user_id.clone()).collect();
+            let mut profiles = batch_get_users(&user_ids, &[]).await.unwrap_or_else(|e| {
+                println!("{:?}", e.to_string());
+                Default::default()
+            });
+            let result: Vec<WorkspaceMember> = rows
+                .into_iter()
+                .map(|ws| WorkspaceMember {
+                    profile: profiles.remove(&ws.user_id),
+                    user_id: ws.user_id,
+                    role: ws.role,
+                })
+                .collect();
+            Ok(axum::Json(AxumRes{code:200, result:serde_json::json!(&result)}))
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });
+            Err(into_reponse(500, ret))
+        }
+    }
+}
+
+#[debug_handler]
+#[handler(method = "PUT",tag = "workspace")]
+pub async fn set_workspace_mfa(
+    payload: Result<Json<WorkspaceMfa>, JsonRejection>,
+    AuthToken(user_id): AuthToken,
+    Extension(pool): Extension<Arc<PgPool>>,
+) -> AxumResult<Json<AxumRes>> {
+    match payload {
+        Ok(payload) => {
+            let mfa = payload.0;
+
+            let set_mfa = async {
+                let mut tx = begin_tenant_tx(&pool, &user_id, Some(&mfa.id.to_string())).await?;
+                let ws = db_get_workspace_by_id(&user_id, &mfa.id, &mut tx).await?;
+                if ws.role != "owner" {
+                    return Ok(false);
+                }
+                db_set_workspace_mfa(&mfa, &mut tx).await?;
+                tx.commit().await?;
+                Ok::<_, sqlx::Error>(true)
+            }.await;
+            match set_mfa {
+                Ok(true) => {
+                    Ok(axum::Json(AxumRes{code:200, result:serde_json::json!(&mfa)}))
+                }
+                Ok(false) => {
+                    let ret = serde_json::json!({
+                        "error": "Only the workspace owner can change this setting",
+                    });
+                    Err(into_reponse(403, ret))
+                }
+                Err(e) => {
+                    println!("{:?}", e.to_string());
+                    let ret = serde_json::json!({
+                        "error": format!("{:?}", e),
+                    });    
+                    Err(into_reponse(500, ret))
+                }
+            }
+        }
+        Err(e) => {
+            println!("{:?}", e.to_string());
+            let ret = serde_json::json!({
+                "error": format!("{:?}", e),
+            });    
+            Err(into_reponse(400, ret))   
+        }
+    }
+}
+
 // Database
-pub async fn db_create_workspace(user_id: &String, workspace: &CreateWorkspace, pool: &PgPool) -> Result<Workspace, sqlx::Error> {
+pub async fn db_create_workspace(user_id: &String, workspace: &CreateWorkspace, conn: &mut PgConnection) -> Result<Workspace, sqlx::Error> {
     let out_workspace = sqlx::query_as!(Workspace, 
         r#"INSERT INTO workspaces (
             user_id, name, role, description) VALUES ($1, $2, $3, $4) RETURNING *"#,
@@ -334,11 +535,11 @@ pub async fn db_create_workspace(user_id: &String, workspace: &CreateWorkspace,
             workspace.name,
             workspace.role,
             workspace.description
-    ).fetch_one(pool).await?;
+    ).fetch_one(&mut *conn).await?;
     Ok(out_workspace)
 }
 
-pub async fn db_update_workspace(user_id: &String, workspace: &UpdateWorkspace, pool: &PgPool) -> Result<Workspace, sqlx::Error> {
+pub async fn db_update_workspace(user_id: &String, workspace: &UpdateWorkspace, conn: &mut PgConnection) -> Result<Workspace, sqlx::Error> {
     let out_workspace = sqlx::query_as!(Workspace, 
         r#"UPDATE workspaces SET name = $1, role = $2, description = $3, updated_at = $4 WHERE user_id = $5 AND workspace_id = $6 RETURNING *"#,
             workspace.name,
@@ -347,43 +548,52 @@ pub async fn db_update_workspace(user_id: &String, workspace: &UpdateWorkspace,
             Utc::now().naive_utc() as NaiveDateTime,
             user_id,
             workspace.id
-    ).fetch_one(pool).await?;
+    ).fetch_one(&mut *conn).await?;
     Ok(out_workspace)
 }
 
-pub async fn db_get_workspace(user_id: &String, params: &OptionalId, pool: &PgPool) -> Result<Vec<Workspace>, sqlx::Error> {
-    let mut query = "SELECT * FROM workspaces WHERE ".to_string();
-    write!(query, "user_id = '{}' ", user_id).unwrap();
-
-    if let Some(params_id) = &params.id {
-        write!(query, "And workspace_id = '{}' ", params_id).unwrap();
-    }
-
-    let str_query: &str = &query[..];
-    let workspaces = sqlx::query_as::<_, Workspace>(str_query)
-        .fetch_all(pool)
+pub async fn db_get_workspace(user_id: &String, params: &OptionalId, conn: &mut PgConnection) -> Result<Vec<Workspace>, sqlx::Error> {
+    let workspaces = sqlx::query_as::<_, Workspace>(
+        "SELECT * FROM workspaces WHERE user_id = $1 AND ($2::uuid IS NULL OR workspace_id = $2)")
+        .bind(user_id)
+        .bind(params.id)
+        .fetch_all(&mut *conn)
         .await?;
     Ok(workspaces)    
 }
 
-pub async fn db_get_workspace_by_id(user_id: &String, id: &Uuid, pool: &PgPool) -> Result<Workspace, sqlx::Error> {
-    let workspace = sqlx::query_as!(Workspace, r#"SELECT * FROM workspaces WHERE user_id = $1 AND workspace_id = $2"#, user_id, id).fetch_one(pool).await?;
+pub async fn db_get_workspace_by_id(user_id: &String, id: &Uuid, conn: &mut PgConnection) -> Result<Workspace, sqlx::Error> {
+    let workspace = sqlx::query_as!(Workspace, r#"SELECT * FROM workspaces WHERE user_id = $1 AND workspace_id = $2"#, user_id, id).fetch_one(&mut *conn).await?;
     Ok(workspace)    
 }
 
-pub async fn db_delete_workspace(_user_id: &String, params: &RequiredId, pool: &PgPool) -> Result<Vec<String>, sqlx::Error> {
+pub async fn db_get_workspace_members(user_id: &String, id: &Uuid, conn: &mut PgConnection) -> Result<Vec<Workspace>, sqlx::Error> {
+    // only members can list the members of the workspace
+    let _ = db_get_workspace_by_id(user_id, id, &mut *conn).await?;
+
+    let members = sqlx::query_as::<_, Workspace>("SELECT * FROM workspaces WHERE workspace_id = $1 ORDER BY created_at")
+        .bind(id)
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(members)
+}
+
+pub async fn db_delete_workspace(user_id: &String, params: &RequiredId, conn: &mut PgConnection) -> Result<Vec<String>, sqlx::Error> {
+    // only members can delete the workspace
+    let _ = db_get_workspace_by_id(user_id, &params.id, &mut *conn).await?;
+
     let mut users = Vec::new();
-    let rows = sqlx::query!("SELECT user_id FROM workspaces WHERE workspace_id = $1", params.id).fetch_all(pool).await?;
+    let rows = sqlx::query!("SELECT user_id FROM workspaces WHERE workspace_id = $1", params.id).fetch_all(&mut *conn).await?;
     for row in rows {
         users.push(row.user_id);
     }
     
-    let _ = sqlx::query!("DELETE FROM workspaces WHERE workspace_id = $1", params.id).execute(pool).await?;    
+    let _ = sqlx::query!("DELETE FROM workspaces WHERE workspace_id = $1", params.id).execute(&mut *conn).await?;    
     Ok(users)
 }
 
-pub async fn db_add_to_workspace(user_id: &String, params: &AddToWorkspace, pool: &PgPool) -> Result<Workspace, sqlx::Error> {
-    let ws = db_get_workspace_by_id(&user_id, &params.id, &pool).await;
+pub async fn db_add_to_workspace(user_id: &String, params: &AddToWorkspace, conn: &mut PgConnection) -> Result<Workspace, sqlx::Error> {
+    let ws = db_get_workspace_by_id(&user_id, &params.id, &mut *conn).await;
     match ws {
         Ok(result) => {
             let out_workspace = sqlx::query_as!(Workspace, 
@@ -394,7 +604,7 @@ pub async fn db_add_to_workspace(user_id: &String, params: &AddToWorkspace, pool
                     result.name,
                     params.role,
                     result.description
-            ).fetch_one(pool).await?;
+            ).fetch_one(&mut *conn).await?;
             Ok(out_workspace)
         }
         Err(e) => {
@@ -404,11 +614,14 @@ pub async fn db_add_to_workspace(user_id: &String, params: &AddToWorkspace, pool
     }    
 }
 
-pub async fn db_remove_from_workspace(_user_id: &String, params: &RemoveFromWorkspace, pool: &PgPool) -> Result<(), sqlx::Error> {
+pub async fn db_remove_from_workspace(user_id: &String, params: &RemoveFromWorkspace, conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+    // only members can remove others from the workspace
+    let _ = db_get_workspace_by_id(user_id, &params.id, &mut *conn).await?;
+
     let _ = sqlx::query!("DELETE FROM workspaces WHERE workspace_id = $1 AND user_id = $2", 
         params.id,
         params.peer_id,
-    ).execute(pool).await?;
+    ).execute(&mut *conn).await?;
     Ok(())
 }
 
@@ -416,6 +629,241 @@ pub async fn db_check_workspace(
     req: &WorkspaceInfo,
     pool: &PgPool,
 ) -> Result<String, sqlx::Error> {
-    let row = sqlx::query!("SELECT workspace_id FROM workspaces where user_id = $1 AND workspace_id = $2", req.user_id, Uuid::parse_str(&req.workspace_id).unwrap()).fetch_one(pool).await?;
+    let workspace_id = Uuid::parse_str(&req.workspace_id).map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+    let mut tx = begin_tenant_tx(pool, &req.user_id, Some(&req.workspace_id)).await?;
+    let row = sqlx::query!("SELECT workspace_id FROM workspaces where user_id = $1 AND workspace_id = $2", req.user_id, workspace_id).fetch_one(&mut tx).await?;
+    tx.commit().await?;
     Ok(row.workspace_id.to_string())
-}
\ No newline at end of file
+}
+
+pub async fn db_list_memberships(user_ids: &[String], pool: &PgPool) -> Result<Vec<(String, Uuid)>, sqlx::Error> {
+    // Each user's own rows, in their tenant
+    let mut rows = Vec::new();
+    for user_id in user_ids {
+        let mut tx = begin_tenant_tx(pool, user_id, None).await?;
+        let memberships: Vec<(String, Uuid)> = sqlx::query_as(
+            "SELECT user_id, workspace_id FROM workspaces WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_all(&mut tx)
+            .await?;
+        tx.commit().await?;
+        rows.extend(memberships);
+    }
+    Ok(rows)
+}
+
+pub async fn db_set_workspace_mfa(mfa: &WorkspaceMfa, conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+    let _ = sqlx::query(
+        "INSERT INTO workspace_settings (workspace_id, mfa_required, updated_at) VALUES ($1, $2, $3) ON CONFLICT (workspace_id) DO UPDATE SET mfa_required = $2, updated_at = $3")
+        .bind(mfa.id)
+        .bind(mfa.required)
+        .bind(Utc::now().naive_utc())
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn db_mfa_required(user_id: &String, pool: &PgPool) -> Result<bool, sqlx::Error> {
+    // The settings of all the user's workspaces, so not one tenant
+    let mut tx = begin_service_tx(pool, "mfa_required").await?;
+    let (required,): (bool,) = sqlx::query_as(
+        "SELECT EXISTS (SELECT 1 FROM workspace_settings s JOIN workspaces w ON w.workspace_id = s.workspace_id WHERE w.user_id = $1 AND s.mfa_required)")
+        .bind(user_id)
+        .fetch_one(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(required)
+}
+// Moves the memberships of a merged account. Where both accounts are members the
+// target's row is kept, taking over ownership from the source.
+pub async fn db_merge_user_workspaces(source: &String, target: &String, pool: &PgPool) -> Result<(), sqlx::Error> {
+    let mut tx = begin_service_tx(pool, "merge_user_workspaces").await?;
+    sqlx::query(
+        "UPDATE workspaces t SET role = s.role, updated_at = $3 FROM workspaces s 
+        WHERE s.user_id = $1 AND t.user_id = $2 AND s.workspace_id = t.workspace_id AND s.role = 'owner'")
+        .bind(source)
+        .bind(target)
+        .bind(Utc::now().naive_utc())
+        .execute(&mut tx)
+        .await?;
+    sqlx::query(
+        "DELETE FROM workspaces s USING workspaces t WHERE s.user_id = $1 AND t.user_id = $2 AND s.workspace_id = t.workspace_id")
+        .bind(source)
+        .bind(target)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query("UPDATE workspaces SET user_id = $1, updated_at = $3 WHERE user_id = $2")
+        .bind(target)
+        .bind(source)
+        .bind(Utc::now().naive_utc())
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+// Erases the memberships of a deleted account. With "transfer" each workspace it owns goes to
+// its longest-standing member; with "delete", or when nobody is left, the workspace is deleted.
+pub async fn erase_user_workspaces(user_id: &String, workspaces: &str, pool: &PgPool) -> Result<Erasure, sqlx::Error> {
+    db_release_owned_workspaces(user_id, workspaces == "transfer", pool).await?;
+    db_erase_user_rows(
+        user_id,
+        &["DELETE FROM workspaces WHERE user_id = $1"],
+        &["SELECT count(*) FROM workspaces WHERE user_id = $1"],
+        pool,
+    )
+    .await
+}
+
+// Removes the memberships of other users in deleted workspaces, telling user_microservice.
+async fn db_release_owned_workspaces(user_id: &String, transfer: bool, pool: &PgPool) -> Result<(), sqlx::Error> {
+    let mut tx = begin_service_tx(pool, "release_owned_workspaces").await?;
+    let owned: Vec<(Uuid,)> = sqlx::query_as("SELECT workspace_id FROM workspaces WHERE user_id = $1 AND role = 'owner'")
+        .bind(user_id)
+        .fetch_all(&mut tx)
+        .await?;
+
+    for (workspace_id,) in owned {
+        if transfer {
+            let heir: Option<(String,)> = sqlx::query_as(
+                "UPDATE workspaces SET role = 'owner', updated_at = $3 WHERE id = (
+                    SELECT id FROM workspaces WHERE workspace_id = $1 AND user_id <> $2 ORDER BY created_at LIMIT 1
+                ) RETURNING user_id")
+                .bind(workspace_id)
+                .bind(user_id)
+                .bind(Utc::now().naive_utc())
+                .fetch_optional(&mut tx)
+                .await?;
+            if heir.is_some() {
+                continue;
+            }
+        }
+
+        let members: Vec<(String,)> = sqlx::query_as("DELETE FROM workspaces WHERE workspace_id = $1 AND user_id <> $2 RETURNING user_id")
+            .bind(workspace_id)
+            .bind(user_id)
+            .fetch_all(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM workspace_settings WHERE workspace_id = $1")
+            .bind(workspace_id)
+            .execute(&mut tx)
+            .await?;
+        for (member_id,) in members {
+            enqueue(&Event::WorkspaceMemberRemoved { user_id: member_id, workspace_id: workspace_id.to_string() }, &mut tx).await?;
+        }
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::{tenant_test_tx, test_pool, test_user_id};
+
+    // Events waiting in the outbox about `user_id`
+    async fn outbox(user_id: &String, pool: &PgPool) -> Vec<Event> {
+        let rows: Vec<(String,)> = sqlx::query_as("SELECT payload FROM event_outbox WHERE payload LIKE '%' || $1 || '%' ORDER BY id")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await
+            .unwrap();
+        rows.into_iter().map(|(payload,)| serde_json::from_str(&payload).unwrap()).collect()
+    }
+
+    async fn create(user_id: &String, pool: &PgPool) -> Uuid {
+        let workspace = CreateWorkspace { name: "Team".to_string(), role: "owner".to_string(), description: None };
+        let res = create_workspace(Ok(Json(workspace)), AuthToken(user_id.clone()), Extension(Arc::new(pool.clone())))
+            .await
+            .unwrap();
+        serde_json::from_value(res.0.result["workspace_id"].clone()).unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn membership_changes_are_queued_with_the_change() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        let workspace_id = create(&user_id, &pool).await;
+        assert_eq!(
+            outbox(&user_id, &pool).await,
+            vec![Event::WorkspaceMemberAdded { user_id: user_id.clone(), workspace_id: workspace_id.to_string() }]
+        );
+
+        delete_workspace(Ok(Json(RequiredId { id: workspace_id })), AuthToken(user_id.clone()), Extension(Arc::new(pool.clone())))
+            .await
+            .unwrap();
+        assert_eq!(
+            outbox(&user_id, &pool).await.last(),
+            Some(&Event::WorkspaceMemberRemoved { user_id: user_id.clone(), workspace_id: workspace_id.to_string() })
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn failed_changes_queue_nothing() {
+        let pool = test_pool().await;
+        let user_id = test_user_id();
+        // not a member of it
+        let res = delete_workspace(Ok(Json(RequiredId { id: Uuid::new_v4() })), AuthToken(user_id.clone()), Extension(Arc::new(pool.clone()))).await;
+        assert!(res.is_err());
+        assert!(outbox(&user_id, &pool).await.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn memberships_are_listed_per_user() {
+        let pool = test_pool().await;
+        let (first, second) = (test_user_id(), test_user_id());
+        let first_workspace = create(&first, &pool).await;
+        let second_workspace = create(&second, &pool).await;
+
+        let mut memberships = db_list_memberships(&[first.clone(), second.clone()], &pool).await.unwrap();
+        memberships.sort();
+        let mut expected = vec![(first, first_workspace), (second, second_workspace)];
+        expected.sort();
+        assert_eq!(memberships, expected);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn workspaces_of_other_tenants_are_out_of_reach() {
+        let pool = test_pool().await;
+        let (owner, other) = (test_user_id(), test_user_id());
+        let workspace_id = create(&owner, &pool).await;
+
+        let mut tx = tenant_test_tx(&pool, &other, None).await;
+        // The service's own query, checking the membership of the owner rather than the caller
+        assert!(db_delete_workspace(&owner, &RequiredId { id: workspace_id }, &mut tx).await.is_err());
+        // And the queries without any user_id filter
+        let rows: Vec<(String,)> = sqlx::query_as("SELECT user_id FROM workspaces WHERE workspace_id = $1")
+            .bind(workspace_id)
+            .fetch_all(&mut tx)
+            .await
+            .unwrap();
+        assert!(rows.is_empty());
+        let renamed = sqlx::query("UPDATE workspaces SET name = 'Taken' WHERE workspace_id = $1")
+            .bind(workspace_id)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(renamed.rows_affected(), 0);
+        let deleted = sqlx::query("DELETE FROM workspaces WHERE workspace_id = $1")
+            .bind(workspace_id)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(deleted.rows_affected(), 0);
+        assert!(sqlx::query("INSERT INTO workspaces (workspace_id, user_id, name, role) VALUES ($1, $2, 'Team', 'owner')")
+            .bind(workspace_id)
+            .bind(&owner)
+            .execute(&mut tx)
+            .await
+            .is_err());
+        tx.rollback().await.unwrap();
+
+        let mut tx = begin_service_tx(&pool, "test").await.unwrap();
+        let workspace = db_get_workspace_by_id(&owner, &workspace_id, &mut tx).await.unwrap();
+        assert_eq!(workspace.name, "Team");
+    }
+}
@@ -6,6 +6,8 @@ use serde::Serialize;
 use sqlx::types::chrono::{NaiveDate, NaiveDateTime};
 use uuid::Uuid;
 
+use microservice_utils::server::users::Profile;
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, JsonSchema, Deserialize)]
 pub struct CreateWorkspace {
     pub name: String,                // workspace name
@@ -34,6 +36,12 @@ pub struct RemoveFromWorkspace {
     pub peer_id: String, // peer id (stytch user id)
 }
 
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorkspaceMfa {
+    pub id: Uuid,       // workspace id
+    pub required: bool, // members must use two-factor authentication
+}
+
 #[derive(sqlx::FromRow, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Workspace {
     pub id: i32,
@@ -46,6 +54,13 @@ pub struct Workspace {
     pub updated_at: NaiveDateTime,   // updated date
 }
 
+#[derive(Default, Debug, Clone, PartialEq, Serialize, JsonSchema, Deserialize)]
+pub struct WorkspaceMember {
+    pub user_id: String,          // user id (stytch user id)
+    pub role: String,             // workspace role, owner, editor, viewer, guest
+    pub profile: Option<Profile>, // None when user_microservice has no profile for the user
+}
+
 impl JsonSchema for RemoveFromWorkspace {
     fn schema_name() -> String {
         "RemoveFromWorkspace".into()
@@ -79,6 +94,17 @@ impl JsonSchema for UpdateWorkspace {
     }
 }
 
+impl JsonSchema for WorkspaceMfa {
+    fn schema_name() -> String {
+        "WorkspaceMfa".into()
+    }
+
+    fn json_schema(_gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        let root_schema = schema_for_value!(WorkspaceMfa::default());
+        Schema::Object(root_schema.schema)
+    }
+}
+
 impl JsonSchema for Workspace {
     fn schema_name() -> String {
         "Workspace".into()